@@ -0,0 +1,73 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USER_TARGET: &str = "riscv64gc-unknown-none-elf";
+
+// Builds the init program in user/init so the kernel can embed it. It gets
+// a target directory of its own, as this build holds the lock on ours, and
+// its own linker script.
+fn build_init(root: &Path, out: &Path) {
+    let init = root.join("user/init");
+    let target_dir = out.join("user");
+
+    let status = Command::new(env::var("CARGO").unwrap())
+        .current_dir(&init)
+        .args([
+            "build",
+            "--release",
+            "--target",
+            USER_TARGET,
+            "--target-dir",
+        ])
+        .arg(&target_dir)
+        .env(
+            "CARGO_ENCODED_RUSTFLAGS",
+            format!("-Clink-arg=-T{}", init.join("link.ld").display()),
+        )
+        .env_remove("RUSTFLAGS")
+        .status()
+        .expect("failed to run cargo for user/init");
+    assert!(status.success(), "building user/init failed");
+
+    fs::copy(
+        target_dir.join(USER_TARGET).join("release/init"),
+        out.join("init"),
+    )
+    .expect("user/init produced no binary");
+
+    println!("cargo:rerun-if-changed=user/init/src");
+    println!("cargo:rerun-if-changed=user/init/link.ld");
+    println!("cargo:rerun-if-changed=user/init/Cargo.toml");
+}
+
+// Every kernel image, tests included, is laid out by src/kernel.ld, and
+// boot.rs relies on the symbols it provides.
+fn install_linker_script(root: &Path) {
+    let script = root.join("src/kernel.ld");
+    println!("cargo:rustc-link-arg=-T{}", script.display());
+    println!("cargo:rerun-if-changed={}", script.display());
+}
+
+// Links in the cpio archive RISCVOS_INITRAMFS names, if it's set, to unpack
+// at boot. An empty file stands in for it otherwise.
+fn copy_initramfs(out: &Path) {
+    let destination = out.join("initramfs.cpio");
+    match env::var_os("RISCVOS_INITRAMFS") {
+        Some(archive) => {
+            fs::copy(&archive, &destination).expect("failed to read RISCVOS_INITRAMFS");
+            println!("cargo:rerun-if-changed={}", Path::new(&archive).display());
+        }
+        None => fs::write(&destination, []).unwrap(),
+    }
+    println!("cargo:rerun-if-env-changed=RISCVOS_INITRAMFS");
+}
+
+fn main() {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    install_linker_script(&root);
+    build_init(&root, &out);
+    copy_initramfs(&out);
+}
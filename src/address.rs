@@ -0,0 +1,292 @@
+use crate::page_allocator::PAGE_SIZE;
+
+// Sv39 maps 39 bit virtual addresses onto 56 bit physical ones.
+const PHYSICAL_ADDRESS_BITS: u32 = 56;
+const VIRTUAL_ADDRESS_BITS: u32 = 39;
+
+#[derive(Debug)]
+pub enum VirtualAddressError {
+    OutOfVirtualMemoryRange,
+    NotMapped,
+    Unaligned,
+}
+
+fn align_down(value: u64, align: u64) -> u64 {
+    assert!(
+        align.is_power_of_two(),
+        "Alignment {} isn't a power of two",
+        align
+    );
+    value & !(align - 1)
+}
+
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    assert!(
+        align.is_power_of_two(),
+        "Alignment {} isn't a power of two",
+        align
+    );
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr(u64);
+
+impl PhysAddr {
+    pub const fn new(address: u64) -> Self {
+        assert!(
+            address >> PHYSICAL_ADDRESS_BITS == 0,
+            "Physical address out of range"
+        );
+        Self(address)
+    }
+
+    fn checked(address: u64) -> Option<Self> {
+        (address >> PHYSICAL_ADDRESS_BITS == 0).then_some(Self(address))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    // Physical memory is identity mapped, so the kernel can use it directly.
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    pub fn align_down(self, align: u64) -> Self {
+        Self(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: u64) -> Option<Self> {
+        Self::checked(align_up(self.0, align)?)
+    }
+
+    pub fn is_aligned(self, align: u64) -> bool {
+        align_down(self.0, align) == self.0
+    }
+
+    pub fn page_offset(self) -> u64 {
+        self.0 % PAGE_SIZE
+    }
+
+    pub fn add_offset(self, bytes: u64) -> Option<Self> {
+        Self::checked(self.0.checked_add(bytes)?)
+    }
+
+    pub fn checked_sub(self, bytes: u64) -> Option<Self> {
+        Some(Self(self.0.checked_sub(bytes)?))
+    }
+}
+
+// An Sv39 address, kept sign extended from bit 38 as the MMU expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr(u64);
+
+impl VirtAddr {
+    // Unlike try_from, takes only addresses that are already sign extended.
+    fn canonical(value: u64) -> Option<Self> {
+        let high = value >> (VIRTUAL_ADDRESS_BITS - 1);
+        (high == 0 || high == u64::MAX >> (VIRTUAL_ADDRESS_BITS - 1)).then_some(Self(value))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    pub fn page_table_index(&self, level: u64) -> u64 {
+        let mask = (1 << 9) - 1;
+        (self.0 >> (12 + level * 9)) & mask
+    }
+
+    // How far into its page the address is.
+    pub fn offset(&self) -> u64 {
+        self.0 & (PAGE_SIZE - 1)
+    }
+
+    pub fn page_base(self) -> Self {
+        self.align_down(PAGE_SIZE)
+    }
+
+    pub fn align_down(self, align: u64) -> Self {
+        Self(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: u64) -> Option<Self> {
+        Self::canonical(align_up(self.0, align)?)
+    }
+
+    pub fn is_aligned(self, align: u64) -> bool {
+        align_down(self.0, align) == self.0
+    }
+
+    // None if that would run off the end of either half of the address
+    // space, rather than wrapping into the other.
+    pub fn add_offset(self, bytes: u64) -> Option<Self> {
+        Self::canonical(self.0.checked_add(bytes)?)
+    }
+}
+
+impl TryFrom<u64> for VirtAddr {
+    type Error = VirtualAddressError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        let mask = !((1 << VIRTUAL_ADDRESS_BITS) - 1);
+        if value & mask != 0 {
+            return Err(VirtualAddressError::OutOfVirtualMemoryRange);
+        }
+
+        match (value >> 38) & 1 {
+            1 => Ok(VirtAddr(value | mask)),
+            0 => Ok(VirtAddr(value & (!mask))),
+            e => panic!("Bitwise and with 1 returned {}", e),
+        }
+    }
+}
+
+// A page of physical memory. Allocators hand these out, so it isn't Copy.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysFrame {
+    start: PhysAddr,
+}
+
+impl PhysFrame {
+    pub fn containing_address(address: PhysAddr) -> Self {
+        Self {
+            start: address.align_down(PAGE_SIZE),
+        }
+    }
+
+    pub fn from_start_address(address: PhysAddr) -> Option<Self> {
+        address
+            .is_aligned(PAGE_SIZE)
+            .then_some(Self { start: address })
+    }
+
+    pub fn from_number(number: u64) -> Self {
+        Self {
+            start: PhysAddr::new(number * PAGE_SIZE),
+        }
+    }
+
+    pub fn start_address(&self) -> PhysAddr {
+        self.start
+    }
+
+    pub fn number(&self) -> u64 {
+        self.start.0 / PAGE_SIZE
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtPage {
+    start: VirtAddr,
+}
+
+impl VirtPage {
+    pub fn containing_address(address: VirtAddr) -> Self {
+        Self {
+            start: address.align_down(PAGE_SIZE),
+        }
+    }
+
+    pub fn from_start_address(address: VirtAddr) -> Option<Self> {
+        address
+            .is_aligned(PAGE_SIZE)
+            .then_some(Self { start: address })
+    }
+
+    // Where the kernel's identity map puts `frame`.
+    pub fn identity(frame: &PhysFrame) -> Result<Self, VirtualAddressError> {
+        let start = frame.start_address().as_u64().try_into()?;
+        Ok(Self { start })
+    }
+
+    pub fn start_address(&self) -> VirtAddr {
+        self.start
+    }
+}
+
+impl TryFrom<u64> for VirtPage {
+    type Error = VirtualAddressError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Self::from_start_address(value.try_into()?).ok_or(VirtualAddressError::Unaligned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prng::Xoshiro256StarStar;
+
+    #[test_case]
+    fn physical_addresses_align_within_range() {
+        let address = PhysAddr::new(0x8020_1234);
+        assert_eq!(address.align_down(PAGE_SIZE), PhysAddr::new(0x8020_1000));
+        assert_eq!(
+            address.align_up(PAGE_SIZE),
+            Some(PhysAddr::new(0x8020_2000))
+        );
+        assert_eq!(address.page_offset(), 0x234);
+        assert!(PhysAddr::new((1 << 56) - 1).add_offset(1).is_none());
+        assert!(PhysAddr::new(0).checked_sub(1).is_none());
+    }
+
+    #[test_case]
+    fn virtual_addresses_stay_canonical() {
+        let top: VirtAddr = 0x7f_ffff_f000.try_into().unwrap();
+        assert_eq!(top.as_u64(), 0xffff_ffff_ffff_f000);
+        assert_eq!(top.add_offset(0xfff).unwrap().as_u64(), u64::MAX);
+        assert!(top.align_up(1 << 20).is_none());
+
+        let below_hole: VirtAddr = 0x3f_ffff_f000.try_into().unwrap();
+        assert!(below_hole.add_offset(PAGE_SIZE).is_none());
+    }
+
+    #[test_case]
+    fn virtual_addresses_split_into_page_base_and_offset() {
+        let mut rng = Xoshiro256StarStar::from_seed(849);
+        for _ in 0..1000 {
+            let raw = rng.next_below(1 << 39);
+            let address: VirtAddr = raw.try_into().unwrap();
+            let base = address.page_base();
+            assert!(base.is_aligned(PAGE_SIZE));
+            assert!(address.offset() < PAGE_SIZE);
+            assert_eq!(address.offset(), raw % PAGE_SIZE);
+            assert_eq!(base.add_offset(address.offset()), Some(address));
+            assert_eq!(VirtPage::containing_address(address).start_address(), base);
+        }
+    }
+
+    #[test_case]
+    fn frames_and_pages_must_be_aligned() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8020_1234));
+        assert_eq!(frame.number(), 0x80201);
+        assert_eq!(PhysFrame::from_number(0x80201), frame);
+        assert!(PhysFrame::from_start_address(PhysAddr::new(0x8020_1234)).is_none());
+
+        assert!(matches!(
+            VirtPage::try_from(0x1234),
+            Err(VirtualAddressError::Unaligned)
+        ));
+        let page = VirtPage::identity(&frame).unwrap();
+        assert_eq!(page.start_address().as_u64(), 0x8020_1000);
+    }
+}
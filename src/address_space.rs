@@ -0,0 +1,956 @@
+use crate::address::{PhysAddr, PhysFrame, VirtPage};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::page_cache;
+use crate::page_table::{PageTableEntry, PageTableEntryMode, VirtualMemory};
+use crate::rusage::PageCharge;
+use crate::satp::{self, Satp};
+use crate::shm::Segment;
+use crate::tlb;
+use crate::trap::LockIrqSave;
+use crate::VIRTUAL_MEMORY;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// User space gets the top quarter of the lower half of the Sv39 address
+// range, above the kernel heap and out of reach of the identity mappings.
+// Everything else is shared with the kernel's own tables.
+pub const USER_START: u64 = 0x30_0000_0000;
+pub const USER_END: u64 = 0x40_0000_0000;
+
+const USER_ROOT_ENTRIES: Range<usize> = 192..256;
+const ROOT_ENTRIES: usize = 512;
+
+static KERNEL_SATP: AtomicU64 = AtomicU64::new(0);
+
+// Pages shared copy-on-write, with how many address spaces map each. A
+// page that isn't in here has only the one.
+static SHARED_PAGES: Mutex<BTreeMap<PhysAddr, usize>> = Mutex::new(BTreeMap::new());
+
+fn share(page: &PhysFrame) {
+    *SHARED_PAGES
+        .lock_irqsave()
+        .entry(page.start_address())
+        .or_insert(1) += 1;
+}
+
+fn is_shared(page: &PhysFrame) -> bool {
+    SHARED_PAGES
+        .lock_irqsave()
+        .contains_key(&page.start_address())
+}
+
+// Drops one address space's hold on `page`, freeing it once none is left.
+// User pages come and go through this hart's page cache, which can take
+// the page allocator's lock, so that mustn't be held.
+fn release(page: PhysFrame) {
+    let last = {
+        let mut shared = SHARED_PAGES.lock_irqsave();
+        match shared.get_mut(&page.start_address()) {
+            Some(count) if *count > 2 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                shared.remove(&page.start_address());
+                false
+            }
+            None => true,
+        }
+    };
+    if last {
+        page_cache::free_page(page);
+    }
+}
+
+fn merge_shootdown(pending: &mut Option<tlb::Shootdown>, shootdown: tlb::Shootdown) {
+    match pending {
+        Some(pending) => pending.merge(shootdown),
+        None => *pending = Some(shootdown),
+    }
+}
+
+fn is_readable(mode: PageTableEntryMode) -> bool {
+    !matches!(
+        mode,
+        PageTableEntryMode::PageTablePointer | PageTableEntryMode::ExecuteOnly
+    )
+}
+
+fn is_executable(mode: PageTableEntryMode) -> bool {
+    matches!(
+        mode,
+        PageTableEntryMode::ExecuteOnly
+            | PageTableEntryMode::ReadExecute
+            | PageTableEntryMode::ReadWriteExecute
+    )
+}
+
+fn is_writable(mode: PageTableEntryMode) -> bool {
+    matches!(
+        mode,
+        PageTableEntryMode::ReadWrite | PageTableEntryMode::ReadWriteExecute
+    )
+}
+
+fn without_write(mode: PageTableEntryMode) -> PageTableEntryMode {
+    match mode {
+        PageTableEntryMode::ReadWrite => PageTableEntryMode::ReadOnly,
+        PageTableEntryMode::ReadWriteExecute => PageTableEntryMode::ReadExecute,
+        mode => mode,
+    }
+}
+
+// Set once the kernel page tables are built, before any thread runs.
+pub fn set_kernel_satp(satp: Satp) {
+    KERNEL_SATP.store(satp.bits(), Ordering::Relaxed);
+}
+
+pub fn kernel_satp() -> Satp {
+    Satp::from_bits(KERNEL_SATP.load(Ordering::Relaxed))
+}
+
+pub fn current_satp() -> Satp {
+    satp::read_satp()
+}
+
+// Without ASIDs, every switch has to flush the whole TLB.
+pub fn activate(satp: Satp) {
+    if current_satp() != satp {
+        unsafe { satp::write_satp(satp) }.expect("Switched to page tables the hart can't use");
+    }
+}
+
+pub fn is_user_range(start: u64, length: u64) -> bool {
+    start >= USER_START && start.checked_add(length).is_some_and(|end| end <= USER_END)
+}
+
+// None if it allows no access at all.
+pub fn mode_for(read: bool, write: bool, execute: bool) -> Option<PageTableEntryMode> {
+    match (read, write, execute) {
+        (_, true, true) => Some(PageTableEntryMode::ReadWriteExecute),
+        (_, true, false) => Some(PageTableEntryMode::ReadWrite),
+        (true, false, true) => Some(PageTableEntryMode::ReadExecute),
+        (false, false, true) => Some(PageTableEntryMode::ExecuteOnly),
+        (true, false, false) => Some(PageTableEntryMode::ReadOnly),
+        (false, false, false) => None,
+    }
+}
+
+fn page_align_up(address: u64) -> u64 {
+    (address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// Memory handed to a process without pages behind it yet. Each page is
+// mapped zeroed the first time it's touched.
+#[derive(Clone, Copy)]
+struct Region {
+    end: u64,
+    mode: PageTableEntryMode,
+}
+
+struct UserPage {
+    page: PhysFrame,
+    // What it was mapped with, which is more than the page tables allow
+    // while it's copy-on-write.
+    mode: PageTableEntryMode,
+    copy_on_write: bool,
+    // Set for shared memory, whose pages are the segment's to free and are
+    // never copied.
+    segment: Option<Arc<Segment>>,
+}
+
+// A process's view of memory: the kernel's mappings, which it can't touch
+// from U-mode, plus pages of its own.
+pub struct AddressSpace {
+    vm: Option<VirtualMemory>,
+    // Page-aligned virtual address to the page mapped there.
+    pages: BTreeMap<u64, UserPage>,
+    // By start address. They never overlap.
+    regions: BTreeMap<u64, Region>,
+    heap_start: u64,
+    brk: u64,
+    // The most pages it may have mapped at once, and the most it has had.
+    page_limit: usize,
+    peak_pages: usize,
+    // Pages its process holds elsewhere, which count against the limit too.
+    charge: PageCharge,
+    // Other harts' TLBs still to flush, left for whoever holds the process
+    // table to send once it's unlocked.
+    shootdown: Option<tlb::Shootdown>,
+}
+
+impl AddressSpace {
+    pub fn new() -> KernelResult<Self> {
+        let mut vm = VirtualMemory::new(&mut PAGE_ALLOCATOR.lock())?;
+        let kernel = VIRTUAL_MEMORY.lock();
+        let kernel = kernel.get().ok_or(KernelError::NotSupported)?;
+        vm.share_root_entries(kernel, 0..USER_ROOT_ENTRIES.start);
+        vm.share_root_entries(kernel, USER_ROOT_ENTRIES.end..ROOT_ENTRIES);
+        Ok(Self {
+            vm: Some(vm),
+            pages: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            heap_start: USER_START,
+            brk: USER_START,
+            page_limit: usize::MAX,
+            peak_pages: 0,
+            charge: PageCharge::new(),
+            shootdown: None,
+        })
+    }
+
+    fn vm(&self) -> &VirtualMemory {
+        self.vm.as_ref().unwrap()
+    }
+
+    pub fn satp(&self) -> Satp {
+        self.vm().satp()
+    }
+
+    // Fails with OutOfMemory if `count` more pages would go over the limit.
+    fn check_page_limit(&self, count: usize) -> KernelResult<()> {
+        if count > self.pages_left() {
+            return Err(KernelError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    // How many more pages the limit leaves room for.
+    pub fn pages_left(&self) -> usize {
+        self.page_limit
+            .saturating_sub(self.pages.len() + self.charge.pages())
+    }
+
+    fn defer(&mut self, shootdown: tlb::Shootdown) {
+        merge_shootdown(&mut self.shootdown, shootdown);
+    }
+
+    // The TLB flushes other harts still need, which the caller must send.
+    pub fn take_shootdown(&mut self) -> Option<tlb::Shootdown> {
+        self.shootdown.take()
+    }
+
+    fn insert_page(&mut self, address: u64, page: UserPage) {
+        self.pages.insert(address, page);
+        self.peak_pages = self.peak_pages.max(self.pages.len());
+    }
+
+    // Maps a zeroed page at `address`, which must be page aligned, and
+    // returns it so the kernel can fill it in through the identity map.
+    pub fn map(&mut self, address: u64, mode: PageTableEntryMode) -> KernelResult<PhysFrame> {
+        if !address.is_multiple_of(PAGE_SIZE) || !is_user_range(address, PAGE_SIZE) {
+            return Err(KernelError::InvalidAddress);
+        }
+        if self.pages.contains_key(&address) {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.check_page_limit(1)?;
+
+        let virt: VirtPage = address.try_into()?;
+        let page = page_cache::alloc_page_zeroed()?;
+        let mapped = self.vm.as_mut().unwrap().map_user(
+            virt,
+            page.clone(),
+            mode,
+            &mut PAGE_ALLOCATOR.lock(),
+        );
+        if let Err(e) = mapped {
+            page_cache::free_page(page);
+            return Err(e.into());
+        }
+        self.insert_page(
+            address,
+            UserPage {
+                page: page.clone(),
+                mode,
+                copy_on_write: false,
+                segment: None,
+            },
+        );
+        Ok(page)
+    }
+
+    pub fn unmap(&mut self, address: u64) -> KernelResult<()> {
+        let page = self
+            .pages
+            .remove(&address)
+            .ok_or(KernelError::InvalidAddress)?;
+        let (_, shootdown) = self
+            .vm
+            .as_mut()
+            .unwrap()
+            .unmap_deferred(address.try_into()?)?;
+        self.defer(shootdown);
+        if page.segment.is_none() {
+            release(page.page);
+        }
+        Ok(())
+    }
+
+    // A copy sharing every page with this one until either writes to it.
+    // Writable pages become read-only in both, and get copied on the first
+    // write fault through fault_in. Shared memory stays shared.
+    pub fn fork(&mut self) -> KernelResult<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        let vm = self.vm.as_mut().unwrap();
+        let mut shootdown = None;
+        for (&address, page) in self.pages.iter_mut() {
+            if is_writable(page.mode) && !page.copy_on_write && page.segment.is_none() {
+                let protected = vm.protect_deferred(address.try_into()?, without_write(page.mode));
+                merge_shootdown(&mut shootdown, protected?);
+                page.copy_on_write = true;
+            }
+        }
+        if let Some(shootdown) = shootdown {
+            self.defer(shootdown);
+        }
+
+        for (&address, page) in self.pages.iter() {
+            let mode = if page.copy_on_write {
+                without_write(page.mode)
+            } else {
+                page.mode
+            };
+            child.vm.as_mut().unwrap().map_user(
+                address.try_into()?,
+                page.page.clone(),
+                mode,
+                &mut PAGE_ALLOCATOR.lock(),
+            )?;
+            if page.segment.is_none() {
+                share(&page.page);
+            }
+            child.pages.insert(
+                address,
+                UserPage {
+                    page: page.page.clone(),
+                    mode: page.mode,
+                    copy_on_write: page.copy_on_write,
+                    segment: page.segment.clone(),
+                },
+            );
+        }
+        child.regions = self.regions.clone();
+        child.heap_start = self.heap_start;
+        child.brk = self.brk;
+        child.page_limit = self.page_limit;
+        child.peak_pages = child.pages.len();
+        Ok(child)
+    }
+
+    // The region overlapping [start, end), if any. As they don't overlap
+    // each other, only the last one starting before `end` can.
+    fn overlapping_region(&self, start: u64, end: u64) -> Option<(u64, Region)> {
+        self.regions
+            .range(..end)
+            .next_back()
+            .filter(|(_, region)| region.end > start)
+            .map(|(&start, &region)| (start, region))
+    }
+
+    // Sets aside [start, start + length) to be faulted in with `mode`. It
+    // mustn't overlap anything already mapped or reserved.
+    pub fn reserve(
+        &mut self,
+        start: u64,
+        length: u64,
+        mode: PageTableEntryMode,
+    ) -> KernelResult<()> {
+        if !start.is_multiple_of(PAGE_SIZE) || length == 0 || !is_user_range(start, length) {
+            return Err(KernelError::InvalidAddress);
+        }
+        let end = page_align_up(start + length);
+        if !self.is_free(start, end) {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        // Growing the region just below, as the heap does, keeps one region.
+        match self.regions.range_mut(..start).next_back() {
+            Some((_, below)) if below.end == start && below.mode == mode => below.end = end,
+            _ => {
+                self.regions.insert(start, Region { end, mode });
+            }
+        }
+        Ok(())
+    }
+
+    // Maps [start, start + length) to `segment` from `offset` on, so writes
+    // there are seen by every other address space mapping the same pages.
+    // Nothing may be mapped or reserved there already.
+    pub fn map_shared(
+        &mut self,
+        start: u64,
+        length: u64,
+        segment: &Arc<Segment>,
+        offset: u64,
+        mode: PageTableEntryMode,
+    ) -> KernelResult<()> {
+        if !start.is_multiple_of(PAGE_SIZE) || length == 0 || !is_user_range(start, length) {
+            return Err(KernelError::InvalidAddress);
+        }
+        let end = page_align_up(start + length);
+        let beyond = offset
+            .checked_add(end - start)
+            .is_none_or(|last| last > segment.size());
+        if !offset.is_multiple_of(PAGE_SIZE) || beyond || !self.is_free(start, end) {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.check_page_limit(((end - start) / PAGE_SIZE) as usize)?;
+
+        let first = (offset / PAGE_SIZE) as usize;
+        for (n, address) in (start..end).step_by(PAGE_SIZE as usize).enumerate() {
+            let frame = segment.frame(first + n).unwrap().clone();
+            let virt: VirtPage = address.try_into()?;
+            let mapped = self.vm.as_mut().unwrap().map_user(
+                virt,
+                frame.clone(),
+                mode,
+                &mut PAGE_ALLOCATOR.lock(),
+            );
+            if let Err(e) = mapped {
+                self.release_range(start, address - start)?;
+                return Err(e.into());
+            }
+            self.insert_page(
+                address,
+                UserPage {
+                    page: frame,
+                    mode,
+                    copy_on_write: false,
+                    segment: Some(segment.clone()),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // Unmaps [start, start + length), reserved or mapped, and forgets any
+    // reservation there.
+    pub fn release_range(&mut self, start: u64, length: u64) -> KernelResult<()> {
+        if !start.is_multiple_of(PAGE_SIZE) || !is_user_range(start, length) {
+            return Err(KernelError::InvalidAddress);
+        }
+        let end = page_align_up(start + length);
+
+        while let Some((region_start, region)) = self.overlapping_region(start, end) {
+            self.regions.remove(&region_start);
+            if region_start < start {
+                self.regions.insert(
+                    region_start,
+                    Region {
+                        end: start,
+                        mode: region.mode,
+                    },
+                );
+            }
+            if region.end > end {
+                self.regions.insert(
+                    end,
+                    Region {
+                        end: region.end,
+                        mode: region.mode,
+                    },
+                );
+            }
+        }
+
+        let mapped: Vec<u64> = self
+            .pages
+            .range(start..end)
+            .map(|(&page, _)| page)
+            .collect();
+        for page in mapped {
+            self.unmap(page)?;
+        }
+        Ok(())
+    }
+
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        self.overlapping_region(start, end).is_none()
+            && self.pages.range(start..end).next().is_none()
+    }
+
+    // The lowest free stretch of `length` bytes at or above `from` and
+    // ending by `limit`.
+    pub fn find_free(&self, from: u64, limit: u64, length: u64) -> Option<u64> {
+        let length = page_align_up(length);
+        let mut start = page_align_up(from);
+        while start.checked_add(length)? <= limit {
+            let end = start + length;
+            if let Some((_, region)) = self.overlapping_region(start, end) {
+                start = region.end;
+            } else if let Some((&page, _)) = self.pages.range(start..end).next_back() {
+                start = page + PAGE_SIZE;
+            } else {
+                return Some(start);
+            }
+        }
+        None
+    }
+
+    // The heap starts empty at `start`, usually just past the program.
+    pub fn set_heap_start(&mut self, start: u64) {
+        self.heap_start = page_align_up(start);
+        self.brk = self.heap_start;
+    }
+
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    // Moves the end of the heap, reserving or releasing the pages between.
+    pub fn set_brk(&mut self, brk: u64) -> KernelResult<()> {
+        if brk < self.heap_start || !is_user_range(brk, 0) {
+            return Err(KernelError::InvalidArgument);
+        }
+        let (old_top, new_top) = (page_align_up(self.brk), page_align_up(brk));
+        if new_top > old_top {
+            self.reserve(old_top, new_top - old_top, PageTableEntryMode::ReadWrite)?;
+        } else if new_top < old_top {
+            self.release_range(new_top, old_top - new_top)?;
+        }
+        self.brk = brk;
+        Ok(())
+    }
+
+    // Lets user mode make `access` at `address`, faulting in a reserved
+    // page or copying a copy-on-write one if need be. Fails with
+    // InvalidAddress if the access isn't allowed at all.
+    pub fn fault_in(&mut self, address: u64, access: Access) -> KernelResult<()> {
+        let base = address & !(PAGE_SIZE - 1);
+        let (mode, mapped) = match self.pages.get(&base) {
+            Some(page) => (page.mode, true),
+            None => match self.overlapping_region(base, base + 1) {
+                Some((_, region)) => (region.mode, false),
+                None => return Err(KernelError::InvalidAddress),
+            },
+        };
+        let allowed = match access {
+            Access::Read => is_readable(mode),
+            Access::Write => is_writable(mode),
+            Access::Execute => is_executable(mode),
+        };
+        if !allowed {
+            return Err(KernelError::InvalidAddress);
+        }
+
+        if !mapped {
+            self.map(base, mode)?;
+        } else if access == Access::Write {
+            self.make_writable(base)?;
+        }
+        Ok(())
+    }
+
+    // Lets user mode write to the page at `address` if it was mapped
+    // writable, copying it first if another address space still shares it.
+    // Fails with InvalidAddress if it wasn't.
+    fn make_writable(&mut self, address: u64) -> KernelResult<()> {
+        let base = address & !(PAGE_SIZE - 1);
+        let page = self
+            .pages
+            .get_mut(&base)
+            .ok_or(KernelError::InvalidAddress)?;
+        if !is_writable(page.mode) {
+            return Err(KernelError::InvalidAddress);
+        }
+        if !page.copy_on_write {
+            return Ok(());
+        }
+
+        let virt: VirtPage = base.try_into()?;
+        let vm = self.vm.as_mut().unwrap();
+        let shootdown = if is_shared(&page.page) {
+            let copy = page_cache::alloc_page()?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    page.page.as_mut_ptr::<u8>(),
+                    copy.as_mut_ptr::<u8>(),
+                    PAGE_SIZE as usize,
+                );
+            }
+            let mapped = vm.map_user(virt, copy.clone(), page.mode, &mut PAGE_ALLOCATOR.lock());
+            if let Err(e) = mapped {
+                page_cache::free_page(copy);
+                return Err(e.into());
+            }
+            release(core::mem::replace(&mut page.page, copy));
+            tlb::Shootdown::new(base, base + PAGE_SIZE)
+        } else {
+            // Everything else sharing it has copied it or gone.
+            vm.protect_deferred(virt, page.mode)?
+        };
+        page.copy_on_write = false;
+        self.defer(shootdown);
+        Ok(())
+    }
+
+    // The physical address behind a user address, if it's mapped.
+    pub fn translate(&self, address: u64) -> Option<PhysAddr> {
+        if !is_user_range(address, 1) {
+            return None;
+        }
+        let page = self.pages.get(&(address & !(PAGE_SIZE - 1)))?;
+        page.page.start_address().add_offset(address % PAGE_SIZE)
+    }
+
+    // The leaf entry mapping `address`, kernel or user.
+    pub fn entry(&self, address: u64) -> Option<PageTableEntry> {
+        self.vm().leaf(address.try_into().ok()?)
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn peak_page_count(&self) -> usize {
+        self.peak_pages
+    }
+
+    // Pages already mapped past a lowered limit stay mapped.
+    pub fn set_page_limit(&mut self, limit: usize) {
+        self.page_limit = limit;
+    }
+
+    pub fn set_page_charge(&mut self, charge: PageCharge) {
+        self.charge = charge;
+    }
+}
+
+// It mustn't be active on any hart by now, so only flushes already owed
+// are left to send.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if let Some(shootdown) = self.shootdown.take() {
+            shootdown.send();
+        }
+        let mut segments = Vec::new();
+        for (_, page) in core::mem::take(&mut self.pages) {
+            match page.segment {
+                Some(segment) => segments.push(segment),
+                None => release(page.page),
+            }
+        }
+        if let Some(vm) = self.vm.take() {
+            let mut allocator = PAGE_ALLOCATOR.lock();
+            unsafe { vm.free_tables(USER_ROOT_ENTRIES, &mut allocator) };
+        }
+        // Only now, with the allocator unlocked, can the segments go, as
+        // the last user of one frees its pages.
+        drop(segments);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shm;
+
+    #[test_case]
+    fn kernel_mappings_are_shared() {
+        let space = AddressSpace::new().unwrap();
+        let function: fn() -> Satp = kernel_satp;
+        let code = function as usize as u64;
+        let kernel = VIRTUAL_MEMORY.lock();
+        let kernel = kernel.get().unwrap();
+        let shared = unsafe { (*space.vm().root_table).walk(code.try_into().unwrap()) };
+        let own = unsafe { (*kernel.root_table).walk(code.try_into().unwrap()) };
+        assert_eq!(shared, own);
+    }
+
+    #[test_case]
+    fn the_kernel_heap_is_shared_and_out_of_user_reach() {
+        let space = AddressSpace::new().unwrap();
+        // Allocated after the address space copied the kernel's root entries.
+        let boxed = alloc::boxed::Box::new([0u64; 64]);
+        let address = boxed.as_ptr() as u64;
+        assert!(!is_user_range(address, 8));
+
+        let kernel = VIRTUAL_MEMORY.lock();
+        let kernel = kernel.get().unwrap();
+        let shared = unsafe { (*space.vm().root_table).walk(address.try_into().unwrap()) };
+        let own = unsafe { (*kernel.root_table).walk(address.try_into().unwrap()) };
+        assert!(shared.is_some());
+        assert_eq!(shared, own);
+    }
+
+    #[test_case]
+    fn only_user_addresses_can_be_mapped() {
+        let mut space = AddressSpace::new().unwrap();
+        let page = space
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        assert_eq!(
+            space.translate(USER_START + 8),
+            page.start_address().add_offset(8)
+        );
+        assert_eq!(
+            space
+                .map(USER_START, PageTableEntryMode::ReadWrite)
+                .unwrap_err(),
+            KernelError::InvalidArgument
+        );
+        assert_eq!(
+            space
+                .map(USER_START - PAGE_SIZE, PageTableEntryMode::ReadWrite)
+                .unwrap_err(),
+            KernelError::InvalidAddress
+        );
+
+        space.unmap(USER_START).unwrap();
+        assert_eq!(space.translate(USER_START), None);
+    }
+
+    #[test_case]
+    fn unmapped_pages_go_back_through_the_page_cache() {
+        if !page_cache::is_enabled() {
+            return;
+        }
+        let mut space = AddressSpace::new().unwrap();
+        // Staying on this hart, and its cache.
+        let _interrupts = crate::trap::InterruptGuard::disable();
+        let page = space
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        space.unmap(USER_START).unwrap();
+
+        let again = page_cache::alloc_page().unwrap();
+        assert_eq!(again, page);
+        page_cache::free_page(again);
+    }
+
+    #[test_case]
+    fn forked_pages_are_copied_on_write() {
+        let mut parent = AddressSpace::new().unwrap();
+        let page = parent
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        parent
+            .map(USER_START + PAGE_SIZE, PageTableEntryMode::ReadExecute)
+            .unwrap();
+        unsafe { page.as_mut_ptr::<u8>().write(42) };
+
+        let mut child = parent.fork().unwrap();
+        assert_eq!(child.translate(USER_START), Some(page.start_address()));
+        assert!(!child.entry(USER_START).unwrap().is_writable());
+        assert!(!parent.entry(USER_START).unwrap().is_writable());
+
+        child.fault_in(USER_START, Access::Write).unwrap();
+        let copy = child.translate(USER_START).unwrap();
+        assert_ne!(copy, page.start_address());
+        assert_eq!(unsafe { *copy.as_ptr::<u8>() }, 42);
+        assert!(child.entry(USER_START).unwrap().is_writable());
+
+        // Nothing shares the original any more, so it's kept.
+        parent.fault_in(USER_START, Access::Write).unwrap();
+        assert_eq!(parent.translate(USER_START), Some(page.start_address()));
+        assert!(parent.entry(USER_START).unwrap().is_writable());
+
+        assert_eq!(
+            child.fault_in(USER_START + PAGE_SIZE, Access::Write),
+            Err(KernelError::InvalidAddress)
+        );
+    }
+
+    #[test_case]
+    fn shootdowns_are_left_for_the_caller_to_send() {
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        assert!(space.take_shootdown().is_none());
+
+        let child = space.fork().unwrap();
+        space.unmap(USER_START).unwrap();
+        space.take_shootdown().unwrap().send();
+        assert!(space.take_shootdown().is_none());
+        drop(child);
+    }
+
+    #[test_case]
+    fn reserved_pages_are_faulted_in_on_first_use() {
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .reserve(USER_START, 3 * PAGE_SIZE, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        assert_eq!(space.page_count(), 0);
+
+        space
+            .fault_in(USER_START + PAGE_SIZE + 8, Access::Read)
+            .unwrap();
+        assert_eq!(space.page_count(), 1);
+        assert!(space.translate(USER_START + PAGE_SIZE).is_some());
+        assert_eq!(
+            space.fault_in(USER_START, Access::Execute),
+            Err(KernelError::InvalidAddress)
+        );
+        assert_eq!(
+            space.reserve(
+                USER_START + PAGE_SIZE,
+                PAGE_SIZE,
+                PageTableEntryMode::ReadWrite
+            ),
+            Err(KernelError::InvalidArgument)
+        );
+
+        // A hole in the middle leaves the reservations either side of it.
+        space
+            .release_range(USER_START + PAGE_SIZE, PAGE_SIZE)
+            .unwrap();
+        assert_eq!(space.page_count(), 0);
+        assert_eq!(
+            space.fault_in(USER_START + PAGE_SIZE, Access::Read),
+            Err(KernelError::InvalidAddress)
+        );
+        space
+            .fault_in(USER_START + 2 * PAGE_SIZE, Access::Write)
+            .unwrap();
+        assert_eq!(
+            space.find_free(USER_START, USER_END, PAGE_SIZE),
+            Some(USER_START + PAGE_SIZE)
+        );
+    }
+
+    #[test_case]
+    fn the_heap_grows_and_shrinks_with_brk() {
+        let mut space = AddressSpace::new().unwrap();
+        space.set_heap_start(USER_START + 100);
+        assert_eq!(space.brk(), USER_START + PAGE_SIZE);
+
+        space.set_brk(USER_START + 3 * PAGE_SIZE).unwrap();
+        space
+            .fault_in(USER_START + 2 * PAGE_SIZE, Access::Write)
+            .unwrap();
+        assert_eq!(space.set_brk(USER_START), Err(KernelError::InvalidArgument));
+
+        space.set_brk(USER_START + 2 * PAGE_SIZE).unwrap();
+        assert_eq!(space.page_count(), 0);
+        assert_eq!(
+            space.fault_in(USER_START + 2 * PAGE_SIZE, Access::Read),
+            Err(KernelError::InvalidAddress)
+        );
+    }
+
+    #[test_case]
+    fn pages_past_the_limit_are_not_mapped() {
+        let mut space = AddressSpace::new().unwrap();
+        space.set_page_limit(2);
+        space
+            .reserve(USER_START, 3 * PAGE_SIZE, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        space.fault_in(USER_START, Access::Write).unwrap();
+        space
+            .fault_in(USER_START + PAGE_SIZE, Access::Write)
+            .unwrap();
+        assert_eq!(
+            space.fault_in(USER_START + 2 * PAGE_SIZE, Access::Write),
+            Err(KernelError::OutOfMemory)
+        );
+
+        space.unmap(USER_START).unwrap();
+        space
+            .fault_in(USER_START + 2 * PAGE_SIZE, Access::Write)
+            .unwrap();
+        assert_eq!(space.peak_page_count(), 2);
+    }
+
+    #[test_case]
+    fn charged_pages_count_against_the_limit() {
+        let mut space = AddressSpace::new().unwrap();
+        let charge = PageCharge::new();
+        space.set_page_limit(2);
+        space.set_page_charge(charge.clone());
+        charge.add(1);
+        assert_eq!(space.pages_left(), 1);
+
+        space
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        assert_eq!(
+            space.map(USER_START + PAGE_SIZE, PageTableEntryMode::ReadWrite),
+            Err(KernelError::OutOfMemory)
+        );
+        charge.remove(1);
+        space
+            .map(USER_START + PAGE_SIZE, PageTableEntryMode::ReadWrite)
+            .unwrap();
+    }
+
+    #[test_case]
+    fn shared_memory_is_shared_through_fork_and_freed_with_its_last_mapping() {
+        let segment = shm::open(
+            "test-address-space",
+            2 * PAGE_SIZE,
+            true,
+            false,
+            &PageCharge::new(),
+            usize::MAX,
+        )
+        .unwrap();
+        let mut parent = AddressSpace::new().unwrap();
+        parent
+            .map_shared(
+                USER_START,
+                2 * PAGE_SIZE,
+                &segment,
+                0,
+                PageTableEntryMode::ReadWrite,
+            )
+            .unwrap();
+
+        let mut child = parent.fork().unwrap();
+        assert!(parent.entry(USER_START).unwrap().is_writable());
+        child.fault_in(USER_START, Access::Write).unwrap();
+        assert_eq!(child.translate(USER_START), parent.translate(USER_START));
+
+        // Another mapping of the same page can allow less.
+        let mut other = AddressSpace::new().unwrap();
+        other
+            .map_shared(
+                USER_START,
+                PAGE_SIZE,
+                &segment,
+                PAGE_SIZE,
+                PageTableEntryMode::ReadOnly,
+            )
+            .unwrap();
+        assert_eq!(
+            other.translate(USER_START),
+            parent.translate(USER_START + PAGE_SIZE)
+        );
+        assert_eq!(
+            other.fault_in(USER_START, Access::Write),
+            Err(KernelError::InvalidAddress)
+        );
+        assert_eq!(
+            other.map_shared(
+                USER_START + PAGE_SIZE,
+                PAGE_SIZE,
+                &segment,
+                2 * PAGE_SIZE,
+                PageTableEntryMode::ReadOnly
+            ),
+            Err(KernelError::InvalidArgument)
+        );
+
+        let weak = Arc::downgrade(&segment);
+        drop(segment);
+        drop(parent);
+        drop(child);
+        assert!(weak.upgrade().is_some());
+        drop(other);
+        assert!(weak.upgrade().is_none());
+    }
+}
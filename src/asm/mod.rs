@@ -1,5 +1,7 @@
 use core::arch::global_asm;
 
-global_asm!(include_str!("boot.S"));
 global_asm!(include_str!("memory_layout.S"));
+global_asm!(include_str!("switch.S"));
 global_asm!(include_str!("trap.S"));
+global_asm!(include_str!("uaccess.S"));
+global_asm!(include_str!("user.S"));
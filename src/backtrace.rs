@@ -0,0 +1,136 @@
+use crate::symbols::Symbolized;
+use crate::{hart, task};
+use crate::{print, println};
+use core::arch::asm;
+
+extern "C" {
+    static STACK_START: u64;
+    static STACK_END: u64;
+}
+
+const MAX_FRAMES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackBounds {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl StackBounds {
+    pub fn boot_stack() -> Self {
+        unsafe {
+            Self {
+                low: STACK_START,
+                high: STACK_END,
+            }
+        }
+    }
+
+    // The stack this hart is running on.
+    pub fn current() -> Self {
+        task::stack_bounds()
+            .or_else(|| hart::stack_bounds(hart::hart_id()))
+            .unwrap_or_else(Self::boot_stack)
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.low <= address && address < self.high
+    }
+}
+
+// Walks the frame pointer chain. With frame pointers forced on, each frame
+// stores the return address at fp - 8 and the caller's fp at fp - 16.
+pub struct Backtrace {
+    fp: u64,
+    bounds: StackBounds,
+    frames: usize,
+}
+
+impl Backtrace {
+    #[inline(always)]
+    pub fn here() -> Self {
+        let fp: u64;
+        unsafe {
+            asm!("mv {}, s0", out(reg) fp);
+        }
+        Self::from_frame_pointer(fp, StackBounds::current())
+    }
+
+    pub fn from_frame_pointer(fp: u64, bounds: StackBounds) -> Self {
+        Self {
+            fp,
+            bounds,
+            frames: 0,
+        }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Anything odd about the frame pointer means the chain is broken, so
+        // stop rather than read through a wild pointer.
+        if self.frames == MAX_FRAMES
+            || !self.fp.is_multiple_of(8)
+            || !self.bounds.contains(self.fp.wrapping_sub(16))
+            || self.fp > self.bounds.high
+        {
+            return None;
+        }
+
+        let (return_address, caller_fp) = unsafe {
+            (
+                *((self.fp - 8) as *const u64),
+                *((self.fp - 16) as *const u64),
+            )
+        };
+        if return_address == 0 {
+            return None;
+        }
+
+        // Callers' frames are further up the stack.
+        self.fp = if caller_fp > self.fp { caller_fp } else { 0 };
+        self.frames += 1;
+        Some(return_address)
+    }
+}
+
+pub fn print(backtrace: Backtrace) {
+    println!("Backtrace:");
+    for (n, return_address) in backtrace.enumerate() {
+        println!("  {:>2}: {}", n, Symbolized(return_address));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn depth_from_here() -> usize {
+        Backtrace::here().count()
+    }
+
+    #[inline(never)]
+    fn one_frame_deeper() -> usize {
+        let depth = depth_from_here();
+        core::hint::black_box(depth)
+    }
+
+    #[test_case]
+    fn backtraces_grow_with_call_depth() {
+        assert!(depth_from_here() > 0);
+        assert_eq!(one_frame_deeper(), depth_from_here() + 1);
+    }
+
+    #[test_case]
+    fn a_frame_pointer_off_the_stack_ends_the_walk() {
+        let bounds = StackBounds {
+            low: 0x1000,
+            high: 0x2000,
+        };
+        assert_eq!(Backtrace::from_frame_pointer(0x8000, bounds).count(), 0);
+        assert_eq!(Backtrace::from_frame_pointer(0x1003, bounds).count(), 0);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::latency::read_cycle;
+use crate::timer::{read_time, timebase_frequency};
+use crate::{cmdline, print, println};
+use alloc::vec::Vec;
+use core::fmt;
+use core::hint::black_box;
+
+// Runs before the measured iterations, to warm caches and the TLB.
+const WARMUP_ITERATIONS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub iterations: usize,
+    pub min_cycles: u64,
+    pub median_cycles: u64,
+    pub ops_per_second: u64,
+}
+
+impl Summary {
+    // From each iteration's cycle count, which get sorted, and the time
+    // they took altogether in `time` ticks.
+    pub fn from_samples(cycles: &mut [u64], ticks: u64, frequency: u64) -> Self {
+        cycles.sort_unstable();
+        let iterations = cycles.len();
+        let ops_per_second = (iterations as u128 * frequency as u128)
+            .checked_div(ticks as u128)
+            .unwrap_or(0);
+        Self {
+            iterations,
+            min_cycles: cycles.first().copied().unwrap_or(0),
+            median_cycles: cycles.get(iterations / 2).copied().unwrap_or(0),
+            ops_per_second: ops_per_second.min(u64::MAX as u128) as u64,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} iterations, min {} cycles, median {} cycles, {} ops/s",
+            self.iterations, self.min_cycles, self.median_cycles, self.ops_per_second
+        )
+    }
+}
+
+// Only with the bench boot argument, since they'd slow every test run
+// down. Otherwise a benchmark runs once, so it at least still works.
+pub fn is_enabled() -> bool {
+    cmdline::has("bench")
+}
+
+// Times `iterations` calls of `f`, after a warmup, and prints the summary
+// as `name`'s result. Interrupts stay on, so the median is the figure to
+// trust over the total.
+pub fn run<R>(name: &str, iterations: usize, mut f: impl FnMut() -> R) -> Summary {
+    let (warmup, iterations) = if is_enabled() {
+        (WARMUP_ITERATIONS, iterations)
+    } else {
+        (0, 1)
+    };
+    for _ in 0..warmup {
+        black_box(f());
+    }
+
+    let mut cycles = Vec::with_capacity(iterations);
+    let start = read_time();
+    for _ in 0..iterations {
+        let before = read_cycle();
+        black_box(f());
+        cycles.push(read_cycle() - before);
+    }
+    let ticks = read_time() - start;
+
+    let summary = Summary::from_samples(&mut cycles, ticks, timebase_frequency());
+    if is_enabled() {
+        println!("\n  {}: {}", name, summary);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn summaries_take_the_middle_sample_and_the_overall_rate() {
+        let mut cycles = [30, 10, 50, 20, 40];
+        let summary = Summary::from_samples(&mut cycles, 1_000, 10_000_000);
+        assert_eq!(summary.iterations, 5);
+        assert_eq!(summary.min_cycles, 10);
+        assert_eq!(summary.median_cycles, 30);
+        assert_eq!(summary.ops_per_second, 50_000);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::{KernelError, KernelResult};
+use crate::trap::LockIrqSave;
+use crate::vfs::{self, FileSystem};
+use crate::{buffer_cache, ext2, fat32};
+use crate::{info, warn};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Devices are addressed in sectors of this many bytes, whatever their
+// hardware does underneath.
+pub const SECTOR_SIZE: usize = 512;
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn sector_count(&self) -> u64;
+
+    // Buffers are whole sectors, starting at `sector`.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> KernelResult<()>;
+    fn write_sectors(&self, sector: u64, bytes: &[u8]) -> KernelResult<()>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.lock_irqsave().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock_irqsave().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock_irqsave()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+type Opener = fn(Arc<dyn BlockDevice>) -> KernelResult<Arc<dyn FileSystem>>;
+
+// Tried in turn on each disk. They fail with InvalidArgument if it isn't
+// one of theirs.
+const FILE_SYSTEMS: &[(&str, Opener)] = &[("FAT32", fat32::open), ("ext2", ext2::open)];
+
+// Mounts each disk with a file system on it at /mnt/<disk>, behind a
+// buffer cache.
+pub fn mount_all() {
+    for device in devices() {
+        let path = format!("/mnt/{}", device.name());
+        let cache = buffer_cache::cached(device.clone());
+        for (name, open) in FILE_SYSTEMS {
+            let mounted = match open(cache.clone()) {
+                Err(KernelError::InvalidArgument) => continue,
+                Ok(fs) => vfs::mkdir_all(&path).and_then(|()| vfs::mount(&path, fs)),
+                Err(e) => Err(e),
+            };
+            match mounted {
+                Ok(()) => info!("Mounted {} ({}) on {}", device.name(), name, path),
+                Err(e) => warn!("Failed to mount {} ({}): {}", device.name(), name, e),
+            }
+            break;
+        }
+    }
+}
+
+fn check_range(device: &dyn BlockDevice, offset: u64, length: usize) -> KernelResult<()> {
+    let end = offset
+        .checked_add(length as u64)
+        .ok_or(KernelError::InvalidArgument)?;
+    if end > device.sector_count() * SECTOR_SIZE as u64 {
+        return Err(KernelError::InvalidArgument);
+    }
+    Ok(())
+}
+
+// Reads any span of bytes, going through a sector of its own at either end
+// if they aren't aligned.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+    check_range(device, offset, buffer.len())?;
+    let mut sector = [0; SECTOR_SIZE];
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let within = (position % SECTOR_SIZE as u64) as usize;
+        let remaining = buffer.len() - done;
+        if within == 0 && remaining >= SECTOR_SIZE {
+            let whole = remaining - remaining % SECTOR_SIZE;
+            device.read_sectors(
+                position / SECTOR_SIZE as u64,
+                &mut buffer[done..done + whole],
+            )?;
+            done += whole;
+        } else {
+            let count = (SECTOR_SIZE - within).min(remaining);
+            device.read_sectors(position / SECTOR_SIZE as u64, &mut sector)?;
+            buffer[done..done + count].copy_from_slice(&sector[within..within + count]);
+            done += count;
+        }
+    }
+    Ok(())
+}
+
+// Writes any span of bytes, reading back the sectors it only partly covers.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, bytes: &[u8]) -> KernelResult<()> {
+    check_range(device, offset, bytes.len())?;
+    let mut sector = [0; SECTOR_SIZE];
+    let mut done = 0;
+    while done < bytes.len() {
+        let position = offset + done as u64;
+        let within = (position % SECTOR_SIZE as u64) as usize;
+        let remaining = bytes.len() - done;
+        if within == 0 && remaining >= SECTOR_SIZE {
+            let whole = remaining - remaining % SECTOR_SIZE;
+            device.write_sectors(position / SECTOR_SIZE as u64, &bytes[done..done + whole])?;
+            done += whole;
+        } else {
+            let count = (SECTOR_SIZE - within).min(remaining);
+            let number = position / SECTOR_SIZE as u64;
+            device.read_sectors(number, &mut sector)?;
+            sector[within..within + count].copy_from_slice(&bytes[done..done + count]);
+            device.write_sectors(number, &sector)?;
+            done += count;
+        }
+    }
+    Ok(())
+}
+
+// A block device in kernel memory.
+pub struct RamDisk {
+    name: String,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, sectors: u64) -> Self {
+        Self {
+            name: String::from(name),
+            data: Mutex::new(vec![0; sectors as usize * SECTOR_SIZE]),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let data = self.data.lock();
+        let start = sector as usize * SECTOR_SIZE;
+        let source = data
+            .get(start..start + buffer.len())
+            .ok_or(KernelError::InvalidArgument)?;
+        buffer.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, bytes: &[u8]) -> KernelResult<()> {
+        let mut data = self.data.lock();
+        let start = sector as usize * SECTOR_SIZE;
+        data.get_mut(start..start + bytes.len())
+            .ok_or(KernelError::InvalidArgument)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn unaligned_spans_keep_the_bytes_around_them() {
+        let disk = RamDisk::new("ram", 4);
+        disk.write_sectors(0, &[0xaa; 4 * SECTOR_SIZE]).unwrap();
+        let bytes: Vec<u8> = (0..700).map(|n| n as u8).collect();
+        write_bytes(&disk, 300, &bytes).unwrap();
+
+        let mut all = vec![0; 4 * SECTOR_SIZE];
+        read_bytes(&disk, 0, &mut all).unwrap();
+        assert!(all[..300].iter().all(|&b| b == 0xaa));
+        assert_eq!(&all[300..1000], &bytes[..]);
+        assert!(all[1000..].iter().all(|&b| b == 0xaa));
+
+        let mut some = [0; 10];
+        read_bytes(&disk, 995, &mut some).unwrap();
+        assert_eq!(&some[..5], &bytes[695..]);
+    }
+
+    #[test_case]
+    fn spans_past_the_end_are_refused() {
+        let disk = RamDisk::new("ram", 1);
+        let mut buffer = [0; 2];
+        assert_eq!(
+            read_bytes(&disk, SECTOR_SIZE as u64 - 1, &mut buffer),
+            Err(KernelError::InvalidArgument)
+        );
+        assert_eq!(
+            write_bytes(&disk, u64::MAX, &buffer),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use crate::backtrace::StackBounds;
+use crate::{hart, stack_guard};
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+// The first hart into _start boots the kernel. OpenSBI holds the others
+// until they're started over HSM, but firmware that releases every hart at
+// once sends the losers to park. It lives in .data as .bss isn't cleared
+// until the race is decided.
+#[link_section = ".data"]
+static BOOT_LOTTERY: AtomicU32 = AtomicU32::new(0);
+
+static BOOT_HART: AtomicU64 = AtomicU64::new(0);
+static DEVICE_TREE: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    fn kernel_main() -> !;
+}
+
+// OpenSBI enters the kernel here in S-mode with the MMU off, the hart id in
+// a0 and the device tree in a1. Nothing may touch the stack or .bss before
+// they're set up, and a0 and a1 have to survive until main.
+#[unsafe(naked)]
+#[no_mangle]
+#[link_section = ".text.init"]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn _start() -> ! {
+    naked_asm!(
+        // Boot code keeps each hart's id in tp for the rest of the kernel.
+        "mv tp, a0",
+        "la t0, {lottery}",
+        "li t1, 1",
+        "amoswap.w t1, t1, (t0)",
+        "bnez t1, 4f",
+        "la sp, _stack_end",
+        // kernel.ld keeps both ends doubleword aligned.
+        "la t0, _bss_start",
+        "la t1, _bss_end",
+        "2:",
+        "bgeu t0, t1, 3f",
+        "sd zero, (t0)",
+        "addi t0, t0, 8",
+        "j 2b",
+        "3:",
+        "tail {main}",
+        "4:",
+        "wfi",
+        "j 4b",
+        lottery = sym BOOT_LOTTERY,
+        main = sym main,
+    )
+}
+
+// Secondary harts are started through SBI HSM with the MMU off, the hart id
+// in a0 and their SecondaryBoot record (see hart.rs) in a1.
+#[unsafe(naked)]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn secondary_start() -> ! {
+    naked_asm!(
+        "mv tp, a0",
+        "ld t0, 0(a1)",
+        "csrw satp, t0",
+        "sfence.vma",
+        // The stack may only be mapped once paging is on.
+        "ld sp, 8(a1)",
+        "tail {main}",
+        main = sym hart::secondary_main,
+    )
+}
+
+// On the boot hart's stack, with .bss cleared and the MMU still off.
+pub extern "C" fn main(hartid: u64, dtb: u64) -> ! {
+    BOOT_HART.store(hartid, Ordering::Relaxed);
+    DEVICE_TREE.store(dtb, Ordering::Relaxed);
+    unsafe {
+        stack_guard::install(&StackBounds::boot_stack());
+        crate::initialise_kernel(hartid, dtb);
+        kernel_main()
+    }
+}
+
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Relaxed) as usize
+}
+
+// The physical address firmware handed over the device tree at.
+pub fn device_tree_address() -> u64 {
+    DEVICE_TREE.load(Ordering::Relaxed)
+}
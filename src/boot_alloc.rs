@@ -0,0 +1,194 @@
+use crate::memory_map::MemoryRegion;
+use crate::page_allocator::{PageAllocator, PAGE_SIZE};
+use core::alloc::Layout;
+use core::{ptr, slice};
+use spin::Mutex;
+
+extern "C" {
+    static BOOT_REGION_START: u64;
+    static BOOT_REGION_END: u64;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootAllocStats {
+    pub size: u64,
+    pub used: u64,
+    pub donated_pages: u64,
+}
+
+pub struct BumpAllocator {
+    start: u64,
+    next: u64,
+    end: u64,
+    donated_pages: u64,
+}
+
+impl BumpAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            start: 0,
+            next: 0,
+            end: 0,
+            donated_pages: 0,
+        }
+    }
+
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            next: start,
+            end,
+            donated_pages: 0,
+        }
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        let align = layout.align() as u64;
+        let address = (self.next + align - 1) & !(align - 1);
+        let next = address.checked_add(layout.size() as u64)?;
+        if next > self.end {
+            return None;
+        }
+
+        self.next = next;
+        Some(address as *mut u8)
+    }
+
+    pub fn alloc_zeroed(&mut self, layout: Layout) -> Option<*mut u8> {
+        let ptr = self.alloc(layout)?;
+        unsafe { ptr::write_bytes(ptr, 0, layout.size()) };
+        Some(ptr)
+    }
+
+    // A copy of `bytes` that lasts as long as the kernel does.
+    pub fn copy(&mut self, bytes: &[u8], align: usize) -> Option<&'static mut [u8]> {
+        let layout = Layout::from_size_align(bytes.len(), align).ok()?;
+        let ptr = self.alloc(layout)?;
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+            Some(slice::from_raw_parts_mut(ptr, bytes.len()))
+        }
+    }
+
+    // Hands every whole page past the last allocation to the page allocator.
+    // Nothing more can be allocated afterwards.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn donate(&mut self, allocator: &mut PageAllocator) -> u64 {
+        let pages = MemoryRegion::new(self.next, self.end).pages();
+        let count = pages.count() as u64;
+        allocator.add_pages(MemoryRegion::new(self.next, self.end).pages());
+
+        self.donated_pages += count;
+        self.next = self.end;
+        count
+    }
+
+    pub fn stats(&self) -> BootAllocStats {
+        BootAllocStats {
+            size: self.end - self.start,
+            used: self.end - self.start - self.donated_pages * PAGE_SIZE,
+            donated_pages: self.donated_pages,
+        }
+    }
+}
+
+static BOOT_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(BumpAllocator::empty());
+
+pub fn init() {
+    unsafe {
+        *BOOT_ALLOCATOR.lock() = BumpAllocator::new(BOOT_REGION_START, BOOT_REGION_END);
+    }
+}
+
+// Only usable until donate() runs, which init_memory does once the page
+// allocator is populated.
+pub fn alloc(layout: Layout) -> Option<*mut u8> {
+    BOOT_ALLOCATOR.lock().alloc(layout)
+}
+
+pub fn alloc_zeroed(layout: Layout) -> Option<*mut u8> {
+    BOOT_ALLOCATOR.lock().alloc_zeroed(layout)
+}
+
+pub fn copy(bytes: &[u8], align: usize) -> Option<&'static mut [u8]> {
+    BOOT_ALLOCATOR.lock().copy(bytes, align)
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn donate(allocator: &mut PageAllocator) -> u64 {
+    BOOT_ALLOCATOR.lock().donate(allocator)
+}
+
+pub fn stats() -> BootAllocStats {
+    BOOT_ALLOCATOR.lock().stats()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::page_allocator::test::heap_addresses;
+
+    fn test_bump_allocator(pages: u64) -> BumpAllocator {
+        let (start, end) = heap_addresses(pages);
+        unsafe {
+            BumpAllocator::new(
+                start.start_address().as_u64(),
+                end.start_address().as_u64() + PAGE_SIZE,
+            )
+        }
+    }
+
+    #[test_case]
+    fn allocations_are_aligned_and_disjoint() {
+        let mut bump = test_bump_allocator(1);
+
+        let a = bump.alloc(Layout::new::<u8>()).unwrap() as u64;
+        let b = bump.alloc(Layout::new::<u64>()).unwrap() as u64;
+
+        assert_eq!(b % 8, 0);
+        assert!(b > a);
+    }
+
+    #[test_case]
+    fn an_exhausted_region_fails_allocation() {
+        let mut bump = test_bump_allocator(1);
+
+        assert!(bump
+            .alloc(Layout::from_size_align(PAGE_SIZE as usize, 8).unwrap())
+            .is_some());
+        assert!(bump.alloc(Layout::new::<u8>()).is_none());
+    }
+
+    #[test_case]
+    fn copies_are_aligned_and_hold_the_same_bytes() {
+        let mut bump = test_bump_allocator(1);
+        bump.alloc(Layout::new::<u8>()).unwrap();
+
+        let copy = bump.copy(b"device tree", 8).unwrap();
+
+        assert_eq!(copy.as_ptr() as u64 % 8, 0);
+        assert_eq!(copy, b"device tree");
+        assert!(bump.copy(&[0; PAGE_SIZE as usize], 1).is_none());
+    }
+
+    #[test_case]
+    fn donating_hands_over_the_unused_pages() {
+        let mut bump = test_bump_allocator(4);
+        let mut allocator = PageAllocator::empty();
+
+        bump.alloc(Layout::new::<u64>()).unwrap();
+        let donated = unsafe { bump.donate(&mut allocator) };
+
+        assert_eq!(donated, 3);
+        assert_eq!(allocator.free_pages(), 3);
+        assert_eq!(bump.stats().used, PAGE_SIZE);
+        assert!(bump.alloc(Layout::new::<u8>()).is_none());
+    }
+
+    #[test_case]
+    fn boot_allocator_is_sealed_after_boot() {
+        assert!(alloc(Layout::new::<u8>()).is_none());
+        assert!(stats().donated_pages > 0);
+    }
+}
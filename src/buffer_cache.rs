@@ -0,0 +1,381 @@
+use crate::address::PhysFrame;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Each buffer holds a page's worth of sectors.
+const SECTORS_PER_BUFFER: u64 = PAGE_SIZE / SECTOR_SIZE as u64;
+// Buffers kept per device before the least recently used go.
+const CAPACITY: usize = 64;
+// How many buffers after a miss are filled too, when it follows on from
+// the last miss.
+const READ_AHEAD: u64 = 4;
+
+static CACHES: Mutex<Vec<Arc<BufferCache>>> = Mutex::new(Vec::new());
+
+struct Contents {
+    page: PhysFrame,
+    // Sectors it covers, fewer than a page's worth at the end of a device.
+    sectors: usize,
+    valid: bool,
+    dirty: bool,
+}
+
+impl Contents {
+    fn bytes(&mut self) -> &mut [u8] {
+        let length = self.sectors * SECTOR_SIZE;
+        unsafe { core::slice::from_raw_parts_mut(self.page.as_mut_ptr(), length) }
+    }
+}
+
+// Its lock is held while it's filled or written back, so each block is
+// only ever being read or written by one hart.
+struct Buffer(Mutex<Contents>);
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let page = self.0.get_mut().page.clone();
+        PAGE_ALLOCATOR.lock().dealloc(page);
+    }
+}
+
+struct Index {
+    buffers: BTreeMap<u64, (Arc<Buffer>, u64)>,
+    // Ticks with each use, to find the least recently used buffer.
+    clock: u64,
+    last_miss: Option<u64>,
+}
+
+// A device's sectors, cached a page at a time. Writes only reach the
+// device on sync or when their buffer is evicted.
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    index: Mutex<Index>,
+}
+
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            device,
+            index: Mutex::new(Index {
+                buffers: BTreeMap::new(),
+                clock: 0,
+                last_miss: None,
+            }),
+        }
+    }
+
+    fn write_back(&self, block: u64, contents: &mut Contents) -> KernelResult<()> {
+        if contents.dirty {
+            self.device
+                .write_sectors(block * SECTORS_PER_BUFFER, contents.bytes())?;
+            contents.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn fill(&self, block: u64, contents: &mut Contents) -> KernelResult<()> {
+        if !contents.valid {
+            self.device
+                .read_sectors(block * SECTORS_PER_BUFFER, contents.bytes())?;
+            contents.valid = true;
+        }
+        Ok(())
+    }
+
+    // Makes room by writing back and dropping the least recently used
+    // buffer nobody is using, if there is one.
+    fn evict(&self, index: &mut Index) -> KernelResult<()> {
+        let victim = index
+            .buffers
+            .iter()
+            .filter(|(_, (buffer, _))| Arc::strong_count(buffer) == 1)
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(block, _)| *block);
+        if let Some(block) = victim {
+            let (buffer, _) = &index.buffers[&block];
+            self.write_back(block, &mut buffer.0.lock())?;
+            index.buffers.remove(&block);
+        }
+        Ok(())
+    }
+
+    // The buffer for `block` and whether it was already cached. It's only
+    // filled once it's locked.
+    fn buffer(&self, block: u64) -> KernelResult<(Arc<Buffer>, bool)> {
+        let mut index = self.index.lock();
+        index.clock += 1;
+        let now = index.clock;
+        if let Some((buffer, used)) = index.buffers.get_mut(&block) {
+            *used = now;
+            return Ok((buffer.clone(), true));
+        }
+        if index.buffers.len() >= CAPACITY {
+            self.evict(&mut index)?;
+        }
+        let page = PAGE_ALLOCATOR.lock().alloc()?;
+        let first = block * SECTORS_PER_BUFFER;
+        let buffer = Arc::new(Buffer(Mutex::new(Contents {
+            page,
+            sectors: (self.device.sector_count() - first).min(SECTORS_PER_BUFFER) as usize,
+            valid: false,
+            dirty: false,
+        })));
+        index.buffers.insert(block, (buffer.clone(), now));
+        Ok((buffer, false))
+    }
+
+    // Fills the buffers after a sequential miss, skipping any in use.
+    fn read_ahead(&self, block: u64) {
+        let blocks = self.device.sector_count().div_ceil(SECTORS_PER_BUFFER);
+        for next in block + 1..(block + 1 + READ_AHEAD).min(blocks) {
+            if let Ok((buffer, false)) = self.buffer(next) {
+                if let Some(mut contents) = buffer.0.try_lock() {
+                    // A failure here is left for the read that needs it.
+                    let _ = self.fill(next, &mut contents);
+                }
+            }
+        }
+    }
+
+    // Each span of a request within one buffer, as (block, first byte in
+    // the buffer, first byte of the request, length).
+    fn spans(sector: u64, length: usize) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+        let buffer_size = SECTORS_PER_BUFFER as usize * SECTOR_SIZE;
+        let start = sector as usize * SECTOR_SIZE;
+        let mut done = 0;
+        core::iter::from_fn(move || {
+            if done == length {
+                return None;
+            }
+            let position = start + done;
+            let within = position % buffer_size;
+            let count = (buffer_size - within).min(length - done);
+            let span = ((position / buffer_size) as u64, within, done, count);
+            done += count;
+            Some(span)
+        })
+    }
+
+    fn check(&self, sector: u64, length: usize) -> KernelResult<()> {
+        let sectors = (length / SECTOR_SIZE) as u64;
+        if !length.is_multiple_of(SECTOR_SIZE) || sector + sectors > self.device.sector_count() {
+            return Err(KernelError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> KernelResult<()> {
+        let buffers: Vec<(u64, Arc<Buffer>)> = self
+            .index
+            .lock()
+            .buffers
+            .iter()
+            .map(|(block, (buffer, _))| (*block, buffer.clone()))
+            .collect();
+        for (block, buffer) in buffers {
+            self.write_back(block, &mut buffer.0.lock())?;
+        }
+        Ok(())
+    }
+
+    pub fn dirty_buffers(&self) -> usize {
+        self.index
+            .lock()
+            .buffers
+            .values()
+            .filter(|(buffer, _)| buffer.0.lock().dirty)
+            .count()
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        self.check(sector, buffer.len())?;
+        for (block, within, offset, count) in Self::spans(sector, buffer.len()) {
+            let (cached, hit) = self.buffer(block)?;
+            let mut contents = cached.0.lock();
+            self.fill(block, &mut contents)?;
+            buffer[offset..offset + count]
+                .copy_from_slice(&contents.bytes()[within..within + count]);
+            drop(contents);
+
+            if !hit {
+                let mut index = self.index.lock();
+                let sequential = index.last_miss.is_some_and(|last| last + 1 == block);
+                index.last_miss = Some(block);
+                drop(index);
+                if sequential {
+                    self.read_ahead(block);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, bytes: &[u8]) -> KernelResult<()> {
+        if self.device.is_read_only() {
+            return Err(KernelError::ReadOnly);
+        }
+        self.check(sector, bytes.len())?;
+        for (block, within, offset, count) in Self::spans(sector, bytes.len()) {
+            let (cached, _) = self.buffer(block)?;
+            let mut contents = cached.0.lock();
+            // A buffer that's entirely overwritten needn't be read first.
+            if count < contents.sectors * SECTOR_SIZE {
+                self.fill(block, &mut contents)?;
+            }
+            contents.bytes()[within..within + count]
+                .copy_from_slice(&bytes[offset..offset + count]);
+            contents.valid = true;
+            contents.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+// Puts `device` behind a cache that sync_all will write back.
+pub fn cached(device: Arc<dyn BlockDevice>) -> Arc<BufferCache> {
+    let cache = Arc::new(BufferCache::new(device));
+    CACHES.lock().push(cache.clone());
+    cache
+}
+
+// Writes every cache's dirty buffers back, carrying on past failures and
+// returning the first.
+pub fn sync_all() -> KernelResult<()> {
+    let caches = CACHES.lock().clone();
+    let mut result = Ok(());
+    for cache in caches {
+        let synced = cache.sync();
+        result = result.and(synced);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{self, RamDisk};
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the requests that reach the disk.
+    struct CountingDisk {
+        disk: RamDisk,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl CountingDisk {
+        fn new(sectors: u64) -> Arc<Self> {
+            Arc::new(Self {
+                disk: RamDisk::new("counting", sectors),
+                reads: AtomicUsize::new(0),
+                writes: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn name(&self) -> &str {
+            self.disk.name()
+        }
+
+        fn sector_count(&self) -> u64 {
+            self.disk.sector_count()
+        }
+
+        fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> KernelResult<()> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.disk.read_sectors(sector, buffer)
+        }
+
+        fn write_sectors(&self, sector: u64, bytes: &[u8]) -> KernelResult<()> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.disk.write_sectors(sector, bytes)
+        }
+    }
+
+    #[test_case]
+    fn reads_are_served_from_the_cache() {
+        let disk = CountingDisk::new(64);
+        let cache = BufferCache::new(disk.clone());
+        let mut bytes = [0; 16];
+        for offset in (0..SECTOR_SIZE as u64 * 4).step_by(16) {
+            block::read_bytes(&cache, offset, &mut bytes).unwrap();
+        }
+        assert_eq!(disk.reads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn sequential_reads_are_read_ahead() {
+        let disk = CountingDisk::new(16 * SECTORS_PER_BUFFER);
+        let cache = BufferCache::new(disk.clone());
+        let mut page = vec![0; PAGE_SIZE as usize];
+        cache.read_sectors(0, &mut page).unwrap();
+        cache.read_sectors(SECTORS_PER_BUFFER, &mut page).unwrap();
+        let reads = disk.reads.load(Ordering::Relaxed);
+        assert_eq!(reads, 2 + READ_AHEAD as usize);
+
+        for block in 2..2 + READ_AHEAD {
+            cache
+                .read_sectors(block * SECTORS_PER_BUFFER, &mut page)
+                .unwrap();
+        }
+        assert_eq!(disk.reads.load(Ordering::Relaxed), reads);
+    }
+
+    #[test_case]
+    fn writes_reach_the_disk_on_sync() {
+        let disk = CountingDisk::new(64);
+        let cache = BufferCache::new(disk.clone());
+        block::write_bytes(&cache, 100, b"cached").unwrap();
+        assert_eq!(cache.dirty_buffers(), 1);
+
+        let mut bytes = [0; 6];
+        block::read_bytes(&disk.disk, 100, &mut bytes).unwrap();
+        assert_eq!(&bytes, &[0; 6]);
+        block::read_bytes(&cache, 100, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"cached");
+
+        cache.sync().unwrap();
+        assert_eq!(cache.dirty_buffers(), 0);
+        block::read_bytes(&disk.disk, 100, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"cached");
+    }
+
+    #[test_case]
+    fn evicted_buffers_are_written_back() {
+        let blocks = CAPACITY as u64 + 1;
+        let disk = CountingDisk::new(blocks * SECTORS_PER_BUFFER);
+        let cache = BufferCache::new(disk.clone());
+        for block in 0..blocks {
+            let sector = [block as u8 + 1; SECTOR_SIZE];
+            cache
+                .write_sectors(block * SECTORS_PER_BUFFER, &sector)
+                .unwrap();
+        }
+        assert_eq!(cache.dirty_buffers(), CAPACITY);
+        let mut sector = [0; SECTOR_SIZE];
+        disk.disk.read_sectors(0, &mut sector).unwrap();
+        assert!(sector.iter().all(|&b| b == 1));
+        cache.sync().unwrap();
+    }
+}
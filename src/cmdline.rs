@@ -0,0 +1,104 @@
+use crate::devicetree;
+
+// Options come from /chosen/bootargs as space separated `key=value` pairs or
+// bare flags, so `-append "log=debug test=page_table"` on the QEMU command
+// line tweaks a boot without a rebuild. Among them: log= for levels,
+// test= and test_timeout= for the test runner, console= and baud= for the
+// console, mem= and dma_limit= to hold back memory, gdb_uart= and
+// gdb_wait for the GDB stub, watchdog= for how long a hart may hang, and
+// maxpages= and maxfds= to limit what each process may hold.
+pub fn bootargs() -> &'static str {
+    devicetree::get()
+        .and_then(|tree| tree.find_node("/chosen"))
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|bootargs| bootargs.as_str())
+        .unwrap_or("")
+}
+
+fn find<'a>(args: &'a str, key: &str) -> Option<Option<&'a str>> {
+    args.split_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (option, None),
+        })
+        .filter(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+        .next_back()
+}
+
+pub fn parse_u64(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// A byte count, optionally with a K, M or G suffix.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_u64(number)?.checked_mul(1 << shift)
+}
+
+pub fn get(key: &str) -> Option<&'static str> {
+    find(bootargs(), key).flatten()
+}
+
+pub fn get_u64(key: &str) -> Option<u64> {
+    get(key).and_then(parse_u64)
+}
+
+pub fn get_size(key: &str) -> Option<u64> {
+    get(key).and_then(parse_size)
+}
+
+pub fn has(key: &str) -> bool {
+    find(bootargs(), key).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn finding_a_key_returns_its_value() {
+        assert_eq!(find("a=1 seed=42 b", "seed"), Some(Some("42")));
+    }
+
+    #[test_case]
+    fn finding_a_flag_returns_no_value() {
+        assert_eq!(find("a=1 quiet", "quiet"), Some(None));
+    }
+
+    #[test_case]
+    fn finding_a_missing_key_returns_none() {
+        assert_eq!(find("a=1 b=2", "c"), None);
+    }
+
+    #[test_case]
+    fn later_options_override_earlier_ones() {
+        assert_eq!(find("a=1 a=2", "a"), Some(Some("2")));
+    }
+
+    #[test_case]
+    fn numbers_parse_in_decimal_and_hex() {
+        assert_eq!(parse_u64("42"), Some(42));
+        assert_eq!(parse_u64("0x2a"), Some(42));
+        assert_eq!(parse_u64("forty-two"), None);
+    }
+
+    #[test_case]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64M"), Some(64 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("0x10K"), Some(16 << 10));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("0x100000000000G"), None);
+    }
+}
@@ -0,0 +1,40 @@
+use crate::error::KernelResult;
+use crate::serial;
+use crate::vfs::{File, FileType, Stat};
+
+// The serial console as a file, for standard input, output and error.
+pub struct Console;
+
+impl File for Console {
+    // Blocks for the first byte, then takes whatever else has already
+    // arrived.
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        buffer[0] = serial::read_byte();
+        let mut read = 1;
+        while read < buffer.len() {
+            match serial::try_read_byte() {
+                Some(byte) => buffer[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        serial::write_bytes(bytes);
+        Ok(bytes.len())
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(Stat {
+            file_type: FileType::CharDevice,
+            permissions: 0o620,
+            inode: 0,
+            size: 0,
+        })
+    }
+}
@@ -0,0 +1,181 @@
+// The "newc" cpio format, as Linux uses for initramfs archives.
+const MAGIC: &[u8] = b"070701";
+// With a checksum of the data, which isn't checked.
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_OFFSET: usize = 14;
+const FILE_SIZE_OFFSET: usize = 54;
+const NAME_SIZE_OFFSET: usize = 94;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    BadMagic,
+    // A header field that isn't hex, or a name that isn't UTF-8.
+    BadHeader,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_directory(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+// Fields are eight hex digits each.
+fn read_field(header: &[u8], offset: usize) -> Result<u32, CpioError> {
+    let digits =
+        core::str::from_utf8(&header[offset..offset + 8]).map_err(|_| CpioError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioError::BadHeader)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// Entries in the order they're stored, up to the trailer. It stops after
+// the first error.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self
+            .archive
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if !header.starts_with(MAGIC) && !header.starts_with(MAGIC_CRC) {
+            return Err(CpioError::BadMagic);
+        }
+        let mode = read_field(header, MODE_OFFSET)?;
+        let file_size = read_field(header, FILE_SIZE_OFFSET)? as usize;
+        let name_size = read_field(header, NAME_SIZE_OFFSET)? as usize;
+
+        // The name's size counts its NUL.
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        let name = name.strip_suffix(&[0]).ok_or(CpioError::BadHeader)?;
+        let name = core::str::from_utf8(name).map_err(|_| CpioError::BadHeader)?;
+
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.offset = align4(data_start + file_size);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.next_entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        archive.extend_from_slice(MAGIC);
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+        for field in fields {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(format!("{:08x}{:08x}", name.len() + 1, 0).as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    // An archive of (name, mode, data) entries, ended with the trailer.
+    pub fn archive(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, mode, data) in entries {
+            push_entry(&mut archive, name, *mode, data);
+        }
+        push_entry(&mut archive, TRAILER, 0, &[]);
+        archive
+    }
+
+    #[test_case]
+    fn entries_are_read_up_to_the_trailer() {
+        let mut bytes = archive(&[
+            ("bin", S_IFDIR | 0o755, &[]),
+            ("bin/tool", S_IFREG | 0o755, b"abcde"),
+        ]);
+        // Anything after the trailer is ignored.
+        bytes.extend_from_slice(b"padding");
+
+        let found: Vec<_> = entries(&bytes).map(Result::unwrap).collect();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].name, "bin");
+        assert!(found[0].is_directory());
+        assert_eq!(found[1].name, "bin/tool");
+        assert!(found[1].is_file());
+        assert_eq!(found[1].data, b"abcde");
+    }
+
+    #[test_case]
+    fn damaged_archives_are_rejected() {
+        let bytes = archive(&[("file", S_IFREG | 0o644, b"contents")]);
+        let mut iter = entries(&bytes[..120]);
+        assert_eq!(iter.next().unwrap().unwrap_err(), CpioError::Truncated);
+        assert!(iter.next().is_none());
+
+        let mut bytes = bytes;
+        bytes[0] = b'1';
+        assert_eq!(
+            entries(&bytes).next().unwrap().unwrap_err(),
+            CpioError::BadMagic
+        );
+    }
+}
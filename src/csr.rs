@@ -0,0 +1,328 @@
+use crate::satp::Satp;
+use bitflags::bitflags;
+use core::arch::asm;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Sstatus: u64 {
+        const SIE = 1 << 1;
+        const SPIE = 1 << 5;
+        const SPP = 1 << 8;
+        const FS = 3 << 13;
+        const SUM = 1 << 18;
+        const MXR = 1 << 19;
+        // Fields without a name survive a read-modify-write untouched.
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    // The layout shared by sie and sip.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Interrupts: u64 {
+        const SOFTWARE = 1 << 1;
+        const TIMER = 1 << 5;
+        const EXTERNAL = 1 << 9;
+        const _ = !0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeMode {
+    User,
+    Supervisor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatState {
+    Off,
+    Initial,
+    Clean,
+    Dirty,
+}
+
+impl Sstatus {
+    pub fn sie(self) -> bool {
+        self.contains(Self::SIE)
+    }
+
+    pub fn spie(self) -> bool {
+        self.contains(Self::SPIE)
+    }
+
+    // The mode the hart was in when it took the trap.
+    pub fn spp(self) -> PrivilegeMode {
+        if self.contains(Self::SPP) {
+            PrivilegeMode::Supervisor
+        } else {
+            PrivilegeMode::User
+        }
+    }
+
+    pub fn fs(self) -> FloatState {
+        match (self.bits() >> 13) & 0b11 {
+            0 => FloatState::Off,
+            1 => FloatState::Initial,
+            2 => FloatState::Clean,
+            _ => FloatState::Dirty,
+        }
+    }
+
+    pub fn sum(self) -> bool {
+        self.contains(Self::SUM)
+    }
+}
+
+const STVEC_MODE_MASK: u64 = 0b11;
+const STVEC_MODE_VECTORED: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stvec(u64);
+
+impl Stvec {
+    pub fn direct(base: u64) -> Self {
+        assert_eq!(
+            base & STVEC_MODE_MASK,
+            0,
+            "trap entry must be 4-byte aligned"
+        );
+        Self(base)
+    }
+
+    pub fn vectored(base: u64) -> Self {
+        assert_eq!(
+            base & STVEC_MODE_MASK,
+            0,
+            "trap entry must be 4-byte aligned"
+        );
+        Self(base | STVEC_MODE_VECTORED)
+    }
+
+    pub fn base(self) -> u64 {
+        self.0 & !STVEC_MODE_MASK
+    }
+
+    pub fn is_vectored(self) -> bool {
+        self.0 & STVEC_MODE_MASK == STVEC_MODE_VECTORED
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scause(u64);
+
+impl Scause {
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_interrupt(self) -> bool {
+        self.0 >> 63 != 0
+    }
+
+    pub fn code(self) -> u64 {
+        self.0 & !(1 << 63)
+    }
+}
+
+trait Register: Copy {
+    fn from_raw(bits: u64) -> Self;
+    fn raw(self) -> u64;
+}
+
+impl Register for u64 {
+    fn from_raw(bits: u64) -> Self {
+        bits
+    }
+
+    fn raw(self) -> u64 {
+        self
+    }
+}
+
+impl Register for Sstatus {
+    fn from_raw(bits: u64) -> Self {
+        Self::from_bits_retain(bits)
+    }
+
+    fn raw(self) -> u64 {
+        self.bits()
+    }
+}
+
+impl Register for Interrupts {
+    fn from_raw(bits: u64) -> Self {
+        Self::from_bits_retain(bits)
+    }
+
+    fn raw(self) -> u64 {
+        self.bits()
+    }
+}
+
+impl Register for Stvec {
+    fn from_raw(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    fn raw(self) -> u64 {
+        self.0
+    }
+}
+
+impl Register for Scause {
+    fn from_raw(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    fn raw(self) -> u64 {
+        self.0
+    }
+}
+
+impl Register for Satp {
+    fn from_raw(bits: u64) -> Self {
+        Self::from_bits(bits)
+    }
+
+    fn raw(self) -> u64 {
+        self.bits()
+    }
+}
+
+macro_rules! read_only {
+    ($csr:ident: $ty:ty) => {
+        pub mod $csr {
+            use super::*;
+
+            pub fn read() -> $ty {
+                let bits: u64;
+                unsafe {
+                    asm!(concat!("csrr {}, ", stringify!($csr)), out(reg) bits);
+                }
+                <$ty as Register>::from_raw(bits)
+            }
+        }
+    };
+}
+
+// Writes are unsafe as a class: among them are the ones that move the trap
+// entry, switch page tables or let the kernel touch user memory.
+macro_rules! read_write {
+    ($csr:ident: $ty:ty) => {
+        #[allow(clippy::missing_safety_doc)]
+        pub mod $csr {
+            use super::*;
+
+            pub fn read() -> $ty {
+                let bits: u64;
+                unsafe {
+                    asm!(concat!("csrr {}, ", stringify!($csr)), out(reg) bits);
+                }
+                <$ty as Register>::from_raw(bits)
+            }
+
+            pub unsafe fn write(value: $ty) {
+                asm!(concat!("csrw ", stringify!($csr), ", {}"), in(reg) value.raw());
+            }
+
+            pub unsafe fn set(bits: $ty) {
+                asm!(concat!("csrs ", stringify!($csr), ", {}"), in(reg) bits.raw());
+            }
+
+            pub unsafe fn clear(bits: $ty) {
+                asm!(concat!("csrc ", stringify!($csr), ", {}"), in(reg) bits.raw());
+            }
+
+            // Atomic with respect to traps, so the old value can decide how
+            // to undo the change.
+            pub unsafe fn read_and_set(bits: $ty) -> $ty {
+                let previous: u64;
+                asm!(
+                    concat!("csrrs {}, ", stringify!($csr), ", {}"),
+                    out(reg) previous,
+                    in(reg) bits.raw()
+                );
+                <$ty as Register>::from_raw(previous)
+            }
+
+            pub unsafe fn read_and_clear(bits: $ty) -> $ty {
+                let previous: u64;
+                asm!(
+                    concat!("csrrc {}, ", stringify!($csr), ", {}"),
+                    out(reg) previous,
+                    in(reg) bits.raw()
+                );
+                <$ty as Register>::from_raw(previous)
+            }
+        }
+    };
+}
+
+read_write!(sstatus: Sstatus);
+read_write!(sie: Interrupts);
+read_write!(sip: Interrupts);
+read_write!(stvec: Stvec);
+read_write!(sscratch: u64);
+read_write!(sepc: u64);
+read_write!(scause: Scause);
+read_write!(stval: u64);
+read_write!(satp: Satp);
+read_only!(time: u64);
+read_only!(cycle: u64);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trap;
+
+    #[test_case]
+    fn sstatus_fields() {
+        let status = Sstatus::SPP | Sstatus::SPIE | Sstatus::from_bits_retain(2 << 13);
+        assert_eq!(status.spp(), PrivilegeMode::Supervisor);
+        assert!(status.spie());
+        assert!(!status.sie());
+        assert_eq!(status.fs(), FloatState::Clean);
+        assert_eq!(Sstatus::empty().spp(), PrivilegeMode::User);
+        assert_eq!(Sstatus::FS.fs(), FloatState::Dirty);
+    }
+
+    #[test_case]
+    fn stvec_keeps_base_and_mode_apart() {
+        let stvec = Stvec::vectored(0x8020_0100);
+        assert_eq!(stvec.base(), 0x8020_0100);
+        assert!(stvec.is_vectored());
+        assert!(!Stvec::direct(0x8020_0100).is_vectored());
+    }
+
+    #[test_case]
+    fn read_and_clear_returns_the_previous_value() {
+        assert!(sstatus::read().sie());
+        let previous = unsafe { sstatus::read_and_clear(Sstatus::SIE) };
+        assert!(previous.sie());
+        assert!(!sstatus::read().sie());
+        unsafe { sstatus::set(Sstatus::SIE) };
+        assert!(sstatus::read().sie());
+    }
+
+    #[test_case]
+    fn sscratch_round_trips() {
+        // Trap entry finds the hart's area through sscratch, so nothing may
+        // trap while it holds anything else.
+        trap::without_interrupts(|| unsafe {
+            let area = sscratch::read();
+            sscratch::write(0x1234_5678);
+            let written = sscratch::read();
+            sscratch::write(area);
+            assert_eq!(written, 0x1234_5678);
+        });
+    }
+
+    #[test_case]
+    fn time_advances() {
+        let start = time::read();
+        while time::read() == start {
+            core::hint::spin_loop();
+        }
+        assert!(cycle::read() != 0);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::backtrace::{self, Backtrace, StackBounds};
+use crate::symbols::Symbolized;
+use crate::trap::{TrapFrame, REGISTER_NAMES};
+use crate::{cmdline, gdb_stub, monitor, serial, watchdog};
+use crate::{print, println};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern "C" {
+    static TEXT_START: u64;
+    static TEXT_END: u64;
+}
+
+// Bytes of code shown either side of the breakpoint.
+const CODE_CONTEXT: u64 = 16;
+
+const MAX_DUMP_WORDS: u64 = 64;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// With the debugger off, breakpoints are reported and then stepped over.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// The window of 16-bit instruction parcels around `pc` that lies in `text`.
+fn code_window(pc: u64, text: (u64, u64)) -> (u64, u64) {
+    let start = pc.saturating_sub(CODE_CONTEXT).max(text.0) & !1;
+    let end = (pc + CODE_CONTEXT).min(text.1) & !1;
+    (start, end)
+}
+
+fn print_code(pc: u64) {
+    let text = unsafe { (TEXT_START, TEXT_END) };
+    if !(text.0..text.1).contains(&pc) {
+        println!("  code: sepc is outside the kernel's text");
+        return;
+    }
+
+    let (start, end) = code_window(pc, text);
+    print!("  code:");
+    for address in (start..end).step_by(2) {
+        let parcel = unsafe { (address as *const u16).read_volatile() };
+        if address == pc {
+            print!(" ({:04x})", parcel);
+        } else {
+            print!(" {:04x}", parcel);
+        }
+    }
+    println!();
+}
+
+fn register_index(name: &str) -> Option<usize> {
+    if let Some(n) = name.strip_prefix('x').and_then(|n| n.parse().ok()) {
+        return (n < 32).then_some(n);
+    }
+    match name {
+        "fp" => Some(8),
+        name => REGISTER_NAMES.iter().position(|&n| n == name),
+    }
+}
+
+enum Flow {
+    Stay,
+    Resume,
+}
+
+fn help() {
+    println!("  c              step over the breakpoint and continue");
+    println!("  regs           print the trap frame");
+    println!("  set REG VALUE  change a saved register");
+    println!("  x ADDR [WORDS] dump memory as 64-bit words");
+    println!("  bt             backtrace from the breakpoint");
+    println!("  mon COMMAND    run a monitor command");
+}
+
+fn set_register(frame: &mut TrapFrame, args: &str) {
+    let (name, value) = args.split_once(' ').unwrap_or((args, ""));
+    match (register_index(name), cmdline::parse_u64(value.trim())) {
+        (Some(0), _) => println!("zero can't be changed"),
+        (Some(n), Some(value)) => frame.set_reg(n, value),
+        (None, _) => println!("Unknown register: {}", name),
+        (_, None) => println!("usage: set REG VALUE"),
+    }
+}
+
+// Reads through whatever mapping is live, so a bad address faults like any
+// other kernel access would.
+fn dump(args: &str) {
+    let mut args = args.split_whitespace();
+    let address = match args.next().and_then(cmdline::parse_u64) {
+        Some(address) if address % 8 == 0 => address,
+        _ => {
+            println!("usage: x ADDR [WORDS], with ADDR 8-byte aligned");
+            return;
+        }
+    };
+    let words = args
+        .next()
+        .and_then(cmdline::parse_u64)
+        .unwrap_or(4)
+        .min(MAX_DUMP_WORDS);
+
+    for row in (0..words).step_by(2) {
+        print!("  {:#018x}:", address + row * 8);
+        for word in row..(row + 2).min(words) {
+            let value = unsafe { ((address + word * 8) as *const u64).read_volatile() };
+            print!(" {:#018x}", value);
+        }
+        println!();
+    }
+}
+
+fn backtrace(frame: &TrapFrame) {
+    println!("  {}", Symbolized(frame.sepc));
+    backtrace::print(Backtrace::from_frame_pointer(
+        frame.reg(8),
+        StackBounds::current(),
+    ));
+}
+
+fn execute(frame: &mut TrapFrame, line: &str) -> Flow {
+    let line = line.trim();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+    match name {
+        "" => (),
+        "c" | "continue" => return Flow::Resume,
+        "regs" => frame.print(),
+        "set" => set_register(frame, args),
+        "x" => dump(args),
+        "bt" => backtrace(frame),
+        // The monitor reports its own errors.
+        "mon" => {
+            let _ = monitor::execute(args);
+        }
+        "help" => help(),
+        name => println!("Unknown command: {} (try help)", name),
+    }
+    Flow::Stay
+}
+
+// Interrupts are off for the whole session, so input is polled rather than
+// waiting on the UART interrupt.
+fn repl(frame: &mut TrapFrame) {
+    let mut buffer = [0; 128];
+    watchdog::pause();
+    loop {
+        print!("kdb> ");
+        let len = serial::poll_line(&mut buffer);
+        let line = core::str::from_utf8(&buffer[..len]).unwrap_or("");
+        if let Flow::Resume = execute(frame, line) {
+            watchdog::resume();
+            return;
+        }
+    }
+}
+
+pub fn handle_breakpoint(frame: &mut TrapFrame) -> bool {
+    if gdb_stub::is_attached() {
+        return gdb_stub::handle_breakpoint(frame);
+    }
+    println!("Breakpoint at {}", Symbolized(frame.sepc));
+    print_code(frame.sepc);
+    frame.print();
+
+    if is_enabled() {
+        repl(frame);
+    }
+    frame.skip_instruction();
+    true
+}
+
+pub fn init() {
+    if cmdline::has("kdebug") {
+        set_enabled(true);
+        println!("Kernel debugger enabled, breakpoints will stop for commands");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn registers_are_found_by_abi_name_or_number() {
+        assert_eq!(register_index("a0"), Some(10));
+        assert_eq!(register_index("fp"), Some(8));
+        assert_eq!(register_index("x31"), Some(31));
+        assert_eq!(register_index("x32"), None);
+        assert_eq!(register_index("pc"), None);
+    }
+
+    #[test_case]
+    fn the_code_window_stays_inside_the_text() {
+        let text = (0x1000, 0x2000);
+        assert_eq!(code_window(0x1800, text), (0x17f0, 0x1810));
+        assert_eq!(code_window(0x1004, text), (0x1000, 0x1014));
+        assert_eq!(code_window(0x1ffe, text), (0x1fee, 0x2000));
+    }
+
+    #[test_case]
+    fn set_changes_a_saved_register() {
+        let mut frame = TrapFrame::zeroed();
+        execute(&mut frame, "set a0 0x2a");
+        execute(&mut frame, "set zero 1");
+        assert_eq!(frame.arg(0), 42);
+        assert_eq!(frame.reg(0), 0);
+    }
+}
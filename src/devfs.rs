@@ -0,0 +1,245 @@
+use crate::console::Console;
+use crate::error;
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::{self, DirEntry, File, FileSystem, FileType, Inode, OpenFile, Stat};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Makes a new file each time the device is opened.
+pub type Opener = fn() -> KernelResult<OpenFile>;
+
+const ROOT_INODE: u64 = 1;
+
+struct Device {
+    inode: u64,
+    open: Opener,
+}
+
+static DEVICES: Mutex<BTreeMap<String, Device>> = Mutex::new(BTreeMap::new());
+static NEXT_INODE: AtomicU64 = AtomicU64::new(ROOT_INODE + 1);
+
+// Gives a driver a node in /dev. It can be called before /dev is mounted.
+pub fn register(name: &str, open: Opener) -> KernelResult<()> {
+    if name.is_empty() || name.contains('/') {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(KernelError::AlreadyExists);
+    }
+    let inode = NEXT_INODE.fetch_add(1, Ordering::Relaxed);
+    devices.insert(String::from(name), Device { inode, open });
+    Ok(())
+}
+
+pub fn device_stat(inode: u64) -> Stat {
+    Stat {
+        file_type: FileType::CharDevice,
+        permissions: 0o666,
+        inode,
+        size: 0,
+    }
+}
+
+struct DeviceNode {
+    inode: u64,
+    open: Opener,
+}
+
+impl Inode for DeviceNode {
+    fn stat(&self) -> Stat {
+        device_stat(self.inode)
+    }
+
+    fn open_device(&self) -> Option<KernelResult<OpenFile>> {
+        Some((self.open)())
+    }
+}
+
+// /dev itself, listing whatever's registered. Nodes can't be made or
+// removed through it.
+struct Directory;
+
+impl Inode for Directory {
+    fn stat(&self) -> Stat {
+        Stat {
+            file_type: FileType::Directory,
+            permissions: 0o755,
+            inode: ROOT_INODE,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> KernelResult<Arc<dyn Inode>> {
+        let devices = DEVICES.lock();
+        let device = devices.get(name).ok_or(KernelError::NotFound)?;
+        Ok(Arc::new(DeviceNode {
+            inode: device.inode,
+            open: device.open,
+        }))
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> KernelResult<Arc<dyn Inode>> {
+        Err(KernelError::NotSupported)
+    }
+
+    fn unlink(&self, _name: &str) -> KernelResult<()> {
+        Err(KernelError::NotSupported)
+    }
+
+    fn rmdir(&self, _name: &str) -> KernelResult<()> {
+        Err(KernelError::NotSupported)
+    }
+
+    fn entries(&self) -> KernelResult<Vec<DirEntry>> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .map(|(name, device)| DirEntry {
+                name: name.clone(),
+                inode: device.inode,
+                file_type: FileType::CharDevice,
+            })
+            .collect())
+    }
+}
+
+pub struct Devfs;
+
+impl FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Directory)
+    }
+}
+
+// Reads as empty and swallows whatever's written.
+struct Null;
+
+impl File for Null {
+    fn read(&self, _buffer: &mut [u8]) -> KernelResult<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        Ok(bytes.len())
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(device_stat(0))
+    }
+}
+
+// Reads as endless zeros and swallows whatever's written.
+struct Zero;
+
+impl File for Zero {
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        Ok(bytes.len())
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(device_stat(0))
+    }
+}
+
+// Registers the devices that have no driver of their own to do it, and
+// mounts /dev.
+pub fn init() {
+    let built_in: [(&str, Opener); 3] = [
+        ("console", || Ok(OpenFile::new(Console))),
+        ("null", || Ok(OpenFile::new(Null))),
+        ("zero", || Ok(OpenFile::new(Zero))),
+    ];
+    for (name, open) in built_in {
+        register(name, open).unwrap();
+    }
+    let mounted = vfs::mkdir_all("/dev").and_then(|()| vfs::mount("/dev", Arc::new(Devfs)));
+    if let Err(e) = mounted {
+        error!("Failed to mount /dev: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::OpenOptions;
+
+    fn open(path: &str) -> KernelResult<OpenFile> {
+        vfs::open(
+            path,
+            OpenOptions {
+                read: true,
+                write: true,
+                ..OpenOptions::default()
+            },
+        )
+    }
+
+    #[test_case]
+    fn null_and_zero_behave() {
+        let null = open("/dev/null").unwrap();
+        assert_eq!(null.write(b"gone"), Ok(4));
+        assert_eq!(null.read(&mut [1; 4]), Ok(0));
+
+        let zero = open("/dev/zero").unwrap();
+        let mut buffer = [1; 16];
+        assert_eq!(zero.read(&mut buffer), Ok(16));
+        assert_eq!(buffer, [0; 16]);
+        assert_eq!(zero.stat().unwrap().file_type, FileType::CharDevice);
+    }
+
+    #[test_case]
+    fn devices_only_allow_the_access_they_were_opened_for() {
+        let read_only = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        let null = vfs::open("/dev/null", read_only).unwrap();
+        assert_eq!(null.read(&mut [1; 4]), Ok(0));
+        assert_eq!(null.write(b"gone"), Err(KernelError::BadDescriptor));
+
+        let write_only = OpenOptions {
+            write: true,
+            ..OpenOptions::default()
+        };
+        let zero = vfs::open("/dev/zero", write_only).unwrap();
+        assert_eq!(zero.write(b"gone"), Ok(4));
+        assert_eq!(zero.read(&mut [1; 4]), Err(KernelError::BadDescriptor));
+    }
+
+    #[test_case]
+    fn registered_devices_appear_in_dev() {
+        fn open_test() -> KernelResult<OpenFile> {
+            Ok(OpenFile::new(Zero))
+        }
+        register("devfs-test", open_test).unwrap();
+        assert_eq!(
+            register("devfs-test", open_test),
+            Err(KernelError::AlreadyExists)
+        );
+
+        let entries = vfs::lookup("/dev").unwrap().entries().unwrap();
+        let entry = entries.iter().find(|e| e.name == "devfs-test").unwrap();
+        assert_eq!(entry.file_type, FileType::CharDevice);
+        assert_eq!(
+            vfs::lookup("/dev/devfs-test").unwrap().stat().inode,
+            entry.inode
+        );
+        assert!(open("/dev/devfs-test").is_ok());
+        assert!(matches!(open("/dev/missing"), Err(KernelError::NotFound)));
+        assert_eq!(vfs::unlink("/dev/null"), Err(KernelError::NotSupported));
+    }
+}
@@ -0,0 +1,490 @@
+use crate::once::StaticOnce;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const MAX_DEPTH: usize = 16;
+
+static DEVICE_TREE: StaticOnce<DeviceTree> = StaticOnce::new();
+
+#[derive(Debug)]
+pub enum DeviceTreeError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + 8)?;
+    Some(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_cells(bytes: &[u8], offset: usize, cells: u32) -> Option<u64> {
+    match cells {
+        0 => Some(0),
+        1 => read_u32(bytes, offset).map(|value| value as u64),
+        2 => read_u64(bytes, offset),
+        _ => None,
+    }
+}
+
+fn read_str(bytes: &[u8], offset: usize) -> Option<&str> {
+    let tail = bytes.get(offset..)?;
+    let length = tail.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&tail[..length]).ok()
+}
+
+fn align_4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+pub struct DeviceTree {
+    blob: &'static [u8],
+    struct_offset: usize,
+    strings_offset: usize,
+    reserve_map_offset: usize,
+}
+
+impl DeviceTree {
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn from_address(address: u64) -> Result<Self, DeviceTreeError> {
+        let header = core::slice::from_raw_parts(address as *const u8, 8);
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(DeviceTreeError::BadMagic);
+        }
+        let total_size = read_u32(header, 4).unwrap() as usize;
+
+        Self::from_bytes(core::slice::from_raw_parts(
+            address as *const u8,
+            total_size,
+        ))
+    }
+
+    pub fn from_bytes(blob: &'static [u8]) -> Result<Self, DeviceTreeError> {
+        if read_u32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(DeviceTreeError::BadMagic);
+        }
+
+        let header_field = |offset| read_u32(blob, offset).ok_or(DeviceTreeError::Truncated);
+
+        let last_compatible_version = header_field(24)?;
+        if last_compatible_version > FDT_LAST_COMPATIBLE_VERSION {
+            return Err(DeviceTreeError::UnsupportedVersion(last_compatible_version));
+        }
+
+        let tree = Self {
+            blob,
+            struct_offset: header_field(8)? as usize,
+            strings_offset: header_field(12)? as usize,
+            reserve_map_offset: header_field(16)? as usize,
+        };
+
+        if tree.struct_offset >= blob.len()
+            || tree.strings_offset >= blob.len()
+            || tree.reserve_map_offset >= blob.len()
+        {
+            return Err(DeviceTreeError::Truncated);
+        }
+
+        Ok(tree)
+    }
+
+    pub fn bytes(&self) -> &'static [u8] {
+        self.blob
+    }
+
+    pub fn address(&self) -> u64 {
+        self.blob.as_ptr() as u64
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.blob.len() as u64
+    }
+
+    pub fn nodes(&self) -> Nodes {
+        Nodes {
+            tree: *self,
+            offset: self.struct_offset,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH + 1],
+        }
+    }
+
+    pub fn root(&self) -> Option<Node> {
+        self.nodes().next()
+    }
+
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut target = components.next();
+        let mut matched = 0;
+
+        for node in self.nodes() {
+            let component = match target {
+                None => return Some(node),
+                Some(component) => component,
+            };
+
+            if node.depth <= matched && matched > 0 {
+                return None;
+            }
+
+            if node.depth == matched + 1 && node.matches_name(component) {
+                matched += 1;
+                target = components.next();
+                if target.is_none() {
+                    return Some(node);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn compatible_nodes<'a>(&self, compatible: &'a str) -> impl Iterator<Item = Node> + 'a {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible))
+    }
+
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node> {
+        self.nodes().find(|node| node.is_compatible(compatible))
+    }
+
+    pub fn reserved_entries(&self) -> ReservedEntries {
+        ReservedEntries {
+            tree: *self,
+            offset: self.reserve_map_offset,
+        }
+    }
+
+    fn string(&self, offset: usize) -> Option<&'static str> {
+        read_str(self.blob, self.strings_offset + offset)
+    }
+}
+
+pub struct Nodes {
+    tree: DeviceTree,
+    offset: usize,
+    depth: usize,
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let blob = self.tree.blob;
+
+        loop {
+            let token = read_u32(blob, self.offset)?;
+            self.offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(blob, self.offset)?;
+                    self.offset = align_4(self.offset + name.len() + 1);
+
+                    if self.depth >= MAX_DEPTH {
+                        return None;
+                    }
+
+                    let (parent_address_cells, parent_size_cells) = self.cells[self.depth];
+                    let node = Node {
+                        tree: self.tree,
+                        name,
+                        depth: self.depth,
+                        parent_address_cells,
+                        parent_size_cells,
+                        properties_offset: self.offset,
+                    };
+
+                    let address_cells = node
+                        .property("#address-cells")
+                        .and_then(|p| p.as_u32())
+                        .unwrap_or(2);
+                    let size_cells = node
+                        .property("#size-cells")
+                        .and_then(|p| p.as_u32())
+                        .unwrap_or(1);
+
+                    self.depth += 1;
+                    self.cells[self.depth] = (address_cells, size_cells);
+
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let length = read_u32(blob, self.offset)? as usize;
+                    self.offset = align_4(self.offset + 8 + length);
+                }
+                FDT_NOP => (),
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node {
+    tree: DeviceTree,
+    pub name: &'static str,
+    pub depth: usize,
+    parent_address_cells: u32,
+    parent_size_cells: u32,
+    properties_offset: usize,
+}
+
+impl Node {
+    pub fn unit_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap()
+    }
+
+    fn matches_name(&self, name: &str) -> bool {
+        if name.contains('@') {
+            self.name == name
+        } else {
+            self.unit_name() == name
+        }
+    }
+
+    pub fn properties(&self) -> Properties {
+        Properties {
+            tree: self.tree,
+            offset: self.properties_offset,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property> {
+        self.properties().find(|p| p.name == name)
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|p| p.as_strings().any(|c| c == compatible))
+    }
+
+    // One cell per interrupt, as the PLIC uses.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> {
+        self.property("interrupts")
+            .into_iter()
+            .flat_map(|p| p.as_u32s())
+    }
+
+    pub fn reg(&self) -> Option<Reg> {
+        Some(Reg {
+            value: self.property("reg")?.value,
+            address_cells: self.parent_address_cells,
+            size_cells: self.parent_size_cells,
+        })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node> {
+        let depth = self.depth;
+        let mut nodes = Nodes {
+            tree: self.tree,
+            offset: self.properties_offset,
+            depth: depth + 1,
+            cells: [(2, 1); MAX_DEPTH + 1],
+        };
+
+        let address_cells = self
+            .property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(2);
+        let size_cells = self
+            .property("#size-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1);
+        nodes.cells[depth + 1] = (address_cells, size_cells);
+
+        nodes
+            .take_while(move |node| node.depth > depth)
+            .filter(move |node| node.depth == depth + 1)
+    }
+}
+
+pub struct Properties {
+    tree: DeviceTree,
+    offset: usize,
+}
+
+impl Iterator for Properties {
+    type Item = Property;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let blob = self.tree.blob;
+
+        loop {
+            match read_u32(blob, self.offset)? {
+                FDT_PROP => {
+                    let length = read_u32(blob, self.offset + 4)? as usize;
+                    let name_offset = read_u32(blob, self.offset + 8)? as usize;
+                    let value_offset = self.offset + 12;
+                    self.offset = align_4(value_offset + length);
+
+                    return Some(Property {
+                        name: self.tree.string(name_offset)?,
+                        value: blob.get(value_offset..value_offset + length)?,
+                    });
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+impl Property {
+    pub fn as_u32(&self) -> Option<u32> {
+        read_u32(self.value, 0)
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.as_u32().map(|value| value as u64),
+            8 => read_u64(self.value, 0),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32s(&self) -> impl Iterator<Item = u32> {
+        self.value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+    }
+
+    pub fn as_str(&self) -> Option<&'static str> {
+        read_str(self.value, 0)
+    }
+
+    pub fn as_strings(&self) -> impl Iterator<Item = &'static str> {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+}
+
+pub struct Reg {
+    value: &'static [u8],
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl Iterator for Reg {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address = read_cells(self.value, 0, self.address_cells)?;
+        let size_offset = 4 * self.address_cells as usize;
+        let size = read_cells(self.value, size_offset, self.size_cells)?;
+        self.value = &self.value[size_offset + 4 * self.size_cells as usize..];
+        Some((address, size))
+    }
+}
+
+pub struct ReservedEntries {
+    tree: DeviceTree,
+    offset: usize,
+}
+
+impl Iterator for ReservedEntries {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address = read_u64(self.tree.blob, self.offset)?;
+        let size = read_u64(self.tree.blob, self.offset + 8)?;
+        self.offset += 16;
+
+        if address == 0 && size == 0 {
+            return None;
+        }
+
+        Some((address, size))
+    }
+}
+
+pub fn init(tree: DeviceTree) {
+    let _ = DEVICE_TREE.set(tree);
+}
+
+pub fn get() -> Option<DeviceTree> {
+    DEVICE_TREE.get().copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn boot_device_tree_is_available() {
+        assert!(get().is_some());
+    }
+
+    #[test_case]
+    fn root_node_has_empty_name() {
+        let root = get().unwrap().root().unwrap();
+        assert_eq!(root.name, "");
+        assert_eq!(root.depth, 0);
+    }
+
+    #[test_case]
+    fn finding_the_root_path_returns_the_root() {
+        let node = get().unwrap().find_node("/").unwrap();
+        assert_eq!(node.depth, 0);
+    }
+
+    #[test_case]
+    fn memory_node_has_a_non_empty_reg() {
+        let memory = get().unwrap().find_node("/memory").unwrap();
+        let (_, size) = memory.reg().unwrap().next().unwrap();
+        assert!(size > 0);
+    }
+
+    #[test_case]
+    fn finding_a_missing_node_returns_none() {
+        assert!(get().unwrap().find_node("/no-such-node").is_none());
+    }
+
+    #[test_case]
+    fn children_of_root_are_one_level_deep() {
+        let root = get().unwrap().root().unwrap();
+        assert!(root.children().all(|child| child.depth == 1));
+        assert!(root.children().any(|child| child.unit_name() == "cpus"));
+    }
+
+    #[test_case]
+    fn nodes_are_found_by_compatible_string() {
+        let tree = get().unwrap();
+        let uart = tree.find_compatible("ns16550a").unwrap();
+        assert_eq!(uart.unit_name(), "serial");
+        assert!(tree.compatible_nodes("no,such-device").next().is_none());
+    }
+
+    #[test_case]
+    fn interrupts_are_read_from_the_node() {
+        let uart = get().unwrap().find_compatible("ns16550a").unwrap();
+        assert_eq!(uart.interrupts().next(), Some(10));
+    }
+}
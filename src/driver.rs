@@ -0,0 +1,146 @@
+use crate::devicetree::{self, DeviceTree, Node};
+use crate::lock::RwSpinLock;
+use crate::{plic, serial, virtio};
+use crate::{print, println, warn};
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    MissingReg,
+    MissingInterrupt,
+    NoInterruptController,
+    AlreadyProbed,
+    Unsupported,
+}
+
+pub type ProbeResult = Result<(), DriverError>;
+
+pub struct Driver {
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    pub probe: fn(&Node) -> ProbeResult,
+}
+
+impl Driver {
+    pub fn matches(&self, node: &Node) -> bool {
+        self.compatible.iter().any(|c| node.is_compatible(c))
+    }
+}
+
+// A device tree node some driver has taken on.
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub driver: &'static str,
+    pub node: &'static str,
+    pub base: Option<u64>,
+    pub irq: Option<u32>,
+}
+
+impl Device {
+    fn new(driver: &Driver, node: &Node) -> Self {
+        Self {
+            driver: driver.name,
+            node: node.name,
+            base: node
+                .reg()
+                .and_then(|mut reg| reg.next())
+                .map(|(base, _)| base),
+            irq: node.interrupts().next(),
+        }
+    }
+}
+
+// Probed in this order, so interrupt controllers come before the devices
+// that route interrupts through them.
+const BUILTIN_DRIVERS: [&Driver; 3] = [&plic::DRIVER, &serial::DRIVER, &virtio::DRIVER];
+
+static DRIVERS: RwSpinLock<Vec<&'static Driver>> = RwSpinLock::new(Vec::new());
+static DEVICES: RwSpinLock<Vec<Device>> = RwSpinLock::new(Vec::new());
+
+pub fn register(driver: &'static Driver) {
+    DRIVERS.write().push(driver);
+}
+
+fn is_bound(node: &Node) -> bool {
+    DEVICES.read().iter().any(|device| device.node == node.name)
+}
+
+// Offers every unclaimed node to each driver that's compatible with it and
+// returns how many were bound.
+pub fn probe_all(tree: &DeviceTree) -> usize {
+    let drivers = DRIVERS.read().clone();
+    let mut bound = 0;
+    for driver in drivers {
+        for node in tree.nodes().filter(|node| driver.matches(node)) {
+            if is_bound(&node) {
+                continue;
+            }
+            match (driver.probe)(&node) {
+                Ok(()) => {
+                    DEVICES.write().push(Device::new(driver, &node));
+                    bound += 1;
+                }
+                Err(DriverError::Unsupported) => (),
+                Err(error) => warn!(
+                    "{}: {} failed to probe: {:?}",
+                    node.name, driver.name, error
+                ),
+            }
+        }
+    }
+    bound
+}
+
+pub fn devices() -> Vec<Device> {
+    DEVICES.read().clone()
+}
+
+pub fn find_device(driver: &str) -> Option<Device> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.driver == driver)
+        .copied()
+}
+
+pub fn report() {
+    for device in devices() {
+        print!("  {:<8} {}", device.driver, device.node);
+        if let Some(irq) = device.irq {
+            print!(", IRQ {}", irq);
+        }
+        println!();
+    }
+}
+
+pub fn init() {
+    for driver in BUILTIN_DRIVERS {
+        register(driver);
+    }
+    match devicetree::get() {
+        Some(tree) => {
+            probe_all(&tree);
+        }
+        None => warn!("No device tree, no drivers probed"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_console_uart_and_plic_are_bound() {
+        let uart = find_device("ns16550").unwrap();
+        assert_eq!(uart.base, Some(serial::base_address()));
+        assert!(uart.irq.is_some());
+        assert!(find_device("plic").is_some());
+    }
+
+    #[test_case]
+    fn bound_nodes_are_not_probed_again() {
+        let count = devices().len();
+        assert_eq!(probe_all(&devicetree::get().unwrap()), 0);
+        assert_eq!(devices().len(), count);
+    }
+}
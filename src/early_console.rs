@@ -0,0 +1,123 @@
+use crate::boot_alloc;
+use crate::serial::{Uart, UartConfig, QEMU_UART0_ADDRESS};
+use core::alloc::Layout;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::{ptr, slice};
+
+// How much of what the early console writes is kept for later.
+const TRANSCRIPT_SIZE: usize = 8 * 1024;
+
+// Output from the first Rust instruction until the serial driver takes
+// over: polled writes straight to the UART the firmware has already set up,
+// with no locks or lazy statics. Harts other than the boot hart aren't
+// running yet, so nothing else touches it.
+static ACTIVE: AtomicBool = AtomicBool::new(true);
+static BASE: AtomicU64 = AtomicU64::new(QEMU_UART0_ADDRESS);
+static REG_SHIFT: AtomicU32 = AtomicU32::new(0);
+static REG_IO_WIDTH: AtomicU32 = AtomicU32::new(1);
+
+// A log UART only comes up once the early console has handed off, so what
+// was written before then is kept in the boot region to be replayed to it.
+// Anything past TRANSCRIPT_SIZE is only sent, not kept.
+static TRANSCRIPT: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static TRANSCRIPT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// Moves to the UART the device tree names, once it's been read.
+pub fn set_config(config: UartConfig) {
+    BASE.store(config.base, Ordering::Relaxed);
+    REG_SHIFT.store(config.reg_shift, Ordering::Relaxed);
+    REG_IO_WIDTH.store(config.reg_io_width, Ordering::Relaxed);
+}
+
+// Starts keeping a transcript, once the boot allocator is up.
+pub fn keep_transcript() {
+    let layout = Layout::from_size_align(TRANSCRIPT_SIZE, 1).unwrap();
+    if let Some(buffer) = boot_alloc::alloc(layout) {
+        TRANSCRIPT.store(buffer, Ordering::Relaxed);
+    }
+}
+
+// Everything written from keep_transcript() up to the hand-off.
+pub fn transcript() -> &'static [u8] {
+    let buffer = TRANSCRIPT.load(Ordering::Relaxed);
+    if buffer.is_null() {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(buffer, TRANSCRIPT_LEN.load(Ordering::Relaxed)) }
+}
+
+fn record(bytes: &[u8]) {
+    let buffer = TRANSCRIPT.load(Ordering::Relaxed);
+    if buffer.is_null() {
+        return;
+    }
+    let len = TRANSCRIPT_LEN.load(Ordering::Relaxed);
+    let count = bytes.len().min(TRANSCRIPT_SIZE - len);
+    unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.add(len), count) };
+    TRANSCRIPT_LEN.store(len + count, Ordering::Relaxed);
+}
+
+struct EarlyConsole;
+
+impl Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn uart() -> Uart {
+    let config = UartConfig {
+        base: BASE.load(Ordering::Relaxed),
+        reg_shift: REG_SHIFT.load(Ordering::Relaxed),
+        reg_io_width: REG_IO_WIDTH.load(Ordering::Relaxed),
+        // The firmware's baud rate is kept.
+        clock_frequency: 0,
+    };
+    unsafe { Uart::new(config) }
+}
+
+pub fn print(args: fmt::Arguments) {
+    let _ = EarlyConsole.write_fmt(args);
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    record(bytes);
+    let mut uart = uart();
+    for &byte in bytes {
+        uart.send(byte);
+    }
+}
+
+// Lets what's already been written drain before the serial driver resets
+// the UART, then sends everything through the driver from here on.
+pub fn hand_off() {
+    if !is_active() {
+        return;
+    }
+    uart().wait_until_idle();
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_serial_driver_has_taken_over_by_the_time_tests_run() {
+        assert!(!is_active());
+        hand_off();
+        assert!(!is_active());
+    }
+
+    #[test_case]
+    fn the_boot_messages_are_kept_for_a_later_log_uart() {
+        let transcript = core::str::from_utf8(transcript()).unwrap();
+        assert!(transcript.contains("Memory layout:"));
+    }
+}
@@ -0,0 +1,189 @@
+use alloc::vec::Vec;
+
+// Just enough of ELF64 to load a statically linked RISC-V executable.
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    BadMagic,
+    // Not a 64-bit little-endian RISC-V executable.
+    Unsupported,
+    Truncated,
+    BadSegment,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+// A PT_LOAD segment. Memory past the end of `data` up to `memory_size` is
+// zeroed.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub address: u64,
+    pub memory_size: u64,
+    pub data: &'a [u8],
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+pub struct Elf<'a> {
+    entry: u64,
+    segments: Vec<Segment<'a>>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if !is_elf(image) {
+            return Err(ElfError::BadMagic);
+        }
+        let header = image.get(..HEADER_SIZE).ok_or(ElfError::Truncated)?;
+        if header[4] != ELFCLASS64
+            || header[5] != ELFDATA2LSB
+            || read_u16(header, 16) != Some(ET_EXEC)
+            || read_u16(header, 18) != Some(EM_RISCV)
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = read_u64(header, 24).unwrap();
+        let table = read_u64(header, 32).unwrap() as usize;
+        let entry_size = read_u16(header, 54).unwrap() as usize;
+        let count = read_u16(header, 56).unwrap() as usize;
+        if entry_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+
+        let mut segments = Vec::new();
+        for n in 0..count {
+            let offset = table
+                .checked_add(n * entry_size)
+                .ok_or(ElfError::Truncated)?;
+            let field = |at| read_u64(image, offset + at).ok_or(ElfError::Truncated);
+            let kind = read_u32(image, offset).ok_or(ElfError::Truncated)?;
+            let flags = read_u32(image, offset + 4).ok_or(ElfError::Truncated)?;
+            if kind != PT_LOAD {
+                continue;
+            }
+
+            let (file_offset, address, file_size, memory_size) =
+                (field(8)?, field(16)?, field(32)?, field(40)?);
+            if file_size > memory_size || address.checked_add(memory_size).is_none() {
+                return Err(ElfError::BadSegment);
+            }
+            let data = file_offset
+                .checked_add(file_size)
+                .and_then(|end| image.get(file_offset as usize..end as usize))
+                .ok_or(ElfError::Truncated)?;
+            segments.push(Segment {
+                address,
+                memory_size,
+                data,
+                readable: flags & PF_R != 0,
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            });
+        }
+
+        Ok(Self { entry, segments })
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment<'a>] {
+        &self.segments
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // An executable with `code` as its one read-execute segment, loaded at
+    // `address` and entered at its start, with `bss` zeroed bytes after it.
+    pub fn executable(address: u64, code: &[u8], bss: u64) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(ELF_MAGIC);
+        image.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1]);
+        image.resize(16, 0);
+        image.extend_from_slice(&ET_EXEC.to_le_bytes());
+        image.extend_from_slice(&EM_RISCV.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&address.to_le_bytes());
+        image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        image.extend_from_slice(&0u64.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&1u16.to_le_bytes());
+        image.resize(HEADER_SIZE, 0);
+
+        let offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+        let size = code.len() as u64;
+        image.extend_from_slice(&PT_LOAD.to_le_bytes());
+        image.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+        for field in [offset, address, address, size, size + bss, 4096] {
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test_case]
+    fn loadable_segments_are_found() {
+        let image = executable(0x1000, &[1, 2, 3, 4], 12);
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry(), 0x1000);
+
+        let segment = elf.segments()[0];
+        assert_eq!(segment.address, 0x1000);
+        assert_eq!(segment.memory_size, 16);
+        assert_eq!(segment.data, &[1, 2, 3, 4]);
+        assert!(segment.readable && segment.executable && !segment.writable);
+    }
+
+    #[test_case]
+    fn malformed_images_are_rejected() {
+        assert_eq!(Elf::parse(b"#!/bin/sh").err(), Some(ElfError::BadMagic));
+
+        let image = executable(0x1000, &[1, 2, 3, 4], 0);
+        assert_eq!(
+            Elf::parse(&image[..image.len() - 1]).err(),
+            Some(ElfError::Truncated)
+        );
+
+        let mut image = image;
+        image[18] = 0x3e;
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::Unsupported));
+    }
+}
@@ -0,0 +1,303 @@
+use core::fmt;
+
+use crate::address::VirtualAddressError;
+use crate::cpio::CpioError;
+use crate::devicetree::DeviceTreeError;
+use crate::driver::DriverError;
+use crate::elf::ElfError;
+use crate::fw_cfg::FwCfgError;
+use crate::page_allocator::PageAllocationError;
+use crate::sbi::SbiError;
+use crate::task::TaskError;
+use crate::virtio::VirtioError;
+
+pub type KernelResult<T> = Result<T, KernelError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    OutOfMemory,
+    InvalidAddress,
+    InvalidArgument,
+    NotFound,
+    PermissionDenied,
+    DeviceError,
+    Busy,
+    WouldBlock,
+    Interrupted,
+    NotSupported,
+    NotExecutable,
+    NoChildren,
+    NoSuchProcess,
+    BadDescriptor,
+    TooManyFiles,
+    BrokenPipe,
+    IllegalSeek,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    Io,
+    NoSpace,
+    ReadOnly,
+    FileTooLarge,
+    NotPermitted,
+}
+
+impl KernelError {
+    const ALL: [KernelError; 26] = [
+        KernelError::OutOfMemory,
+        KernelError::InvalidAddress,
+        KernelError::InvalidArgument,
+        KernelError::NotFound,
+        KernelError::PermissionDenied,
+        KernelError::DeviceError,
+        KernelError::Busy,
+        KernelError::WouldBlock,
+        KernelError::Interrupted,
+        KernelError::NotSupported,
+        KernelError::NotExecutable,
+        KernelError::NoChildren,
+        KernelError::NoSuchProcess,
+        KernelError::BadDescriptor,
+        KernelError::TooManyFiles,
+        KernelError::BrokenPipe,
+        KernelError::IllegalSeek,
+        KernelError::AlreadyExists,
+        KernelError::NotADirectory,
+        KernelError::IsADirectory,
+        KernelError::DirectoryNotEmpty,
+        KernelError::Io,
+        KernelError::NoSpace,
+        KernelError::ReadOnly,
+        KernelError::FileTooLarge,
+        KernelError::NotPermitted,
+    ];
+
+    // Linux errno values, so user space can use the usual constants.
+    pub fn errno(self) -> i64 {
+        let code = match self {
+            KernelError::NotPermitted => 1,
+            KernelError::NotFound => 2,
+            KernelError::NoSuchProcess => 3,
+            KernelError::Interrupted => 4,
+            KernelError::Io => 5,
+            KernelError::NotExecutable => 8,
+            KernelError::BadDescriptor => 9,
+            KernelError::NoChildren => 10,
+            KernelError::WouldBlock => 11,
+            KernelError::OutOfMemory => 12,
+            KernelError::PermissionDenied => 13,
+            KernelError::InvalidAddress => 14,
+            KernelError::Busy => 16,
+            KernelError::AlreadyExists => 17,
+            KernelError::DeviceError => 19,
+            KernelError::NotADirectory => 20,
+            KernelError::IsADirectory => 21,
+            KernelError::InvalidArgument => 22,
+            KernelError::TooManyFiles => 24,
+            KernelError::FileTooLarge => 27,
+            KernelError::NoSpace => 28,
+            KernelError::IllegalSeek => 29,
+            KernelError::ReadOnly => 30,
+            KernelError::BrokenPipe => 32,
+            KernelError::NotSupported => 38,
+            KernelError::DirectoryNotEmpty => 39,
+        };
+        -code
+    }
+
+    pub fn from_errno(value: i64) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.errno() == value)
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            KernelError::OutOfMemory => "out of memory",
+            KernelError::InvalidAddress => "invalid address",
+            KernelError::InvalidArgument => "invalid argument",
+            KernelError::NotFound => "not found",
+            KernelError::PermissionDenied => "permission denied",
+            KernelError::DeviceError => "device error",
+            KernelError::Busy => "device or resource busy",
+            KernelError::WouldBlock => "operation would block",
+            KernelError::Interrupted => "interrupted",
+            KernelError::NotSupported => "not supported",
+            KernelError::NotExecutable => "not an executable",
+            KernelError::NoChildren => "no child processes",
+            KernelError::NoSuchProcess => "no such process",
+            KernelError::BadDescriptor => "bad file descriptor",
+            KernelError::TooManyFiles => "too many open files",
+            KernelError::BrokenPipe => "broken pipe",
+            KernelError::IllegalSeek => "illegal seek",
+            KernelError::AlreadyExists => "already exists",
+            KernelError::NotADirectory => "not a directory",
+            KernelError::IsADirectory => "is a directory",
+            KernelError::DirectoryNotEmpty => "directory not empty",
+            KernelError::Io => "I/O error",
+            KernelError::NoSpace => "no space left on device",
+            KernelError::ReadOnly => "read-only file system",
+            KernelError::FileTooLarge => "file too large",
+            KernelError::NotPermitted => "operation not permitted",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl From<PageAllocationError> for KernelError {
+    fn from(e: PageAllocationError) -> Self {
+        match e {
+            PageAllocationError::NoPagesAvailable => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<VirtualAddressError> for KernelError {
+    fn from(e: VirtualAddressError) -> Self {
+        match e {
+            VirtualAddressError::OutOfVirtualMemoryRange => KernelError::InvalidAddress,
+            VirtualAddressError::NotMapped => KernelError::InvalidAddress,
+            VirtualAddressError::Unaligned => KernelError::InvalidAddress,
+        }
+    }
+}
+
+impl From<TaskError> for KernelError {
+    fn from(e: TaskError) -> Self {
+        match e {
+            TaskError::OutOfMemory => KernelError::OutOfMemory,
+            TaskError::InvalidAffinity => KernelError::InvalidArgument,
+            TaskError::NotFound => KernelError::NoSuchProcess,
+        }
+    }
+}
+
+impl From<DriverError> for KernelError {
+    fn from(e: DriverError) -> Self {
+        match e {
+            DriverError::AlreadyProbed => KernelError::Busy,
+            DriverError::MissingReg
+            | DriverError::MissingInterrupt
+            | DriverError::NoInterruptController
+            | DriverError::Unsupported => KernelError::DeviceError,
+        }
+    }
+}
+
+impl From<VirtioError> for KernelError {
+    fn from(e: VirtioError) -> Self {
+        match e {
+            VirtioError::OutOfMemory => KernelError::OutOfMemory,
+            VirtioError::QueueFull => KernelError::WouldBlock,
+            VirtioError::QueueInUse => KernelError::Busy,
+            VirtioError::BadMagic(_)
+            | VirtioError::UnsupportedVersion(_)
+            | VirtioError::NoDevice
+            | VirtioError::FeaturesRejected
+            | VirtioError::QueueUnavailable => KernelError::DeviceError,
+        }
+    }
+}
+
+impl From<FwCfgError> for KernelError {
+    fn from(e: FwCfgError) -> Self {
+        match e {
+            FwCfgError::NotPresent => KernelError::DeviceError,
+            FwCfgError::FileNotFound => KernelError::NotFound,
+            FwCfgError::OutOfMemory => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<SbiError> for KernelError {
+    fn from(e: SbiError) -> Self {
+        match e {
+            SbiError::NotSupported => KernelError::NotSupported,
+            SbiError::InvalidParam => KernelError::InvalidArgument,
+            SbiError::Denied => KernelError::PermissionDenied,
+            SbiError::InvalidAddress => KernelError::InvalidAddress,
+            SbiError::AlreadyAvailable | SbiError::AlreadyStarted | SbiError::AlreadyStopped => {
+                KernelError::Busy
+            }
+            SbiError::Failed | SbiError::Unknown(_) => KernelError::DeviceError,
+        }
+    }
+}
+
+impl From<DeviceTreeError> for KernelError {
+    fn from(e: DeviceTreeError) -> Self {
+        match e {
+            DeviceTreeError::BadMagic
+            | DeviceTreeError::UnsupportedVersion(_)
+            | DeviceTreeError::Truncated => KernelError::InvalidArgument,
+        }
+    }
+}
+
+impl From<CpioError> for KernelError {
+    fn from(e: CpioError) -> Self {
+        match e {
+            CpioError::BadMagic | CpioError::BadHeader | CpioError::Truncated => {
+                KernelError::InvalidArgument
+            }
+        }
+    }
+}
+
+impl From<ElfError> for KernelError {
+    fn from(e: ElfError) -> Self {
+        match e {
+            ElfError::BadMagic
+            | ElfError::Unsupported
+            | ElfError::Truncated
+            | ElfError::BadSegment => KernelError::NotExecutable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn errnos_are_negative() {
+        assert!(KernelError::ALL.iter().all(|e| e.errno() < 0));
+    }
+
+    #[test_case]
+    fn errnos_round_trip() {
+        for e in KernelError::ALL {
+            assert_eq!(KernelError::from_errno(e.errno()), Some(e));
+        }
+    }
+
+    #[test_case]
+    fn unknown_errnos_are_rejected() {
+        assert_eq!(KernelError::from_errno(0), None);
+        assert_eq!(KernelError::from_errno(-9999), None);
+    }
+
+    #[test_case]
+    fn allocation_failure_is_out_of_memory() {
+        let e: KernelError = PageAllocationError::NoPagesAvailable.into();
+        assert_eq!(e, KernelError::OutOfMemory);
+    }
+
+    #[test_case]
+    fn firmware_errors_map_to_categories() {
+        assert_eq!(
+            KernelError::from(SbiError::Denied),
+            KernelError::PermissionDenied
+        );
+        assert_eq!(
+            KernelError::from(SbiError::Unknown(-42)),
+            KernelError::DeviceError
+        );
+        assert_eq!(
+            KernelError::from(DriverError::AlreadyProbed),
+            KernelError::Busy
+        );
+        assert_eq!(KernelError::Busy.errno(), -16);
+    }
+}
@@ -0,0 +1,560 @@
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Stat};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+// Features a reader has to understand. Flexible block groups only move
+// the bitmaps and inode tables, which the group descriptors say anyway.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const ROOT_INODE: u32 = 2;
+// The size of every inode before the dynamic revision.
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+
+const DIRECT_BLOCKS: u64 = 12;
+const SINGLE_INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+
+const DIRENT_HEADER_SIZE: usize = 8;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    blocks: [u32; 15],
+}
+
+impl RawInode {
+    // None for links, device nodes and the like, which can't be opened.
+    fn file_type(&self) -> Option<FileType> {
+        match self.mode & S_IFMT {
+            S_IFREG => Some(FileType::Regular),
+            S_IFDIR => Some(FileType::Directory),
+            _ => None,
+        }
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    // The first block of each group's inode table.
+    inode_tables: Vec<u32>,
+    // Otherwise directory entries have a 16-bit name length and no type.
+    typed_entries: bool,
+}
+
+impl Volume {
+    // Fails with InvalidArgument if there's no ext2 file system on it, and
+    // NotSupported if it needs features this can't read.
+    fn open(device: Arc<dyn BlockDevice>) -> KernelResult<Self> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        let device_size = device.sector_count() * SECTOR_SIZE as u64;
+        if device_size < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(KernelError::InvalidArgument);
+        }
+        block::read_bytes(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+
+        let inode_count = read_u32(&superblock, 0);
+        let block_count = read_u32(&superblock, 4) as u64;
+        let first_data_block = read_u32(&superblock, 20) as u64;
+        let log_block_size = read_u32(&superblock, 24);
+        let blocks_per_group = read_u32(&superblock, 32) as u64;
+        let inodes_per_group = read_u32(&superblock, 40);
+        if read_u16(&superblock, 56) != MAGIC || log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(KernelError::InvalidArgument);
+        }
+        let block_size = 1024 << log_block_size;
+        let inode_size = match read_u32(&superblock, 76) {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => read_u16(&superblock, 88) as u64,
+        };
+        let valid = inode_count > 0
+            && blocks_per_group > 0
+            && inodes_per_group > 0
+            && block_count > first_data_block
+            && block_count * block_size <= device_size
+            && inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size.is_power_of_two()
+            && inode_size <= block_size;
+        if !valid {
+            return Err(KernelError::InvalidArgument);
+        }
+        let incompat = read_u32(&superblock, 96);
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(KernelError::NotSupported);
+        }
+
+        // The group descriptors are in the block after the superblock's.
+        let groups = (block_count - first_data_block).div_ceil(blocks_per_group) as usize;
+        let mut descriptors = vec![0; groups * GROUP_DESCRIPTOR_SIZE];
+        block::read_bytes(
+            &*device,
+            (first_data_block + 1) * block_size,
+            &mut descriptors,
+        )?;
+        Ok(Self {
+            device,
+            block_size,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables: descriptors
+                .chunks_exact(GROUP_DESCRIPTOR_SIZE)
+                .map(|descriptor| read_u32(descriptor, 8))
+                .collect(),
+            typed_entries: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        block::read_bytes(&*self.device, offset, buffer)
+    }
+
+    fn read_inode(&self, number: u32) -> KernelResult<RawInode> {
+        if number == 0 || number > self.inode_count {
+            return Err(KernelError::Io);
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = ((number - 1) % self.inodes_per_group) as u64;
+        let table = *self.inode_tables.get(group).ok_or(KernelError::Io)?;
+        let mut bytes = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.read(
+            table as u64 * self.block_size + index * self.inode_size,
+            &mut bytes,
+        )?;
+
+        let mode = read_u16(&bytes, 0);
+        // The top half of a file's size is where directories keep an ACL.
+        let size_high = match mode & S_IFMT {
+            S_IFREG => read_u32(&bytes, 108) as u64,
+            _ => 0,
+        };
+        let mut blocks = [0; 15];
+        for (n, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(&bytes, 40 + n * 4);
+        }
+        Ok(RawInode {
+            mode,
+            size: size_high << 32 | read_u32(&bytes, 4) as u64,
+            blocks,
+        })
+    }
+
+    // The `index`th block number in the indirect block `block`, which, like
+    // the number returned, is zero for a hole.
+    fn indirect(&self, block: u32, index: u64) -> KernelResult<u32> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut bytes = [0; 4];
+        self.read(block as u64 * self.block_size + index * 4, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    // Where the `index`th block of an inode's data is, or zero for a hole.
+    fn block_at(&self, inode: &RawInode, index: u64) -> KernelResult<u32> {
+        let per_block = self.block_size / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return self.indirect(inode.blocks[SINGLE_INDIRECT], index);
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let table = self.indirect(inode.blocks[DOUBLE_INDIRECT], index / per_block)?;
+            return self.indirect(table, index % per_block);
+        }
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let tables = self.indirect(
+                inode.blocks[TRIPLE_INDIRECT],
+                index / (per_block * per_block),
+            )?;
+            let table = self.indirect(tables, index / per_block % per_block)?;
+            return self.indirect(table, index % per_block);
+        }
+        Err(KernelError::Io)
+    }
+
+    fn read_data(&self, inode: &RawInode, offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let count = ((self.block_size - within) as usize).min(buffer.len() - done);
+            let chunk = &mut buffer[done..done + count];
+            match self.block_at(inode, position / self.block_size)? {
+                0 => chunk.fill(0),
+                block => self.read(block as u64 * self.block_size + within, chunk)?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    raw: RawInode,
+}
+
+impl Ext2Inode {
+    fn is_directory(&self) -> bool {
+        self.raw.mode & S_IFMT == S_IFDIR
+    }
+
+    // What's refused for everything, as the volume is only ever read.
+    fn modify<T>(&self, of_directories: bool) -> KernelResult<T> {
+        match (self.is_directory(), of_directories) {
+            (true, false) => Err(KernelError::IsADirectory),
+            (false, true) => Err(KernelError::NotADirectory),
+            _ => Err(KernelError::ReadOnly),
+        }
+    }
+
+    // The name, inode and type byte of each entry but "." and "..".
+    fn records(&self) -> KernelResult<Vec<(String, u32, u8)>> {
+        if !self.is_directory() {
+            return Err(KernelError::NotADirectory);
+        }
+        let mut data = vec![0; self.raw.size as usize];
+        self.volume.read_data(&self.raw, 0, &mut data)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset + DIRENT_HEADER_SIZE <= data.len() {
+            let entry = &data[offset..];
+            let inode = read_u32(entry, 0);
+            let length = read_u16(entry, 4) as usize;
+            let (name_length, file_type) = match self.volume.typed_entries {
+                true => (entry[6] as usize, entry[7]),
+                false => (read_u16(entry, 6) as usize, 0),
+            };
+            if length < DIRENT_HEADER_SIZE
+                || length > entry.len()
+                || DIRENT_HEADER_SIZE + name_length > length
+            {
+                return Err(KernelError::Io);
+            }
+            let name = &entry[DIRENT_HEADER_SIZE..DIRENT_HEADER_SIZE + name_length];
+            if inode != 0 && name != b"." && name != b".." {
+                records.push((String::from_utf8_lossy(name).into_owned(), inode, file_type));
+            }
+            offset += length;
+        }
+        Ok(records)
+    }
+
+    fn open(volume: Arc<Volume>, number: u32) -> KernelResult<Arc<Ext2Inode>> {
+        let raw = volume.read_inode(number)?;
+        if raw.file_type().is_none() {
+            return Err(KernelError::NotSupported);
+        }
+        Ok(Arc::new(Ext2Inode {
+            volume,
+            number,
+            raw,
+        }))
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn stat(&self) -> Stat {
+        Stat {
+            file_type: self.raw.file_type().unwrap_or(FileType::Regular),
+            permissions: self.raw.mode & 0o777,
+            inode: self.number as u64,
+            size: self.raw.size,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+        if self.is_directory() {
+            return Err(KernelError::IsADirectory);
+        }
+        let count = (buffer.len() as u64).min(self.raw.size.saturating_sub(offset)) as usize;
+        self.volume
+            .read_data(&self.raw, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _bytes: &[u8]) -> KernelResult<usize> {
+        self.modify(false)
+    }
+
+    fn truncate(&self, _size: u64) -> KernelResult<()> {
+        self.modify(false)
+    }
+
+    fn lookup(&self, name: &str) -> KernelResult<Arc<dyn Inode>> {
+        let (_, number, _) = self
+            .records()?
+            .into_iter()
+            .find(|(record, _, _)| record == name)
+            .ok_or(KernelError::NotFound)?;
+        Ok(Ext2Inode::open(self.volume.clone(), number)?)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> KernelResult<Arc<dyn Inode>> {
+        self.modify(true)
+    }
+
+    fn unlink(&self, _name: &str) -> KernelResult<()> {
+        self.modify(true)
+    }
+
+    fn rmdir(&self, _name: &str) -> KernelResult<()> {
+        self.modify(true)
+    }
+
+    // Only what lookup can open is listed.
+    fn entries(&self) -> KernelResult<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for (name, inode, file_type) in self.records()? {
+            let file_type = match (self.volume.typed_entries, file_type) {
+                (true, FT_REG_FILE) => Some(FileType::Regular),
+                (true, FT_DIR) => Some(FileType::Directory),
+                (true, _) => None,
+                (false, _) => self.volume.read_inode(inode)?.file_type(),
+            };
+            if let Some(file_type) = file_type {
+                entries.push(DirEntry {
+                    name,
+                    inode: inode as u64,
+                    file_type,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Mounted read-only whatever the device allows.
+pub struct Ext2 {
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2 {
+    pub fn open(device: Arc<dyn BlockDevice>) -> KernelResult<Arc<Self>> {
+        let volume = Arc::new(Volume::open(device)?);
+        let root = Ext2Inode::open(volume, ROOT_INODE)?;
+        if !root.is_directory() {
+            return Err(KernelError::Io);
+        }
+        Ok(Arc::new(Self { root }))
+    }
+}
+
+// As block::mount_all wants it.
+pub fn open(device: Arc<dyn BlockDevice>) -> KernelResult<Arc<dyn FileSystem>> {
+    Ok(Ext2::open(device)?)
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BLOCK: usize = 1024;
+    const S_IFLNK: u16 = 0o120000;
+
+    fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+        bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // Inode tables start at block 5.
+    fn put_inode(image: &mut [u8], number: usize, mode: u16, size: u32, blocks: &[u32]) {
+        let inode = &mut image[5 * BLOCK + (number - 1) * 128..][..128];
+        put_u16(inode, 0, mode);
+        put_u32(inode, 4, size);
+        for (n, block) in blocks.iter().enumerate() {
+            put_u32(inode, 40 + n * 4, *block);
+        }
+    }
+
+    fn put_directory(image: &mut [u8], block: usize, entries: &[(u32, &str, u8)]) {
+        let data = &mut image[block * BLOCK..][..BLOCK];
+        let mut offset = 0;
+        for (n, (inode, name, file_type)) in entries.iter().enumerate() {
+            let length = match n == entries.len() - 1 {
+                true => BLOCK - offset,
+                false => (DIRENT_HEADER_SIZE + name.len()).next_multiple_of(4),
+            };
+            put_u32(data, offset, *inode);
+            put_u16(data, offset + 4, length as u16);
+            data[offset + 6] = name.len() as u8;
+            data[offset + 7] = *file_type;
+            data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += length;
+        }
+    }
+
+    // A 64-block volume with one group, holding /hello, a link, and
+    // /bin/big, whose 14 blocks go through its indirect block, the last
+    // being a hole.
+    fn image(incompat: u32) -> Arc<dyn BlockDevice> {
+        let mut image = vec![0; 64 * BLOCK];
+        let superblock = &mut image[SUPERBLOCK_OFFSET as usize..][..SUPERBLOCK_SIZE];
+        put_u32(superblock, 0, 16);
+        put_u32(superblock, 4, 64);
+        put_u32(superblock, 20, 1);
+        put_u32(superblock, 32, 8192);
+        put_u32(superblock, 40, 16);
+        put_u16(superblock, 56, MAGIC);
+        put_u32(superblock, 76, 1);
+        put_u16(superblock, 88, 128);
+        put_u32(superblock, 96, incompat);
+        put_u32(&mut image[2 * BLOCK..], 8, 5);
+
+        put_inode(&mut image, 2, S_IFDIR | 0o755, BLOCK as u32, &[7]);
+        put_inode(&mut image, 11, S_IFREG | 0o644, 5, &[23]);
+        put_inode(&mut image, 12, S_IFDIR | 0o755, BLOCK as u32, &[8]);
+        let mut big: Vec<u32> = (9..=20).collect();
+        big.push(21);
+        put_inode(&mut image, 13, S_IFREG | 0o755, 14 * BLOCK as u32, &big);
+        put_inode(&mut image, 14, S_IFLNK | 0o777, 5, &[]);
+        put_u32(&mut image[21 * BLOCK..], 0, 22);
+        for block in (9..=20).chain([22]) {
+            image[block * BLOCK..][..BLOCK].fill(block as u8);
+        }
+        image[23 * BLOCK..][..5].copy_from_slice(b"hello");
+        put_directory(
+            &mut image,
+            7,
+            &[
+                (2, ".", FT_DIR),
+                (2, "..", FT_DIR),
+                (11, "hello", FT_REG_FILE),
+                (12, "bin", FT_DIR),
+                (14, "link", 7),
+            ],
+        );
+        put_directory(
+            &mut image,
+            8,
+            &[
+                (12, ".", FT_DIR),
+                (2, "..", FT_DIR),
+                (13, "big", FT_REG_FILE),
+            ],
+        );
+
+        let disk = RamDisk::new("ext2", (image.len() / SECTOR_SIZE) as u64);
+        disk.write_sectors(0, &image).unwrap();
+        Arc::new(disk)
+    }
+
+    #[test_case]
+    fn files_are_read_through_their_indirect_blocks() {
+        let fs = Ext2::open(image(INCOMPAT_FILETYPE)).unwrap();
+        let big = fs.root().lookup("bin").unwrap().lookup("big").unwrap();
+        let mut data = vec![0xff; 15 * BLOCK];
+        assert_eq!(big.read_at(0, &mut data), Ok(14 * BLOCK));
+        for (n, block) in data[..14 * BLOCK].chunks(BLOCK).enumerate() {
+            let expected = match n {
+                0..=11 => 9 + n as u8,
+                12 => 22,
+                _ => 0,
+            };
+            assert!(block.iter().all(|&b| b == expected));
+        }
+        assert_eq!(big.stat().permissions, 0o755);
+    }
+
+    #[test_case]
+    fn directories_list_what_can_be_opened() {
+        let fs = Ext2::open(image(INCOMPAT_FILETYPE)).unwrap();
+        let root = fs.root();
+        let names: Vec<String> = root
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["hello", "bin"]);
+
+        let mut hello = [0; 8];
+        assert_eq!(root.lookup("hello").unwrap().read_at(0, &mut hello), Ok(5));
+        assert_eq!(&hello[..5], b"hello");
+        assert!(matches!(
+            root.lookup("link"),
+            Err(KernelError::NotSupported)
+        ));
+        assert!(matches!(root.lookup("HELLO"), Err(KernelError::NotFound)));
+    }
+
+    #[test_case]
+    fn nothing_can_be_changed() {
+        let fs = Ext2::open(image(INCOMPAT_FILETYPE)).unwrap();
+        let root = fs.root();
+        let hello = root.lookup("hello").unwrap();
+        assert_eq!(hello.write_at(0, b"x"), Err(KernelError::ReadOnly));
+        assert!(matches!(
+            root.create("new", FileType::Regular),
+            Err(KernelError::ReadOnly)
+        ));
+        assert_eq!(root.unlink("hello"), Err(KernelError::ReadOnly));
+        assert_eq!(hello.unlink("x"), Err(KernelError::NotADirectory));
+    }
+
+    #[test_case]
+    fn unreadable_volumes_are_refused() {
+        // Extents.
+        assert!(matches!(
+            Ext2::open(image(0x0040)),
+            Err(KernelError::NotSupported)
+        ));
+        let blank: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("blank", 8));
+        assert!(matches!(
+            Ext2::open(blank),
+            Err(KernelError::InvalidArgument)
+        ));
+    }
+}
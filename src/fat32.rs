@@ -0,0 +1,1270 @@
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Stat};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xaa55_0000;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+const ENTRY_SIZE: usize = 32;
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+// Stands in for a first byte of 0xe5, which would mean deleted.
+const KANJI_E5: u8 = 0x05;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_LONG_NAME_MASK: u8 = 0x3f;
+
+// Case flags Windows sets in place of a long name for names like
+// "readme.txt".
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_ENTRY_ORDER: u8 = 0x1f;
+const LONG_NAME_UNITS: usize = 13;
+// Where each of a long entry's UTF-16 units sits.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_UNITS: usize = 255;
+
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FREE_CLUSTER: u32 = 0;
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const FIRST_CLUSTER: u32 = 2;
+// Sizes are stored in 32 bits.
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+// There's no clock to date files by, so they get the FAT epoch, 1980-01-01.
+const EPOCH_DATE: u16 = (1 << 5) | 1;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Where everything is on the volume, in bytes, from its BIOS parameter
+// block.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    cluster_size: u64,
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u64,
+    data_offset: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    info_offset: Option<u64>,
+}
+
+impl Geometry {
+    // FAT32 is told apart by its BPB alone, as formatters happily make
+    // small volumes with fewer clusters than the spec says it should have.
+    fn parse(boot: &[u8], device_size: u64) -> KernelResult<Self> {
+        let bytes_per_sector = read_u16(boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = read_u16(boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = read_u16(boot, 17);
+        let total_sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors_16 = read_u16(boot, 22);
+        let fat_sectors = read_u32(boot, 36) as u64;
+        let root_cluster = read_u32(boot, 44);
+        let info_sector = read_u16(boot, 48) as u64;
+
+        let valid = boot[510..512] == BOOT_SIGNATURE
+            && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            && root_entries == 0
+            && fat_sectors_16 == 0
+            && fat_sectors > 0
+            && total_sectors * bytes_per_sector <= device_size;
+        let metadata_sectors = reserved_sectors + fat_count * fat_sectors;
+        if !valid || total_sectors <= metadata_sectors {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        let fat_size = fat_sectors * bytes_per_sector;
+        let cluster_count = ((total_sectors - metadata_sectors) / sectors_per_cluster)
+            .min(fat_size / 4 - FIRST_CLUSTER as u64) as u32;
+        if !(FIRST_CLUSTER..FIRST_CLUSTER + cluster_count).contains(&root_cluster) {
+            return Err(KernelError::InvalidArgument);
+        }
+        Ok(Self {
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size,
+            fat_count,
+            data_offset: metadata_sectors * bytes_per_sector,
+            cluster_count,
+            root_cluster,
+            info_offset: match info_sector {
+                0 | 0xffff => None,
+                sector => Some(sector * bytes_per_sector),
+            },
+        })
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+}
+
+// A directory entry, which may have a long name spread over the entries
+// before its short one.
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    // Of its short entry, and of the first of its entries.
+    slot: u32,
+    first_slot: u32,
+}
+
+impl Record {
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || short_name_display(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn short_name_display(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let trimmed = bytes.iter().rposition(|&b| b != b' ').map_or(0, |n| n + 1);
+        bytes[..trimmed]
+            .iter()
+            .map(|&b| match lower {
+                true => b.to_ascii_lowercase() as char,
+                false => b as char,
+            })
+            .collect()
+    };
+    let base = part(&short_name[..8], case & LOWER_CASE_BASE != 0);
+    let extension = part(&short_name[8..], case & LOWER_CASE_EXTENSION != 0);
+    match extension.is_empty() {
+        true => base,
+        false => format!("{}.{}", base, extension),
+    }
+}
+
+// The long name collected so far from the entries before a short one.
+struct PendingName {
+    units: Vec<u16>,
+    // The order of the next entry expected, counting down to 1.
+    next: u8,
+    checksum: u8,
+    first_slot: u32,
+}
+
+fn parse_directory(bytes: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut pending: Option<PendingName> = None;
+    for (slot, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        let slot = slot as u32;
+        match entry[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => {
+                pending = None;
+                continue;
+            }
+            _ => (),
+        }
+
+        let attributes = entry[11];
+        if attributes & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let order = entry[0] & LONG_ENTRY_ORDER;
+            if entry[0] & LAST_LONG_ENTRY != 0 {
+                pending = Some(PendingName {
+                    units: vec![0xffff; order as usize * LONG_NAME_UNITS],
+                    next: order,
+                    checksum: entry[13],
+                    first_slot: slot,
+                });
+            }
+            pending = pending
+                .filter(|name| order != 0 && order == name.next && entry[13] == name.checksum);
+            if let Some(name) = &mut pending {
+                let start = (order as usize - 1) * LONG_NAME_UNITS;
+                for (n, offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    name.units[start + n] = read_u16(entry, *offset);
+                }
+                name.next -= 1;
+            }
+            continue;
+        }
+
+        let long_name = pending.take();
+        if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+        let mut short_name: [u8; 11] = entry[..11].try_into().unwrap();
+        if short_name[0] == KANJI_E5 {
+            short_name[0] = DELETED;
+        }
+        let (name, first_slot) = match long_name {
+            Some(long) if long.next == 0 && long.checksum == short_name_checksum(&short_name) => {
+                let end = long
+                    .units
+                    .iter()
+                    .position(|&u| u == 0)
+                    .unwrap_or(long.units.len());
+                let name = char::decode_utf16(long.units[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long.first_slot)
+            }
+            _ => (short_name_display(&short_name, entry[12]), slot),
+        };
+        records.push(Record {
+            name,
+            short_name,
+            attributes,
+            first_cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+            size: read_u32(entry, 28),
+            slot,
+            first_slot,
+        });
+    }
+    records
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= MAX_NAME_UNITS
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+fn short_name_byte(c: char) -> Option<u8> {
+    match c {
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        c if c.is_ascii() && SHORT_NAME_SPECIALS.contains(&(c as u8)) => Some(c as u8),
+        _ => None,
+    }
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    }
+}
+
+// The short name for `name` if it needs no long one: upper case, and
+// fitting 8.3 with nothing but the characters short names allow.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = split_extension(name);
+    if base.len() > 8 || extension.len() > 3 || name.chars().any(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    for (to, c) in short_name.iter_mut().zip(base.chars()) {
+        *to = short_name_byte(c)?;
+    }
+    for (to, c) in short_name[8..].iter_mut().zip(extension.chars()) {
+        *to = short_name_byte(c)?;
+    }
+    Some(short_name)
+}
+
+// A "BASIS~N.EXT" short name that no record already has.
+fn generated_short_name(name: &str, records: &[Record]) -> KernelResult<[u8; 11]> {
+    let (base, extension) = split_extension(name.trim_start_matches('.'));
+    let squash = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| short_name_byte(c).unwrap_or(b'_'))
+            .collect()
+    };
+    let base = squash(base);
+    let extension = squash(extension);
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let mut short_name = [b' '; 11];
+        let kept = base.len().min(8 - tail.len()).max(1);
+        let basis = if base.is_empty() {
+            b"_".as_slice()
+        } else {
+            &base[..kept.min(base.len())]
+        };
+        for (to, from) in short_name
+            .iter_mut()
+            .zip(basis.iter().chain(tail.as_bytes()))
+        {
+            *to = *from;
+        }
+        for (to, from) in short_name[8..].iter_mut().zip(&extension) {
+            *to = *from;
+        }
+        if !records.iter().any(|record| record.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(KernelError::NoSpace)
+}
+
+fn short_entry(short_name: &[u8; 11], attributes: u8, first_cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    if entry[0] == DELETED {
+        entry[0] = KANJI_E5;
+    }
+    entry[11] = attributes;
+    write_u16(&mut entry, 16, EPOCH_DATE);
+    write_u16(&mut entry, 18, EPOCH_DATE);
+    write_u16(&mut entry, 20, (first_cluster >> 16) as u16);
+    write_u16(&mut entry, 24, EPOCH_DATE);
+    write_u16(&mut entry, 26, first_cluster as u16);
+    entry
+}
+
+// In the order they go on disk, the last part of the name first.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LONG_NAME_UNITS) {
+        units.push(0);
+        units.resize(units.len().next_multiple_of(LONG_NAME_UNITS), 0xffff);
+    }
+    let count = units.len() / LONG_NAME_UNITS;
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let part = &units[(order - 1) * LONG_NAME_UNITS..][..LONG_NAME_UNITS];
+            for (unit, offset) in part.iter().zip(LONG_NAME_OFFSETS) {
+                write_u16(&mut entry, offset, *unit);
+            }
+            entry
+        })
+        .collect()
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    // Where the search for a free cluster starts.
+    next_free: u32,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        block::read_bytes(&*self.device, offset, buffer)
+    }
+
+    fn write(&self, offset: u64, bytes: &[u8]) -> KernelResult<()> {
+        if self.device.is_read_only() {
+            return Err(KernelError::ReadOnly);
+        }
+        block::write_bytes(&*self.device, offset, bytes)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.geometry.data_offset + (cluster - FIRST_CLUSTER) as u64 * self.geometry.cluster_size
+    }
+
+    fn fat_entry(&self, cluster: u32) -> KernelResult<u32> {
+        let mut bytes = [0; 4];
+        self.read(self.geometry.fat_offset + cluster as u64 * 4, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes) & FAT_ENTRY_MASK)
+    }
+
+    // Updates every copy of the FAT, leaving the top four bits of the
+    // entry as they were.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> KernelResult<()> {
+        let mut bytes = [0; 4];
+        self.read(self.geometry.fat_offset + cluster as u64 * 4, &mut bytes)?;
+        let value = (u32::from_le_bytes(bytes) & !FAT_ENTRY_MASK) | value;
+        for copy in 0..self.geometry.fat_count {
+            let offset = self.geometry.fat_offset + copy * self.geometry.fat_size;
+            self.write(offset + cluster as u64 * 4, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // The clusters of the chain starting at `first`, none if it's zero.
+    fn chain(&self, first: u32) -> KernelResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != FREE_CLUSTER && cluster < END_OF_CHAIN {
+            if !self.geometry.is_cluster(cluster)
+                || chain.len() >= self.geometry.cluster_count as usize
+            {
+                return Err(KernelError::Io);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    // Takes a free cluster, zeroes it and adds it to the end of `chain`.
+    fn allocate(&mut self, chain: &mut Vec<u32>) -> KernelResult<u32> {
+        let count = self.geometry.cluster_count;
+        let start = self.next_free.saturating_sub(FIRST_CLUSTER) % count;
+        for n in 0..count {
+            let cluster = FIRST_CLUSTER + (start + n) % count;
+            if self.fat_entry(cluster)? != FREE_CLUSTER {
+                continue;
+            }
+            self.write(
+                self.cluster_offset(cluster),
+                &vec![0; self.geometry.cluster_size as usize],
+            )?;
+            self.set_fat_entry(cluster, FAT_ENTRY_MASK)?;
+            if let Some(&last) = chain.last() {
+                self.set_fat_entry(last, cluster)?;
+            }
+            chain.push(cluster);
+            self.next_free = cluster + 1;
+            return Ok(cluster);
+        }
+        Err(KernelError::NoSpace)
+    }
+
+    fn free_chain(&mut self, first: u32) -> KernelResult<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+        }
+        Ok(())
+    }
+
+    fn free_clusters(&self) -> KernelResult<u32> {
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + self.geometry.cluster_count {
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    // Reads or writes the span of a chain's data starting `offset` bytes
+    // in, which the chain must already cover.
+    fn read_chain(&self, chain: &[u32], offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let cluster_size = self.geometry.cluster_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let within = position % cluster_size;
+            let count = ((cluster_size - within) as usize).min(buffer.len() - done);
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(KernelError::Io)?;
+            self.read(
+                self.cluster_offset(cluster) + within,
+                &mut buffer[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write_chain(&self, chain: &[u32], offset: u64, bytes: &[u8]) -> KernelResult<()> {
+        let cluster_size = self.geometry.cluster_size;
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = offset + done as u64;
+            let within = position % cluster_size;
+            let count = ((cluster_size - within) as usize).min(bytes.len() - done);
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(KernelError::Io)?;
+            self.write(
+                self.cluster_offset(cluster) + within,
+                &bytes[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_directory(&self, first: u32) -> KernelResult<(Vec<u32>, Vec<u8>)> {
+        let chain = self.chain(first)?;
+        let mut bytes = vec![0; chain.len() * self.geometry.cluster_size as usize];
+        self.read_chain(&chain, 0, &mut bytes)?;
+        Ok((chain, bytes))
+    }
+
+    fn find(&self, directory: u32, name: &str) -> KernelResult<Record> {
+        let (_, bytes) = self.read_directory(directory)?;
+        parse_directory(&bytes)
+            .into_iter()
+            .find(|record| record.matches(name))
+            .ok_or(KernelError::NotFound)
+    }
+
+    // Adds entries for `name` where there's room for all of them together,
+    // growing the directory if there isn't.
+    fn add_entry(
+        &mut self,
+        directory: u32,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+    ) -> KernelResult<Record> {
+        if !is_valid_name(name) {
+            return Err(KernelError::InvalidArgument);
+        }
+        let (mut chain, bytes) = self.read_directory(directory)?;
+        let records = parse_directory(&bytes);
+        if records.iter().any(|record| record.matches(name)) {
+            return Err(KernelError::AlreadyExists);
+        }
+
+        let (short_name, mut entries) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => {
+                let short_name = generated_short_name(name, &records)?;
+                (
+                    short_name,
+                    long_entries(name, short_name_checksum(&short_name)),
+                )
+            }
+        };
+        entries.push(short_entry(&short_name, attributes, first_cluster));
+
+        // Everything from the end marker on is free too.
+        let mut first_slot = 0;
+        let mut run = 0;
+        for (slot, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+            if entry[0] == END_OF_DIRECTORY {
+                run += bytes.len() / ENTRY_SIZE - slot;
+                break;
+            }
+            if entry[0] == DELETED {
+                run += 1;
+            } else {
+                first_slot = slot + 1;
+                run = 0;
+            }
+            if run == entries.len() {
+                break;
+            }
+        }
+        let entries_per_cluster = self.geometry.cluster_size as usize / ENTRY_SIZE;
+        while run < entries.len() {
+            self.allocate(&mut chain)?;
+            run += entries_per_cluster;
+        }
+
+        let bytes: Vec<u8> = entries.iter().flatten().copied().collect();
+        self.write_chain(&chain, (first_slot * ENTRY_SIZE) as u64, &bytes)?;
+        Ok(Record {
+            name: String::from(name),
+            short_name,
+            attributes,
+            first_cluster,
+            size: 0,
+            slot: (first_slot + entries.len() - 1) as u32,
+            first_slot: first_slot as u32,
+        })
+    }
+
+    fn remove_entry(&self, directory: u32, record: &Record) -> KernelResult<()> {
+        let chain = self.chain(directory)?;
+        for slot in record.first_slot..=record.slot {
+            self.write_chain(&chain, slot as u64 * ENTRY_SIZE as u64, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn update_entry(
+        &self,
+        directory: u32,
+        slot: u32,
+        first_cluster: u32,
+        size: u32,
+    ) -> KernelResult<()> {
+        let chain = self.chain(directory)?;
+        let offset = slot as u64 * ENTRY_SIZE as u64;
+        let mut entry = [0; ENTRY_SIZE];
+        self.read_chain(&chain, offset, &mut entry)?;
+        write_u16(&mut entry, 20, (first_cluster >> 16) as u16);
+        write_u16(&mut entry, 26, first_cluster as u16);
+        write_u32(&mut entry, 28, size);
+        self.write_chain(&chain, offset, &entry)
+    }
+}
+
+// Where an inode's short entry is: its directory's first cluster and slot.
+type EntryKey = (u32, u32);
+
+struct Shared {
+    volume: Mutex<Volume>,
+    // So a file looked up twice is the same inode, and sees its own writes.
+    inodes: Mutex<BTreeMap<EntryKey, Weak<FatInode>>>,
+    // The first clusters of unlinked files let go of since the volume was
+    // last locked, which are freed when it next is.
+    orphans: Mutex<Vec<u32>>,
+}
+
+impl Shared {
+    fn volume(&self) -> KernelResult<MutexGuard<'_, Volume>> {
+        let mut volume = self.volume.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for first in orphans {
+            volume.free_chain(first)?;
+        }
+        Ok(volume)
+    }
+}
+
+struct Node {
+    first_cluster: u32,
+    size: u32,
+    entry: Option<EntryKey>,
+    // Freed once the last user lets go of it.
+    unlinked: bool,
+}
+
+struct FatInode {
+    shared: Arc<Shared>,
+    is_directory: bool,
+    number: u64,
+    node: Mutex<Node>,
+}
+
+fn inode_number((directory, slot): EntryKey) -> u64 {
+    (directory as u64) << 32 | slot as u64
+}
+
+impl FatInode {
+    fn root(shared: Arc<Shared>, root_cluster: u32) -> Arc<Self> {
+        Arc::new(Self {
+            shared,
+            is_directory: true,
+            number: 1,
+            node: Mutex::new(Node {
+                first_cluster: root_cluster,
+                size: 0,
+                entry: None,
+                unlinked: false,
+            }),
+        })
+    }
+
+    // The inode for a record in this directory, which is already in use if
+    // anyone has it.
+    fn child(&self, directory: u32, record: &Record) -> Arc<FatInode> {
+        let key = (directory, record.slot);
+        let mut inodes = self.shared.inodes.lock();
+        if let Some(inode) = inodes.get(&key).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new(FatInode {
+            shared: self.shared.clone(),
+            is_directory: record.is_directory(),
+            number: inode_number(key),
+            node: Mutex::new(Node {
+                first_cluster: record.first_cluster,
+                size: if record.is_directory() {
+                    0
+                } else {
+                    record.size
+                },
+                entry: Some(key),
+                unlinked: false,
+            }),
+        });
+        inodes.insert(key, Arc::downgrade(&inode));
+        inode
+    }
+
+    // Takes the inode for `key` out of the cache, if anyone has it.
+    fn detach(&self, key: EntryKey) -> Option<Arc<FatInode>> {
+        let inode = self.shared.inodes.lock().remove(&key)?;
+        inode.upgrade()
+    }
+
+    fn directory_cluster(&self) -> KernelResult<u32> {
+        if !self.is_directory {
+            return Err(KernelError::NotADirectory);
+        }
+        Ok(self.node.lock().first_cluster)
+    }
+
+    fn save(volume: &Volume, node: &Node) -> KernelResult<()> {
+        match node.entry {
+            Some((directory, slot)) => {
+                volume.update_entry(directory, slot, node.first_cluster, node.size)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Grows the file's chain to cover `end` bytes, zeroing whatever was
+    // past its old size in the clusters it already had.
+    fn extend(
+        volume: &mut Volume,
+        node: &mut Node,
+        chain: &mut Vec<u32>,
+        end: u64,
+    ) -> KernelResult<()> {
+        let allocated = chain.len() as u64 * volume.geometry.cluster_size;
+        let stale = end.min(allocated);
+        if stale > node.size as u64 {
+            let zeroes = vec![0; (stale - node.size as u64) as usize];
+            volume.write_chain(chain, node.size as u64, &zeroes)?;
+        }
+        while (chain.len() as u64 * volume.geometry.cluster_size) < end {
+            volume.allocate(chain)?;
+            node.first_cluster = chain[0];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let node = self.node.get_mut();
+        if node.unlinked && node.first_cluster != FREE_CLUSTER {
+            self.shared.orphans.lock().push(node.first_cluster);
+        }
+        if let Some(key) = node.entry {
+            let mut inodes = self.shared.inodes.lock();
+            if inodes
+                .get(&key)
+                .is_some_and(|inode| core::ptr::eq(inode.as_ptr(), self))
+            {
+                inodes.remove(&key);
+            }
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> Stat {
+        let node = self.node.lock();
+        Stat {
+            file_type: if self.is_directory {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            permissions: if self.is_directory { 0o755 } else { 0o644 },
+            inode: self.number,
+            size: node.size as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+        if self.is_directory {
+            return Err(KernelError::IsADirectory);
+        }
+        let volume = self.shared.volume()?;
+        let node = self.node.lock();
+        let available = (node.size as u64).saturating_sub(offset);
+        let count = (buffer.len() as u64).min(available) as usize;
+        if count > 0 {
+            let chain = volume.chain(node.first_cluster)?;
+            volume.read_chain(&chain, offset, &mut buffer[..count])?;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> KernelResult<usize> {
+        if self.is_directory {
+            return Err(KernelError::IsADirectory);
+        }
+        let end = offset
+            .checked_add(bytes.len() as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(KernelError::FileTooLarge)?;
+        let mut volume = self.shared.volume()?;
+        let mut node = self.node.lock();
+        let mut chain = volume.chain(node.first_cluster)?;
+        if end > node.size as u64 {
+            let extended = FatInode::extend(&mut volume, &mut node, &mut chain, end);
+            // Clusters it got before running out stay, but aren't counted.
+            FatInode::save(&volume, &node)?;
+            extended?;
+        }
+        volume.write_chain(&chain, offset, bytes)?;
+        if end > node.size as u64 {
+            node.size = end as u32;
+            FatInode::save(&volume, &node)?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn truncate(&self, size: u64) -> KernelResult<()> {
+        if self.is_directory {
+            return Err(KernelError::IsADirectory);
+        }
+        if size > MAX_FILE_SIZE {
+            return Err(KernelError::FileTooLarge);
+        }
+        let mut volume = self.shared.volume()?;
+        let mut node = self.node.lock();
+        let mut chain = volume.chain(node.first_cluster)?;
+        if size > node.size as u64 {
+            FatInode::extend(&mut volume, &mut node, &mut chain, size)?;
+        } else {
+            let keep = size.div_ceil(volume.geometry.cluster_size) as usize;
+            if let Some(&first_freed) = chain.get(keep) {
+                match keep {
+                    0 => node.first_cluster = FREE_CLUSTER,
+                    keep => volume.set_fat_entry(chain[keep - 1], FAT_ENTRY_MASK)?,
+                }
+                volume.free_chain(first_freed)?;
+            }
+        }
+        node.size = size as u32;
+        FatInode::save(&volume, &node)
+    }
+
+    fn lookup(&self, name: &str) -> KernelResult<Arc<dyn Inode>> {
+        let directory = self.directory_cluster()?;
+        let record = self.shared.volume()?.find(directory, name)?;
+        Ok(self.child(directory, &record))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> KernelResult<Arc<dyn Inode>> {
+        let directory = self.directory_cluster()?;
+        let mut volume = self.shared.volume()?;
+        let record = match file_type {
+            FileType::Regular => volume.add_entry(directory, name, ATTR_ARCHIVE, FREE_CLUSTER)?,
+            FileType::Directory => {
+                let mut chain = Vec::new();
+                let first = volume.allocate(&mut chain)?;
+                // ".." is zero for the root.
+                let parent = match self.node.lock().entry {
+                    Some(_) => directory,
+                    None => FREE_CLUSTER,
+                };
+                let mut dots = short_entry(b".          ", ATTR_DIRECTORY, first).to_vec();
+                dots.extend_from_slice(&short_entry(b"..         ", ATTR_DIRECTORY, parent));
+                let added = volume
+                    .write_chain(&chain, 0, &dots)
+                    .and_then(|()| volume.add_entry(directory, name, ATTR_DIRECTORY, first));
+                if added.is_err() {
+                    volume.free_chain(first)?;
+                }
+                added?
+            }
+            _ => return Err(KernelError::NotSupported),
+        };
+        Ok(self.child(directory, &record))
+    }
+
+    fn unlink(&self, name: &str) -> KernelResult<()> {
+        let directory = self.directory_cluster()?;
+        let mut volume = self.shared.volume()?;
+        let record = volume.find(directory, name)?;
+        if record.is_directory() {
+            return Err(KernelError::IsADirectory);
+        }
+        volume.remove_entry(directory, &record)?;
+        match self.detach((directory, record.slot)) {
+            Some(inode) => {
+                let mut node = inode.node.lock();
+                node.entry = None;
+                node.unlinked = true;
+            }
+            None => volume.free_chain(record.first_cluster)?,
+        }
+        Ok(())
+    }
+
+    fn rmdir(&self, name: &str) -> KernelResult<()> {
+        let directory = self.directory_cluster()?;
+        let mut volume = self.shared.volume()?;
+        let record = volume.find(directory, name)?;
+        if !record.is_directory() {
+            return Err(KernelError::NotADirectory);
+        }
+        let (_, contents) = volume.read_directory(record.first_cluster)?;
+        if !parse_directory(&contents).is_empty() {
+            return Err(KernelError::DirectoryNotEmpty);
+        }
+        volume.remove_entry(directory, &record)?;
+        if let Some(inode) = self.detach((directory, record.slot)) {
+            inode.node.lock().entry = None;
+        }
+        volume.free_chain(record.first_cluster)
+    }
+
+    fn entries(&self) -> KernelResult<Vec<DirEntry>> {
+        let directory = self.directory_cluster()?;
+        let (_, bytes) = self.shared.volume()?.read_directory(directory)?;
+        Ok(parse_directory(&bytes)
+            .into_iter()
+            .map(|record| DirEntry {
+                inode: inode_number((directory, record.slot)),
+                file_type: if record.is_directory() {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                name: record.name,
+            })
+            .collect())
+    }
+}
+
+pub struct Fat32 {
+    root: Arc<FatInode>,
+}
+
+impl Fat32 {
+    // Fails with InvalidArgument if there's no FAT32 file system on it.
+    pub fn open(device: Arc<dyn BlockDevice>) -> KernelResult<Arc<Self>> {
+        let mut boot = [0; SECTOR_SIZE];
+        block::read_bytes(&*device, 0, &mut boot)?;
+        let geometry = Geometry::parse(&boot, device.sector_count() * SECTOR_SIZE as u64)?;
+        let volume = Volume {
+            device,
+            geometry,
+            next_free: FIRST_CLUSTER,
+        };
+
+        // The free count FSInfo keeps isn't kept up to date here, so it's
+        // marked as unknown for whoever next mounts the volume.
+        if let Some(offset) = geometry
+            .info_offset
+            .filter(|_| !volume.device.is_read_only())
+        {
+            let mut info = [0; SECTOR_SIZE];
+            volume.read(offset, &mut info)?;
+            if read_u32(&info, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(&info, 484) == FSINFO_STRUCT_SIGNATURE
+                && read_u32(&info, 488) != FSINFO_UNKNOWN
+            {
+                volume.write(offset + 488, &FSINFO_UNKNOWN.to_le_bytes())?;
+            }
+        }
+
+        let shared = Arc::new(Shared {
+            volume: Mutex::new(volume),
+            inodes: Mutex::new(BTreeMap::new()),
+            orphans: Mutex::new(Vec::new()),
+        });
+        Ok(Arc::new(Self {
+            root: FatInode::root(shared, geometry.root_cluster),
+        }))
+    }
+
+    pub fn free_space(&self) -> KernelResult<u64> {
+        let volume = self.root.shared.volume()?;
+        Ok(volume.free_clusters()? as u64 * volume.geometry.cluster_size)
+    }
+}
+
+// As block::mount_all wants it.
+pub fn open(device: Arc<dyn BlockDevice>) -> KernelResult<Arc<dyn FileSystem>> {
+    Ok(Fat32::open(device)?)
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// Makes an empty FAT32 file system covering all of `device`, with two FATs
+// and the usual 32 reserved sectors.
+pub fn format(device: &dyn BlockDevice) -> KernelResult<()> {
+    const RESERVED_SECTORS: u64 = 32;
+    const FAT_COUNT: u64 = 2;
+    const INFO_SECTOR: u64 = 1;
+    const BACKUP_BOOT_SECTOR: u64 = 6;
+
+    let total_sectors = device.sector_count().min(u32::MAX as u64);
+    let sectors_per_cluster: u64 = if total_sectors < 1 << 17 { 1 } else { 8 };
+    let clusters = total_sectors.saturating_sub(RESERVED_SECTORS) / sectors_per_cluster;
+    let fat_sectors = ((clusters + FIRST_CLUSTER as u64) * 4).div_ceil(SECTOR_SIZE as u64);
+    let metadata_sectors = RESERVED_SECTORS + FAT_COUNT * fat_sectors;
+    if total_sectors < metadata_sectors + sectors_per_cluster {
+        return Err(KernelError::NoSpace);
+    }
+
+    let mut boot = [0; SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"RISCVOS ");
+    write_u16(&mut boot, 11, SECTOR_SIZE as u16);
+    boot[13] = sectors_per_cluster as u8;
+    write_u16(&mut boot, 14, RESERVED_SECTORS as u16);
+    boot[16] = FAT_COUNT as u8;
+    // Fixed disk media.
+    boot[21] = 0xf8;
+    write_u16(&mut boot, 24, 32);
+    write_u16(&mut boot, 26, 64);
+    write_u32(&mut boot, 32, total_sectors as u32);
+    write_u32(&mut boot, 36, fat_sectors as u32);
+    write_u32(&mut boot, 44, FIRST_CLUSTER);
+    write_u16(&mut boot, 48, INFO_SECTOR as u16);
+    write_u16(&mut boot, 50, BACKUP_BOOT_SECTOR as u16);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[71..82].copy_from_slice(b"NO NAME    ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&BOOT_SIGNATURE);
+
+    let mut info = [0; SECTOR_SIZE];
+    write_u32(&mut info, 0, FSINFO_LEAD_SIGNATURE);
+    write_u32(&mut info, 484, FSINFO_STRUCT_SIGNATURE);
+    write_u32(&mut info, 488, FSINFO_UNKNOWN);
+    write_u32(&mut info, 492, FSINFO_UNKNOWN);
+    write_u32(&mut info, 508, FSINFO_TRAIL_SIGNATURE);
+
+    let sector = |n: u64| n * SECTOR_SIZE as u64;
+    let zeroes = vec![0; SECTOR_SIZE];
+    for n in 0..metadata_sectors + sectors_per_cluster {
+        block::write_bytes(device, sector(n), &zeroes)?;
+    }
+    for at in [0, BACKUP_BOOT_SECTOR] {
+        block::write_bytes(device, sector(at), &boot)?;
+        block::write_bytes(device, sector(at + INFO_SECTOR), &info)?;
+    }
+
+    // The media byte, an end of chain, and the root directory's one cluster.
+    let mut fat_start = [0; 12];
+    write_u32(&mut fat_start, 0, 0x0fff_ff00 | boot[21] as u32);
+    write_u32(&mut fat_start, 4, FAT_ENTRY_MASK);
+    write_u32(&mut fat_start, 8, FAT_ENTRY_MASK);
+    for copy in 0..FAT_COUNT {
+        block::write_bytes(
+            device,
+            sector(RESERVED_SECTORS + copy * fat_sectors),
+            &fat_start,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::vfs;
+
+    // Small enough to make, big enough for files of a few clusters.
+    fn formatted_disk() -> Arc<dyn BlockDevice> {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("fat", 2048));
+        format(&*disk).unwrap();
+        disk
+    }
+
+    fn names(directory: &Arc<dyn Inode>) -> Vec<String> {
+        directory
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[test_case]
+    fn short_names_are_made_for_long_ones() {
+        assert_eq!(exact_short_name("README.TXT"), Some(*b"README  TXT"));
+        assert_eq!(exact_short_name("readme.txt"), None);
+        assert_eq!(exact_short_name("TOO-LONG-NAME"), None);
+
+        let records = parse_directory(&short_entry(b"LONGFI~1TXT", ATTR_ARCHIVE, 0));
+        assert_eq!(
+            generated_short_name("longfile.txt", &[]).unwrap(),
+            *b"LONGFI~1TXT"
+        );
+        assert_eq!(
+            generated_short_name("Long File.txt", &records).unwrap(),
+            *b"LONGFI~2TXT"
+        );
+        assert_eq!(
+            generated_short_name(".profile", &[]).unwrap(),
+            *b"PROFIL~1   "
+        );
+    }
+
+    #[test_case]
+    fn long_names_survive_being_read_back() {
+        let name = "A rather long name, with spaces.text";
+        let short_name = generated_short_name(name, &[]).unwrap();
+        let mut bytes: Vec<u8> = long_entries(name, short_name_checksum(&short_name))
+            .into_iter()
+            .flatten()
+            .collect();
+        bytes.extend_from_slice(&short_entry(&short_name, ATTR_ARCHIVE, 5));
+
+        let records = parse_directory(&bytes);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, name);
+        assert_eq!(records[0].first_slot, 0);
+        assert_eq!(records[0].first_cluster, 5);
+
+        // A long name whose checksum doesn't match is ignored.
+        bytes[13] ^= 1;
+        assert_eq!(parse_directory(&bytes)[0].name, "ARATHE~1.TEX");
+    }
+
+    #[test_case]
+    fn files_span_clusters_and_persist() {
+        let disk = formatted_disk();
+        let contents: Vec<u8> = (0..1500).map(|n| (n % 251) as u8).collect();
+        {
+            let fs = Fat32::open(disk.clone()).unwrap();
+            let file = fs
+                .root()
+                .create("Data File.bin", FileType::Regular)
+                .unwrap();
+            assert_eq!(file.write_at(0, &contents), Ok(1500));
+        }
+
+        let fs = Fat32::open(disk).unwrap();
+        assert_eq!(names(&fs.root()), ["Data File.bin"]);
+        let file = fs.root().lookup("DATA FILE.BIN").unwrap();
+        assert_eq!(file.stat().size, 1500);
+        let mut read = vec![0; 1600];
+        assert_eq!(file.read_at(0, &mut read), Ok(1500));
+        assert_eq!(&read[..1500], &contents[..]);
+        assert_eq!(file.read_at(1000, &mut read[..100]), Ok(100));
+        assert_eq!(&read[..100], &contents[1000..1100]);
+    }
+
+    #[test_case]
+    fn gaps_and_truncation_read_as_zeroes() {
+        let fs = Fat32::open(formatted_disk()).unwrap();
+        let file = fs.root().create("SPARSE", FileType::Regular).unwrap();
+        file.write_at(0, &[0xff; 700]).unwrap();
+        file.truncate(10).unwrap();
+        file.write_at(1200, b"end").unwrap();
+        assert_eq!(file.stat().size, 1203);
+
+        let mut read = vec![0xaa; 1203];
+        file.read_at(0, &mut read).unwrap();
+        assert!(read[..10].iter().all(|&b| b == 0xff));
+        assert!(read[10..1200].iter().all(|&b| b == 0));
+        assert_eq!(&read[1200..], b"end");
+    }
+
+    #[test_case]
+    fn files_cannot_grow_past_four_gibibytes() {
+        let fs = Fat32::open(formatted_disk()).unwrap();
+        let file = fs.root().create("HUGE", FileType::Regular).unwrap();
+        assert_eq!(
+            file.write_at(u64::MAX, b"end"),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(
+            file.write_at(MAX_FILE_SIZE - 2, b"end"),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(
+            file.truncate(MAX_FILE_SIZE + 1),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(file.stat().size, 0);
+    }
+
+    #[test_case]
+    fn removing_files_and_directories_frees_their_clusters() {
+        let fs = Fat32::open(formatted_disk()).unwrap();
+        let free = fs.free_space().unwrap();
+        let root = fs.root();
+        let directory = root.create("dir", FileType::Directory).unwrap();
+        let file = directory.create("file", FileType::Regular).unwrap();
+        file.write_at(0, &[1; 2000]).unwrap();
+        assert!(fs.free_space().unwrap() < free);
+
+        assert_eq!(root.rmdir("dir"), Err(KernelError::DirectoryNotEmpty));
+        assert_eq!(root.unlink("dir"), Err(KernelError::IsADirectory));
+        directory.unlink("file").unwrap();
+        // Still readable until it's let go of.
+        let mut byte = [0];
+        assert_eq!(file.read_at(1999, &mut byte), Ok(1));
+        drop(file);
+        root.rmdir("dir").unwrap();
+        assert_eq!(fs.free_space().unwrap(), free);
+        assert!(names(&root).is_empty());
+    }
+
+    #[test_case]
+    fn directories_grow_to_fit_their_entries() {
+        let fs = Fat32::open(formatted_disk()).unwrap();
+        let root = fs.root();
+        // Two or three entries each, and 16 fit in a cluster.
+        for n in 0..20 {
+            root.create(&format!("file number {}", n), FileType::Regular)
+                .unwrap();
+        }
+        assert_eq!(names(&root).len(), 20);
+        assert!(root.lookup("file number 19").is_ok());
+        assert!(matches!(
+            root.create("FILE NUMBER 3", FileType::Regular),
+            Err(KernelError::AlreadyExists)
+        ));
+    }
+
+    #[test_case]
+    fn volumes_can_be_mounted_in_the_tree() {
+        vfs::mkdir("/fat32-test").unwrap();
+        vfs::mount("/fat32-test", Fat32::open(formatted_disk()).unwrap()).unwrap();
+        vfs::mkdir("/fat32-test/sub").unwrap();
+        vfs::write_file("/fat32-test/sub/hello.txt", b"hi").unwrap();
+        assert_eq!(vfs::read_file("/fat32-test/sub/HELLO.TXT").unwrap(), b"hi");
+        assert!(vfs::mounts()
+            .iter()
+            .any(|(path, name)| path == "/fat32-test" && *name == "fat32"));
+    }
+
+    #[test_case]
+    fn other_disks_are_not_mistaken_for_fat32() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("blank", 64));
+        assert!(matches!(
+            Fat32::open(disk),
+            Err(KernelError::InvalidArgument)
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::console::Console;
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::OpenFile;
+use alloc::vec::Vec;
+
+// Linux's default limit on open files per process.
+pub const MAX_FILES: usize = 1024;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// A process's open files, indexed by descriptor. Cloning it, as fork does,
+// shares every open file with the copy.
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<OpenFile>>,
+    // Descriptors must be below this, which is at most MAX_FILES.
+    limit: usize,
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            limit: MAX_FILES,
+        }
+    }
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Standard input, output and error all on one opening of the console.
+    pub fn with_console() -> Self {
+        let console = OpenFile::new(Console);
+        Self {
+            files: [STDIN, STDOUT, STDERR]
+                .iter()
+                .map(|_| Some(console.clone()))
+                .collect(),
+            limit: MAX_FILES,
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> KernelResult<OpenFile> {
+        self.files
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(KernelError::BadDescriptor)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // Descriptors already open at or above a lowered limit stay open.
+    pub fn set_limit(&mut self, limit: usize) -> KernelResult<()> {
+        if limit > MAX_FILES {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.limit = limit;
+        Ok(())
+    }
+
+    // Takes the lowest free descriptor, as POSIX requires.
+    pub fn insert(&mut self, file: OpenFile) -> KernelResult<usize> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.files.len());
+        if fd >= self.limit {
+            return Err(KernelError::TooManyFiles);
+        }
+        if fd == self.files.len() {
+            self.files.push(None);
+        }
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    // A new descriptor, the lowest free, for the same open file as `fd`.
+    pub fn dup(&mut self, fd: usize) -> KernelResult<usize> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    // Points `new_fd` at the same open file as `fd`, closing whatever it
+    // referred to before.
+    pub fn dup_to(&mut self, fd: usize, new_fd: usize) -> KernelResult<usize> {
+        let file = self.get(fd)?;
+        if new_fd >= self.limit {
+            return Err(KernelError::BadDescriptor);
+        }
+        if new_fd >= self.files.len() {
+            self.files.resize(new_fd + 1, None);
+        }
+        // Dropped only once the table has let go of it.
+        let _old = self.files[new_fd].replace(file);
+        Ok(new_fd)
+    }
+
+    pub fn close(&mut self, fd: usize) -> KernelResult<()> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .map(drop)
+            .ok_or(KernelError::BadDescriptor)
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_lowest_free_descriptor_is_used() {
+        let mut files = FdTable::with_console();
+        assert_eq!(files.insert(OpenFile::new(Console)), Ok(3));
+        files.close(STDIN).unwrap();
+        assert_eq!(files.insert(OpenFile::new(Console)), Ok(STDIN));
+        assert_eq!(files.open_count(), 4);
+    }
+
+    #[test_case]
+    fn closed_descriptors_are_bad() {
+        let mut files = FdTable::with_console();
+        files.close(STDOUT).unwrap();
+        assert!(matches!(files.get(STDOUT), Err(KernelError::BadDescriptor)));
+        assert_eq!(files.close(STDOUT), Err(KernelError::BadDescriptor));
+        assert_eq!(files.close(99), Err(KernelError::BadDescriptor));
+    }
+
+    #[test_case]
+    fn duplicates_share_the_open_file() {
+        let mut files = FdTable::new();
+        let fd = files.insert(OpenFile::new(Console)).unwrap();
+        let copy = files.dup(fd).unwrap();
+        assert_eq!(copy, fd + 1);
+        assert!(files.get(fd).unwrap().same_as(&files.get(copy).unwrap()));
+
+        assert_eq!(files.dup_to(fd, 10), Ok(10));
+        assert!(files.get(10).unwrap().same_as(&files.get(fd).unwrap()));
+        assert!(matches!(files.dup(7), Err(KernelError::BadDescriptor)));
+    }
+
+    #[test_case]
+    fn descriptors_stop_at_the_limit() {
+        let mut files = FdTable::with_console();
+        files.set_limit(4).unwrap();
+        assert_eq!(files.insert(OpenFile::new(Console)), Ok(3));
+        assert_eq!(
+            files.insert(OpenFile::new(Console)),
+            Err(KernelError::TooManyFiles)
+        );
+        assert_eq!(files.dup_to(STDIN, 4), Err(KernelError::BadDescriptor));
+
+        files.close(STDERR).unwrap();
+        assert_eq!(files.insert(OpenFile::new(Console)), Ok(STDERR));
+        assert_eq!(
+            files.set_limit(MAX_FILES + 1),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use crate::page_table::PageTableEntry;
+use crate::print;
+use crate::satp::Satp;
+use crate::symbols::Symbolized;
+use crate::trap::{TrapFrame, REGISTER_NAMES};
+use core::fmt;
+
+const HEXDUMP_ROW: usize = 16;
+
+// The classic offset, hex and ASCII layout, as `hexdump -C` prints it, with
+// `start` as the offset of the first byte.
+pub struct Hexdump<'a> {
+    pub start: u64,
+    pub bytes: &'a [u8],
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, row) in self.bytes.chunks(HEXDUMP_ROW).enumerate() {
+            write!(f, "{:016x} ", self.start + (n * HEXDUMP_ROW) as u64)?;
+            for i in 0..HEXDUMP_ROW {
+                if i % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match row.get(i) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str(" |")?;
+            for &byte in row {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+// Prints `len` bytes of memory from `address`, labelled with their
+// addresses. The caller makes sure they're mapped and readable.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn hexdump(address: u64, len: usize) {
+    let bytes = core::slice::from_raw_parts(address as *const u8, len);
+    print!(
+        "{}",
+        Hexdump {
+            start: address,
+            bytes
+        }
+    );
+}
+
+// "rwxugad", with a dash for each bit that's clear.
+pub fn pte_flags(pte: &PageTableEntry) -> [u8; 7] {
+    let flag = |set, c| if set { c } else { b'-' };
+    [
+        flag(pte.is_readable(), b'r'),
+        flag(pte.is_writable(), b'w'),
+        flag(pte.is_executable(), b'x'),
+        flag(pte.is_user_accessible(), b'u'),
+        flag(pte.is_global(), b'g'),
+        flag(pte.has_been_accessed(), b'a'),
+        flag(pte.is_dirty(), b'd'),
+    ]
+}
+
+impl fmt::Display for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = self.physical_page() << 12;
+        if !self.is_valid() {
+            f.write_str("invalid")
+        } else if self.is_leaf() {
+            let flags = pte_flags(self);
+            write!(
+                f,
+                "{:#012x} {}",
+                address,
+                core::str::from_utf8(&flags).unwrap()
+            )
+        } else {
+            write!(f, "table at {:#012x}", address)
+        }
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  cause {:?} stval {:#018x}", self.cause(), self.stval)?;
+        writeln!(f, "  sepc {}", Symbolized(self.sepc))?;
+        writeln!(f, "  sstatus {:#018x}", self.sstatus)?;
+        writeln!(f, "  satp {}", Satp::from_bits(self.satp))?;
+        for row in (1..32).step_by(4) {
+            for (n, name) in REGISTER_NAMES.iter().enumerate().skip(row).take(4) {
+                write!(f, "  {:>4} {:#018x}", name, self.reg(n))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn hexdump_lays_out_offset_hex_and_ascii() {
+        let dump = format!(
+            "{}",
+            Hexdump {
+                start: 0x1000,
+                bytes: b"Hello, world!\n\x00\xffmore",
+            }
+        );
+        let mut lines = dump.lines();
+        assert_eq!(
+            lines.next(),
+            Some(
+                "0000000000001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|"
+            )
+        );
+        assert_eq!(
+            lines.next(),
+            Some("0000000000001010  6d 6f 72 65                                       |more|")
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
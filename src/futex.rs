@@ -0,0 +1,177 @@
+use crate::error::{KernelError, KernelResult};
+use crate::trap::LockIrqSave;
+use crate::wait_queue::WaitQueue;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+// The wait queues behind user space's futexes, in a hash table keyed by
+// the physical address of the futex word, so processes sharing the page
+// share the futex. RAM is identity mapped, so the key is also where the
+// kernel reads the word.
+
+// Must be a power of two.
+const BUCKETS: usize = 64;
+
+struct Futex {
+    key: u64,
+    waiters: usize,
+    // Wakeups handed out that waiters have yet to take.
+    wakeups: usize,
+    queue: Arc<WaitQueue>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUCKET: Mutex<Vec<Futex>> = Mutex::new(Vec::new());
+
+static FUTEXES: [Mutex<Vec<Futex>>; BUCKETS] = [EMPTY_BUCKET; BUCKETS];
+
+fn bucket(key: u64) -> &'static Mutex<Vec<Futex>> {
+    let hash = (key >> 2).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+    &FUTEXES[hash as usize % BUCKETS]
+}
+
+// Blocks until woken, unless the word at `key` no longer holds `value`, in
+// which case it fails with WouldBlock, EAGAIN to user space. The word is
+// checked with the bucket locked, so a wake can't come between the check
+// and the wait. A signal cuts the wait short with Interrupted.
+pub fn wait(key: u64, value: u32) -> KernelResult<()> {
+    let bucket = bucket(key);
+    let queue = {
+        let mut futexes = bucket.lock_irqsave();
+        let word = unsafe { &*(key as *const AtomicU32) };
+        if word.load(Ordering::SeqCst) != value {
+            return Err(KernelError::WouldBlock);
+        }
+        let futex = match futexes.iter().position(|futex| futex.key == key) {
+            Some(n) => &mut futexes[n],
+            None => {
+                futexes.push(Futex {
+                    key,
+                    waiters: 0,
+                    wakeups: 0,
+                    queue: Arc::new(WaitQueue::new()),
+                });
+                futexes.last_mut().unwrap()
+            }
+        };
+        futex.waiters += 1;
+        futex.queue.clone()
+    };
+
+    let result = queue.wait_until_interruptible(|| take_wakeup(bucket, key));
+    if result.is_err() && cancel_wait(bucket, key) {
+        return Ok(());
+    }
+    result
+}
+
+// Stops waiting on the futex, unless a wakeup came in the meantime, which
+// is taken instead. Returns whether one was.
+fn cancel_wait(bucket: &Mutex<Vec<Futex>>, key: u64) -> bool {
+    let mut futexes = bucket.lock_irqsave();
+    let Some(n) = futexes.iter().position(|futex| futex.key == key) else {
+        return false;
+    };
+    let futex = &mut futexes[n];
+    let woken = futex.wakeups > 0;
+    if woken {
+        futex.wakeups -= 1;
+    }
+    futex.waiters -= 1;
+    if futex.waiters == 0 {
+        futexes.swap_remove(n);
+    }
+    woken
+}
+
+// The futex goes once its last waiter has been woken.
+fn take_wakeup(bucket: &Mutex<Vec<Futex>>, key: u64) -> bool {
+    let mut futexes = bucket.lock_irqsave();
+    let Some(n) = futexes.iter().position(|futex| futex.key == key) else {
+        return false;
+    };
+    let futex = &mut futexes[n];
+    if futex.wakeups == 0 {
+        return false;
+    }
+    futex.wakeups -= 1;
+    futex.waiters -= 1;
+    if futex.waiters == 0 {
+        futexes.swap_remove(n);
+    }
+    true
+}
+
+// Wakes up to `count` of the threads waiting on `key`, returning how many.
+pub fn wake(key: u64, count: usize) -> usize {
+    let (woken, queue) = {
+        let mut futexes = bucket(key).lock_irqsave();
+        let Some(futex) = futexes.iter_mut().find(|futex| futex.key == key) else {
+            return 0;
+        };
+        let woken = count.min(futex.waiters - futex.wakeups);
+        futex.wakeups += woken;
+        (woken, futex.queue.clone())
+    };
+    // A waiter yet to join the queue takes its wakeup without a
+    // notification.
+    for _ in 0..woken {
+        queue.notify_one();
+    }
+    woken
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{self, test::yield_until};
+    use core::sync::atomic::AtomicUsize;
+
+    // In the kernel image, which is identity mapped, so its address is
+    // physical.
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn key() -> u64 {
+        &WORD as *const AtomicU32 as u64
+    }
+
+    fn waiters() -> usize {
+        bucket(key())
+            .lock_irqsave()
+            .iter()
+            .find(|futex| futex.key == key())
+            .map_or(0, |futex| futex.waiters - futex.wakeups)
+    }
+
+    #[test_case]
+    fn waiting_on_a_word_that_has_changed_fails_at_once() {
+        WORD.store(1, Ordering::SeqCst);
+        assert_eq!(wait(key(), 0), Err(KernelError::WouldBlock));
+        assert_eq!(wake(key(), 1), 0);
+    }
+
+    #[test_case]
+    fn wake_wakes_no_more_than_it_is_asked_to() {
+        WORD.store(0, Ordering::SeqCst);
+        WOKEN.store(0, Ordering::SeqCst);
+        for _ in 0..2 {
+            task::spawn(|| {
+                wait(key(), 0).unwrap();
+                WOKEN.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        yield_until(|| waiters() == 2);
+
+        assert_eq!(wake(key(), 1), 1);
+        yield_until(|| WOKEN.load(Ordering::SeqCst) == 1);
+        assert_eq!(waiters(), 1);
+
+        assert_eq!(wake(key(), 5), 1);
+        yield_until(|| WOKEN.load(Ordering::SeqCst) == 2);
+        assert_eq!(wake(key(), 1), 0);
+    }
+}
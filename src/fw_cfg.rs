@@ -0,0 +1,188 @@
+use crate::devicetree::{self, DeviceTree};
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::page_table::PageTableEntryMode;
+use crate::{info, VIRTUAL_MEMORY};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const COMPATIBLE: &str = "qemu,fw-cfg-mmio";
+
+const DATA_OFFSET: u64 = 0x0;
+const SELECTOR_OFFSET: u64 = 0x8;
+
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+
+const FILE_NAME_LENGTH: usize = 56;
+const PAYLOAD_PREFIX: &str = "opt/riscvos/";
+
+// Payloads are mapped one after another from here.
+const PAYLOAD_WINDOW_START: u64 = 0x10_0000_0000;
+static NEXT_PAYLOAD_ADDRESS: AtomicU64 = AtomicU64::new(PAYLOAD_WINDOW_START);
+
+#[derive(Debug)]
+pub enum FwCfgError {
+    NotPresent,
+    FileNotFound,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FwCfgFile {
+    pub size: u32,
+    pub select: u16,
+    name: [u8; FILE_NAME_LENGTH],
+}
+
+impl FwCfgFile {
+    pub fn name(&self) -> &str {
+        let length = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(FILE_NAME_LENGTH);
+        core::str::from_utf8(&self.name[..length]).unwrap_or("")
+    }
+}
+
+pub fn base_address(tree: &DeviceTree) -> Option<u64> {
+    let node = tree.find_compatible(COMPATIBLE)?;
+    let (address, _) = node.reg()?.next()?;
+    Some(address)
+}
+
+pub struct FwCfg {
+    base: u64,
+}
+
+impl FwCfg {
+    pub fn probe() -> Result<Self, FwCfgError> {
+        let base = devicetree::get()
+            .and_then(|tree| base_address(&tree))
+            .ok_or(FwCfgError::NotPresent)?;
+        let fw_cfg = Self { base };
+
+        let mut signature = [0; 4];
+        fw_cfg.select(SIGNATURE_KEY);
+        fw_cfg.read(&mut signature);
+        if &signature != b"QEMU" {
+            return Err(FwCfgError::NotPresent);
+        }
+
+        Ok(fw_cfg)
+    }
+
+    fn select(&self, key: u16) {
+        let selector = (self.base + SELECTOR_OFFSET) as *mut u16;
+        unsafe { selector.write_volatile(key.to_be()) };
+    }
+
+    fn read(&self, bytes: &mut [u8]) {
+        let data = (self.base + DATA_OFFSET) as *const u8;
+        for byte in bytes.iter_mut() {
+            *byte = unsafe { data.read_volatile() };
+        }
+    }
+
+    fn read_u32(&self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    fn read_u16(&self) -> u16 {
+        let mut bytes = [0; 2];
+        self.read(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = FwCfgFile> + '_ {
+        self.select(FILE_DIR_KEY);
+        let count = self.read_u32();
+
+        // Entries are read in order from the data register, so the iterator
+        // must be drained before the device is used for anything else.
+        (0..count).map(move |_| {
+            let size = self.read_u32();
+            let select = self.read_u16();
+            let _reserved = self.read_u16();
+            let mut name = [0; FILE_NAME_LENGTH];
+            self.read(&mut name);
+            FwCfgFile { size, select, name }
+        })
+    }
+
+    pub fn find_file(&self, name: &str) -> Option<FwCfgFile> {
+        let mut found = None;
+        for file in self.files() {
+            if found.is_none() && file.name() == name {
+                found = Some(file);
+            }
+        }
+        found
+    }
+
+    pub fn read_file(&self, file: &FwCfgFile, bytes: &mut [u8]) -> usize {
+        let length = bytes.len().min(file.size as usize);
+        self.select(file.select);
+        self.read(&mut bytes[..length]);
+        length
+    }
+}
+
+// Maps fresh pages for the named file and copies it in.
+pub fn load(name: &str) -> Result<&'static [u8], FwCfgError> {
+    let fw_cfg = FwCfg::probe()?;
+    let file = fw_cfg.find_file(name).ok_or(FwCfgError::FileNotFound)?;
+
+    let size = file.size as u64;
+    let pages = size.div_ceil(PAGE_SIZE);
+    let start = NEXT_PAYLOAD_ADDRESS.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed);
+
+    {
+        let mut vm = VIRTUAL_MEMORY.lock();
+        let vm = vm.get_mut().ok_or(FwCfgError::NotPresent)?;
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        for page in 0..pages {
+            let virt = (start + page * PAGE_SIZE).try_into().unwrap();
+            vm.map(virt, PageTableEntryMode::ReadWrite, &mut allocator)
+                .map_err(|_| FwCfgError::OutOfMemory)?;
+        }
+        unsafe { asm!("sfence.vma") };
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, size as usize) };
+    fw_cfg.read_file(&file, bytes);
+    Ok(bytes)
+}
+
+pub fn init() {
+    let fw_cfg = match FwCfg::probe() {
+        Ok(fw_cfg) => fw_cfg,
+        Err(_) => return,
+    };
+
+    for file in fw_cfg.files() {
+        if file.name().starts_with(PAYLOAD_PREFIX) {
+            info!("fw_cfg payload: {} ({} bytes)", file.name(), file.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn fw_cfg_is_present_on_the_virt_machine() {
+        assert!(FwCfg::probe().is_ok());
+    }
+
+    #[test_case]
+    fn loading_a_missing_file_fails() {
+        assert!(matches!(
+            load("opt/riscvos/does-not-exist"),
+            Err(FwCfgError::FileNotFound)
+        ));
+    }
+}
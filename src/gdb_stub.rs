@@ -0,0 +1,826 @@
+use crate::address::VirtAddr;
+use crate::csr::Sstatus;
+use crate::hart::{hart_id, online_harts};
+use crate::page_allocator::PAGE_SIZE;
+use crate::page_table::{PageTable, PageTableEntry, PteFlags};
+use crate::serial::Uart;
+use crate::trap::{LockIrqSave, TrapFrame, REGISTER_NAMES};
+use crate::{cmdline, info, plic, satp, sbi, tlb, trap, watchdog};
+use core::arch::asm;
+use core::fmt::Write;
+use spin::Mutex;
+
+// The GDB remote serial protocol, spoken over the UART gdb_uart names, so
+// `target remote` on QEMU's second serial port can stop the kernel, look at
+// and change its registers and memory, and set breakpoints. Breakpoints are
+// ebreaks patched into the code, and single steps are emulated with
+// temporary breakpoints on every instruction that could run next. Only the
+// hart that stopped is held; the others keep running.
+
+// Longest packet, before the framing, in either direction.
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Sent by GDB to stop a running target.
+const INTERRUPT: u8 = 0x03;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+// Register numbers as GDB has them: x0 to x31, then pc.
+const PC_REGISTER: usize = 32;
+const REGISTERS: usize = 33;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Breakpoint {
+    address: u64,
+    // 2 for a c.ebreak, 4 for an ebreak.
+    len: usize,
+    original: [u8; 4],
+}
+
+enum Resume {
+    Continue,
+    Step,
+    Detach,
+}
+
+struct Stub {
+    uart: Uart,
+    // A byte read while running that the next packet starts with.
+    pending: Option<u8>,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    // The temporary breakpoints for a single step, and whether interrupts
+    // were enabled before it.
+    stepping: bool,
+    step: [Option<Breakpoint>; 2],
+    step_spie: bool,
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+
+pub fn attach(uart: Uart, irq: Option<u32>) {
+    *STUB.lock_irqsave() = Some(Stub {
+        uart,
+        pending: None,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        stepping: false,
+        step: [None; 2],
+        step_spie: false,
+    });
+    // Without an interrupt GDB can only take over at a breakpoint.
+    if let Some(irq) = irq.filter(|_| plic::get().is_some()) {
+        plic::enable_irq(irq, 1, handle_uart_interrupt);
+    }
+    info!("GDB stub listening on its UART");
+}
+
+pub fn is_attached() -> bool {
+    STUB.lock_irqsave().is_some()
+}
+
+// With gdb_wait, boot stops here until GDB connects and continues.
+pub fn init() {
+    if is_attached() && cmdline::has("gdb_wait") {
+        info!("Waiting for GDB to attach");
+        unsafe { asm!("ebreak") };
+    }
+}
+
+fn bits(instruction: u32, high: u32, low: u32) -> u32 {
+    (instruction >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+fn sign_extend(value: u64, width_bits: u32) -> i64 {
+    let shift = 64 - width_bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(text: &[u8]) -> Option<u64> {
+    u64::from_str_radix(core::str::from_utf8(text).ok()?, 16).ok()
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+// Where execution can go after the instruction at `pc`: the next one, and
+// for jumps and branches their target.
+fn next_pcs(frame: &TrapFrame, pc: u64, instruction: u32) -> [Option<u64>; 2] {
+    let relative = |offset: i64| Some(pc.wrapping_add(offset as u64));
+    if instruction & 0b11 != 0b11 {
+        let fallthrough = Some(pc + 2);
+        let register = |n| frame.reg(n as usize);
+        return match (instruction & 0b11, bits(instruction, 15, 13)) {
+            // c.j
+            (0b01, 0b101) => {
+                let offset = bits(instruction, 12, 12) << 11
+                    | bits(instruction, 11, 11) << 4
+                    | bits(instruction, 10, 9) << 8
+                    | bits(instruction, 8, 8) << 10
+                    | bits(instruction, 7, 7) << 6
+                    | bits(instruction, 6, 6) << 7
+                    | bits(instruction, 5, 3) << 1
+                    | bits(instruction, 2, 2) << 5;
+                [relative(sign_extend(offset as u64, 12)), None]
+            }
+            // c.beqz and c.bnez
+            (0b01, 0b110 | 0b111) => {
+                let offset = bits(instruction, 12, 12) << 8
+                    | bits(instruction, 11, 10) << 3
+                    | bits(instruction, 6, 5) << 6
+                    | bits(instruction, 4, 3) << 1
+                    | bits(instruction, 2, 2) << 5;
+                [fallthrough, relative(sign_extend(offset as u64, 9))]
+            }
+            // c.jr and c.jalr
+            (0b10, 0b100) if bits(instruction, 6, 2) == 0 && bits(instruction, 11, 7) != 0 => {
+                [Some(register(bits(instruction, 11, 7)) & !1), None]
+            }
+            _ => [fallthrough, None],
+        };
+    }
+
+    let fallthrough = Some(pc + 4);
+    match instruction & 0x7f {
+        // jal
+        0x6f => {
+            let offset = bits(instruction, 31, 31) << 20
+                | bits(instruction, 30, 21) << 1
+                | bits(instruction, 20, 20) << 11
+                | bits(instruction, 19, 12) << 12;
+            [relative(sign_extend(offset as u64, 21)), None]
+        }
+        // jalr
+        0x67 => {
+            let base = frame.reg(bits(instruction, 19, 15) as usize);
+            let offset = (instruction as i32 >> 20) as i64;
+            [Some(base.wrapping_add(offset as u64) & !1), None]
+        }
+        // Conditional branches
+        0x63 => {
+            let offset = bits(instruction, 31, 31) << 12
+                | bits(instruction, 30, 25) << 5
+                | bits(instruction, 11, 8) << 1
+                | bits(instruction, 7, 7) << 11;
+            [fallthrough, relative(sign_extend(offset as u64, 13))]
+        }
+        _ => [fallthrough, None],
+    }
+}
+
+// The live leaf mapping `address`, if there is one the kernel can use.
+// User pages are left alone.
+fn kernel_leaf(address: u64) -> Option<*mut PageTableEntry> {
+    let virt = VirtAddr::try_from(address).ok()?;
+    let root = satp::read_satp().root().as_mut_ptr::<PageTable>();
+    let pte = unsafe { (*root).walk(virt)? };
+    let entry = unsafe { *pte };
+    (entry.is_valid() && entry.is_leaf() && !entry.is_user_accessible()).then_some(pte)
+}
+
+fn read_memory(address: u64) -> Option<u8> {
+    let pte = kernel_leaf(address)?;
+    if !unsafe { (*pte).is_readable() } {
+        return None;
+    }
+    Some(unsafe { (address as *const u8).read_volatile() })
+}
+
+// Read-only pages, such as the kernel's text, are made writable just for
+// the write.
+fn write_memory(address: u64, byte: u8) -> Option<()> {
+    let pte = kernel_leaf(address)?;
+    let page = address & !(PAGE_SIZE - 1);
+    let writable = unsafe { (*pte).is_writable() };
+    if !writable {
+        unsafe { (*pte).modify_flags(|flags| flags.insert(PteFlags::WRITE)) };
+        tlb::flush_local(page, page + PAGE_SIZE);
+    }
+    unsafe { (address as *mut u8).write_volatile(byte) };
+    if !writable {
+        unsafe { (*pte).modify_flags(|flags| flags.remove(PteFlags::WRITE)) };
+        tlb::flush_local(page, page + PAGE_SIZE);
+    }
+    Some(())
+}
+
+// After patching code, so no hart runs what its instruction cache still
+// holds.
+fn sync_instructions() {
+    unsafe { asm!("fence.i") };
+    let others = online_harts() & !(1 << hart_id());
+    if others != 0 {
+        let _ = sbi::remote_fence_i(others, 0);
+    }
+}
+
+fn read_instruction(pc: u64) -> Option<u32> {
+    let low = read_memory(pc)? as u32 | (read_memory(pc + 1)? as u32) << 8;
+    if low & 0b11 != 0b11 {
+        return Some(low);
+    }
+    Some(low | (read_memory(pc + 2)? as u32) << 16 | (read_memory(pc + 3)? as u32) << 24)
+}
+
+fn is_ebreak(instruction: u32) -> bool {
+    instruction == EBREAK || instruction == C_EBREAK as u32
+}
+
+impl Breakpoint {
+    fn insert(address: u64, len: usize) -> Option<Breakpoint> {
+        let mut original = [0; 4];
+        for (n, byte) in original.iter_mut().enumerate().take(len) {
+            *byte = read_memory(address + n as u64)?;
+        }
+        let ebreak = match len {
+            2 => (C_EBREAK as u32).to_le_bytes(),
+            _ => EBREAK.to_le_bytes(),
+        };
+        for (n, &byte) in ebreak.iter().enumerate().take(len) {
+            write_memory(address + n as u64, byte)?;
+        }
+        sync_instructions();
+        Some(Breakpoint {
+            address,
+            len,
+            original,
+        })
+    }
+
+    fn remove(&self) {
+        for (n, &byte) in self.original.iter().enumerate().take(self.len) {
+            write_memory(self.address + n as u64, byte);
+        }
+        sync_instructions();
+    }
+}
+
+// A reply being built, without the framing.
+struct Packet {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Self {
+            bytes: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    // Anything past the end is dropped; callers keep replies short enough.
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.push(DIGITS[(byte >> 4) as usize]);
+        self.push(DIGITS[(byte & 0xf) as usize]);
+    }
+
+    // Registers go over the wire in target byte order.
+    fn push_register(&mut self, value: u64) {
+        value
+            .to_le_bytes()
+            .iter()
+            .for_each(|&byte| self.push_hex(byte));
+    }
+}
+
+impl Write for Packet {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+// Reads little-endian 64-bit registers from hex.
+fn parse_register(hex: &[u8]) -> Option<u64> {
+    if hex.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn register(frame: &TrapFrame, n: usize) -> u64 {
+    match n {
+        PC_REGISTER => frame.sepc,
+        n => frame.reg(n),
+    }
+}
+
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) {
+    match n {
+        PC_REGISTER => frame.sepc = value,
+        n => frame.set_reg(n, value),
+    }
+}
+
+// Just the integer registers and pc, so GDB doesn't ask for the rest.
+fn write_target_xml(out: &mut impl Write) -> core::fmt::Result {
+    out.write_str(concat!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target><architecture>riscv:rv64</architecture>",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">"
+    ))?;
+    for (n, name) in REGISTER_NAMES.iter().enumerate() {
+        let kind = match n {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        write!(
+            out,
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, n
+        )?;
+    }
+    out.write_str("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>")?;
+    out.write_str("</feature></target>")
+}
+
+// Keeps `len` bytes of what's written, from `skip` bytes in, for replies
+// to qXfer reads. The heap isn't safe to use with a hart stopped.
+struct Window<'a> {
+    packet: &'a mut Packet,
+    skip: usize,
+    len: usize,
+    more: bool,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.skip > 0 {
+                self.skip -= 1;
+            } else if self.len > 0 {
+                self.packet.push(byte);
+                self.len -= 1;
+            } else {
+                self.more = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `ADDR,LEN`, both in hex.
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+impl Stub {
+    fn receive(&mut self) -> u8 {
+        if let Some(byte) = self.pending.take() {
+            return byte;
+        }
+        loop {
+            if let Some(byte) = self.uart.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Acknowledges the packet and returns its contents. Bad checksums are
+    // refused so that GDB sends the packet again.
+    fn read_packet<'a>(&mut self, buffer: &'a mut [u8]) -> &'a [u8] {
+        loop {
+            while self.receive() != b'$' {}
+            let mut len = 0;
+            let mut overflowed = false;
+            loop {
+                match self.receive() {
+                    b'#' => break,
+                    byte if len < buffer.len() => {
+                        buffer[len] = byte;
+                        len += 1;
+                    }
+                    _ => overflowed = true,
+                }
+            }
+            let sum = hex_digit(self.receive())
+                .zip(hex_digit(self.receive()))
+                .map(|(high, low)| high << 4 | low);
+            if !overflowed && sum == Some(checksum(&buffer[..len])) {
+                self.uart.send(b'+');
+                return &buffer[..len];
+            }
+            self.uart.send(b'-');
+        }
+    }
+
+    // Resent until GDB acknowledges it. Anything other than a refusal
+    // counts as an acknowledgement, and is kept if it starts a packet.
+    fn send_packet(&mut self, packet: &[u8]) {
+        loop {
+            self.uart.send(b'$');
+            packet.iter().for_each(|&byte| self.uart.send(byte));
+            let _ = write!(self.uart, "#{:02x}", checksum(packet));
+
+            match self.receive() {
+                b'-' => continue,
+                b'+' => return,
+                byte => {
+                    self.pending = Some(byte);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send_stop(&mut self, signal: u8) {
+        let mut reply = Packet::new();
+        reply.push(b'S');
+        reply.push_hex(signal);
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn breakpoint_at(&self, address: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| breakpoint.is_some_and(|b| b.address == address))
+    }
+
+    fn insert_breakpoint(&mut self, address: u64, len: usize) -> bool {
+        if self.breakpoint_at(address).is_some() {
+            return true;
+        }
+        let Some(slot) = self.breakpoints.iter().position(Option::is_none) else {
+            return false;
+        };
+        self.breakpoints[slot] = Breakpoint::insert(address, len);
+        self.breakpoints[slot].is_some()
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        let Some(slot) = self.breakpoint_at(address) else {
+            return false;
+        };
+        if let Some(breakpoint) = self.breakpoints[slot].take() {
+            breakpoint.remove();
+        }
+        true
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for breakpoint in self.breakpoints.iter_mut().filter_map(Option::take) {
+            breakpoint.remove();
+        }
+    }
+
+    // Plants a temporary breakpoint wherever the instruction at sepc can
+    // go, and runs it with interrupts off so the step doesn't land in a
+    // handler instead.
+    fn start_step(&mut self, frame: &mut TrapFrame, instruction: u32) {
+        let targets = next_pcs(frame, frame.sepc, instruction);
+        for (slot, target) in self.step.iter_mut().zip(targets) {
+            *slot = target
+                .filter(|&target| {
+                    self.breakpoints
+                        .iter()
+                        .flatten()
+                        .all(|b| b.address != target)
+                })
+                .and_then(|target| Breakpoint::insert(target, 2));
+        }
+        self.stepping = true;
+        self.step_spie = Sstatus::from_bits_retain(frame.sstatus).spie();
+        frame.sstatus &= !Sstatus::SPIE.bits();
+    }
+
+    // Any breakpoint ends a step, whether or not it was one of the step's.
+    fn finish_step(&mut self, frame: &mut TrapFrame) {
+        if !self.stepping {
+            return;
+        }
+        self.stepping = false;
+        for breakpoint in self.step.iter_mut().filter_map(Option::take) {
+            breakpoint.remove();
+        }
+        if self.step_spie {
+            frame.sstatus |= Sstatus::SPIE.bits();
+        }
+    }
+
+    fn read_registers(&self, frame: &TrapFrame, reply: &mut Packet) {
+        for n in 0..REGISTERS {
+            reply.push_register(register(frame, n));
+        }
+    }
+
+    fn write_registers(&self, frame: &mut TrapFrame, hex: &[u8], reply: &mut Packet) {
+        if hex.len() < REGISTERS * 16 {
+            return reply.push_str("E01");
+        }
+        for (n, chunk) in hex.chunks(16).take(REGISTERS).enumerate() {
+            if let Some(value) = parse_register(chunk) {
+                set_register(frame, n, value);
+            }
+        }
+        reply.push_str("OK");
+    }
+
+    fn handle_read_memory(&self, args: &[u8], reply: &mut Packet) {
+        let Some((address, len)) = parse_range(args) else {
+            return reply.push_str("E01");
+        };
+        let len = len.min(PACKET_SIZE as u64 / 2);
+        for offset in 0..len {
+            match read_memory(address.wrapping_add(offset)) {
+                Some(byte) => reply.push_hex(byte),
+                // A short read is fine, as long as it isn't empty.
+                None if offset > 0 => return,
+                None => return reply.push_str("E14"),
+            }
+        }
+    }
+
+    fn handle_write_memory(&self, args: &[u8], reply: &mut Packet) {
+        let colon = args.iter().position(|&byte| byte == b':');
+        let Some((address, len)) = colon.and_then(|colon| parse_range(&args[..colon])) else {
+            return reply.push_str("E01");
+        };
+        let data = &args[colon.unwrap() + 1..];
+        if data.len() as u64 != len * 2 {
+            return reply.push_str("E01");
+        }
+        for (offset, pair) in data.chunks(2).enumerate() {
+            let byte = hex_digit(pair[0]).zip(hex_digit(pair[1]));
+            let written = byte.and_then(|(high, low)| {
+                write_memory(address.wrapping_add(offset as u64), high << 4 | low)
+            });
+            if written.is_none() {
+                return reply.push_str("E14");
+            }
+        }
+        sync_instructions();
+        reply.push_str("OK");
+    }
+
+    // `Z0,ADDR,KIND` and `z0,ADDR,KIND`; only software breakpoints are
+    // supported, and an empty reply tells GDB so for the rest.
+    fn breakpoint(&mut self, insert: bool, args: &[u8], reply: &mut Packet) {
+        let Some(args) = args.strip_prefix(b"0,") else {
+            return;
+        };
+        let Some((address, kind)) = parse_range(args) else {
+            return reply.push_str("E01");
+        };
+        let done = match (insert, kind) {
+            (true, 2 | 4) => self.insert_breakpoint(address, kind as usize),
+            (true, _) => false,
+            (false, _) => self.remove_breakpoint(address),
+        };
+        reply.push_str(if done { "OK" } else { "E0e" });
+    }
+
+    fn query(&self, packet: &[u8], reply: &mut Packet) {
+        if packet.starts_with(b"qSupported") {
+            let _ = write!(reply, "PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        } else if packet == b"qAttached" {
+            reply.push_str("1");
+        } else if let Some(args) = packet.strip_prefix(b"qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(args) else {
+                return reply.push_str("E01");
+            };
+            let mut xml = Packet::new();
+            let mut window = Window {
+                packet: &mut xml,
+                skip: offset as usize,
+                len: (len as usize).min(PACKET_SIZE - 1),
+                more: false,
+            };
+            let _ = write_target_xml(&mut window);
+            reply.push(if window.more { b'm' } else { b'l' });
+            xml.as_bytes().iter().for_each(|&byte| reply.push(byte));
+        }
+    }
+
+    // Answers a packet, or returns how to resume if it says to.
+    fn execute(
+        &mut self,
+        frame: &mut TrapFrame,
+        packet: &[u8],
+        reply: &mut Packet,
+    ) -> Option<Resume> {
+        let (&command, args) = packet.split_first()?;
+        match command {
+            b'?' => reply.push_str("S05"),
+            b'g' => self.read_registers(frame, reply),
+            b'G' => self.write_registers(frame, args, reply),
+            b'p' => match parse_hex(args).map(|n| n as usize) {
+                Some(n) if n < REGISTERS => reply.push_register(register(frame, n)),
+                _ => reply.push_str("E01"),
+            },
+            b'P' => {
+                let equals = args.iter().position(|&byte| byte == b'=');
+                let n = equals.and_then(|equals| parse_hex(&args[..equals]));
+                let value = equals.and_then(|equals| parse_register(&args[equals + 1..]));
+                match (n, value) {
+                    (Some(n), Some(value)) if (n as usize) < REGISTERS => {
+                        set_register(frame, n as usize, value);
+                        reply.push_str("OK");
+                    }
+                    _ => reply.push_str("E01"),
+                }
+            }
+            b'm' => self.handle_read_memory(args, reply),
+            b'M' => self.handle_write_memory(args, reply),
+            b'Z' => self.breakpoint(true, args, reply),
+            b'z' => self.breakpoint(false, args, reply),
+            b'q' => self.query(packet, reply),
+            b'H' => reply.push_str("OK"),
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(args) {
+                    frame.sepc = address;
+                }
+                return Some(if command == b'c' {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            b'D' => {
+                reply.push_str("OK");
+                return Some(Resume::Detach);
+            }
+            b'k' => return Some(Resume::Detach),
+            _ => (),
+        }
+        None
+    }
+
+    // Talks to GDB until it resumes the kernel. `signal` is reported first
+    // unless GDB is only now connecting, in which case it asks.
+    fn session(&mut self, frame: &mut TrapFrame, signal: Option<u8>) {
+        watchdog::pause();
+        if let Some(signal) = signal {
+            self.send_stop(signal);
+        }
+        let mut buffer = [0; PACKET_SIZE];
+        loop {
+            let packet = self.read_packet(&mut buffer);
+            let mut reply = Packet::new();
+            // Resuming goes unanswered, bar detaching's OK.
+            let resume = self.execute(frame, packet, &mut reply);
+            if resume.is_none() || reply.len > 0 {
+                self.send_packet(reply.as_bytes());
+            }
+            let Some(resume) = resume else { continue };
+
+            // An ebreak in the code itself would only stop again.
+            let instruction = read_instruction(frame.sepc);
+            let compiled_in =
+                instruction.is_some_and(is_ebreak) && self.breakpoint_at(frame.sepc).is_none();
+            match resume {
+                Resume::Continue if compiled_in => frame.skip_instruction(),
+                Resume::Continue => (),
+                Resume::Step if compiled_in => {
+                    frame.skip_instruction();
+                    self.send_stop(SIGTRAP);
+                    continue;
+                }
+                Resume::Step => match instruction {
+                    Some(instruction) => self.start_step(frame, instruction),
+                    None => {
+                        self.send_stop(SIGTRAP);
+                        continue;
+                    }
+                },
+                Resume::Detach => {
+                    self.remove_all_breakpoints();
+                    if compiled_in {
+                        frame.skip_instruction();
+                    }
+                }
+            }
+            watchdog::resume();
+            return;
+        }
+    }
+}
+
+// Any ebreak while the stub is attached stops for GDB, including ones
+// compiled into the kernel.
+pub fn handle_breakpoint(frame: &mut TrapFrame) -> bool {
+    let mut stub = STUB.lock_irqsave();
+    let Some(stub) = stub.as_mut() else {
+        return false;
+    };
+    stub.finish_step(frame);
+    stub.session(frame, Some(SIGTRAP));
+    true
+}
+
+// GDB interrupts a running kernel with a break byte, and connects by just
+// sending a packet, so any input stops whatever this hart was running.
+fn handle_uart_interrupt(_irq: u32) {
+    let mut stub = STUB.lock_irqsave();
+    let Some(stub) = stub.as_mut() else {
+        return;
+    };
+    let Some(byte) = stub.uart.try_receive() else {
+        return;
+    };
+    let signal = match byte {
+        INTERRUPT => Some(SIGINT),
+        b'+' | b'-' => return,
+        byte => {
+            stub.pending = Some(byte);
+            None
+        }
+    };
+    trap::with_current_frame(|frame| stub.session(frame, signal));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn registers_round_trip_through_hex() {
+        let mut packet = Packet::new();
+        packet.push_register(0x8020_0000);
+        assert_eq!(packet.as_bytes(), b"0000208000000000");
+        assert_eq!(parse_register(packet.as_bytes()), Some(0x8020_0000));
+        assert_eq!(parse_register(b"00"), None);
+    }
+
+    #[test_case]
+    fn checksums_are_the_byte_sum_mod_256() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test_case]
+    fn jumps_and_branches_have_two_possible_next_pcs() {
+        let mut frame = TrapFrame::zeroed();
+        frame.set_reg(1, 0x8000_1235);
+        let pc = 0x8000_0100;
+        // j +8 and jal ra, -16
+        assert_eq!(next_pcs(&frame, pc, 0x0080_006f), [Some(pc + 8), None]);
+        assert_eq!(next_pcs(&frame, pc, 0xff1f_f0ef), [Some(pc - 16), None]);
+        // beqz a0, -8
+        assert_eq!(
+            next_pcs(&frame, pc, 0xfe05_0ce3),
+            [Some(pc + 4), Some(pc - 8)]
+        );
+        // ret, in full and compressed
+        assert_eq!(next_pcs(&frame, pc, 0x8067), [Some(0x8000_1234), None]);
+        assert_eq!(next_pcs(&frame, pc, 0x8082), [Some(0x8000_1234), None]);
+        // c.j -2 and c.bnez s1, +32
+        assert_eq!(next_pcs(&frame, pc, 0xbffd), [Some(pc - 2), None]);
+        assert_eq!(next_pcs(&frame, pc, 0xe085), [Some(pc + 2), Some(pc + 32)]);
+        // addi a0, a0, 1
+        assert_eq!(next_pcs(&frame, pc, 0x0015_0513), [Some(pc + 4), None]);
+    }
+
+    #[test_case]
+    fn register_packets_read_and_write_the_frame() {
+        let mut frame = TrapFrame::zeroed();
+        frame.sepc = 0x8020_0000;
+        let mut reply = Packet::new();
+        for n in 0..REGISTERS {
+            reply.push_register(register(&frame, n));
+        }
+        assert_eq!(reply.len, REGISTERS * 16);
+        assert_eq!(
+            parse_register(&reply.as_bytes()[PC_REGISTER * 16..]),
+            Some(0x8020_0000)
+        );
+
+        set_register(&mut frame, 10, 42);
+        set_register(&mut frame, PC_REGISTER, 0x8020_0004);
+        assert_eq!(frame.arg(0), 42);
+        assert_eq!(frame.sepc, 0x8020_0004);
+    }
+}
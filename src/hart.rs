@@ -0,0 +1,173 @@
+use crate::backtrace::StackBounds;
+use crate::boot;
+use crate::devicetree::{self, DeviceTree};
+use crate::page_allocator::PAGE_SIZE;
+use crate::sbi::{self, SbiError};
+use crate::warn;
+use crate::{ipi, per_hart, satp, stack_guard, task, timer, trap};
+use alloc::alloc::{alloc_zeroed, Layout};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const MAX_HARTS: usize = 8;
+
+const SECONDARY_STACK_SIZE: usize = 64 * 1024;
+
+static ONLINE_HARTS: AtomicU64 = AtomicU64::new(0);
+
+// Read by boot::secondary_start before the hart has a stack, so the
+// layout is fixed and it lives in the identity mapped kernel image.
+#[repr(C)]
+struct SecondaryBoot {
+    satp: AtomicU64,
+    stack_top: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BOOT: SecondaryBoot = SecondaryBoot {
+    satp: AtomicU64::new(0),
+    stack_top: AtomicU64::new(0),
+};
+
+static SECONDARY_BOOT: [SecondaryBoot; MAX_HARTS] = [NO_BOOT; MAX_HARTS];
+
+// Boot code keeps each hart's id in tp.
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+pub fn mark_online(hart: usize) {
+    ONLINE_HARTS.fetch_or(1 << hart, Ordering::Relaxed);
+}
+
+pub fn mark_offline(hart: usize) {
+    ONLINE_HARTS.fetch_and(!(1 << hart), Ordering::Relaxed);
+}
+
+// Bit n is set while hart n is running the kernel.
+pub fn online_harts() -> u64 {
+    ONLINE_HARTS.load(Ordering::Relaxed)
+}
+
+// The harts listed under /cpus, up to MAX_HARTS of them.
+pub fn hart_ids(tree: &DeviceTree) -> impl Iterator<Item = usize> {
+    tree.find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| {
+            node.property("device_type").and_then(|p| p.as_str()) == Some("cpu")
+                && node
+                    .property("status")
+                    .and_then(|p| p.as_str())
+                    .is_none_or(|status| status == "okay")
+        })
+        .filter_map(|node| node.property("reg").and_then(|p| p.as_u32()))
+        .map(|id| id as usize)
+        .filter(|&id| id < MAX_HARTS)
+}
+
+pub fn hart_count() -> usize {
+    devicetree::get().map_or(1, |tree| hart_ids(&tree).count())
+}
+
+// The stack secondary harts run on; the boot hart uses the linker's stack.
+pub fn stack_bounds(hart: usize) -> Option<StackBounds> {
+    match SECONDARY_BOOT[hart].stack_top.load(Ordering::Relaxed) {
+        0 => None,
+        high => Some(StackBounds {
+            low: high - SECONDARY_STACK_SIZE as u64,
+            high,
+        }),
+    }
+}
+
+pub(crate) extern "C" fn secondary_main(hart: usize) -> ! {
+    per_hart::init(hart);
+    trap::init_hart();
+    ipi::init_hart();
+    timer::init_hart();
+    mark_online(hart);
+    trap::enable_interrupts();
+    task::run_idle()
+}
+
+fn start_hart(hart: usize) -> Result<(), SbiError> {
+    let boot = &SECONDARY_BOOT[hart];
+    if boot.stack_top.load(Ordering::Relaxed) == 0 {
+        let layout = Layout::from_size_align(SECONDARY_STACK_SIZE, PAGE_SIZE as usize).unwrap();
+        let stack = unsafe { alloc_zeroed(layout) };
+        if stack.is_null() {
+            return Err(SbiError::Failed);
+        }
+        boot.stack_top.store(
+            stack as u64 + SECONDARY_STACK_SIZE as u64,
+            Ordering::Relaxed,
+        );
+        unsafe { stack_guard::install(&stack_bounds(hart).unwrap()) };
+    }
+
+    boot.satp.store(satp::read_satp().bits(), Ordering::Release);
+
+    let entry: unsafe extern "C" fn() -> ! = boot::secondary_start;
+    sbi::hart_start(
+        hart,
+        entry as usize as u64,
+        boot as *const SecondaryBoot as u64,
+    )
+}
+
+fn wait_until_online(hart: usize) -> bool {
+    let deadline = timer::read_time() + timer::timebase_frequency();
+    while online_harts() & 1 << hart == 0 {
+        if timer::read_time() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Starts every hart the firmware is holding back and returns how many came
+// online.
+pub fn start_all_harts() -> usize {
+    let tree = match devicetree::get() {
+        Some(tree) => tree,
+        None => return 0,
+    };
+    if !sbi::probe_extension(sbi::EXTENSION_HSM) {
+        warn!("SBI HSM is unavailable, running on one hart");
+        return 0;
+    }
+
+    let mut started = 0;
+    for hart in hart_ids(&tree).filter(|&hart| online_harts() & 1 << hart == 0) {
+        match start_hart(hart) {
+            Ok(()) if wait_until_online(hart) => started += 1,
+            Ok(()) => warn!("Hart {} didn't come online", hart),
+            Err(SbiError::AlreadyAvailable) => (),
+            Err(error) => warn!("Failed to start hart {}: {:?}", hart, error),
+        }
+    }
+    started
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn this_hart_is_listed_in_the_device_tree() {
+        let tree = devicetree::get().unwrap();
+        assert!(hart_ids(&tree).any(|hart| hart == hart_id()));
+    }
+
+    #[test_case]
+    fn every_hart_comes_online() {
+        start_all_harts();
+        assert_eq!(online_harts().count_ones() as usize, hart_count());
+    }
+}
@@ -0,0 +1,477 @@
+#[cfg(feature = "kasan")]
+use crate::kasan;
+use crate::memory_map;
+use crate::page_allocator::{PageAllocationError, PageAllocator, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::page_table::{PageTableEntryMode, VirtualMemory};
+use crate::trap::LockIrqSave;
+use crate::VIRTUAL_MEMORY;
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// Root entries 128 to 191, just below user space.
+const HEAP_START: usize = 0x20_0000_0000;
+const HEAP_MAX_SIZE: usize = 0x10_0000_0000;
+// How much of the window each root page table entry covers.
+const ROOT_ENTRY_SIZE: usize = 1 << 30;
+const MIN_GROWTH: usize = 64 * PAGE_SIZE as usize;
+const LOW_MEMORY_MIN_GROWTH: usize = 4 * PAGE_SIZE as usize;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// Every allocation is padded so that, once freed, it can hold a FreeBlock.
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(MIN_BLOCK_SIZE), BLOCK_ALIGN)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+}
+
+pub struct LinkedListHeap {
+    head: *mut FreeBlock,
+    stats: HeapStats,
+}
+
+unsafe impl Send for LinkedListHeap {}
+
+impl LinkedListHeap {
+    pub const fn empty() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            stats: HeapStats { size: 0, used: 0 },
+        }
+    }
+
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned_start = align_up(start, BLOCK_ALIGN);
+        let size = (size - (aligned_start - start)) & !(BLOCK_ALIGN - 1);
+        if size < MIN_BLOCK_SIZE {
+            return;
+        }
+
+        self.stats.size += size;
+        self.insert(aligned_start, size);
+    }
+
+    // Insert a free block, keeping the list sorted by address and merging neighbours.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let block_start = current as usize;
+            let block_end = block_start + unsafe { (*current).size };
+
+            let mut alloc_start = align_up(block_start, align);
+            if alloc_start != block_start && alloc_start - block_start < MIN_BLOCK_SIZE {
+                alloc_start = align_up(block_start + MIN_BLOCK_SIZE, align);
+            }
+            let alloc_end = alloc_start + size;
+
+            let tail = block_end.saturating_sub(alloc_end);
+            if alloc_end <= block_end && (tail == 0 || tail >= MIN_BLOCK_SIZE) {
+                unsafe {
+                    let next = (*current).next;
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if alloc_start > block_start {
+                        self.insert(block_start, alloc_start - block_start);
+                    }
+                    if tail > 0 {
+                        self.insert(alloc_end, tail);
+                    }
+                }
+
+                self.stats.used += size;
+                return alloc_start as *mut u8;
+            }
+
+            prev = current;
+            current = unsafe { (*current).next };
+        }
+
+        ptr::null_mut()
+    }
+
+    // Takes free space off the end of the heap, which ends at `end`, down to
+    // a multiple of `align` but not below `floor`. Returns where it ends now.
+    pub fn release_tail(&mut self, end: usize, floor: usize, align: usize) -> usize {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut last = self.head;
+        if last.is_null() {
+            return end;
+        }
+        unsafe {
+            while !(*last).next.is_null() {
+                prev = last;
+                last = (*last).next;
+            }
+        }
+
+        let start = last as usize;
+        if start + unsafe { (*last).size } != end {
+            return end;
+        }
+        let mut new_end = align_up(start.max(floor), align);
+        if new_end > start && new_end - start < MIN_BLOCK_SIZE {
+            new_end += align;
+        }
+        if new_end >= end {
+            return end;
+        }
+
+        unsafe {
+            if new_end > start {
+                (*last).size = new_end - start;
+            } else if prev.is_null() {
+                self.head = ptr::null_mut();
+            } else {
+                (*prev).next = ptr::null_mut();
+            }
+        }
+        self.stats.size -= end - new_end;
+        new_end
+    }
+
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let size = block_size(&layout);
+        self.stats.used -= size;
+        self.insert(ptr as usize, size);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+}
+
+pub struct KernelHeap {
+    heap: Mutex<LinkedListHeap>,
+    // Held while growing, with how much of the window is mapped.
+    mapped: Mutex<usize>,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            heap: Mutex::new(LinkedListHeap::empty()),
+            mapped: Mutex::new(0),
+        }
+    }
+
+    // Map more pages onto the end of the heap window. The heap starts empty and
+    // grows on first use, since boot code runs before paging is enabled.
+    // The heap itself isn't locked while the kernel page tables and page
+    // allocator are, so code holding either of those can still allocate
+    // unless that has to grow the heap, which it mustn't.
+    fn grow(&self, layout: &Layout) -> bool {
+        let wanted = block_size(layout) + layout.align().max(BLOCK_ALIGN) + MIN_BLOCK_SIZE;
+        let min_growth = if memory_map::is_low_memory() {
+            LOW_MEMORY_MIN_GROWTH
+        } else {
+            MIN_GROWTH
+        };
+        let growth = align_up(wanted.max(min_growth), PAGE_SIZE as usize);
+        let mut size = self.mapped.lock_irqsave();
+        let start = HEAP_START + *size;
+        if *size + growth > HEAP_LIMIT.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut mapped = 0;
+        {
+            let mut vm = VIRTUAL_MEMORY.lock();
+            let vm = match vm.get_mut() {
+                Some(vm) => vm,
+                None => return false,
+            };
+            let mut allocator = PAGE_ALLOCATOR.lock();
+            while mapped < growth {
+                let virt = ((start + mapped) as u64).try_into().unwrap();
+                if vm
+                    .map(virt, PageTableEntryMode::ReadWrite, &mut allocator)
+                    .is_err()
+                {
+                    break;
+                }
+                mapped += PAGE_SIZE as usize;
+            }
+        }
+
+        unsafe {
+            asm!("sfence.vma");
+            if mapped > 0 {
+                self.heap.lock_irqsave().add_region(start, mapped);
+            }
+        }
+        *size += mapped;
+
+        mapped == growth
+    }
+
+    // Unmaps the end of the heap window, as much of it as is free, until
+    // `size` bytes are left, and gives its pages back.
+    fn shrink_to(&self, size: usize) {
+        let mut mapped = self.mapped.lock_irqsave();
+        if *mapped <= size {
+            return;
+        }
+        let end = HEAP_START + *mapped;
+        let floor = HEAP_START + size;
+        let new_end = self
+            .heap
+            .lock_irqsave()
+            .release_tail(end, floor, PAGE_SIZE as usize);
+
+        let mut vm = VIRTUAL_MEMORY.lock();
+        let vm = vm.get_mut().expect("the heap grew without page tables");
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        for page in (new_end..end).step_by(PAGE_SIZE as usize) {
+            let frame = vm.unmap((page as u64).try_into().unwrap()).unwrap();
+            allocator.dealloc(frame);
+        }
+        *mapped = new_end - HEAP_START;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.heap.lock_irqsave().stats()
+    }
+
+    #[cfg(feature = "kasan")]
+    fn bounds(&self) -> (u64, u64) {
+        let size = self.stats().size;
+        (HEAP_START as u64, (HEAP_START + size) as u64)
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.heap.lock_irqsave().alloc(layout);
+        // Another hart may take what was added before this gets to it.
+        while ptr.is_null() && self.grow(&layout) {
+            ptr = self.heap.lock_irqsave().alloc(layout);
+        }
+
+        // Quarantined chunks are the last resort.
+        #[cfg(feature = "kasan")]
+        while ptr.is_null() {
+            let Some((chunk, chunk_layout)) = kasan::release_heap_chunk() else {
+                break;
+            };
+            let mut heap = self.heap.lock_irqsave();
+            heap.dealloc(chunk, chunk_layout);
+            ptr = heap.alloc(layout);
+        }
+
+        #[cfg(feature = "kasan")]
+        if !ptr.is_null() {
+            kasan::heap_alloc(ptr, layout);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        let Some((ptr, layout)) = kasan::heap_free(ptr, layout, self.bounds()) else {
+            return;
+        };
+
+        self.heap.lock_irqsave().dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+// How far the heap may grow; zero until init.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+// Gives the root entries over the heap window their tables up front, for as
+// much of it as `memory` bytes could ever fill, before any address space
+// copies the kernel's root entries. Growing the heap then only changes the
+// tables below them, which every address space shares.
+pub fn init(
+    vm: &mut VirtualMemory,
+    memory: u64,
+    allocator: &mut PageAllocator,
+) -> Result<(), PageAllocationError> {
+    let size = align_up(memory as usize, ROOT_ENTRY_SIZE).min(HEAP_MAX_SIZE);
+    for start in (HEAP_START..HEAP_START + size).step_by(ROOT_ENTRY_SIZE) {
+        vm.prepare_root_entry((start as u64).try_into().unwrap(), allocator)?;
+    }
+    HEAP_LIMIT.store(size, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn stats() -> HeapStats {
+    KERNEL_HEAP.stats()
+}
+
+// For undoing the growth a test caused; the heap otherwise never shrinks.
+pub fn shrink_to(size: usize) {
+    KERNEL_HEAP.shrink_to(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sandbox::Sandbox;
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    #[repr(align(4096))]
+    struct Arena([u8; 4096]);
+
+    fn arena_heap(arena: &mut Arena) -> LinkedListHeap {
+        let mut heap = LinkedListHeap::empty();
+        unsafe { heap.add_region(arena.0.as_mut_ptr() as usize, arena.0.len()) };
+        heap
+    }
+
+    #[test_case]
+    fn allocations_are_aligned() {
+        let mut arena = Arena([0; 4096]);
+        let mut heap = arena_heap(&mut arena);
+
+        let _ = heap.alloc(Layout::from_size_align(3, 1).unwrap());
+        let ptr = heap.alloc(Layout::from_size_align(64, 256).unwrap());
+
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % 256, 0);
+    }
+
+    #[test_case]
+    fn freeing_everything_merges_back_into_one_block() {
+        let mut arena = Arena([0; 4096]);
+        let mut heap = arena_heap(&mut arena);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let a = heap.alloc(layout);
+        let b = heap.alloc(layout);
+        let c = heap.alloc(layout);
+        unsafe {
+            heap.dealloc(b, layout);
+            heap.dealloc(a, layout);
+            heap.dealloc(c, layout);
+        }
+
+        assert_eq!(heap.stats().used, 0);
+        let whole = heap.alloc(Layout::from_size_align(4096, 16).unwrap());
+        assert!(!whole.is_null());
+    }
+
+    #[test_case]
+    fn allocating_more_than_the_heap_fails() {
+        let mut arena = Arena([0; 4096]);
+        let mut heap = arena_heap(&mut arena);
+
+        let ptr = heap.alloc(Layout::from_size_align(8192, 8).unwrap());
+
+        assert!(ptr.is_null());
+    }
+
+    #[test_case]
+    fn boxes_and_vecs_work() {
+        let boxed = Box::new(41);
+        assert_eq!(*boxed + 1, 42);
+
+        let mut numbers = Vec::new();
+        for n in 0..1000u64 {
+            numbers.push(n);
+        }
+        assert_eq!(numbers.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn only_free_space_at_the_end_is_released() {
+        let mut arena = Arena([0; 4096]);
+        let mut heap = arena_heap(&mut arena);
+        let start = arena.0.as_ptr() as usize;
+        let end = start + 4096;
+
+        let ptr = heap.alloc(Layout::from_size_align(100, 8).unwrap());
+        assert_eq!(heap.release_tail(end, start, 1024), start + 1024);
+        assert_eq!(heap.stats().size, 1024);
+        assert_eq!(heap.release_tail(start + 1024, start, 1024), start + 1024);
+        unsafe { heap.dealloc(ptr, Layout::from_size_align(100, 8).unwrap()) };
+        assert_eq!(heap.release_tail(start + 1024, start, 1024), start);
+        assert_eq!(heap.stats().size, 0);
+    }
+
+    #[test_case]
+    fn large_allocations_grow_the_heap() {
+        let _sandbox = Sandbox::new(1);
+        let size_before = stats().size;
+        let big = Vec::<u8>::with_capacity(size_before + 4096);
+        assert!(stats().size > size_before);
+        drop(big);
+    }
+
+    #[test_case]
+    fn growing_without_the_heap_locked_keeps_its_size_in_step() {
+        let _sandbox = Sandbox::new(1);
+        let big = Vec::<u8>::with_capacity(stats().size + 4096);
+        assert_eq!(*KERNEL_HEAP.mapped.lock_irqsave(), stats().size);
+        drop(big);
+    }
+
+    #[test_case]
+    fn collections_work() {
+        let mut map = BTreeMap::new();
+        map.insert(String::from("one"), 1);
+        map.insert(String::from("two"), 2);
+        assert_eq!(map.get("two"), Some(&2));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::cpio;
+use crate::devicetree::{self, DeviceTree};
+use crate::error::KernelResult;
+use crate::memory_map::MemoryRegion;
+use crate::vfs;
+use crate::{error, info};
+
+// A cpio archive build.rs links in when RISCVOS_INITRAMFS names one, and
+// otherwise empty.
+static LINKED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+// Where QEMU's -initrd put its archive, as /chosen says.
+pub fn initrd_region(tree: &DeviceTree) -> Option<MemoryRegion> {
+    let chosen = tree.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_u64()?;
+    let end = chosen.property("linux,initrd-end")?.as_u64()?;
+    let region = MemoryRegion::new(start, end);
+    (!region.is_empty()).then_some(region)
+}
+
+// Copies the directories and regular files in `archive` into the root file
+// system, replacing files already there, and returns how many it copied.
+// Anything else, such as links and device nodes, is skipped.
+pub fn unpack(archive: &[u8]) -> KernelResult<usize> {
+    let mut unpacked = 0;
+    for entry in cpio::entries(archive) {
+        let entry = entry?;
+        let components = vfs::components(entry.name);
+        if components.is_empty() || !(entry.is_directory() || entry.is_file()) {
+            continue;
+        }
+
+        // Archives usually list directories before what's in them, but
+        // needn't.
+        if entry.is_directory() {
+            vfs::mkdir_all(entry.name)?;
+        } else {
+            vfs::mkdir_all(&components[..components.len() - 1].join("/"))?;
+            vfs::write_file(entry.name, entry.data)?;
+        }
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+fn unpack_from(source: &str, archive: &[u8]) {
+    match unpack(archive) {
+        Ok(count) => info!("Unpacked {} entries from the {} initramfs", count, source),
+        Err(e) => error!("Failed to unpack the {} initramfs: {}", source, e),
+    }
+}
+
+// Unpacks the linked-in archive and then the one from -initrd, so the
+// latter's files win. The initrd's memory stays reserved.
+pub fn init() {
+    if !LINKED.is_empty() {
+        unpack_from("linked-in", LINKED);
+    }
+    if let Some(region) = devicetree::get().and_then(|tree| initrd_region(&tree)) {
+        let archive = unsafe {
+            core::slice::from_raw_parts(region.start as *const u8, region.size() as usize)
+        };
+        unpack_from("-initrd", archive);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpio::{test::archive, S_IFDIR, S_IFREG};
+    use crate::error::KernelError;
+
+    #[test_case]
+    fn archives_are_unpacked_into_the_root() {
+        let bytes = archive(&[
+            ("initramfs-test/a/file", S_IFREG | 0o644, b"first"),
+            ("initramfs-test/b", S_IFDIR | 0o755, &[]),
+            ("./initramfs-test/a/file", S_IFREG | 0o644, b"second"),
+            ("initramfs-test/link", 0o120777, b"a/file"),
+        ]);
+        assert_eq!(unpack(&bytes), Ok(3));
+        assert_eq!(vfs::read_file("/initramfs-test/a/file").unwrap(), b"second");
+        assert!(vfs::lookup("/initramfs-test/link").is_err());
+
+        vfs::unlink("/initramfs-test/a/file").unwrap();
+        vfs::rmdir("/initramfs-test/a").unwrap();
+        vfs::rmdir("/initramfs-test/b").unwrap();
+        vfs::rmdir("/initramfs-test").unwrap();
+    }
+
+    #[test_case]
+    fn damaged_archives_fail_to_unpack() {
+        assert_eq!(unpack(b"not an archive"), Err(KernelError::InvalidArgument));
+    }
+}
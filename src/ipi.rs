@@ -0,0 +1,222 @@
+use crate::csr::{self, Interrupts};
+use crate::hart::{self, hart_id, online_harts, MAX_HARTS};
+use crate::sbi::{self, SbiResult};
+use crate::tlb;
+use crate::trap::{self, LockIrqSave, TrapCause, TrapFrame};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const QUEUE_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiMessage {
+    Reschedule,
+    // Flush translations for [start, end); an empty range flushes everything.
+    TlbShootdown { start: u64, end: u64 },
+    Halt,
+}
+
+struct MessageQueue {
+    messages: [Option<IpiMessage>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl MessageQueue {
+    const fn new() -> Self {
+        Self {
+            messages: [None; QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, message: IpiMessage) -> bool {
+        // Repeated requests for the same thing don't need to queue twice.
+        if self.iter().any(|queued| queued == message) {
+            return true;
+        }
+        if self.len == QUEUE_CAPACITY {
+            return false;
+        }
+        self.messages[(self.head + self.len) % QUEUE_CAPACITY] = Some(message);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<IpiMessage> {
+        if self.len == 0 {
+            return None;
+        }
+        let message = self.messages[self.head].take();
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        message
+    }
+
+    // Takes the oldest shootdown out of the queue, leaving the other
+    // messages in order.
+    fn take_shootdown(&mut self) -> Option<IpiMessage> {
+        let slot = |n: usize| (self.head + n) % QUEUE_CAPACITY;
+        let found = (0..self.len).find(|&n| {
+            matches!(
+                self.messages[slot(n)],
+                Some(IpiMessage::TlbShootdown { .. })
+            )
+        })?;
+        let message = self.messages[slot(found)].take();
+        for n in found..self.len - 1 {
+            self.messages[slot(n)] = self.messages[slot(n + 1)].take();
+        }
+        self.len -= 1;
+        message
+    }
+
+    fn iter(&self) -> impl Iterator<Item = IpiMessage> + '_ {
+        (0..self.len).filter_map(move |n| self.messages[(self.head + n) % QUEUE_CAPACITY])
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: Mutex<MessageQueue> = Mutex::new(MessageQueue::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+
+static QUEUES: [Mutex<MessageQueue>; MAX_HARTS] = [EMPTY_QUEUE; MAX_HARTS];
+static NEED_RESCHEDULE: [AtomicBool; MAX_HARTS] = [NO_RESCHEDULE; MAX_HARTS];
+
+pub fn send(hart: usize, message: IpiMessage) -> SbiResult<()> {
+    // A full queue drains as soon as the target takes the interrupt.
+    while !QUEUES[hart].lock_irqsave().push(message) {
+        sbi::send_ipi(1 << hart, 0)?;
+        core::hint::spin_loop();
+    }
+    sbi::send_ipi(1 << hart, 0)
+}
+
+pub fn send_to_others(message: IpiMessage) -> SbiResult<()> {
+    let others = online_harts() & !(1 << hart_id());
+    for hart in (0..MAX_HARTS).filter(|hart| others & 1 << hart != 0) {
+        send(hart, message)?;
+    }
+    Ok(())
+}
+
+// Set by a Reschedule message; the scheduler clears it when it acts on it.
+pub fn take_reschedule() -> bool {
+    NEED_RESCHEDULE[hart_id()].swap(false, Ordering::Relaxed)
+}
+
+fn process(message: IpiMessage) {
+    match message {
+        IpiMessage::Reschedule => NEED_RESCHEDULE[hart_id()].store(true, Ordering::Relaxed),
+        IpiMessage::TlbShootdown { start, end } => {
+            tlb::flush_local(start, end);
+            tlb::acknowledge();
+        }
+        IpiMessage::Halt => {
+            hart::mark_offline(hart_id());
+            trap::disable_interrupts();
+            loop {
+                unsafe { asm!("wfi") };
+            }
+        }
+    }
+}
+
+// Acts on the shootdowns queued for this hart without waiting for the
+// interrupt, for a hart that spins with interrupts off while another hart
+// waits on its acknowledgement.
+pub fn handle_pending_shootdowns() {
+    loop {
+        let message = QUEUES[hart_id()].lock_irqsave().take_shootdown();
+        match message {
+            Some(message) => process(message),
+            None => return,
+        }
+    }
+}
+
+fn handle_software_interrupt(_frame: &mut TrapFrame) -> bool {
+    unsafe {
+        csr::sip::clear(Interrupts::SOFTWARE);
+    }
+
+    // Messages queued after the queue empties raise a fresh interrupt.
+    loop {
+        let message = QUEUES[hart_id()].lock().pop();
+        match message {
+            Some(message) => process(message),
+            None => return true,
+        }
+    }
+}
+
+// Every hart takes software interrupts; the handler is shared.
+pub fn init_hart() {
+    unsafe {
+        csr::sie::set(Interrupts::SOFTWARE);
+    }
+}
+
+pub fn init() {
+    trap::register_handler(TrapCause::SoftwareInterrupt, handle_software_interrupt);
+    init_hart();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn duplicate_messages_are_coalesced() {
+        let mut queue = MessageQueue::new();
+        assert!(queue.push(IpiMessage::Reschedule));
+        assert!(queue.push(IpiMessage::Reschedule));
+        assert_eq!(queue.pop(), Some(IpiMessage::Reschedule));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case]
+    fn a_full_queue_refuses_new_messages() {
+        let mut queue = MessageQueue::new();
+        for page in 0..QUEUE_CAPACITY as u64 {
+            assert!(queue.push(IpiMessage::TlbShootdown {
+                start: page,
+                end: page + 1
+            }));
+        }
+        assert!(!queue.push(IpiMessage::Reschedule));
+    }
+
+    #[test_case]
+    fn taking_a_shootdown_keeps_the_other_messages_in_order() {
+        let mut queue = MessageQueue::new();
+        let shootdown = IpiMessage::TlbShootdown { start: 0, end: 1 };
+        assert!(queue.push(IpiMessage::Reschedule));
+        assert!(queue.push(shootdown));
+        assert!(queue.push(IpiMessage::Halt));
+        assert_eq!(queue.take_shootdown(), Some(shootdown));
+        assert_eq!(queue.take_shootdown(), None);
+        assert_eq!(queue.pop(), Some(IpiMessage::Reschedule));
+        assert_eq!(queue.pop(), Some(IpiMessage::Halt));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case]
+    fn a_reschedule_ipi_to_this_hart_is_delivered() {
+        // The scheduler takes the flag on the way out of the interrupt, so
+        // look for the interrupt itself.
+        let delivered = || trap::stats().count(TrapCause::SoftwareInterrupt);
+        let before = delivered();
+        send(hart_id(), IpiMessage::Reschedule).unwrap();
+
+        let deadline = crate::timer::read_time() + crate::timer::ticks_per_jiffy();
+        while delivered() == before && crate::timer::read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        assert!(delivered() > before);
+        assert!(!take_reschedule());
+    }
+}
@@ -0,0 +1,576 @@
+use crate::backtrace::{Backtrace, StackBounds};
+use crate::hart::{self, hart_id};
+use crate::page_allocator::PAGE_SIZE;
+use crate::per_hart;
+use crate::symbols::Symbolized;
+use crate::trap::LockIrqSave;
+use core::alloc::Layout;
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+// Tracks what the page allocator and the heap have handed out, so frees of
+// memory that isn't allocated are caught as they happen. Freed memory is
+// poisoned and held in quarantine for a while before it can be reused, and
+// any write to it in the meantime is caught when it leaves. Reports show
+// where the memory was allocated and freed.
+
+// The same pattern as page poisoning, so the two features can be on
+// together.
+pub const POISON: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+const POISON_BYTE: u8 = 0x6b;
+
+// Return addresses kept for each allocation and free.
+const TRACE_DEPTH: usize = 4;
+
+const MAX_TRACKED_PAGES: usize = 1 << 18;
+const TRACKED_WINDOW_ALIGN: u64 = 1 << 30;
+const PAGE_QUARANTINE: usize = 64;
+// Pages whose traces are remembered, most recently allocated first.
+const PAGE_HISTORY: usize = 256;
+
+// Must be a power of two.
+const MAX_LIVE_CHUNKS: usize = 4096;
+const HEAP_QUARANTINE: usize = 256;
+const CHUNK_HISTORY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trace([u64; TRACE_DEPTH]);
+
+impl Trace {
+    const UNKNOWN: Trace = Trace([0; TRACE_DEPTH]);
+
+    // Allocations are made with all sorts of locks held, the scheduler's
+    // among them, so the stack bounds come from the hart area instead.
+    #[inline(always)]
+    fn capture() -> Self {
+        let (fp, sp): (u64, u64);
+        unsafe {
+            asm!("mv {}, s0", "mv {}, sp", out(reg) fp, out(reg) sp);
+        }
+        let high = per_hart::area().kernel_sp.load(Ordering::Relaxed);
+        let bounds = if high > sp {
+            StackBounds { low: sp, high }
+        } else {
+            hart::stack_bounds(hart_id())
+                .filter(|bounds| bounds.contains(sp))
+                .unwrap_or_else(StackBounds::boot_stack)
+        };
+
+        let mut trace = Self::UNKNOWN;
+        for (slot, address) in trace
+            .0
+            .iter_mut()
+            .zip(Backtrace::from_frame_pointer(fp, bounds))
+        {
+            *slot = address;
+        }
+        trace
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Self::UNKNOWN {
+            return writeln!(f, "    (unknown)");
+        }
+        for &address in self.0.iter().take_while(|&&address| address != 0) {
+            writeln!(f, "    {}", Symbolized(address))?;
+        }
+        Ok(())
+    }
+}
+
+fn poison(start: u64, len: usize) {
+    unsafe { core::ptr::write_bytes(start as *mut u8, POISON_BYTE, len) };
+}
+
+// The address of the first byte that isn't poison.
+fn find_write(start: u64, len: usize) -> Option<u64> {
+    (0..len as u64)
+        .map(|offset| start + offset)
+        .find(|&address| unsafe { (address as *const u8).read_volatile() } != POISON_BYTE)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PageRecord {
+    page: u64,
+    allocated: Trace,
+    freed: Option<Trace>,
+}
+
+const NO_PAGE_RECORD: PageRecord = PageRecord {
+    page: u64::MAX,
+    allocated: Trace::UNKNOWN,
+    freed: None,
+};
+
+fn test_bit(map: &[u64], idx: usize) -> bool {
+    map[idx / 64] & (1 << (idx % 64)) != 0
+}
+
+fn set_bit(map: &mut [u64], idx: usize, value: bool) {
+    if value {
+        map[idx / 64] |= 1 << (idx % 64);
+    } else {
+        map[idx / 64] &= !(1 << (idx % 64));
+    }
+}
+
+// The page allocator's shadow: a bit per page for whether it was ever
+// added and whether it's allocated, for pages in the same 1 GiB window as
+// page poisoning tracks. Pages outside it go unchecked.
+pub struct PageShadow {
+    base: Option<u64>,
+    owned: [u64; MAX_TRACKED_PAGES / 64],
+    allocated: [u64; MAX_TRACKED_PAGES / 64],
+    // Oldest first, from `quarantine_head`.
+    quarantine: [u64; PAGE_QUARANTINE],
+    quarantine_head: usize,
+    quarantined: usize,
+    history: [PageRecord; PAGE_HISTORY],
+    next_record: usize,
+}
+
+impl fmt::Debug for PageShadow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageShadow")
+            .field("base", &self.base)
+            .field("quarantined", &self.quarantined)
+            .finish()
+    }
+}
+
+impl PageShadow {
+    pub const fn new() -> Self {
+        Self {
+            base: None,
+            owned: [0; MAX_TRACKED_PAGES / 64],
+            allocated: [0; MAX_TRACKED_PAGES / 64],
+            quarantine: [0; PAGE_QUARANTINE],
+            quarantine_head: 0,
+            quarantined: 0,
+            history: [NO_PAGE_RECORD; PAGE_HISTORY],
+            next_record: 0,
+        }
+    }
+
+    fn index(&self, page: u64) -> Option<usize> {
+        let base = self.base?;
+        if page < base {
+            return None;
+        }
+        let idx = ((page - base) / PAGE_SIZE) as usize;
+        (idx < MAX_TRACKED_PAGES).then_some(idx)
+    }
+
+    pub fn is_allocated(&self, page: u64) -> Option<bool> {
+        Some(test_bit(&self.allocated, self.index(page)?))
+    }
+
+    pub fn quarantined(&self) -> usize {
+        self.quarantined
+    }
+
+    fn latest(&mut self, page: u64) -> Option<&mut PageRecord> {
+        let idx = (1..=PAGE_HISTORY)
+            .map(|n| (self.next_record + PAGE_HISTORY - n) % PAGE_HISTORY)
+            .find(|&idx| self.history[idx].page == page)?;
+        Some(&mut self.history[idx])
+    }
+
+    fn record(&mut self, record: PageRecord) {
+        self.history[self.next_record] = record;
+        self.next_record = (self.next_record + 1) % PAGE_HISTORY;
+    }
+
+    pub fn on_add(&mut self, page: u64) {
+        if self.base.is_none() {
+            self.base = Some(page & !(TRACKED_WINDOW_ALIGN - 1));
+        }
+        if let Some(idx) = self.index(page) {
+            set_bit(&mut self.owned, idx, true);
+        }
+    }
+
+    pub fn on_alloc(&mut self, page: u64) {
+        if let Some(idx) = self.index(page) {
+            set_bit(&mut self.allocated, idx, true);
+        }
+        self.record(PageRecord {
+            page,
+            allocated: Trace::capture(),
+            freed: None,
+        });
+    }
+
+    // The page is poisoned and quarantined. Returns the page that leaves
+    // quarantine to make room, if any, which the allocator can reuse.
+    pub fn on_dealloc(&mut self, page: u64) -> Option<u64> {
+        let freed = Trace::capture();
+        if page % PAGE_SIZE != 0 {
+            panic!(
+                "kasan: freeing unaligned page address {:#x}\n  freed at:\n{}",
+                page, freed
+            );
+        }
+        let Some(idx) = self.index(page) else {
+            return Some(page);
+        };
+        if !test_bit(&self.owned, idx) {
+            panic!(
+                "kasan: freeing page {:#x}, which the allocator doesn't own\n  freed at:\n{}",
+                page, freed
+            );
+        }
+        if !test_bit(&self.allocated, idx) {
+            let record = self.latest(page).map_or(NO_PAGE_RECORD, |record| *record);
+            panic!(
+                "kasan: double free of page {:#x}\n  allocated at:\n{}  first freed at:\n{}  freed again at:\n{}",
+                page,
+                record.allocated,
+                record.freed.unwrap_or(Trace::UNKNOWN),
+                freed
+            );
+        }
+
+        set_bit(&mut self.allocated, idx, false);
+        match self.latest(page) {
+            Some(record) => record.freed = Some(freed),
+            None => self.record(PageRecord {
+                page,
+                allocated: Trace::UNKNOWN,
+                freed: Some(freed),
+            }),
+        }
+        poison(page, PAGE_SIZE as usize);
+
+        let released = (self.quarantined == PAGE_QUARANTINE)
+            .then(|| self.release())
+            .flatten();
+        let tail = (self.quarantine_head + self.quarantined) % PAGE_QUARANTINE;
+        self.quarantine[tail] = page;
+        self.quarantined += 1;
+        released
+    }
+
+    // Takes the oldest page out of quarantine, checking nothing wrote to it
+    // while it was there.
+    pub fn release(&mut self) -> Option<u64> {
+        if self.quarantined == 0 {
+            return None;
+        }
+        let page = self.quarantine[self.quarantine_head];
+        self.quarantine_head = (self.quarantine_head + 1) % PAGE_QUARANTINE;
+        self.quarantined -= 1;
+
+        if let Some(address) = find_write(page, PAGE_SIZE as usize) {
+            let record = self.latest(page).map_or(NO_PAGE_RECORD, |record| *record);
+            panic!(
+                "kasan: use after free: page {:#x} was written at {:#x} after being freed\n  allocated at:\n{}  freed at:\n{}",
+                page,
+                address,
+                record.allocated,
+                record.freed.unwrap_or(Trace::UNKNOWN)
+            );
+        }
+        Some(page)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    ptr: u64,
+    size: usize,
+    allocated: Trace,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FreedChunk {
+    chunk: Chunk,
+    freed: Trace,
+}
+
+// The heap's shadow: every live chunk in an open addressed table keyed by
+// address, and the traces of the most recently freed ones.
+struct HeapShadow {
+    live: [Option<Chunk>; MAX_LIVE_CHUNKS],
+    live_count: usize,
+    // Set once an allocation didn't fit in the table, after which frees of
+    // unknown chunks can't be told from frees of untracked ones.
+    overflowed: bool,
+    quarantine: [Option<(u64, Layout)>; HEAP_QUARANTINE],
+    quarantine_head: usize,
+    quarantined: usize,
+    freed: [Option<FreedChunk>; CHUNK_HISTORY],
+    next_freed: usize,
+}
+
+static HEAP_SHADOW: Mutex<HeapShadow> = Mutex::new(HeapShadow::new());
+
+fn home_slot(ptr: u64) -> usize {
+    ((ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % MAX_LIVE_CHUNKS
+}
+
+impl HeapShadow {
+    const fn new() -> Self {
+        Self {
+            live: [None; MAX_LIVE_CHUNKS],
+            live_count: 0,
+            overflowed: false,
+            quarantine: [None; HEAP_QUARANTINE],
+            quarantine_head: 0,
+            quarantined: 0,
+            freed: [None; CHUNK_HISTORY],
+            next_freed: 0,
+        }
+    }
+
+    fn find(&self, ptr: u64) -> Option<usize> {
+        let mut slot = home_slot(ptr);
+        while let Some(chunk) = self.live[slot] {
+            if chunk.ptr == ptr {
+                return Some(slot);
+            }
+            slot = (slot + 1) % MAX_LIVE_CHUNKS;
+        }
+        None
+    }
+
+    // Half full at most, so probes stay short.
+    fn insert(&mut self, chunk: Chunk) {
+        if self.live_count >= MAX_LIVE_CHUNKS / 2 {
+            self.overflowed = true;
+            return;
+        }
+        let mut slot = home_slot(chunk.ptr);
+        while self.live[slot].is_some() {
+            slot = (slot + 1) % MAX_LIVE_CHUNKS;
+        }
+        self.live[slot] = Some(chunk);
+        self.live_count += 1;
+    }
+
+    // Shifts later chunks back into the hole, so lookups never need to skip
+    // over deleted slots.
+    fn remove(&mut self, mut hole: usize) -> Chunk {
+        let removed = self.live[hole].take().unwrap();
+        self.live_count -= 1;
+        let mut next = (hole + 1) % MAX_LIVE_CHUNKS;
+        while let Some(chunk) = self.live[next] {
+            let home = home_slot(chunk.ptr);
+            let distance = |slot: usize| (slot + MAX_LIVE_CHUNKS - home) % MAX_LIVE_CHUNKS;
+            if distance(hole) < distance(next) {
+                self.live[hole] = self.live[next].take();
+                hole = next;
+            }
+            next = (next + 1) % MAX_LIVE_CHUNKS;
+        }
+        removed
+    }
+
+    fn last_freed(&self, ptr: u64) -> Option<FreedChunk> {
+        (1..=CHUNK_HISTORY)
+            .map(|n| (self.next_freed + CHUNK_HISTORY - n) % CHUNK_HISTORY)
+            .filter_map(|idx| self.freed[idx])
+            .find(|freed| freed.chunk.ptr == ptr)
+    }
+
+    fn alloc(&mut self, ptr: u64, layout: Layout) {
+        let allocated = Trace::capture();
+        if let Some(slot) = self.find(ptr) {
+            let chunk = self.live[slot].unwrap();
+            panic!(
+                "kasan: the heap handed out {:#x} while it was still allocated\n  first allocated at:\n{}  allocated again at:\n{}",
+                ptr, chunk.allocated, allocated
+            );
+        }
+        self.insert(Chunk {
+            ptr,
+            size: layout.size(),
+            allocated,
+        });
+    }
+
+    // Returns the chunk that leaves quarantine to make room, if any, for
+    // the heap to take back.
+    fn free(&mut self, ptr: u64, layout: Layout, heap: (u64, u64)) -> Option<(u64, Layout)> {
+        let freed = Trace::capture();
+        let Some(slot) = self.find(ptr) else {
+            if !(heap.0..heap.1).contains(&ptr) {
+                panic!(
+                    "kasan: freeing {:#x}, which is outside the heap at {:#x}..{:#x}\n  freed at:\n{}",
+                    ptr, heap.0, heap.1, freed
+                );
+            }
+            if self.overflowed {
+                return Some((ptr, layout));
+            }
+            if let Some(last) = self.last_freed(ptr) {
+                panic!(
+                    "kasan: double free of {:#x}\n  allocated at:\n{}  first freed at:\n{}  freed again at:\n{}",
+                    ptr, last.chunk.allocated, last.freed, freed
+                );
+            }
+            panic!(
+                "kasan: freeing {:#x}, which the heap never handed out\n  freed at:\n{}",
+                ptr, freed
+            );
+        };
+
+        let chunk = self.remove(slot);
+        if chunk.size != layout.size() {
+            panic!(
+                "kasan: {:#x} was allocated with {} bytes but freed with {}\n  allocated at:\n{}  freed at:\n{}",
+                ptr,
+                chunk.size,
+                layout.size(),
+                chunk.allocated,
+                freed
+            );
+        }
+        self.freed[self.next_freed] = Some(FreedChunk { chunk, freed });
+        self.next_freed = (self.next_freed + 1) % CHUNK_HISTORY;
+        poison(ptr, layout.size());
+
+        let released = (self.quarantined == HEAP_QUARANTINE)
+            .then(|| self.release())
+            .flatten();
+        let tail = (self.quarantine_head + self.quarantined) % HEAP_QUARANTINE;
+        self.quarantine[tail] = Some((ptr, layout));
+        self.quarantined += 1;
+        released
+    }
+
+    fn release(&mut self) -> Option<(u64, Layout)> {
+        if self.quarantined == 0 {
+            return None;
+        }
+        let (ptr, layout) = self.quarantine[self.quarantine_head].take()?;
+        self.quarantine_head = (self.quarantine_head + 1) % HEAP_QUARANTINE;
+        self.quarantined -= 1;
+
+        if let Some(address) = find_write(ptr, layout.size()) {
+            let last = self.last_freed(ptr);
+            panic!(
+                "kasan: use after free: {:#x} was written at {:#x} after being freed\n  allocated at:\n{}  freed at:\n{}",
+                ptr,
+                address,
+                last.map_or(Trace::UNKNOWN, |last| last.chunk.allocated),
+                last.map_or(Trace::UNKNOWN, |last| last.freed)
+            );
+        }
+        Some((ptr, layout))
+    }
+}
+
+pub fn heap_alloc(ptr: *mut u8, layout: Layout) {
+    HEAP_SHADOW.lock_irqsave().alloc(ptr as u64, layout);
+}
+
+// `heap` is the range the heap has mapped, for telling stray frees apart.
+pub fn heap_free(ptr: *mut u8, layout: Layout, heap: (u64, u64)) -> Option<(*mut u8, Layout)> {
+    HEAP_SHADOW
+        .lock_irqsave()
+        .free(ptr as u64, layout, heap)
+        .map(|(ptr, layout)| (ptr as *mut u8, layout))
+}
+
+// For when the heap runs out: quarantined chunks are only held back while
+// there's other memory to use.
+pub fn release_heap_chunk() -> Option<(*mut u8, Layout)> {
+    HEAP_SHADOW
+        .lock_irqsave()
+        .release()
+        .map(|(ptr, layout)| (ptr as *mut u8, layout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::page_allocator::test::heap_addresses;
+
+    #[test_case]
+    fn traces_start_at_the_caller() {
+        let trace = Trace::capture();
+        assert_ne!(trace, Trace::UNKNOWN);
+    }
+
+    #[test_case]
+    fn freed_pages_are_quarantined_and_poisoned() {
+        let (start, _) = heap_addresses(1);
+        let page = start.start_address().as_u64();
+        let mut shadow = PageShadow::new();
+        shadow.on_add(page);
+        shadow.on_alloc(page);
+        assert_eq!(shadow.is_allocated(page), Some(true));
+
+        assert_eq!(shadow.on_dealloc(page), None);
+        assert_eq!(shadow.is_allocated(page), Some(false));
+        assert_eq!(shadow.quarantined(), 1);
+        assert_eq!(unsafe { (page as *const u64).read() }, POISON);
+        assert_eq!(shadow.release(), Some(page));
+    }
+
+    #[test_case]
+    fn writes_after_free_are_caught_leaving_quarantine() {
+        let (start, _) = heap_addresses(1);
+        let page = start.start_address().as_u64();
+        let mut shadow = PageShadow::new();
+        shadow.on_add(page);
+        shadow.on_alloc(page);
+        shadow.on_dealloc(page);
+
+        unsafe { ((page + 8) as *mut u64).write(0) };
+        crate::test::should_panic();
+        shadow.release();
+    }
+
+    #[test_case]
+    fn double_page_frees_are_caught() {
+        let (start, _) = heap_addresses(1);
+        let page = start.start_address().as_u64();
+        let mut shadow = PageShadow::new();
+        shadow.on_add(page);
+        shadow.on_alloc(page);
+        shadow.on_dealloc(page);
+
+        crate::test::should_panic();
+        shadow.on_dealloc(page);
+    }
+
+    // Too big for the stack.
+    static TEST_SHADOW: Mutex<HeapShadow> = Mutex::new(HeapShadow::new());
+
+    #[test_case]
+    fn live_chunks_survive_removal_of_their_neighbours() {
+        let mut shadow = TEST_SHADOW.lock_irqsave();
+        *shadow = HeapShadow::new();
+        let mut words = [0u64; 4];
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let chunks: [u64; 4] = core::array::from_fn(|n| &mut words[n] as *mut u64 as u64);
+        for &ptr in &chunks {
+            shadow.alloc(ptr, layout);
+        }
+        shadow.free(chunks[1], layout, (0, u64::MAX));
+
+        assert!(shadow.find(chunks[1]).is_none());
+        assert_eq!(words[1], POISON);
+        for ptr in [chunks[0], chunks[2], chunks[3]] {
+            assert!(shadow.find(ptr).is_some());
+        }
+    }
+
+    #[test_case]
+    fn freeing_with_the_wrong_size_is_caught() {
+        let mut shadow = TEST_SHADOW.lock_irqsave();
+        *shadow = HeapShadow::new();
+        let mut words = [0u64; 2];
+        let ptr = words.as_mut_ptr() as u64;
+        shadow.alloc(ptr, Layout::from_size_align(16, 8).unwrap());
+
+        crate::test::should_panic();
+        shadow.free(ptr, Layout::from_size_align(8, 8).unwrap(), (0, u64::MAX));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::csr;
+use crate::{print, println};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+const BUCKETS: usize = 32;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PENDING_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static ENTRY_CYCLE: AtomicU64 = AtomicU64::new(0);
+
+static ENTRY_LATENCY: Mutex<Histogram> = Mutex::new(Histogram::new());
+static HANDLER_DURATION: Mutex<Histogram> = Mutex::new(Histogram::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct Histogram {
+    count: u64,
+    min: u64,
+    max: u64,
+    total: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            total: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+
+    // Bucket n holds values in [2^(n-1), 2^n), with 0 in bucket 0.
+    fn bucket(value: u64) -> usize {
+        let bits = (u64::BITS - value.leading_zeros()) as usize;
+        bits.min(BUCKETS - 1)
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.total = self.total.saturating_add(value);
+        self.buckets[Self::bucket(value)] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<u64> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+
+    pub fn print(&self, name: &str, unit: &str) {
+        let mean = match self.mean() {
+            None => {
+                println!("{}: no samples", name);
+                return;
+            }
+            Some(mean) => mean,
+        };
+
+        println!(
+            "{}: {} samples, min {} / avg {} / max {} {}",
+            name, self.count, self.min, mean, self.max, unit
+        );
+        for (idx, &count) in self.buckets.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let low = if idx == 0 { 0 } else { 1u64 << (idx - 1) };
+            println!("  >= {:>10} {}: {}", low, unit, count);
+        }
+    }
+}
+
+pub fn read_cycle() -> u64 {
+    csr::cycle::read()
+}
+
+fn read_time() -> u64 {
+    csr::time::read()
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn reset() {
+    *ENTRY_LATENCY.lock() = Histogram::new();
+    *HANDLER_DURATION.lock() = Histogram::new();
+}
+
+// Called when the timer is programmed; the deadline is in `time` ticks.
+pub fn timer_armed(deadline: u64) {
+    PENDING_DEADLINE.store(deadline, Ordering::Relaxed);
+}
+
+pub fn handler_entry() {
+    if !is_enabled() {
+        return;
+    }
+
+    let now = read_time();
+    ENTRY_CYCLE.store(read_cycle(), Ordering::Relaxed);
+
+    let deadline = PENDING_DEADLINE.swap(0, Ordering::Relaxed);
+    if deadline != 0 && now >= deadline {
+        ENTRY_LATENCY.lock().record(now - deadline);
+    }
+}
+
+pub fn handler_exit() {
+    if !is_enabled() {
+        return;
+    }
+
+    let entry = ENTRY_CYCLE.swap(0, Ordering::Relaxed);
+    if entry != 0 {
+        HANDLER_DURATION.lock().record(read_cycle() - entry);
+    }
+}
+
+pub fn report() {
+    println!(
+        "latency measurement is {}",
+        if is_enabled() { "on" } else { "off" }
+    );
+    ENTRY_LATENCY
+        .lock()
+        .print("timer request to handler entry", "ticks");
+    HANDLER_DURATION
+        .lock()
+        .print("handler entry to exit", "cycles");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn empty_histogram_has_no_mean() {
+        assert_eq!(Histogram::new().mean(), None);
+    }
+
+    #[test_case]
+    fn histogram_tracks_min_mean_and_max() {
+        let mut histogram = Histogram::new();
+        for value in [4, 8, 12] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.min, 4);
+        assert_eq!(histogram.mean(), Some(8));
+        assert_eq!(histogram.max, 12);
+    }
+
+    #[test_case]
+    fn values_land_in_power_of_two_buckets() {
+        assert_eq!(Histogram::bucket(0), 0);
+        assert_eq!(Histogram::bucket(1), 1);
+        assert_eq!(Histogram::bucket(3), 2);
+        assert_eq!(Histogram::bucket(4), 3);
+        assert_eq!(Histogram::bucket(u64::MAX), BUCKETS - 1);
+    }
+}
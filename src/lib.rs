@@ -1,51 +1,296 @@
 #![no_std]
 #![cfg_attr(test, no_main)]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
-#![feature(once_cell)]
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use core::cell::OnceCell;
-use spin::Mutex;
+extern crate alloc;
 
+use crate::lock::SpinLockIrq;
+use crate::once::InitCell;
+
+pub mod address;
+pub mod address_space;
 pub mod asm;
+pub mod backtrace;
+pub mod bench;
+pub mod block;
+pub mod boot;
+pub mod boot_alloc;
+pub mod buffer_cache;
+pub mod cmdline;
+pub mod console;
+pub mod cpio;
+pub mod csr;
+pub mod debugger;
+pub mod devfs;
+pub mod devicetree;
+pub mod driver;
+pub mod early_console;
+pub mod elf;
+pub mod error;
+pub mod ext2;
+pub mod fat32;
+pub mod fd_table;
+pub mod fmt;
+pub mod futex;
+pub mod fw_cfg;
+pub mod gdb_stub;
+pub mod hart;
+pub mod heap;
+pub mod initramfs;
+pub mod ipi;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod latency;
+pub mod lock;
+#[cfg(feature = "lock-debug")]
+pub mod lock_debug;
+pub mod log;
+pub mod memory_map;
+pub mod misaligned;
+pub mod monitor;
+pub mod once;
 pub mod page_allocator;
+pub mod page_cache;
+#[cfg(feature = "page-poisoning")]
+pub mod page_poison;
 pub mod page_table;
+pub mod panic;
+pub mod per_hart;
+pub mod pipe;
+pub mod plic;
+pub mod power;
+pub mod prng;
+pub mod process;
+pub mod rand;
+pub mod rusage;
+pub mod satp;
+pub mod sbi;
+pub mod sched;
 pub mod serial;
+pub mod shm;
+pub mod signal;
+pub mod slab;
+pub mod stack_guard;
+pub mod style;
+pub mod symbols;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod timer;
+pub mod tlb;
+pub mod tmpfs;
 pub mod trap;
+pub mod uaccess;
+pub mod user;
+pub mod vfs;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_rng;
+pub mod wait_queue;
+pub mod watchdog;
 
+#[cfg(test)]
+pub mod sandbox;
 #[cfg(test)]
 pub mod test;
 
-use crate::page_allocator::{PageAddr, PageAllocator};
-use crate::page_table::VirtualMemory;
-use core::arch::asm;
+use crate::devicetree::DeviceTree;
+use crate::error::KernelResult;
+use crate::memory_map::{BootLayout, MemoryMap, MemoryRegion, RegionKind};
+use crate::page_allocator::PAGE_ALLOCATOR;
+use crate::page_table::{MappingGranularity, PageTableEntryMode, VirtualMemory};
+use crate::trap::TrapMode;
 
-static VIRTUAL_MEMORY: Mutex<OnceCell<VirtualMemory>> = Mutex::new(OnceCell::new());
+// Pages that must be left over after building the kernel page tables.
+const MIN_FREE_PAGES: u64 = 256;
 
-const PAGE_SIZE: u64 = 4096;
+pub static VIRTUAL_MEMORY: SpinLockIrq<InitCell<VirtualMemory>> = SpinLockIrq::new(InitCell::new());
 
 extern "C" {
+    static MEMORY_START: u64;
     static HEAP_START: u64;
-    static HEAP_END: u64;
-    static TRAP: u64;
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn initialise_kernel() {
-    let page_allocator = PageAllocator::new(
-        PageAddr {
-            address: HEAP_START + PAGE_SIZE - (HEAP_START % PAGE_SIZE),
-        },
-        PageAddr {
-            address: HEAP_END - (HEAP_END % PAGE_SIZE) - 1,
-        },
+unsafe fn init_memory(dtb: u64) -> KernelResult<VirtualMemory> {
+    boot_alloc::init();
+    early_console::keep_transcript();
+    // Parsed from a copy in the boot region if it fits, so the firmware's
+    // pages go to the page allocator like the rest of RAM.
+    let firmware_tree = DeviceTree::from_address(dtb)?;
+    let device_tree = match boot_alloc::copy(firmware_tree.bytes(), 8) {
+        Some(blob) => DeviceTree::from_bytes(blob)?,
+        None => firmware_tree,
+    };
+    let copied = device_tree.address() != firmware_tree.address();
+    devicetree::init(device_tree);
+    early_console::set_config(serial::console_config());
+    serial::choose_backend();
+
+    let mut memory_map = MemoryMap::from_device_tree(&device_tree);
+    if let Some(limit) = cmdline::get_size("mem") {
+        memory_map.truncate(limit);
+    }
+    // Everything below the heap holds the kernel image and boot stack.
+    memory_map.remove(MemoryRegion::new(MEMORY_START, HEAP_START));
+    let initrd = initramfs::initrd_region(&device_tree);
+    if let Some(region) = initrd {
+        memory_map.remove(region);
+    }
+    let mut layout = BootLayout::with_kernel_image();
+    layout.add_reserved(&device_tree);
+
+    let mut page_allocator = PAGE_ALLOCATOR.lock();
+    if let Some(limit) = cmdline::get_size("dma_limit") {
+        page_allocator.set_dma_limit(limit);
+    }
+    // Still mapped, but tests' own allocators have it to themselves.
+    #[cfg(test)]
+    memory_map.remove(page_allocator::test::scratch_region());
+    for region in memory_map.regions() {
+        page_allocator.add_pages(region.pages());
+    }
+    #[cfg(test)]
+    memory_map.add(page_allocator::test::scratch_region());
+    boot_alloc::donate(&mut page_allocator);
+
+    let free_pages = page_allocator.free_pages();
+    let granularity = choose_granularity(&memory_map, free_pages);
+
+    let mut vm = VirtualMemory::new(&mut page_allocator)?;
+    vm.init(&memory_map, granularity, &mut page_allocator)?;
+    let memory = page_allocator.stats().total_pages * page_allocator::PAGE_SIZE;
+    heap::init(&mut vm, memory, &mut page_allocator)?;
+    // A copy is mapped along with the rest of the boot region.
+    if !copied {
+        let region = memory_map::device_tree_region(&device_tree);
+        layout.add("device tree", RegionKind::Reserved, region);
+        for page in region.pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadOnly, &mut page_allocator)?;
+        }
+    }
+    if let Some(region) = initrd {
+        layout.add("initrd", RegionKind::Reserved, region);
+    }
+    for page in initrd.iter().flat_map(MemoryRegion::pages_covering) {
+        vm.identity_map(page, PageTableEntryMode::ReadOnly, &mut page_allocator)?;
+    }
+    let uart = serial::base_address();
+    let log_uart = serial::log_uart_config(&device_tree)
+        .map(|config| config.base)
+        .filter(|&base| base != uart);
+    let gdb_uart = serial::gdb_uart_config(&device_tree).map(|config| config.base);
+    let uarts = [
+        ("uart", Some(uart)),
+        ("log uart", log_uart),
+        ("gdb uart", gdb_uart),
+    ];
+    for (name, uart) in uarts {
+        let Some(uart) = uart else { continue };
+        layout.add_mmio(name, MemoryRegion::new(uart, uart + 1));
+        for page in MemoryRegion::new(uart, uart + 1).pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadWrite, &mut page_allocator)?;
+        }
+    }
+    if let Some(address) = fw_cfg::base_address(&device_tree) {
+        layout.add_mmio("fw_cfg", MemoryRegion::new(address, address + 1));
+        for page in MemoryRegion::new(address, address + 1).pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadWrite, &mut page_allocator)?;
+        }
+    }
+    if let Some(address) = power::test_device_address(&device_tree) {
+        layout.add_mmio("test device", MemoryRegion::new(address, address + 1));
+        for page in MemoryRegion::new(address, address + 1).pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadWrite, &mut page_allocator)?;
+        }
+    }
+    if let Some(region) = plic::mmio_region(&device_tree) {
+        layout.add_mmio("plic", region);
+        for page in region.pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadWrite, &mut page_allocator)?;
+        }
+    }
+    for region in virtio::mmio_regions(&device_tree) {
+        layout.add_mmio("virtio", region);
+        for page in region.pages_covering() {
+            vm.identity_map(page, PageTableEntryMode::ReadWrite, &mut page_allocator)?;
+        }
+    }
+    let stats = page_allocator.stats();
+    layout.total_pages = stats.total_pages;
+    layout.free_pages = stats.free_pages();
+    memory_map::init(memory_map);
+    drop(page_allocator);
+    layout.report();
+    memory_map::set_boot_layout(layout);
+    page_cache::init();
+
+    if memory_map::is_low_memory() || granularity != MappingGranularity::Pages {
+        report_degraded_boot(free_pages, granularity);
+    }
+
+    Ok(vm)
+}
+
+fn choose_granularity(memory_map: &MemoryMap, free_pages: u64) -> MappingGranularity {
+    let page_cost = VirtualMemory::identity_map_cost(memory_map, MappingGranularity::Pages);
+    if free_pages >= page_cost + MIN_FREE_PAGES {
+        return MappingGranularity::Pages;
+    }
+
+    let megapage_cost = VirtualMemory::identity_map_cost(memory_map, MappingGranularity::Megapages);
+    if free_pages < megapage_cost + MIN_FREE_PAGES {
+        panic!(
+            "Not enough memory to boot: {} KiB free, need at least {} KiB",
+            free_pages * page_allocator::PAGE_SIZE / 1024,
+            (megapage_cost + MIN_FREE_PAGES) * page_allocator::PAGE_SIZE / 1024
+        );
+    }
+    MappingGranularity::Megapages
+}
+
+fn report_degraded_boot(free_pages: u64, granularity: MappingGranularity) {
+    warn!(
+        "Low memory: {} KiB free at boot, running in a reduced configuration",
+        free_pages * page_allocator::PAGE_SIZE / 1024
     );
-    let mut vm = VirtualMemory::new(page_allocator).unwrap();
-    vm.init().unwrap();
-    asm!("csrw satp, {}", in(reg) vm.satp());
+    if granularity == MappingGranularity::Megapages {
+        warn!("  - RAM is identity mapped with 2 MiB pages where possible");
+    }
+    if !page_cache::is_enabled() {
+        warn!("  - per-hart page caches are disabled");
+    }
+    if memory_map::is_low_memory() {
+        warn!("  - the kernel heap grows in small steps");
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn initialise_kernel(hartid: u64, dtb: u64) {
+    per_hart::init(hartid as usize);
+    let vm = match init_memory(dtb) {
+        Ok(vm) => vm,
+        Err(e) => panic!("Failed to initialise memory: {}", e),
+    };
+
+    trap::set_mode(if cmdline::has("vectored_traps") {
+        TrapMode::Vectored
+    } else {
+        TrapMode::Direct
+    });
+    address_space::set_kernel_satp(vm.satp());
+    satp::write_satp(vm.satp()).expect("Sv39 isn't supported");
     VIRTUAL_MEMORY.lock().set(vm).unwrap();
-    asm!("csrw stvec, {}", in(reg) TRAP);
+    hart::mark_online(hartid as usize);
+    early_console::hand_off();
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    panic!("Kernel heap allocation failed: {:?}", layout);
 }
 
 #[cfg(test)]
@@ -58,9 +303,22 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
 #[cfg(test)]
 #[no_mangle]
+#[allow(clippy::empty_loop)]
 extern "C" fn kernel_main() -> ! {
     println!("ohhai tester");
 
+    prng::init();
+    style::init();
+    log::init();
+    task::init();
+    timer::init();
+    watchdog::init();
+    driver::init();
+    rand::init();
+    ipi::init();
+    tmpfs::init();
+    devfs::init();
+    user::install_programs().unwrap();
     test_main();
 
     loop {}
@@ -69,34 +327,40 @@ extern "C" fn kernel_main() -> ! {
 #[cfg(test)]
 mod temp_test {
     use super::*;
-    use crate::page_table::PageTableEntryMode;
+    use crate::csr::{self, Sstatus};
 
     #[test_case]
     fn read_virtual_address() {
-        let virtual_address = 0x90000000;
-        VIRTUAL_MEMORY
-            .lock()
-            .get_mut()
-            .map(|vm| {
-                vm.map(
-                    virtual_address.try_into().unwrap(),
-                    PageTableEntryMode::ReadWrite,
-                )
-                .unwrap();
-            })
+        // A user page, so that the mapping goes away with the sandbox. The
+        // kernel can only touch it with SUM set.
+        let mut sandbox = sandbox::Sandbox::new(1);
+        let virtual_address = address_space::USER_START;
+        sandbox
+            .address_space()
+            .map(virtual_address, PageTableEntryMode::ReadWrite)
             .unwrap();
         let ptr = virtual_address as *mut u8;
-        unsafe {
+        let value = trap::without_interrupts(|| unsafe {
+            csr::sstatus::set(Sstatus::SUM);
             ptr.write(1);
-            assert_eq!(ptr.read(), 1);
+            let value = ptr.read();
+            csr::sstatus::clear(Sstatus::SUM);
+            value
+        });
+        assert_eq!(value, 1);
+    }
+
+    #[test_case]
+    fn unmapped_reads_fault() {
+        crate::test::expect_trap(trap::TrapCause::LoadPageFault);
+        unsafe {
+            (u64::MAX as *const u8).read_volatile();
         }
     }
 
     #[test_case]
-    fn enter_trap() {
-        use core::ptr;
-        // unsafe { (ptr::null() as *const u64).read(); }
-        // unsafe { (0 as *const u64).read(); }
-        unsafe { (u64::MAX as *const u8).read(); }
+    fn panics_can_be_expected() {
+        crate::test::should_panic();
+        panic!("expected");
     }
 }
@@ -0,0 +1,364 @@
+#[cfg(feature = "lock-debug")]
+use crate::lock_debug::LockTracker;
+use crate::trap::{InterruptGuard, IrqSave, LockIrqSave};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+// A spin lock for kernel state. With the lock-debug feature it also keeps
+// track of where it was taken, so that a wait long enough to be a deadlock,
+// or two locks taken in both orders, panics naming the code involved.
+pub struct Lock<T> {
+    inner: Mutex<T>,
+    #[cfg(feature = "lock-debug")]
+    tracker: LockTracker,
+}
+
+impl<T> Lock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            #[cfg(feature = "lock-debug")]
+            tracker: LockTracker::new(),
+        }
+    }
+
+    // Told apart from other locks by its address.
+    #[cfg(feature = "lock-debug")]
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> LockGuard<'_, T> {
+        let site = Location::caller();
+        let mut spins = 0;
+        loop {
+            if let Some(guard) = self.try_lock_at(site) {
+                return guard;
+            }
+            self.wait(site, &mut spins);
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<LockGuard<'_, T>> {
+        self.try_lock_at(Location::caller())
+    }
+
+    fn try_lock_at(&self, site: &'static Location<'static>) -> Option<LockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lock-debug")]
+        self.tracker.acquired(self.id(), site);
+        #[cfg(not(feature = "lock-debug"))]
+        let _ = site;
+        Some(LockGuard {
+            guard,
+            #[cfg(feature = "lock-debug")]
+            tracker: Some((&self.tracker, self.id())),
+        })
+    }
+
+    fn wait(&self, site: &'static Location<'static>, spins: &mut u64) {
+        #[cfg(feature = "lock-debug")]
+        self.tracker.waiting(site, spins);
+        #[cfg(not(feature = "lock-debug"))]
+        let _ = (site, spins);
+        core::hint::spin_loop();
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    // For a panic, when the holder is never going to let go.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.tracker.forget();
+        self.inner.force_unlock();
+    }
+}
+
+impl<T> LockIrqSave<T> for Lock<T> {
+    // Interrupts stay as they were while waiting, as for a Mutex.
+    #[track_caller]
+    fn lock_irqsave(&self) -> IrqSave<'_, T> {
+        let site = Location::caller();
+        let mut spins = 0;
+        loop {
+            let interrupts = InterruptGuard::disable();
+            if let Some(guard) = self.try_lock_at(site) {
+                return IrqSave::new(guard, interrupts);
+            }
+            drop(interrupts);
+            self.wait(site, &mut spins);
+        }
+    }
+}
+
+// A Lock that keeps interrupts off on the hart holding it, for state an
+// interrupt handler uses too, so that the handler can't spin on a lock the
+// code it interrupted holds. Interrupts go back to how they were once it's
+// released.
+pub struct SpinLockIrq<T> {
+    lock: Lock<T>,
+}
+
+impl<T> SpinLockIrq<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: Lock::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqSave<'_, T> {
+        self.lock.lock_irqsave()
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSave<'_, T>> {
+        let interrupts = InterruptGuard::disable();
+        let guard = self.lock.try_lock()?;
+        Some(IrqSave::new(guard, interrupts))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock();
+    }
+}
+
+impl<T> LockIrqSave<T> for SpinLockIrq<T> {
+    #[track_caller]
+    fn lock_irqsave(&self) -> IrqSave<'_, T> {
+        self.lock()
+    }
+}
+
+// RwSpinLock's state: the low bits are flags and the rest count readers.
+const WRITER: usize = 1;
+const WRITER_WAITING: usize = 2;
+const READER: usize = 4;
+
+// Any number of readers or one writer, for state that's read far more
+// often than it changes. A waiting writer keeps new readers out, so a
+// steady stream of them can't starve it. As with SpinLockIrq, interrupts
+// are off while it's held.
+pub struct RwSpinLock<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let interrupts = InterruptGuard::disable();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(ReadGuard {
+            lock: self,
+            _interrupts: interrupts,
+        })
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    }
+
+    // Any other writer still waiting sets WRITER_WAITING again.
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let interrupts = InterruptGuard::disable();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WRITER_WAITING != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(WriteGuard {
+            lock: self,
+            _interrupts: interrupts,
+        })
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+pub struct LockGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    // None for a plain Mutex's guard, which has nothing to report to.
+    #[cfg(feature = "lock-debug")]
+    tracker: Option<(&'a LockTracker, usize)>,
+}
+
+impl<'a, T> From<MutexGuard<'a, T>> for LockGuard<'a, T> {
+    fn from(guard: MutexGuard<'a, T>) -> Self {
+        Self {
+            guard,
+            #[cfg(feature = "lock-debug")]
+            tracker: None,
+        }
+    }
+}
+
+impl<T> Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lock-debug")]
+impl<T> Drop for LockGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some((tracker, id)) = self.tracker {
+            tracker.released(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trap;
+
+    #[test_case]
+    fn a_held_lock_cant_be_taken_again() {
+        let lock = Lock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.lock_irqsave(), 2);
+        assert!(!lock.is_locked());
+    }
+
+    #[test_case]
+    fn interrupts_are_off_while_an_irq_lock_is_held() {
+        let lock = SpinLockIrq::new(());
+        let enabled = trap::interrupts_enabled();
+        {
+            let _guard = lock.lock();
+            assert!(!trap::interrupts_enabled());
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(trap::interrupts_enabled(), enabled);
+    }
+
+    #[test_case]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwSpinLock::new(1);
+        {
+            let first = lock.read();
+            let second = lock.read();
+            assert_eq!(*first + *second, 2);
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let mut writer = lock.write();
+            *writer = 3;
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+        }
+        assert_eq!(*lock.read(), 3);
+    }
+
+    #[test_case]
+    fn a_waiting_writer_keeps_new_readers_out() {
+        let lock = RwSpinLock::new(());
+        let reader = lock.read();
+        lock.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        assert!(lock.try_write().is_some());
+        assert!(lock.try_read().is_some());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::hart::{hart_id, MAX_HARTS};
+use crate::per_hart::PerHart;
+use crate::trap::InterruptGuard;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use spin::Mutex;
+
+// Waiting this long for a lock is taken to mean it will never come free.
+const DEADLOCK_SPINS: u64 = 100_000_000;
+const MAX_HELD: usize = 16;
+const MAX_ORDERINGS: usize = 256;
+const NO_OWNER: usize = usize::MAX;
+
+type Site = &'static Location<'static>;
+
+// Set by the first report, so the panic after it can take locks without
+// being reported again.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    id: usize,
+    site: Site,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    locks: [Option<HeldLock>; MAX_HELD],
+}
+
+static HELD: PerHart<Held> = PerHart::new(
+    [Held {
+        locks: [None; MAX_HELD],
+    }; MAX_HARTS],
+);
+
+// One lock taken while another was held, and where each was taken.
+#[derive(Clone, Copy)]
+struct LockOrder {
+    first: HeldLock,
+    second: HeldLock,
+}
+
+// Every ordering seen so far, until the table fills up. Locks are told
+// apart by address, so one in freed memory can be mistaken for whatever
+// later takes its place; only long-lived locks should be tracked.
+static ORDERINGS: Mutex<[Option<LockOrder>; MAX_ORDERINGS]> = Mutex::new([None; MAX_ORDERINGS]);
+
+pub struct LockTracker {
+    owner: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl LockTracker {
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            site: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    // After each failed attempt at the lock from `site`.
+    pub fn waiting(&self, site: Site, spins: &mut u64) {
+        *spins += 1;
+        if *spins != DEADLOCK_SPINS || REPORTED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let owner = self.owner.load(Ordering::Relaxed);
+        let taken_at = self.site.load(Ordering::Relaxed);
+        match unsafe { taken_at.as_ref() } {
+            Some(taken_at) if owner != NO_OWNER => panic!(
+                "Probable deadlock on hart {}: waiting at {} for a lock hart {} took at {}",
+                hart_id(),
+                site,
+                owner,
+                taken_at
+            ),
+            _ => panic!(
+                "Probable deadlock on hart {}: waiting at {} for a lock that isn't tracked",
+                hart_id(),
+                site
+            ),
+        }
+    }
+
+    pub fn acquired(&self, id: usize, site: Site) {
+        self.owner.store(hart_id(), Ordering::Relaxed);
+        self.site
+            .store(site as *const Location as *mut Location, Ordering::Relaxed);
+        let taking = HeldLock { id, site };
+        let held = HELD.with(|held| {
+            let before = held.locks;
+            if let Some(slot) = held.locks.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(taking);
+            }
+            before
+        });
+        for holding in held.iter().flatten().filter(|holding| holding.id != id) {
+            check_order(*holding, taking);
+        }
+    }
+
+    pub fn released(&self, id: usize) {
+        self.forget();
+        HELD.with(|held| {
+            if let Some(slot) = held
+                .locks
+                .iter_mut()
+                .rev()
+                .find(|slot| slot.map_or(false, |lock| lock.id == id))
+            {
+                *slot = None;
+            }
+        });
+    }
+
+    pub fn forget(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.site.store(core::ptr::null_mut(), Ordering::Relaxed);
+    }
+}
+
+// Records that `second` was taken while `first` was held, or panics if
+// they've been taken the other way round before: two harts doing one each
+// at the same time would deadlock.
+fn check_order(first: HeldLock, second: HeldLock) {
+    let inverted = {
+        let _interrupts = InterruptGuard::disable();
+        let mut orderings = ORDERINGS.lock();
+        let seen = |a: usize, b: usize| {
+            orderings
+                .iter()
+                .flatten()
+                .find(|ordering| ordering.first.id == a && ordering.second.id == b)
+                .copied()
+        };
+        let inverted = seen(second.id, first.id);
+        if inverted.is_none() && seen(first.id, second.id).is_none() {
+            if let Some(slot) = orderings.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(LockOrder { first, second });
+            }
+        }
+        inverted
+    };
+    if let Some(earlier) = inverted {
+        if !REPORTED.swap(true, Ordering::Relaxed) {
+            panic!(
+                "Lock order inversion on hart {}: took a lock at {} while holding one taken at {}, \
+                 but earlier the second was taken at {} while holding the first from {}",
+                hart_id(),
+                second.site,
+                first.site,
+                earlier.second.site,
+                earlier.first.site
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lock::Lock;
+
+    #[test_case]
+    fn taking_locks_in_both_orders_panics() {
+        static A: Lock<()> = Lock::new(());
+        static B: Lock<()> = Lock::new(());
+        {
+            let _a = A.lock();
+            let _b = B.lock();
+        }
+        let ids = [&A as *const _ as usize, &B as *const _ as usize];
+        assert!(HELD
+            .get()
+            .locks
+            .iter()
+            .flatten()
+            .all(|lock| !ids.contains(&lock.id)));
+
+        crate::test::should_panic();
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+}
@@ -0,0 +1,365 @@
+use crate::error::{KernelError, KernelResult};
+use crate::hart::hart_id;
+use crate::style::{Color, Style};
+use crate::time::{ticks_to_duration, Instant};
+use crate::trap::LockIrqSave;
+use crate::{cmdline, serial};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+
+const MAX_SINKS: usize = 4;
+// How much recent output dmesg keeps.
+const RING_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|level| level.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Level {
+        Level::ALL[value as usize - 1]
+    }
+
+    pub fn style(self) -> Style {
+        match self {
+            Level::Error => Style::PLAIN.fg(Color::Red).bold(),
+            Level::Warn => Style::PLAIN.fg(Color::Yellow),
+            Level::Info => Style::PLAIN.fg(Color::Green),
+            Level::Debug => Style::PLAIN.fg(Color::Cyan),
+            Level::Trace => Style::PLAIN,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    // Without the crate's name, so "virtio" rather than "riscvos::virtio".
+    pub module: &'a str,
+    pub time: Instant,
+    pub hart: usize,
+    pub args: fmt::Arguments<'a>,
+}
+
+// "[seconds.micros] hart level module: message", without a newline. The
+// alternate form, {:#}, colours the level.
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_boot = ticks_to_duration(self.time.ticks());
+        let style = if f.alternate() {
+            self.level.style()
+        } else {
+            Style::PLAIN
+        };
+        write!(
+            f,
+            "[{:>5}.{:06}] {} {:<5} {}: {}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros(),
+            self.hart,
+            style.paint(self.level),
+            self.module,
+            self.args
+        )
+    }
+}
+
+// Somewhere records go. Sinks run with interrupts off and the sink list
+// locked, so they mustn't log themselves.
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, record: &Record) {
+        serial::print_log(format_args!("{:#}\n", record));
+    }
+}
+
+// The last RING_SIZE bytes of formatted records, oldest first once it has
+// wrapped.
+struct Ring {
+    bytes: [u8; RING_SIZE],
+    end: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn contents(&self) -> impl Iterator<Item = u8> + '_ {
+        let start = (self.end + RING_SIZE - self.len) % RING_SIZE;
+        (0..self.len).map(move |i| self.bytes[(start + i) % RING_SIZE])
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.bytes[self.end] = byte;
+            self.end = (self.end + 1) % RING_SIZE;
+            self.len = (self.len + 1).min(RING_SIZE);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    bytes: [0; RING_SIZE],
+    end: 0,
+    len: 0,
+});
+
+struct RingSink;
+
+impl Sink for RingSink {
+    fn write(&self, record: &Record) {
+        let _ = writeln!(RING.lock_irqsave(), "{}", record);
+    }
+}
+
+// The ring comes first so records are kept however slow the console is.
+static SINKS: Mutex<[Option<&'static dyn Sink>; MAX_SINKS]> =
+    Mutex::new([Some(&RingSink), Some(&SerialSink), None, None]);
+
+pub fn add_sink(sink: &'static dyn Sink) {
+    let mut sinks = SINKS.lock_irqsave();
+    match sinks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(sink),
+        None => panic!("Too many log sinks (max {})", MAX_SINKS),
+    }
+}
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+// Module prefixes with levels of their own. Checked only when there are
+// some, as most of the time there aren't.
+static FILTERS: Mutex<Vec<(String, Level)>> = Mutex::new(Vec::new());
+static HAS_FILTERS: AtomicBool = AtomicBool::new(false);
+
+fn short_module(module: &str) -> &str {
+    module.split_once("::").map_or(module, |(_, rest)| rest)
+}
+
+fn covers(prefix: &str, module: &str) -> bool {
+    module == prefix
+        || module
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with("::"))
+}
+
+pub fn set_level(level: Level) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// `module` is a path within the kernel, such as "virtio" or "fs::fat32",
+// and covers the modules inside it.
+pub fn set_module_level(module: &str, level: Level) {
+    let mut filters = FILTERS.lock_irqsave();
+    filters.retain(|(prefix, _)| prefix != module);
+    filters.push((String::from(module), level));
+    HAS_FILTERS.store(true, Ordering::Relaxed);
+}
+
+pub fn level_for(module: &str) -> Level {
+    let default = Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed));
+    if !HAS_FILTERS.load(Ordering::Relaxed) {
+        return default;
+    }
+    let module = short_module(module);
+    FILTERS
+        .lock_irqsave()
+        .iter()
+        .filter(|(prefix, _)| covers(prefix, module))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(default, |(_, level)| *level)
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level <= level_for(module)
+}
+
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    let record = Record {
+        level,
+        module: short_module(module),
+        time: Instant::now(),
+        hart: hart_id(),
+        args,
+    };
+    for sink in SINKS.lock_irqsave().iter().flatten() {
+        sink.write(&record);
+    }
+}
+
+// The log as far back as the ring goes, starting at a whole line.
+pub fn dmesg() -> String {
+    let ring = RING.lock_irqsave();
+    let mut bytes: Vec<u8> = ring.contents().collect();
+    if ring.len == RING_SIZE {
+        let first_line = bytes.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+        bytes.drain(..first_line);
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// A comma-separated list of levels, each either for everything or for a
+// module, as in "warn,virtio=debug,fat32=trace".
+pub fn configure(spec: &str) -> KernelResult<()> {
+    for directive in spec.split(',').filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((module, level)) => {
+                let level = Level::parse(level).ok_or(KernelError::InvalidArgument)?;
+                set_module_level(module, level);
+            }
+            None => set_level(Level::parse(directive).ok_or(KernelError::InvalidArgument)?),
+        }
+    }
+    Ok(())
+}
+
+// Takes levels from the log= boot argument.
+pub fn init() {
+    if let Some(spec) = cmdline::get("log") {
+        if configure(spec).is_err() {
+            crate::warn!("Ignoring log={} from its first bad directive on", spec);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::log($level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    // Counts the records from this module, whichever test logged them.
+    struct CountingSink(AtomicUsize);
+
+    impl Sink for CountingSink {
+        fn write(&self, record: &Record) {
+            if record.module == "log::test" {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    static COUNTED: CountingSink = CountingSink(AtomicUsize::new(0));
+
+    #[test_case]
+    fn levels_parse_and_order() {
+        assert_eq!(Level::parse("debug"), Some(Level::Debug));
+        assert_eq!(Level::parse("loud"), None);
+        assert!(Level::Error < Level::Trace);
+    }
+
+    #[test_case]
+    fn the_longest_matching_module_filter_wins() {
+        set_module_level("log-test", Level::Error);
+        set_module_level("log-test::inner", Level::Trace);
+        assert_eq!(level_for("riscvos::log-test::inner::deeper"), Level::Trace);
+        assert_eq!(level_for("riscvos::log-test::other"), Level::Error);
+        assert_eq!(
+            level_for("riscvos::log-tests"),
+            level_for("riscvos::elsewhere")
+        );
+        assert!(configure("log-test=loud").is_err());
+    }
+
+    #[test_case]
+    fn records_below_the_level_are_not_written() {
+        add_sink(&COUNTED);
+        set_module_level("log::test", Level::Info);
+        crate::info!("logged");
+        crate::debug!("not logged");
+        assert_eq!(COUNTED.0.load(Ordering::Relaxed), 1);
+
+        set_module_level("log::test", Level::Debug);
+        crate::debug!("logged too");
+        assert_eq!(COUNTED.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test_case]
+    fn dmesg_keeps_the_latest_whole_lines() {
+        crate::warn!("dmesg-test {}", 1);
+        assert!(dmesg().contains("warn  log::test: dmesg-test 1\n"));
+
+        let filler = "x".repeat(99);
+        for _ in 0..RING_SIZE / 100 + 1 {
+            writeln!(RING.lock_irqsave(), "{}", filler).unwrap();
+        }
+        crate::warn!("dmesg-test {}", 2);
+        let log = dmesg();
+        assert!(log.len() < RING_SIZE);
+        assert!(!log.contains("dmesg-test 1"));
+        assert!(log.starts_with(&filler));
+        assert!(log.ends_with("dmesg-test 2\n"));
+    }
+}
@@ -4,30 +4,58 @@
 #![test_runner(test::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-pub mod asm;
-pub mod serial;
-
 #[cfg(test)]
 pub mod test;
+#[cfg(test)]
+extern crate alloc;
 
-use riscvos::initialise_kernel;
+#[cfg(test)]
+use riscvos::{cmdline, csr, power, serial, style, task, time, timer, trap};
+use riscvos::{error, info, print, println};
 
 #[no_mangle]
 extern "C" fn kernel_main() -> ! {
     println!("ohhai");
 
+    riscvos::style::init();
+    riscvos::log::init();
+    riscvos::debugger::init();
+    riscvos::task::init();
+    riscvos::timer::init();
+    riscvos::watchdog::init();
+    riscvos::driver::init();
+    riscvos::gdb_stub::init();
+    riscvos::rand::init();
+    riscvos::ipi::init();
+    let started = riscvos::hart::start_all_harts();
+    if started > 0 {
+        info!("Started {} more harts", started);
+    }
+    riscvos::fw_cfg::init();
+    riscvos::tmpfs::init();
+    riscvos::devfs::init();
+    if let Err(e) = riscvos::user::install_programs() {
+        error!("Failed to install programs: {}", e);
+    }
+    riscvos::initramfs::init();
+    riscvos::block::mount_all();
+    if let Err(e) = riscvos::user::start_init() {
+        error!("Failed to start init: {}", e);
+    }
+    riscvos::monitor::run_boot_script();
+    riscvos::monitor::start_shell();
+
     #[cfg(test)]
     test_main();
 
-    loop {}
+    riscvos::task::exit()
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("Panic:");
-    println!("{}", info);
-    loop {}
+    riscvos::panic::report(info);
+    riscvos::panic::finish()
 }
 
 #[cfg(test)]
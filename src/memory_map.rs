@@ -0,0 +1,510 @@
+use crate::address::{PhysAddr, PhysFrame};
+use crate::devicetree::DeviceTree;
+use crate::once::StaticOnce;
+use crate::page_allocator::{PageRange, HEAP_END, HEAP_START, PAGE_SIZE};
+use crate::{info, warn};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MAX_REGIONS: usize = 32;
+const MAX_LAYOUT_ENTRIES: usize = 48;
+
+extern "C" {
+    static TEXT_START: u64;
+    static TEXT_END: u64;
+    static RODATA_START: u64;
+    static RODATA_END: u64;
+    static DATA_START: u64;
+    static DATA_END: u64;
+    static BSS_START: u64;
+    static BSS_END: u64;
+    static STACK_START: u64;
+    static STACK_END: u64;
+    static BOOT_REGION_START: u64;
+    static BOOT_REGION_END: u64;
+}
+
+// Below this much usable RAM the kernel trades throughput for footprint.
+pub const LOW_MEMORY_THRESHOLD: u64 = 32 << 20;
+
+static MEMORY_MAP: StaticOnce<MemoryMap> = StaticOnce::new();
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+static BOOT_LAYOUT: StaticOnce<BootLayout> = StaticOnce::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MemoryRegion {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn overlaps(&self, other: &MemoryRegion) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    // Grown out to whole pages.
+    pub fn page_aligned(&self) -> MemoryRegion {
+        let start = self.start & !(PAGE_SIZE - 1);
+        let end = (self.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        MemoryRegion::new(start, end)
+    }
+
+    pub fn pages_covering(&self) -> PageRange {
+        self.page_aligned().pages()
+    }
+
+    pub fn pages(&self) -> PageRange {
+        let first = (self.start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = self.end & !(PAGE_SIZE - 1);
+
+        if end <= first {
+            // An empty range: the first page is past the last one.
+            return PageRange::new(PhysFrame::from_number(1), PhysFrame::from_number(0));
+        }
+
+        PageRange::new(
+            PhysFrame::containing_address(PhysAddr::new(first)),
+            PhysFrame::containing_address(PhysAddr::new(end - PAGE_SIZE)),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    count: usize,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self {
+            regions: [MemoryRegion::new(0, 0); MAX_REGIONS],
+            count: 0,
+        }
+    }
+
+    pub fn from_device_tree(tree: &DeviceTree) -> Self {
+        let mut map = Self::new();
+
+        let memory_nodes = tree.nodes().filter(|node| {
+            node.depth == 1
+                && (node.unit_name() == "memory"
+                    || (node.property("device_type").and_then(|p| p.as_str()) == Some("memory")))
+        });
+        for node in memory_nodes {
+            for (address, size) in node.reg().into_iter().flatten() {
+                map.add(MemoryRegion::new(address, address + size));
+            }
+        }
+
+        for region in reserved_regions(tree) {
+            map.remove(region);
+        }
+        map.remove(device_tree_region(tree));
+
+        map
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.count]
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.regions().iter().map(|r| r.size()).sum()
+    }
+
+    pub fn add(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        if self.count == MAX_REGIONS {
+            panic!("Too many memory regions (max {})", MAX_REGIONS);
+        }
+        self.regions[self.count] = region;
+        self.count += 1;
+    }
+
+    // Keeps only the lowest `size` bytes of memory.
+    pub fn truncate(&mut self, size: u64) {
+        let mut sorted = self.regions;
+        sorted[..self.count].sort_unstable_by_key(|region| region.start);
+
+        let mut remaining = size;
+        for region in &sorted[..self.count] {
+            if remaining < region.size() {
+                self.remove(MemoryRegion::new(region.start + remaining, u64::MAX));
+                return;
+            }
+            remaining -= region.size();
+        }
+    }
+
+    pub fn remove(&mut self, hole: MemoryRegion) {
+        if hole.is_empty() {
+            return;
+        }
+
+        let mut idx = 0;
+        while idx < self.count {
+            let region = self.regions[idx];
+
+            if hole.end <= region.start || region.end <= hole.start {
+                idx += 1;
+                continue;
+            }
+
+            let below = MemoryRegion::new(region.start, hole.start.max(region.start));
+            let above = MemoryRegion::new(hole.end.min(region.end), region.end);
+
+            self.regions[idx] = self.regions[self.count - 1];
+            self.count -= 1;
+
+            // The trimmed pieces are clear of the hole, so revisiting them is harmless.
+            self.add(below);
+            self.add(above);
+        }
+    }
+}
+
+// Memory the firmware asked the kernel to keep its hands off, from both the
+// reservation block and /reserved-memory.
+pub fn reserved_regions(tree: &DeviceTree) -> impl Iterator<Item = MemoryRegion> {
+    let entries = tree
+        .reserved_entries()
+        .map(|(address, size)| MemoryRegion::new(address, address + size));
+    let nodes = tree
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|reserved| reserved.children())
+        .flat_map(|child| child.reg().into_iter().flatten())
+        .map(|(address, size)| MemoryRegion::new(address, address + size));
+    entries.chain(nodes)
+}
+
+pub fn device_tree_region(tree: &DeviceTree) -> MemoryRegion {
+    MemoryRegion::new(tree.address(), tree.address() + tree.total_size())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Kernel,
+    Heap,
+    Mmio,
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LayoutEntry {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub region: MemoryRegion,
+}
+
+impl LayoutEntry {
+    // The heap is carved around reservations, so those two may overlap.
+    fn conflicts_with(&self, other: &LayoutEntry) -> bool {
+        let expected = matches!(
+            (self.kind, other.kind),
+            (RegionKind::Heap, RegionKind::Reserved) | (RegionKind::Reserved, RegionKind::Heap)
+        );
+        !expected && self.region.overlaps(&other.region)
+    }
+}
+
+impl fmt::Display for LayoutEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#012x}-{:#012x} {:>8} KiB {:<8} {}",
+            self.region.start,
+            self.region.end,
+            self.region.size() / 1024,
+            match self.kind {
+                RegionKind::Kernel => "kernel",
+                RegionKind::Heap => "heap",
+                RegionKind::Mmio => "mmio",
+                RegionKind::Reserved => "reserved",
+            },
+            self.name
+        )
+    }
+}
+
+// Where everything landed at boot: the kernel image as linked, the MMIO
+// windows mapped for drivers and what the firmware reserved, kept so
+// layout bugs show up at a glance.
+#[derive(Debug, Clone)]
+pub struct BootLayout {
+    entries: [LayoutEntry; MAX_LAYOUT_ENTRIES],
+    count: usize,
+    pub total_pages: u64,
+    pub free_pages: u64,
+}
+
+impl Default for BootLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootLayout {
+    pub fn new() -> Self {
+        Self {
+            entries: [LayoutEntry {
+                name: "",
+                kind: RegionKind::Reserved,
+                region: MemoryRegion::new(0, 0),
+            }; MAX_LAYOUT_ENTRIES],
+            count: 0,
+            total_pages: 0,
+            free_pages: 0,
+        }
+    }
+
+    pub fn with_kernel_image() -> Self {
+        let mut layout = Self::new();
+        unsafe {
+            layout.add(
+                "text",
+                RegionKind::Kernel,
+                MemoryRegion::new(TEXT_START, TEXT_END),
+            );
+            layout.add(
+                "rodata",
+                RegionKind::Kernel,
+                MemoryRegion::new(RODATA_START, RODATA_END),
+            );
+            layout.add(
+                "data",
+                RegionKind::Kernel,
+                MemoryRegion::new(DATA_START, DATA_END),
+            );
+            layout.add(
+                "bss",
+                RegionKind::Kernel,
+                MemoryRegion::new(BSS_START, BSS_END),
+            );
+            layout.add(
+                "boot stack",
+                RegionKind::Kernel,
+                MemoryRegion::new(STACK_START, STACK_END),
+            );
+            layout.add(
+                "boot allocator",
+                RegionKind::Kernel,
+                MemoryRegion::new(BOOT_REGION_START, BOOT_REGION_END),
+            );
+            layout.add(
+                "heap",
+                RegionKind::Heap,
+                MemoryRegion::new(HEAP_START, HEAP_END),
+            );
+        }
+        layout
+    }
+
+    // It's only a report, so anything past the last slot goes unrecorded.
+    pub fn add(&mut self, name: &'static str, kind: RegionKind, region: MemoryRegion) {
+        if region.is_empty() || self.count == MAX_LAYOUT_ENTRIES {
+            return;
+        }
+        self.entries[self.count] = LayoutEntry { name, kind, region };
+        self.count += 1;
+    }
+
+    // Drivers get whole pages mapped, so that's what's recorded.
+    pub fn add_mmio(&mut self, name: &'static str, region: MemoryRegion) {
+        self.add(name, RegionKind::Mmio, region.page_aligned());
+    }
+
+    pub fn add_reserved(&mut self, tree: &DeviceTree) {
+        for region in reserved_regions(tree) {
+            self.add("firmware", RegionKind::Reserved, region);
+        }
+    }
+
+    // In address order.
+    pub fn entries(&self) -> impl Iterator<Item = &LayoutEntry> {
+        let mut order = [0; MAX_LAYOUT_ENTRIES];
+        for (i, slot) in order.iter_mut().enumerate() {
+            *slot = i;
+        }
+        order[..self.count].sort_unstable_by_key(|&i| self.entries[i].region.start);
+        (0..self.count).map(move |i| &self.entries[order[i]])
+    }
+
+    pub fn overlaps(&self) -> impl Iterator<Item = (&LayoutEntry, &LayoutEntry)> {
+        let entries = &self.entries[..self.count];
+        entries.iter().enumerate().flat_map(move |(i, a)| {
+            entries[i + 1..]
+                .iter()
+                .filter(move |b| a.conflicts_with(b))
+                .map(move |b| (a, b))
+        })
+    }
+
+    pub fn report(&self) {
+        info!("Memory layout:");
+        for entry in self.entries() {
+            info!("  {}", entry);
+        }
+        info!(
+            "  {} pages, {} free ({} KiB)",
+            self.total_pages,
+            self.free_pages,
+            self.free_pages * PAGE_SIZE / 1024
+        );
+        for (a, b) in self.overlaps() {
+            warn!("{} overlaps {}", a, b);
+        }
+    }
+}
+
+pub fn set_boot_layout(layout: BootLayout) {
+    let _ = BOOT_LAYOUT.set(layout);
+}
+
+pub fn boot_layout() -> Option<&'static BootLayout> {
+    BOOT_LAYOUT.get()
+}
+
+pub fn init(map: MemoryMap) {
+    LOW_MEMORY.store(map.total_size() < LOW_MEMORY_THRESHOLD, Ordering::Relaxed);
+    let _ = MEMORY_MAP.set(map);
+}
+
+pub fn is_low_memory() -> bool {
+    LOW_MEMORY.load(Ordering::Relaxed)
+}
+
+pub fn get() -> Option<MemoryMap> {
+    MEMORY_MAP.get().cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn removing_a_hole_splits_a_region() {
+        let mut map = MemoryMap::new();
+        map.add(MemoryRegion::new(0x1000, 0x5000));
+        map.remove(MemoryRegion::new(0x2000, 0x3000));
+        assert_eq!(map.regions().len(), 2);
+        assert_eq!(map.total_size(), 0x3000);
+    }
+
+    #[test_case]
+    fn removing_an_overlapping_hole_trims_a_region() {
+        let mut map = MemoryMap::new();
+        map.add(MemoryRegion::new(0x1000, 0x5000));
+        map.remove(MemoryRegion::new(0x4000, 0x9000));
+        assert_eq!(map.regions(), &[MemoryRegion::new(0x1000, 0x4000)]);
+    }
+
+    #[test_case]
+    fn removing_a_covering_hole_drops_a_region() {
+        let mut map = MemoryMap::new();
+        map.add(MemoryRegion::new(0x1000, 0x2000));
+        map.add(MemoryRegion::new(0x8000, 0x9000));
+        map.remove(MemoryRegion::new(0x0, 0x3000));
+        assert_eq!(map.regions(), &[MemoryRegion::new(0x8000, 0x9000)]);
+    }
+
+    #[test_case]
+    fn truncating_keeps_the_lowest_memory() {
+        let mut map = MemoryMap::new();
+        map.add(MemoryRegion::new(0x8000, 0x9000));
+        map.add(MemoryRegion::new(0x1000, 0x3000));
+        map.truncate(0x2800);
+        assert_eq!(map.total_size(), 0x2800);
+        assert!(map.regions().contains(&MemoryRegion::new(0x1000, 0x3000)));
+        assert!(map.regions().contains(&MemoryRegion::new(0x8000, 0x8800)));
+
+        map.truncate(0x10000);
+        assert_eq!(map.total_size(), 0x2800);
+    }
+
+    #[test_case]
+    fn pages_of_an_unaligned_region_are_whole_pages() {
+        let region = MemoryRegion::new(0x1001, 0x4fff);
+        let mut pages = region.pages();
+        assert_eq!(pages.next().unwrap().start_address().as_u64(), 0x2000);
+        assert_eq!(pages.next().unwrap().start_address().as_u64(), 0x3000);
+        assert!(pages.next().is_none());
+    }
+
+    #[test_case]
+    fn pages_of_a_sub_page_region_is_empty() {
+        let region = MemoryRegion::new(0x1001, 0x1fff);
+        assert!(region.pages().next().is_none());
+    }
+
+    #[test_case]
+    fn layout_entries_come_out_in_address_order() {
+        let mut layout = BootLayout::new();
+        layout.add("b", RegionKind::Kernel, MemoryRegion::new(0x3000, 0x4000));
+        layout.add("a", RegionKind::Kernel, MemoryRegion::new(0x1000, 0x2000));
+        layout.add_mmio("c", MemoryRegion::new(0x2000, 0x2001));
+        let names: [&str; 3] = core::array::from_fn(|i| layout.entries().nth(i).unwrap().name);
+        assert_eq!(names, ["a", "c", "b"]);
+        assert_eq!(
+            layout.entries().nth(1).unwrap().region,
+            MemoryRegion::new(0x2000, 0x3000)
+        );
+    }
+
+    #[test_case]
+    fn layout_overlaps_skip_reservations_inside_the_heap() {
+        let mut layout = BootLayout::new();
+        layout.add("heap", RegionKind::Heap, MemoryRegion::new(0x1000, 0x9000));
+        layout.add(
+            "initrd",
+            RegionKind::Reserved,
+            MemoryRegion::new(0x2000, 0x3000),
+        );
+        assert_eq!(layout.overlaps().count(), 0);
+
+        layout.add_mmio("uart", MemoryRegion::new(0x8000, 0x8001));
+        let (a, b) = layout.overlaps().next().unwrap();
+        assert_eq!((a.name, b.name), ("heap", "uart"));
+        assert_eq!(layout.overlaps().count(), 1);
+    }
+
+    #[test_case]
+    fn the_boot_layout_has_no_overlaps() {
+        let layout = boot_layout().unwrap();
+        assert!(layout.entries().any(|entry| entry.name == "text"));
+        assert!(layout.free_pages > 0 && layout.free_pages <= layout.total_pages);
+        assert_eq!(layout.overlaps().count(), 0);
+    }
+
+    #[test_case]
+    fn boot_memory_map_excludes_the_device_tree() {
+        let tree = crate::devicetree::get().unwrap();
+        let map = MemoryMap::from_device_tree(&tree);
+        assert!(map.total_size() > 0);
+        assert!(map
+            .regions()
+            .iter()
+            .all(|r| tree.address() >= r.end || tree.address() < r.start));
+    }
+}
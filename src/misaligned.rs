@@ -0,0 +1,287 @@
+use crate::trap::TrapFrame;
+
+const OPCODE_LOAD: u32 = 0x03;
+const OPCODE_STORE: u32 = 0x23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Load { rd: usize, signed: bool },
+    Store { rs2: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Instruction {
+    access: Access,
+    width: u64,
+    base: usize,
+    offset: i64,
+    length: u64,
+}
+
+fn bits(instruction: u32, high: u32, low: u32) -> u32 {
+    (instruction >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+fn sign_extend(value: u64, width_bits: u32) -> i64 {
+    let shift = 64 - width_bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn decode_standard(instruction: u32) -> Option<Instruction> {
+    let funct3 = bits(instruction, 14, 12);
+    let base = bits(instruction, 19, 15) as usize;
+    let (access, width, offset) = match instruction & 0x7f {
+        OPCODE_LOAD => {
+            let (width, signed) = match funct3 {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 => (8, true),
+                4 => (1, false),
+                5 => (2, false),
+                6 => (4, false),
+                _ => return None,
+            };
+            let rd = bits(instruction, 11, 7) as usize;
+            let offset = (instruction as i32 >> 20) as i64;
+            (Access::Load { rd, signed }, width, offset)
+        }
+        OPCODE_STORE => {
+            let width = match funct3 {
+                0..=3 => 1 << funct3,
+                _ => return None,
+            };
+            let rs2 = bits(instruction, 24, 20) as usize;
+            let offset = bits(instruction, 31, 25) << 5 | bits(instruction, 11, 7);
+            (Access::Store { rs2 }, width, sign_extend(offset as u64, 12))
+        }
+        _ => return None,
+    };
+
+    Some(Instruction {
+        access,
+        width,
+        base,
+        offset,
+        length: 4,
+    })
+}
+
+// Only the integer loads and stores; compressed float accesses aren't emulated.
+fn decode_compressed(instruction: u32) -> Option<Instruction> {
+    let funct3 = bits(instruction, 15, 13);
+    // Registers x8 to x15, as encoded in three bits.
+    let low_register = |low| bits(instruction, low + 2, low) as usize + 8;
+
+    let (access, width, base, offset) = match (instruction & 0b11, funct3) {
+        // c.lw and c.ld
+        (0b00, 0b010) => {
+            let offset = bits(instruction, 12, 10) << 3
+                | bits(instruction, 6, 6) << 2
+                | bits(instruction, 5, 5) << 6;
+            let rd = low_register(2);
+            (
+                Access::Load { rd, signed: true },
+                4,
+                low_register(7),
+                offset,
+            )
+        }
+        (0b00, 0b011) => {
+            let offset = bits(instruction, 12, 10) << 3 | bits(instruction, 6, 5) << 6;
+            let rd = low_register(2);
+            (
+                Access::Load { rd, signed: true },
+                8,
+                low_register(7),
+                offset,
+            )
+        }
+        // c.sw and c.sd
+        (0b00, 0b110) => {
+            let offset = bits(instruction, 12, 10) << 3
+                | bits(instruction, 6, 6) << 2
+                | bits(instruction, 5, 5) << 6;
+            let rs2 = low_register(2);
+            (Access::Store { rs2 }, 4, low_register(7), offset)
+        }
+        (0b00, 0b111) => {
+            let offset = bits(instruction, 12, 10) << 3 | bits(instruction, 6, 5) << 6;
+            let rs2 = low_register(2);
+            (Access::Store { rs2 }, 8, low_register(7), offset)
+        }
+        // c.lwsp and c.ldsp
+        (0b10, 0b010) => {
+            let offset = bits(instruction, 12, 12) << 5
+                | bits(instruction, 6, 4) << 2
+                | bits(instruction, 3, 2) << 6;
+            let rd = bits(instruction, 11, 7) as usize;
+            (Access::Load { rd, signed: true }, 4, 2, offset)
+        }
+        (0b10, 0b011) => {
+            let offset = bits(instruction, 12, 12) << 5
+                | bits(instruction, 6, 5) << 3
+                | bits(instruction, 4, 2) << 6;
+            let rd = bits(instruction, 11, 7) as usize;
+            (Access::Load { rd, signed: true }, 8, 2, offset)
+        }
+        // c.swsp and c.sdsp
+        (0b10, 0b110) => {
+            let offset = bits(instruction, 12, 9) << 2 | bits(instruction, 8, 7) << 6;
+            let rs2 = bits(instruction, 6, 2) as usize;
+            (Access::Store { rs2 }, 4, 2, offset)
+        }
+        (0b10, 0b111) => {
+            let offset = bits(instruction, 12, 10) << 3 | bits(instruction, 9, 7) << 6;
+            let rs2 = bits(instruction, 6, 2) as usize;
+            (Access::Store { rs2 }, 8, 2, offset)
+        }
+        _ => return None,
+    };
+
+    Some(Instruction {
+        access,
+        width,
+        base,
+        offset: offset as i64,
+        length: 2,
+    })
+}
+
+// sepc is only 2-byte aligned, so the instruction is fetched in halves.
+fn fetch(pc: u64) -> u32 {
+    let low = unsafe { (pc as *const u16).read_volatile() } as u32;
+    if low & 0b11 != 0b11 {
+        return low;
+    }
+    let high = unsafe { ((pc + 2) as *const u16).read_volatile() } as u32;
+    high << 16 | low
+}
+
+fn decode(instruction: u32) -> Option<Instruction> {
+    match instruction & 0b11 {
+        0b11 => decode_standard(instruction),
+        _ => decode_compressed(instruction),
+    }
+}
+
+// Redoes a misaligned load or store a byte at a time, for harts that trap
+// on misaligned accesses rather than performing them.
+pub fn handle_misaligned(frame: &mut TrapFrame) -> bool {
+    let instruction = match decode(fetch(frame.sepc)) {
+        Some(instruction) => instruction,
+        None => return false,
+    };
+    let address = frame
+        .reg(instruction.base)
+        .wrapping_add(instruction.offset as u64);
+
+    match instruction.access {
+        Access::Load { rd, signed } => {
+            let mut value = 0;
+            for n in 0..instruction.width {
+                let byte = unsafe { ((address + n) as *const u8).read_volatile() };
+                value |= (byte as u64) << (8 * n);
+            }
+            if signed {
+                value = sign_extend(value, 8 * instruction.width as u32) as u64;
+            }
+            frame.set_reg(rd, value);
+        }
+        Access::Store { rs2 } => {
+            let value = frame.reg(rs2);
+            for n in 0..instruction.width {
+                unsafe {
+                    ((address + n) as *mut u8).write_volatile((value >> (8 * n)) as u8);
+                }
+            }
+        }
+    }
+
+    frame.sepc += instruction.length;
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame_at(instruction: &u32) -> TrapFrame {
+        let mut frame = TrapFrame::zeroed();
+        frame.sepc = instruction as *const u32 as u64;
+        frame
+    }
+
+    #[test_case]
+    fn standard_loads_and_stores_are_decoded() {
+        // lw a0, 0(a1)
+        assert_eq!(
+            decode(0x0005_a503),
+            Some(Instruction {
+                access: Access::Load {
+                    rd: 10,
+                    signed: true
+                },
+                width: 4,
+                base: 11,
+                offset: 0,
+                length: 4,
+            })
+        );
+        // sd a0, -8(a1)
+        let sd = decode(0xfea5_bc23).unwrap();
+        assert_eq!(sd.access, Access::Store { rs2: 10 });
+        assert_eq!((sd.width, sd.offset), (8, -8));
+    }
+
+    #[test_case]
+    fn compressed_loads_and_stores_are_decoded() {
+        // c.lw a0, 4(a1)
+        let lw = decode(0x41c8).unwrap();
+        assert_eq!(
+            lw.access,
+            Access::Load {
+                rd: 10,
+                signed: true
+            }
+        );
+        assert_eq!((lw.width, lw.base, lw.offset, lw.length), (4, 11, 4, 2));
+
+        // c.sdsp a0, 16(sp)
+        let sdsp = decode(0xe82a).unwrap();
+        assert_eq!(sdsp.access, Access::Store { rs2: 10 });
+        assert_eq!((sdsp.width, sdsp.base, sdsp.offset), (8, 2, 16));
+    }
+
+    #[test_case]
+    fn other_instructions_are_not_decoded() {
+        // addi a0, a0, 1
+        assert_eq!(decode(0x0015_0513), None);
+    }
+
+    #[test_case]
+    fn a_misaligned_load_is_emulated_and_sign_extended() {
+        let bytes: [u8; 8] = [0, 0xfe, 0xff, 0xff, 0xff, 0, 0, 0];
+        // lw a0, 0(a1)
+        let instruction: u32 = 0x0005_a503;
+        let mut frame = frame_at(&instruction);
+        frame.set_reg(11, bytes.as_ptr() as u64 + 1);
+
+        assert!(handle_misaligned(&mut frame));
+        assert_eq!(frame.reg(10), -2i64 as u64);
+        assert_eq!(frame.sepc, &instruction as *const u32 as u64 + 4);
+    }
+
+    #[test_case]
+    fn a_misaligned_store_is_emulated() {
+        let mut bytes = [0u8; 16];
+        // sd a0, -8(a1)
+        let instruction: u32 = 0xfea5_bc23;
+        let mut frame = frame_at(&instruction);
+        frame.set_reg(10, 0x0807_0605_0403_0201);
+        frame.set_reg(11, bytes.as_mut_ptr() as u64 + 11);
+
+        assert!(handle_misaligned(&mut frame));
+        assert_eq!(bytes[3..11], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,486 @@
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::page_table::{PageTableEntry, PAGE_TABLES};
+use crate::process::PROCESS_STRUCTS;
+use crate::trap::TRAP_FRAMES;
+use crate::{
+    boot_alloc, buffer_cache, cmdline, devicetree, driver, fmt, heap, latency, log, memory_map,
+    power, print, println, process, sched, serial, style, task, trap, VIRTUAL_MEMORY,
+};
+
+const DEFAULT_DUMP_BYTES: u64 = 64;
+const MAX_DUMP_BYTES: u64 = 4096;
+
+// Why a command didn't do what was asked. It has already said so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown,
+    Usage,
+    Failed,
+}
+
+type CommandResult = Result<(), CommandError>;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&str) -> CommandResult,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        help: "hexdump kernel memory <addr> [len]",
+        run: mem,
+    },
+    Command {
+        name: "free",
+        help: "page allocator and heap statistics",
+        run: free,
+    },
+    Command {
+        name: "pt",
+        help: "the kernel's page table mappings",
+        run: pt,
+    },
+    Command {
+        name: "maps",
+        help: "usable physical memory regions",
+        run: maps,
+    },
+    Command {
+        name: "layout",
+        help: "the memory layout recorded at boot",
+        run: layout,
+    },
+    Command {
+        name: "selftest",
+        help: "quick sanity checks of core subsystems",
+        run: selftest,
+    },
+    Command {
+        name: "latency",
+        help: "interrupt latency [on|off|reset]",
+        run: latency,
+    },
+    Command {
+        name: "devices",
+        help: "devices bound to a driver",
+        run: devices,
+    },
+    Command {
+        name: "ps",
+        help: "processes and threads and their states",
+        run: ps,
+    },
+    Command {
+        name: "sched",
+        help: "context switches, migrations, idle time and load by hart",
+        run: sched_stats,
+    },
+    Command {
+        name: "trap",
+        help: "trap counts by cause and recent traps",
+        run: traps,
+    },
+    Command {
+        name: "color",
+        help: "console colours [on|off]",
+        run: color,
+    },
+    Command {
+        name: "dmesg",
+        help: "recent kernel log messages",
+        run: dmesg,
+    },
+    Command {
+        name: "halt",
+        help: "power off the machine",
+        run: halt,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: reboot,
+    },
+];
+
+fn help(_args: &str) -> CommandResult {
+    for command in COMMANDS {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+    Ok(())
+}
+
+// Only bytes the kernel's page tables map readable are shown, so a bad
+// address is refused rather than faulting.
+fn mem(args: &str) -> CommandResult {
+    let mut args = args.split([' ', ',']).filter(|a| !a.is_empty());
+    let Some(address) = args.next().and_then(cmdline::parse_u64) else {
+        println!("usage: mem <addr> [len]");
+        return Err(CommandError::Usage);
+    };
+    let len = args
+        .next()
+        .and_then(cmdline::parse_u64)
+        .unwrap_or(DEFAULT_DUMP_BYTES)
+        .min(MAX_DUMP_BYTES);
+    let end = address.saturating_add(len);
+
+    let readable = {
+        let vm = VIRTUAL_MEMORY.lock();
+        let Some(vm) = vm.get() else {
+            println!("No kernel page tables");
+            return Err(CommandError::Failed);
+        };
+        (address & !(PAGE_SIZE - 1)..end)
+            .step_by(PAGE_SIZE as usize)
+            .all(|page| {
+                page.try_into()
+                    .ok()
+                    .and_then(|page| vm.leaf(page))
+                    .is_some_and(|pte| pte.is_readable())
+            })
+    };
+    if !readable {
+        println!("{:#x}..{:#x} isn't all mapped readable", address, end);
+        return Err(CommandError::Failed);
+    }
+
+    unsafe { fmt::hexdump(address, (end - address) as usize) };
+    Ok(())
+}
+
+fn free(_args: &str) -> CommandResult {
+    let stats = PAGE_ALLOCATOR.lock().stats();
+    println!(
+        "pages: {} total, {} allocated, {} free, {} peak, {} failed allocations",
+        stats.total_pages,
+        stats.allocated_pages,
+        stats.free_pages(),
+        stats.peak_allocated_pages,
+        stats.failed_allocations
+    );
+
+    let heap = heap::stats();
+    println!(
+        "heap: {} bytes mapped, {} bytes in use",
+        heap.size, heap.used
+    );
+
+    #[cfg(feature = "page-poisoning")]
+    println!(
+        "poisoning: {} pages untracked",
+        crate::page_poison::TRACKER.lock().untracked_pages()
+    );
+
+    let boot = boot_alloc::stats();
+    println!(
+        "boot: {} bytes used, {} pages donated",
+        boot.used, boot.donated_pages
+    );
+
+    for slab in [
+        PAGE_TABLES.lock().stats(),
+        TRAP_FRAMES.lock().stats(),
+        PROCESS_STRUCTS.lock().stats(),
+    ] {
+        println!(
+            "{}: {} of {} in use ({} peak) across {} pages",
+            slab.name,
+            slab.allocated,
+            slab.capacity(),
+            slab.peak_allocated,
+            slab.slabs
+        );
+    }
+    Ok(())
+}
+
+fn maps(_args: &str) -> CommandResult {
+    let Some(map) = memory_map::get() else {
+        println!("No memory map");
+        return Err(CommandError::Failed);
+    };
+    for region in map.regions() {
+        println!(
+            "  {:#012x}-{:#012x} {:>8} KiB",
+            region.start,
+            region.end,
+            region.size() / 1024
+        );
+    }
+    Ok(())
+}
+
+fn layout(_args: &str) -> CommandResult {
+    let Some(layout) = memory_map::boot_layout() else {
+        println!("No boot layout");
+        return Err(CommandError::Failed);
+    };
+    for entry in layout.entries() {
+        println!("  {}", entry);
+    }
+    println!("  {} pages, {} free", layout.total_pages, layout.free_pages);
+    for (a, b) in layout.overlaps() {
+        println!("  overlap: {} and {}", a.name, b.name);
+    }
+    Ok(())
+}
+
+fn selftest(_args: &str) -> CommandResult {
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("  {:<24} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    check("device tree", devicetree::get().is_some());
+
+    let page_round_trip = {
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        let before = allocator.free_pages();
+        match allocator.alloc() {
+            Err(_) => false,
+            Ok(page) => {
+                let ptr: *mut u8 = page.as_mut_ptr();
+                unsafe {
+                    ptr.write_volatile(0xa5);
+                    ptr.add(PAGE_SIZE as usize - 1).write_volatile(0x5a);
+                }
+                let readable = unsafe {
+                    ptr.read_volatile() == 0xa5
+                        && ptr.add(PAGE_SIZE as usize - 1).read_volatile() == 0x5a
+                };
+                allocator.dealloc(page);
+                readable && allocator.free_pages() == before
+            }
+        }
+    };
+    check("page allocation", page_round_trip);
+
+    println!("selftest: {} failure(s)", failures);
+    match failures {
+        0 => Ok(()),
+        _ => Err(CommandError::Failed),
+    }
+}
+
+// Accessed and dirty are left out so they don't break up ranges.
+fn pte_flags(pte: &PageTableEntry) -> [u8; 5] {
+    fmt::pte_flags(pte)[..5].try_into().unwrap()
+}
+
+// Neighbouring mappings that continue each other with the same flags are
+// shown as one range.
+fn pt(_args: &str) -> CommandResult {
+    let vm = VIRTUAL_MEMORY.lock();
+    let Some(vm) = vm.get() else {
+        println!("No kernel page tables");
+        return Err(CommandError::Failed);
+    };
+
+    let print_range = |(start, end, phys, flags): (u64, u64, u64, [u8; 5])| {
+        println!(
+            "  {:#018x}-{:#018x} -> {:#012x} {}",
+            start,
+            end,
+            phys,
+            core::str::from_utf8(&flags).unwrap()
+        );
+    };
+    let mut range: Option<(u64, u64, u64, [u8; 5])> = None;
+    vm.for_each_leaf(|virt, size, pte| {
+        let phys = pte.physical_page() << 12;
+        let flags = pte_flags(&pte);
+        match range.as_mut() {
+            Some((start, end, range_phys, range_flags))
+                if *end == virt
+                    && *range_phys + (*end - *start) == phys
+                    && *range_flags == flags =>
+            {
+                *end += size;
+            }
+            _ => {
+                if let Some(previous) = range.replace((virt, virt + size, phys, flags)) {
+                    print_range(previous);
+                }
+            }
+        }
+    });
+    if let Some(last) = range {
+        print_range(last);
+    }
+    Ok(())
+}
+
+fn latency(args: &str) -> CommandResult {
+    match args {
+        "on" => latency::set_enabled(true),
+        "off" => latency::set_enabled(false),
+        "reset" => latency::reset(),
+        "" => latency::report(),
+        _ => {
+            println!("usage: latency [on|off|reset]");
+            return Err(CommandError::Usage);
+        }
+    }
+    Ok(())
+}
+
+fn devices(_args: &str) -> CommandResult {
+    driver::report();
+    Ok(())
+}
+
+fn ps(_args: &str) -> CommandResult {
+    process::report();
+    task::report();
+    Ok(())
+}
+
+fn sched_stats(_args: &str) -> CommandResult {
+    sched::stats();
+    Ok(())
+}
+
+fn traps(_args: &str) -> CommandResult {
+    trap::report();
+    Ok(())
+}
+
+fn color(args: &str) -> CommandResult {
+    match args {
+        "on" => style::set_enabled(true),
+        "off" => style::set_enabled(false),
+        "" => println!("colours {}", if style::is_enabled() { "on" } else { "off" }),
+        _ => {
+            println!("usage: color [on|off]");
+            return Err(CommandError::Usage);
+        }
+    }
+    Ok(())
+}
+
+fn dmesg(_args: &str) -> CommandResult {
+    print!("{}", log::dmesg());
+    Ok(())
+}
+
+fn sync_disks() {
+    if let Err(e) = buffer_cache::sync_all() {
+        println!("Failed to write back disk caches: {}", e);
+    }
+}
+
+fn halt(_args: &str) -> CommandResult {
+    sync_disks();
+    power::shutdown();
+}
+
+fn reboot(_args: &str) -> CommandResult {
+    sync_disks();
+    power::reboot();
+}
+
+// Arguments may be separated by commas, since boot arguments can't contain spaces.
+pub fn execute(line: &str) -> CommandResult {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let (name, args) = line.split_once([' ', ',']).unwrap_or((line, ""));
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim()),
+        None => {
+            println!("Unknown command: {} (try help)", name);
+            Err(CommandError::Unknown)
+        }
+    }
+}
+
+// Commands are separated by semicolons or newlines. The script stops at the
+// first one that fails.
+pub fn run_script(script: &str) -> CommandResult {
+    for line in script.split([';', '\n']).map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        println!("monitor> {}", line);
+        execute(line)?;
+    }
+    Ok(())
+}
+
+pub fn run_boot_script() {
+    if let Some(script) = cmdline::get("monitor") {
+        // The failing command has already said why.
+        let _ = run_script(script);
+    }
+}
+
+fn shell(mut read_line: impl FnMut(&mut [u8]) -> usize) -> ! {
+    let mut buffer = [0; 128];
+    loop {
+        print!("monitor> ");
+        let len = read_line(&mut buffer);
+        let _ = execute(core::str::from_utf8(&buffer[..len]).unwrap_or(""));
+    }
+}
+
+// With the shell boot argument, a kernel thread takes commands from the
+// console. It competes with user programs for input, so it's off by default.
+pub fn start_shell() {
+    if !cmdline::has("shell") {
+        return;
+    }
+    if let Err(e) = task::spawn_named("monitor", || shell(serial::read_line)) {
+        println!("Failed to start the monitor shell: {:?}", e);
+    }
+}
+
+// For after a panic, with interrupts off and the scheduler not to be
+// trusted.
+pub fn panic_shell() -> ! {
+    println!("Entering the monitor, try help");
+    shell(serial::poll_line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn unknown_commands_are_refused() {
+        assert_eq!(execute("frobnicate"), Err(CommandError::Unknown));
+        assert_eq!(execute("   "), Ok(()));
+    }
+
+    #[test_case]
+    fn bad_arguments_are_a_usage_error() {
+        assert_eq!(execute("mem"), Err(CommandError::Usage));
+        assert_eq!(execute("mem nowhere"), Err(CommandError::Usage));
+        assert_eq!(execute("latency,sideways"), Err(CommandError::Usage));
+        assert_eq!(execute("color sideways"), Err(CommandError::Usage));
+    }
+
+    #[test_case]
+    fn a_script_stops_at_the_first_failing_command() {
+        let enabled = style::is_enabled();
+        let result = run_script("color off\ncolor sideways; color on");
+        let after = style::is_enabled();
+        style::set_enabled(enabled);
+
+        assert_eq!(result, Err(CommandError::Usage));
+        assert!(!after);
+        assert_eq!(run_script("color on;\n;color off"), Ok(()));
+        style::set_enabled(enabled);
+    }
+}
@@ -0,0 +1,149 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const EMPTY: u8 = 0;
+const INITIALISING: u8 = 1;
+const READY: u8 = 2;
+
+// Set once, usually during boot, and read without locking from then on.
+pub struct StaticOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for StaticOnce<T> {}
+unsafe impl<T: Send + Sync> Sync for StaticOnce<T> {}
+
+impl<T> Default for StaticOnce<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> StaticOnce<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_initialised(&self) -> bool {
+        self.get().is_some()
+    }
+
+    // Hands `value` back if the cell was already set, or is being set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(EMPTY, INITIALISING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(())
+    }
+
+    // Only one caller runs `init`; any others spin until it's done, so it
+    // mustn't be called from an interrupt handler that may have interrupted
+    // the initialising thread.
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(EMPTY, INITIALISING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(init()) };
+                self.state.store(READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != READY {
+                    spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Drop for StaticOnce<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+// A value that's filled in once but needs mutable access afterwards, so
+// lives behind a lock that already serialises the initialisation.
+pub struct InitCell<T> {
+    value: Option<T>,
+}
+
+impl<T> Default for InitCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> InitCell<T> {
+    pub const fn new() -> Self {
+        Self { value: None }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.value.as_mut()
+    }
+
+    pub fn set(&mut self, value: T) -> Result<(), T> {
+        if self.value.is_some() {
+            return Err(value);
+        }
+        self.value = Some(value);
+        Ok(())
+    }
+
+    pub fn get_or_init(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        self.value.get_or_insert_with(init)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn static_once_is_set_once() {
+        let once = StaticOnce::new();
+        assert!(once.get().is_none());
+        assert_eq!(once.set(1), Ok(()));
+        assert_eq!(once.set(2), Err(2));
+        assert_eq!(*once.get_or_init(|| 3), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test_case]
+    fn init_cell_keeps_the_first_value() {
+        let mut cell = InitCell::new();
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+        assert_eq!(cell.set(2), Err(2));
+        *cell.get_mut().unwrap() += 1;
+        assert_eq!(cell.get(), Some(&2));
+    }
+}
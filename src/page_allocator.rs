@@ -1,5 +1,9 @@
-use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::address::{PhysAddr, PhysFrame};
+#[cfg(feature = "kasan")]
+use crate::kasan::PageShadow;
+use crate::lock::SpinLockIrq;
+#[cfg(feature = "page-poisoning")]
+use crate::page_poison::TRACKER;
 
 pub const PAGE_SIZE: u64 = 4096;
 
@@ -8,33 +12,22 @@ extern "C" {
     pub static HEAP_END: u64;
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct PageAddr {
-    pub address: u64,
-}
-
-impl PageAddr {
-    pub fn as_mut_ptr(self) -> *mut u8 {
-        return self.address as *mut u8;
-    }
-}
-
 pub struct PageRange {
     next_page: u64,
     last_page: u64,
 }
 
 impl PageRange {
-    pub fn new(start: PageAddr, end: PageAddr) -> Self {
+    pub fn new(start: PhysFrame, end: PhysFrame) -> Self {
         Self {
-            next_page: start.address,
-            last_page: end.address,
+            next_page: start.start_address().as_u64(),
+            last_page: end.start_address().as_u64(),
         }
     }
 }
 
 impl Iterator for PageRange {
-    type Item = PageAddr;
+    type Item = PhysFrame;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_page > self.last_page {
@@ -44,7 +37,7 @@ impl Iterator for PageRange {
         let address = self.next_page;
         self.next_page += PAGE_SIZE;
 
-        Some(PageAddr { address })
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
     }
 }
 
@@ -57,97 +50,293 @@ pub enum PageAllocationError {
     NoPagesAvailable,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Dma32 = 0,
+    Normal = 1,
+}
+
+const ZONE_COUNT: usize = 2;
+
+pub const DEFAULT_DMA32_LIMIT: u64 = 1 << 32;
+
+const MAX_LOW_MEMORY_HANDLERS: usize = 8;
+
+pub type LowMemoryHandler = fn(&mut PageAllocator);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageAllocatorStats {
+    pub total_pages: u64,
+    pub allocated_pages: u64,
+    pub peak_allocated_pages: u64,
+    pub failed_allocations: u64,
+}
+
+impl PageAllocatorStats {
+    pub fn free_pages(&self) -> u64 {
+        self.total_pages - self.allocated_pages
+    }
+}
+
 #[derive(Debug)]
 pub struct PageAllocator {
-    free_list: Option<*mut FreePageNode>,
+    free_lists: [Option<*mut FreePageNode>; ZONE_COUNT],
+    zone_free_pages: [u64; ZONE_COUNT],
+    dma_limit: u64,
+    stats: PageAllocatorStats,
+    low_watermark: u64,
+    low_memory_handlers: [Option<LowMemoryHandler>; MAX_LOW_MEMORY_HANDLERS],
+    reclaiming: bool,
+    #[cfg(feature = "kasan")]
+    shadow: PageShadow,
 }
 
 impl PageAllocator {
-    pub unsafe fn new(heap_start: PageAddr, heap_end: PageAddr) -> Self {
-        let mut result = Self { free_list: None };
-
-        for page in PageRange::new(heap_start, heap_end) {
-            result.dealloc(page);
+    pub const fn empty() -> Self {
+        Self {
+            free_lists: [None; ZONE_COUNT],
+            zone_free_pages: [0; ZONE_COUNT],
+            dma_limit: DEFAULT_DMA32_LIMIT,
+            stats: PageAllocatorStats {
+                total_pages: 0,
+                allocated_pages: 0,
+                peak_allocated_pages: 0,
+                failed_allocations: 0,
+            },
+            low_watermark: 0,
+            low_memory_handlers: [None; MAX_LOW_MEMORY_HANDLERS],
+            reclaiming: false,
+            #[cfg(feature = "kasan")]
+            shadow: PageShadow::new(),
         }
+    }
 
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn new(heap_start: PhysFrame, heap_end: PhysFrame) -> Self {
+        let mut result = Self::empty();
+        result.add_pages(PageRange::new(heap_start, heap_end));
         result
     }
 
-    pub fn alloc(&mut self) -> Result<PageAddr, PageAllocationError> {
-        match self.free_list {
-            None => return Err(PageAllocationError::NoPagesAvailable),
-            Some(page_ptr) => {
-                let page_address = PageAddr {
-                    address: page_ptr as u64,
-                };
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn add_pages(&mut self, pages: PageRange) {
+        for page in pages {
+            #[cfg(feature = "page-poisoning")]
+            TRACKER.lock().on_add(page.start_address().as_u64());
+            #[cfg(feature = "kasan")]
+            self.shadow.on_add(page.start_address().as_u64());
+
+            self.push_free(page);
+            self.stats.total_pages += 1;
+        }
+    }
+
+    pub fn set_dma_limit(&mut self, limit: u64) {
+        if self.stats.total_pages != 0 {
+            panic!("The DMA zone limit must be set before pages are added");
+        }
+        self.dma_limit = limit;
+    }
+
+    pub fn zone_of(&self, page: &PhysFrame) -> Zone {
+        if page.start_address().as_u64() < self.dma_limit {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    pub fn free_pages_in_zone(&self, zone: Zone) -> u64 {
+        self.zone_free_pages[zone as usize]
+    }
+
+    pub fn set_low_watermark(&mut self, pages: u64) {
+        self.low_watermark = pages;
+    }
+
+    pub fn register_low_memory_handler(&mut self, handler: LowMemoryHandler) {
+        match self.low_memory_handlers.iter_mut().find(|h| h.is_none()) {
+            Some(slot) => *slot = Some(handler),
+            None => panic!(
+                "Too many low memory handlers (max {})",
+                MAX_LOW_MEMORY_HANDLERS
+            ),
+        }
+    }
+
+    fn reclaim(&mut self) {
+        if self.reclaiming {
+            return;
+        }
+
+        self.reclaiming = true;
+        for handler in self.low_memory_handlers.into_iter().flatten() {
+            handler(self);
+        }
+        self.reclaiming = false;
+    }
 
-                unsafe {
-                    self.free_list = (*page_ptr).next;
-                }
+    pub fn alloc(&mut self) -> Result<PhysFrame, PageAllocationError> {
+        self.alloc_in_zone(Zone::Normal)
+    }
 
-                unsafe {
-                    core::ptr::write_bytes(page_ptr as *mut u8, 0, PAGE_SIZE as usize);
-                }
+    // Allocations may fall back to lower zones, but never take from higher ones.
+    pub fn alloc_in_zone(&mut self, zone: Zone) -> Result<PhysFrame, PageAllocationError> {
+        if self.free_pages_up_to(zone) <= self.low_watermark {
+            self.reclaim();
+        }
 
-                Ok(page_address)
+        let zone = match self.nonempty_zone(zone) {
+            None => {
+                self.stats.failed_allocations += 1;
+                return Err(PageAllocationError::NoPagesAvailable);
             }
+            Some(zone) => zone,
+        };
+
+        let page_ptr = self.free_lists[zone].unwrap();
+        let page = PhysFrame::containing_address(PhysAddr::new(page_ptr as u64));
+
+        unsafe {
+            self.free_lists[zone] = (*page_ptr).next;
+        }
+
+        #[cfg(feature = "page-poisoning")]
+        {
+            let checked = TRACKER.lock().on_alloc(
+                page.start_address().as_u64(),
+                self.free_lists[zone].map(|p| p as u64),
+            );
+            checked.unwrap_or_else(|e| panic!("{}", e));
+        }
+        #[cfg(feature = "kasan")]
+        self.shadow.on_alloc(page.start_address().as_u64());
+
+        self.zone_free_pages[zone] -= 1;
+        self.stats.allocated_pages += 1;
+        if self.stats.allocated_pages > self.stats.peak_allocated_pages {
+            self.stats.peak_allocated_pages = self.stats.allocated_pages;
+        }
+
+        Ok(page)
+    }
+
+    // Free pages in the zones an allocation from `zone` can be served from.
+    fn free_pages_up_to(&self, zone: Zone) -> u64 {
+        self.zone_free_pages[Zone::Dma32 as usize..=zone as usize]
+            .iter()
+            .sum()
+    }
+
+    // The highest zone at or below `zone` with a free page. Quarantined
+    // pages are only held back while there are others to hand out.
+    fn nonempty_zone(&mut self, zone: Zone) -> Option<usize> {
+        let zones = Zone::Dma32 as usize..=zone as usize;
+
+        #[cfg(feature = "kasan")]
+        while !zones.clone().any(|z| self.free_lists[z].is_some()) {
+            let page = self.shadow.release()?;
+            self.push_free(PhysFrame::containing_address(PhysAddr::new(page)));
+        }
+
+        zones.rev().find(|&z| self.free_lists[z].is_some())
+    }
+
+    pub fn alloc_zeroed(&mut self) -> Result<PhysFrame, PageAllocationError> {
+        let page = self.alloc()?;
+
+        unsafe {
+            core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+        }
+
+        Ok(page)
+    }
+
+    pub fn dealloc(&mut self, page: PhysFrame) {
+        #[cfg(feature = "page-poisoning")]
+        {
+            let checked = TRACKER.lock().on_dealloc(page.start_address().as_u64());
+            checked.unwrap_or_else(|e| panic!("{}", e));
         }
+
+        self.stats.allocated_pages = match self.stats.allocated_pages.checked_sub(1) {
+            Some(allocated) => allocated,
+            None => panic!(
+                "Double free of page {:#x}: no pages are allocated",
+                page.start_address().as_u64()
+            ),
+        };
+
+        #[cfg(feature = "kasan")]
+        let Some(page) = self
+            .shadow
+            .on_dealloc(page.start_address().as_u64())
+            .map(|page| PhysFrame::containing_address(PhysAddr::new(page)))
+        else {
+            return;
+        };
+
+        self.push_free(page);
     }
 
-    pub fn dealloc(&mut self, page: PageAddr) {
+    fn push_free(&mut self, page: PhysFrame) {
+        let zone = self.zone_of(&page) as usize;
         let next_node = FreePageNode {
-            next: self.free_list,
+            next: self.free_lists[zone],
         };
 
-        let page_ptr = page.as_mut_ptr() as *mut FreePageNode;
+        let page_ptr: *mut FreePageNode = page.as_mut_ptr();
 
         unsafe {
             *page_ptr = next_node;
         }
 
-        self.free_list = Some(page_ptr)
+        self.free_lists[zone] = Some(page_ptr);
+        self.zone_free_pages[zone] += 1;
     }
 
     pub fn free_pages(&self) -> u64 {
-        let mut count = 0;
-        let mut node = self.free_list;
-        while let Some(page_ptr) = node {
-            node = unsafe { (*page_ptr).next };
-            count += 1;
-        }
-        count
+        self.stats.free_pages()
+    }
+
+    pub fn stats(&self) -> PageAllocatorStats {
+        self.stats
     }
 }
 
 unsafe impl Send for PageAllocator {}
 
-lazy_static! {
-    pub static ref PAGE_ALLOCATOR: Mutex<PageAllocator> = {
-        Mutex::new(unsafe {
-            PageAllocator::new(
-                PageAddr {
-                    address: HEAP_START + PAGE_SIZE - (HEAP_START % PAGE_SIZE),
-                },
-                PageAddr {
-                    address: HEAP_END - 2 * PAGE_SIZE,
-                },
-            )
-        })
-    };
-}
+pub static PAGE_ALLOCATOR: SpinLockIrq<PageAllocator> = SpinLockIrq::new(PageAllocator::empty());
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::bench;
+    use crate::lock::Lock;
+    use crate::memory_map::MemoryRegion;
+    use crate::prng;
+
+    // Test kernels keep this many pages at the start of the heap back from
+    // PAGE_ALLOCATOR, for tests to build allocators of their own from.
+    pub const SCRATCH_PAGES: u64 = 256;
+
+    pub fn scratch_region() -> MemoryRegion {
+        let (start, _) = heap_addresses(1);
+        let start = start.start_address().as_u64();
+        MemoryRegion::new(start, start + SCRATCH_PAGES * PAGE_SIZE)
+    }
 
-    pub fn heap_addresses(size: u64) -> (PageAddr, PageAddr) {
+    pub fn heap_addresses(size: u64) -> (PhysFrame, PhysFrame) {
+        assert!(
+            size <= SCRATCH_PAGES,
+            "Only {} scratch pages",
+            SCRATCH_PAGES
+        );
         let heap_start_address = unsafe { HEAP_START + PAGE_SIZE - (HEAP_START % PAGE_SIZE) };
-        let heap_start = PageAddr {
-            address: heap_start_address,
-        };
-        let heap_end = PageAddr {
-            address: heap_start_address + (size - 1) * PAGE_SIZE,
-        };
+        let heap_start = PhysFrame::containing_address(PhysAddr::new(heap_start_address));
+        let heap_end = PhysFrame::containing_address(PhysAddr::new(
+            heap_start_address + (size - 1) * PAGE_SIZE,
+        ));
         (heap_start, heap_end)
     }
 
@@ -163,36 +352,37 @@ pub mod test {
 
         let allocator = unsafe { PageAllocator::new(heap_start, heap_end) };
 
-        assert!(allocator.free_list.is_some());
+        assert!(allocator.free_lists[Zone::Dma32 as usize].is_some());
     }
 
     #[test_case]
     fn allocating_one_page_succeeds() {
         let (heap_start, heap_end) = heap_addresses(1);
-        let expected = heap_start.address;
+        let expected = heap_start.clone();
 
         let mut allocator = unsafe { PageAllocator::new(heap_start, heap_end) };
 
         let page = allocator.alloc();
         assert!(page.is_ok());
-        assert_eq!(page.unwrap().address, expected);
+        assert_eq!(page.unwrap(), expected);
     }
 
     #[test_case]
+    #[allow(clippy::identity_op, clippy::erasing_op)]
     fn allocating_two_pages_succeeds() {
         let (heap_start, heap_end) = heap_addresses(2);
-        let first_expected = heap_start.address + 1 * PAGE_SIZE;
-        let second_expected = heap_start.address + 0 * PAGE_SIZE;
+        let first_expected = heap_start.start_address().as_u64() + 1 * PAGE_SIZE;
+        let second_expected = heap_start.start_address().as_u64() + 0 * PAGE_SIZE;
 
         let mut allocator = unsafe { PageAllocator::new(heap_start, heap_end) };
 
         let page_one = allocator.alloc();
         assert!(page_one.is_ok());
-        assert_eq!(page_one.unwrap().address, first_expected);
+        assert_eq!(page_one.unwrap().start_address().as_u64(), first_expected);
 
         let page_two = allocator.alloc();
         assert!(page_two.is_ok());
-        assert_eq!(page_two.unwrap().address, second_expected);
+        assert_eq!(page_two.unwrap().start_address().as_u64(), second_expected);
     }
 
     #[test_case]
@@ -207,6 +397,20 @@ pub mod test {
         assert!(page_two.is_err());
     }
 
+    #[test_case]
+    fn allocating_a_zeroed_page_clears_old_contents() {
+        let mut allocator = test_page_allocator(1);
+
+        let page = allocator.alloc().unwrap();
+        unsafe { core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0xaa, PAGE_SIZE as usize) };
+        allocator.dealloc(page);
+
+        let page = allocator.alloc_zeroed().unwrap();
+        let bytes =
+            unsafe { core::slice::from_raw_parts(page.as_mut_ptr::<u8>(), PAGE_SIZE as usize) };
+        assert!(bytes.iter().all(|&b| b == 0));
+    }
+
     #[test_case]
     fn deallocating_a_page_succeeds() {
         let (heap_start, heap_end) = heap_addresses(1);
@@ -217,6 +421,8 @@ pub mod test {
         allocator.dealloc(page.unwrap());
     }
 
+    // Freed pages sit in quarantine instead of the free list.
+    #[cfg(not(feature = "kasan"))]
     #[test_case]
     fn deallocating_a_page_keeps_other_page_allocated() {
         let (heap_start, heap_end) = heap_addresses(2);
@@ -228,8 +434,9 @@ pub mod test {
 
         allocator.dealloc(page_one.unwrap());
 
-        assert!(allocator.free_list.is_some());
-        assert!(unsafe { (*allocator.free_list.unwrap()).next.is_none() });
+        let free_list = allocator.free_lists[Zone::Dma32 as usize];
+        assert!(free_list.is_some());
+        assert!(unsafe { (*free_list.unwrap()).next.is_none() });
     }
 
     #[test_case]
@@ -254,6 +461,18 @@ pub mod test {
         assert_eq!(allocator.free_pages(), 5);
     }
 
+    #[test_case]
+    fn adding_pages_to_an_empty_allocator_makes_them_free() {
+        let (heap_start, heap_end) = heap_addresses(4);
+
+        let mut allocator = PageAllocator::empty();
+        assert_eq!(allocator.free_pages(), 0);
+
+        unsafe { allocator.add_pages(PageRange::new(heap_start, heap_end)) };
+
+        assert_eq!(allocator.free_pages(), 4);
+    }
+
     #[test_case]
     fn allocating_a_page_reduces_number_of_pages() {
         let (heap_start, heap_end) = heap_addresses(5);
@@ -276,4 +495,178 @@ pub mod test {
 
         assert_eq!(allocator.free_pages(), 0);
     }
+
+    #[test_case]
+    fn stats_track_peak_usage_after_freeing() {
+        let mut allocator = test_page_allocator(4);
+
+        let page_one = allocator.alloc().unwrap();
+        let page_two = allocator.alloc().unwrap();
+        allocator.dealloc(page_one);
+        allocator.dealloc(page_two);
+        let _ = allocator.alloc();
+
+        let stats = allocator.stats();
+        assert_eq!(stats.total_pages, 4);
+        assert_eq!(stats.allocated_pages, 1);
+        assert_eq!(stats.peak_allocated_pages, 2);
+    }
+
+    #[test_case]
+    fn failed_allocations_are_counted() {
+        let mut allocator = test_page_allocator(1);
+
+        let _ = allocator.alloc();
+        let _ = allocator.alloc();
+        let _ = allocator.alloc();
+
+        assert_eq!(allocator.stats().failed_allocations, 2);
+    }
+
+    fn split_zone_allocator() -> (PageAllocator, u64) {
+        let (heap_start, heap_end) = heap_addresses(4);
+        let limit = heap_start.start_address().as_u64() + 2 * PAGE_SIZE;
+
+        let mut allocator = PageAllocator::empty();
+        allocator.set_dma_limit(limit);
+        unsafe { allocator.add_pages(PageRange::new(heap_start, heap_end)) };
+
+        (allocator, limit)
+    }
+
+    #[test_case]
+    fn pages_are_split_between_zones_at_the_dma_limit() {
+        let (allocator, _) = split_zone_allocator();
+
+        assert_eq!(allocator.free_pages_in_zone(Zone::Dma32), 2);
+        assert_eq!(allocator.free_pages_in_zone(Zone::Normal), 2);
+    }
+
+    #[test_case]
+    fn dma_allocations_come_from_below_the_limit() {
+        let (mut allocator, limit) = split_zone_allocator();
+
+        for _ in 0..2 {
+            assert!(
+                allocator
+                    .alloc_in_zone(Zone::Dma32)
+                    .unwrap()
+                    .start_address()
+                    .as_u64()
+                    < limit
+            );
+        }
+        assert!(allocator.alloc_in_zone(Zone::Dma32).is_err());
+    }
+
+    #[test_case]
+    fn normal_allocations_prefer_the_normal_zone_then_fall_back() {
+        let (mut allocator, limit) = split_zone_allocator();
+
+        for _ in 0..2 {
+            assert!(allocator.alloc().unwrap().start_address().as_u64() >= limit);
+        }
+        assert!(allocator.alloc().unwrap().start_address().as_u64() < limit);
+        assert_eq!(allocator.free_pages_in_zone(Zone::Dma32), 1);
+    }
+
+    static RECLAIMED_PAGE: Lock<Option<PhysFrame>> = Lock::new(None);
+
+    fn give_back_reclaimed_page(allocator: &mut PageAllocator) {
+        if let Some(page) = RECLAIMED_PAGE.lock().take() {
+            allocator.dealloc(page);
+        }
+    }
+
+    #[test_case]
+    fn low_memory_handlers_run_before_allocation_fails() {
+        let mut allocator = test_page_allocator(1);
+        allocator.register_low_memory_handler(give_back_reclaimed_page);
+
+        *RECLAIMED_PAGE.lock() = Some(allocator.alloc().unwrap());
+
+        assert!(allocator.alloc().is_ok());
+        assert_eq!(allocator.stats().failed_allocations, 0);
+    }
+
+    #[test_case]
+    fn exhausting_the_dma_zone_reclaims_while_normal_pages_are_free() {
+        let (mut allocator, limit) = split_zone_allocator();
+        allocator.register_low_memory_handler(give_back_reclaimed_page);
+
+        allocator.alloc_in_zone(Zone::Dma32).unwrap();
+        *RECLAIMED_PAGE.lock() = Some(allocator.alloc_in_zone(Zone::Dma32).unwrap());
+
+        let page = allocator.alloc_in_zone(Zone::Dma32).unwrap();
+        assert!(page.start_address().as_u64() < limit);
+        assert!(RECLAIMED_PAGE.lock().is_none());
+        assert_eq!(allocator.free_pages_in_zone(Zone::Normal), 2);
+        assert_eq!(allocator.stats().failed_allocations, 0);
+    }
+
+    #[cfg(feature = "page-poisoning")]
+    #[test_case]
+    fn freed_pages_are_poisoned() {
+        use crate::page_poison::POISON;
+
+        let mut allocator = test_page_allocator(2);
+
+        let page = allocator.alloc().unwrap();
+        let address = page.start_address().as_u64();
+        allocator.dealloc(page);
+
+        let last_word = unsafe { ((address + PAGE_SIZE - 8) as *const u64).read() };
+        assert_eq!(last_word, POISON);
+    }
+
+    #[cfg(feature = "page-poisoning")]
+    #[test_case]
+    fn tracker_follows_page_state() {
+        let mut allocator = test_page_allocator(2);
+
+        let page = allocator.alloc().unwrap();
+        let address = page.start_address().as_u64();
+        assert_eq!(TRACKER.lock().is_free(address), Some(false));
+
+        allocator.dealloc(page);
+        assert_eq!(TRACKER.lock().is_free(address), Some(true));
+    }
+
+    #[test_case]
+    fn freeing_more_pages_than_were_allocated_panics() {
+        let mut allocator = test_page_allocator(1);
+        let page = allocator.alloc().unwrap();
+        allocator.dealloc(page.clone());
+
+        crate::test::should_panic();
+        allocator.dealloc(page);
+    }
+
+    #[test_case]
+    fn random_allocs_and_frees_keep_counts_consistent() {
+        const NO_PAGE: Option<PhysFrame> = None;
+        let mut allocator = test_page_allocator(16);
+        let mut held = [NO_PAGE; 16];
+
+        for _ in 0..1000 {
+            let slot = prng::next_below(16) as usize;
+            match held[slot].take() {
+                Some(page) => allocator.dealloc(page),
+                None => held[slot] = Some(allocator.alloc().unwrap()),
+            }
+
+            let in_use = held.iter().filter(|page| page.is_some()).count() as u64;
+            assert_eq!(allocator.stats().allocated_pages, in_use);
+            assert_eq!(allocator.free_pages(), 16 - in_use);
+        }
+    }
+
+    #[test_case]
+    fn bench_alloc_and_dealloc() {
+        let mut allocator = test_page_allocator(16);
+        bench::run("allocate and free a page", 10_000, || {
+            let page = allocator.alloc().unwrap();
+            allocator.dealloc(page);
+        });
+    }
 }
@@ -0,0 +1,168 @@
+use crate::address::PhysFrame;
+use crate::hart::{hart_id, MAX_HARTS};
+use crate::memory_map;
+use crate::page_allocator::{PageAllocationError, PageAllocator, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::trap::LockIrqSave;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const BATCH_SIZE: usize = 16;
+const CACHE_CAPACITY: usize = 2 * BATCH_SIZE;
+
+pub struct PageCache {
+    // Frame numbers.
+    pages: [u64; CACHE_CAPACITY],
+    count: usize,
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: [0; CACHE_CAPACITY],
+            count: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(PhysFrame::from_number(self.pages[self.count]))
+    }
+
+    fn push(&mut self, page: PhysFrame) {
+        self.pages[self.count] = page.number();
+        self.count += 1;
+    }
+
+    fn refill(&mut self, allocator: &mut PageAllocator) -> Result<(), PageAllocationError> {
+        while self.count < BATCH_SIZE {
+            match allocator.alloc() {
+                Ok(page) => self.push(page),
+                Err(e) if self.count == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, allocator: &mut PageAllocator, keep: usize) {
+        while self.count > keep {
+            allocator.dealloc(self.pop().unwrap());
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new());
+
+static PAGE_CACHES: [Mutex<PageCache>; MAX_HARTS] = [EMPTY_CACHE; MAX_HARTS];
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn this_hart_cache() -> &'static Mutex<PageCache> {
+    &PAGE_CACHES[hart_id()]
+}
+
+pub fn alloc_page() -> Result<PhysFrame, PageAllocationError> {
+    if !is_enabled() {
+        return PAGE_ALLOCATOR.lock().alloc();
+    }
+
+    let mut cache = this_hart_cache().lock_irqsave();
+    if let Some(page) = cache.pop() {
+        return Ok(page);
+    }
+
+    cache.refill(&mut PAGE_ALLOCATOR.lock())?;
+    Ok(cache.pop().unwrap())
+}
+
+pub fn alloc_page_zeroed() -> Result<PhysFrame, PageAllocationError> {
+    let page = alloc_page()?;
+    unsafe {
+        core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+    }
+    Ok(page)
+}
+
+pub fn free_page(page: PhysFrame) {
+    if !is_enabled() {
+        return PAGE_ALLOCATOR.lock().dealloc(page);
+    }
+
+    let mut cache = this_hart_cache().lock_irqsave();
+    if cache.count == CACHE_CAPACITY {
+        cache.flush(&mut PAGE_ALLOCATOR.lock(), BATCH_SIZE);
+    }
+    cache.push(page);
+}
+
+pub fn cached_pages() -> usize {
+    this_hart_cache().lock_irqsave().count
+}
+
+// Low memory handler: runs with the global allocator already locked, so caches
+// that are busy refilling or flushing are skipped rather than waited on.
+pub fn reclaim(allocator: &mut PageAllocator) {
+    for cache in PAGE_CACHES.iter() {
+        if let Some(mut cache) = cache.try_lock() {
+            cache.flush(allocator, 0);
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Pages parked in per-hart caches are too dear to spare on tiny machines.
+pub fn init() {
+    if memory_map::is_low_memory() {
+        return;
+    }
+    PAGE_ALLOCATOR.lock().register_low_memory_handler(reclaim);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn an_empty_cache_refills_a_batch_from_the_global_allocator() {
+        reclaim(&mut PAGE_ALLOCATOR.lock());
+        let allocated_before = PAGE_ALLOCATOR.lock().stats().allocated_pages;
+
+        let page = alloc_page().unwrap();
+
+        assert_eq!(cached_pages(), BATCH_SIZE - 1);
+        assert_eq!(
+            PAGE_ALLOCATOR.lock().stats().allocated_pages,
+            allocated_before + BATCH_SIZE as u64
+        );
+        free_page(page);
+    }
+
+    #[test_case]
+    fn freed_pages_are_reused_from_the_cache() {
+        let page = alloc_page().unwrap();
+        let frame = page.clone();
+        free_page(page);
+
+        let page = alloc_page().unwrap();
+        assert_eq!(page, frame);
+        free_page(page);
+    }
+
+    #[test_case]
+    fn reclaiming_returns_cached_pages() {
+        let page = alloc_page().unwrap();
+        free_page(page);
+        assert!(cached_pages() > 0);
+
+        reclaim(&mut PAGE_ALLOCATOR.lock());
+
+        assert_eq!(cached_pages(), 0);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::lock::SpinLockIrq;
+use crate::page_allocator::PAGE_SIZE;
+use crate::warn;
+use core::fmt;
+
+pub const POISON: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+
+const MAX_TRACKED_PAGES: usize = 1 << 18;
+const TRACKED_WINDOW_ALIGN: u64 = 1 << 30;
+
+// The first word of a free page holds the free list link, so it can't be poisoned.
+const POISON_START: usize = 1;
+const PAGE_WORDS: usize = (PAGE_SIZE / 8) as usize;
+
+pub struct PageTracker {
+    base: Option<u64>,
+    free: [u64; MAX_TRACKED_PAGES / 64],
+    // Added pages past the end of the window, which go unchecked.
+    untracked: u64,
+}
+
+impl fmt::Debug for PageTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageTracker")
+            .field("base", &self.base)
+            .field("untracked", &self.untracked)
+            .finish()
+    }
+}
+
+impl PageTracker {
+    pub const fn new() -> Self {
+        Self {
+            base: None,
+            free: [0; MAX_TRACKED_PAGES / 64],
+            untracked: 0,
+        }
+    }
+
+    fn index(&self, page: u64) -> Option<usize> {
+        let base = self.base?;
+        if page < base {
+            return None;
+        }
+        let idx = ((page - base) / PAGE_SIZE) as usize;
+        (idx < MAX_TRACKED_PAGES).then_some(idx)
+    }
+
+    pub fn is_free(&self, page: u64) -> Option<bool> {
+        let idx = self.index(page)?;
+        Some(self.free[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    fn set_free(&mut self, page: u64, free: bool) {
+        if let Some(idx) = self.index(page) {
+            if free {
+                self.free[idx / 64] |= 1 << (idx % 64);
+            } else {
+                self.free[idx / 64] &= !(1 << (idx % 64));
+            }
+        }
+    }
+
+    pub fn untracked_pages(&self) -> u64 {
+        self.untracked
+    }
+
+    // The window is the gigabyte the first page added falls in, so RAM
+    // outside it is poisoned but never checked. That's said once, and counted.
+    pub fn on_add(&mut self, page: u64) {
+        let base = *self.base.get_or_insert(page & !(TRACKED_WINDOW_ALIGN - 1));
+        if self.index(page).is_none() {
+            if self.untracked == 0 {
+                warn!(
+                    "Page poisoning only tracks {:#x}..{:#x}; pages outside it go unchecked",
+                    base,
+                    base + MAX_TRACKED_PAGES as u64 * PAGE_SIZE
+                );
+            }
+            self.untracked += 1;
+        }
+        self.set_free(page, true);
+        poison(page);
+    }
+
+    pub fn on_dealloc(&mut self, page: u64) -> Result<(), PageError> {
+        if page % PAGE_SIZE != 0 {
+            return Err(PageError::Unaligned(page));
+        }
+        if self.is_free(page) == Some(true) {
+            return Err(PageError::DoubleFree(page));
+        }
+        self.set_free(page, true);
+        poison(page);
+        Ok(())
+    }
+
+    pub fn on_alloc(&mut self, page: u64, next: Option<u64>) -> Result<(), PageError> {
+        if self.is_free(page) == Some(false) {
+            return Err(PageError::AlreadyAllocated(page));
+        }
+        if let Some(next) = next {
+            if self.is_free(next) == Some(false) || next % PAGE_SIZE != 0 {
+                return Err(PageError::BadLink { page, next });
+            }
+        }
+        check_poison(page)?;
+        self.set_free(page, false);
+        Ok(())
+    }
+}
+
+// What the allocator panics with, once it has let go of the tracker.
+#[derive(Debug)]
+pub enum PageError {
+    Unaligned(u64),
+    DoubleFree(u64),
+    AlreadyAllocated(u64),
+    BadLink { page: u64, next: u64 },
+    WrittenAfterFree { page: u64, value: u64, address: u64 },
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PageError::Unaligned(page) => write!(f, "Freeing unaligned page address {:#x}", page),
+            PageError::DoubleFree(page) => write!(f, "Double free of page {:#x}", page),
+            PageError::AlreadyAllocated(page) => write!(
+                f,
+                "Free list corrupted: page {:#x} is already allocated",
+                page
+            ),
+            PageError::BadLink { page, next } => write!(
+                f,
+                "Free list corrupted: page {:#x} links to {:#x}, which isn't free",
+                page, next
+            ),
+            PageError::WrittenAfterFree {
+                page,
+                value,
+                address,
+            } => write!(
+                f,
+                "Page {:#x} was written after being freed: found {:#018x} at {:#x}",
+                page, value, address
+            ),
+        }
+    }
+}
+
+// Shared by every allocator, which is fine as their pages never overlap,
+// and kept out of them since it's too big for the stacks tests build
+// allocators on. It reports problems rather than panicking itself, so a
+// test expecting the panic doesn't leave it locked.
+pub static TRACKER: SpinLockIrq<PageTracker> = SpinLockIrq::new(PageTracker::new());
+
+fn poison(page: u64) {
+    let words = page as *mut u64;
+    for idx in POISON_START..PAGE_WORDS {
+        unsafe { words.add(idx).write_volatile(POISON) };
+    }
+}
+
+fn check_poison(page: u64) -> Result<(), PageError> {
+    let words = page as *const u64;
+    for idx in POISON_START..PAGE_WORDS {
+        let value = unsafe { words.add(idx).read_volatile() };
+        if value != POISON {
+            return Err(PageError::WrittenAfterFree {
+                page,
+                value,
+                address: page + 8 * idx as u64,
+            });
+        }
+    }
+    Ok(())
+}
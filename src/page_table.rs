@@ -1,6 +1,17 @@
-use crate::page_allocator::{PageAddr, PageAllocationError, PageAllocator, PageRange};
+use crate::address::{PhysAddr, PhysFrame, VirtAddr, VirtPage, VirtualAddressError};
+use crate::lock::SpinLockIrq;
+use crate::memory_map::{MemoryMap, MemoryRegion};
+use crate::page_allocator::{PageAllocationError, PageAllocator, PageRange, PAGE_SIZE};
+use crate::satp::{Satp, SatpMode};
+use crate::slab::SlabCache;
+use crate::tlb;
+use bitflags::bitflags;
+use core::ops::Range;
 use core::ptr;
 
+pub const MEGAPAGE_SIZE: u64 = 1 << 21;
+const GIGAPAGE_SIZE: u64 = 1 << 30;
+
 extern "C" {
     static TEXT_START: u64;
     static TEXT_END: u64;
@@ -12,79 +23,30 @@ extern "C" {
     static BSS_END: u64;
     static STACK_START: u64;
     static STACK_END: u64;
-    static HEAP_START: u64;
-    static HEAP_END: u64;
-}
-
-#[derive(Debug)]
-pub struct PhysicalAddress {
-    pub address: u64,
-}
-
-impl PhysicalAddress {
-    pub fn new(address: u64) -> Self {
-        Self { address }
-    }
-}
-
-impl From<u64> for PhysicalAddress {
-    fn from(value: u64) -> Self {
-        Self::new(value)
-    }
-}
-
-#[derive(Debug)]
-pub enum VirtualAddressError {
-    OutOfVirtualMemoryRange,
-}
-
-#[derive(Debug, Clone)]
-pub struct VirtualAddress {
-    value: u64,
-}
-
-impl VirtualAddress {
-    pub fn page_table_index(&self, level: u64) -> u64 {
-        let mask = (1 << 9) - 1;
-        (self.value >> 12 + level * 9) & mask
-    }
-
-    pub fn offset(&self) -> u64 {
-        let mask = !((1 << 12) - 1);
-        self.value & mask
-    }
+    static BOOT_REGION_START: u64;
+    static BOOT_REGION_END: u64;
 }
 
-impl TryFrom<u64> for VirtualAddress {
-    type Error = VirtualAddressError;
-
-    fn try_from(value: u64) -> Result<Self, Self::Error> {
-        let mask = !((1 << 39) - 1);
-        if value & mask != 0 {
-            return Err(VirtualAddressError::OutOfVirtualMemoryRange);
-        }
-
-        match (value >> 38) & 1 {
-            1 => Ok(VirtualAddress {
-                value: value | mask,
-            }),
-            0 => Ok(VirtualAddress {
-                value: value & (!mask),
-            }),
-            e => panic!("Bitwise and with 1 returned {}", e),
-        }
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PteFlags: u64 {
+        const VALID = 1 << 0;
+        const READ = 1 << 1;
+        const WRITE = 1 << 2;
+        const EXECUTE = 1 << 3;
+        const USER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const ACCESSED = 1 << 6;
+        const DIRTY = 1 << 7;
+        // Reserved for software; the MMU ignores them.
+        const RSW0 = 1 << 8;
+        const RSW1 = 1 << 9;
     }
 }
 
-impl TryFrom<PageAddr> for VirtualAddress {
-    type Error = VirtualAddressError;
-
-    fn try_from(value: PageAddr) -> Result<Self, Self::Error> {
-        value.address.try_into()
-    }
-}
+const FLAG_BITS: u64 = (1 << 10) - 1;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageTableEntryMode {
     PageTablePointer,
     ReadOnly,
@@ -94,6 +56,21 @@ pub enum PageTableEntryMode {
     ReadWriteExecute,
 }
 
+impl PageTableEntryMode {
+    pub fn flags(self) -> PteFlags {
+        match self {
+            PageTableEntryMode::PageTablePointer => PteFlags::empty(),
+            PageTableEntryMode::ReadOnly => PteFlags::READ,
+            PageTableEntryMode::ReadWrite => PteFlags::READ | PteFlags::WRITE,
+            PageTableEntryMode::ExecuteOnly => PteFlags::EXECUTE,
+            PageTableEntryMode::ReadExecute => PteFlags::READ | PteFlags::EXECUTE,
+            PageTableEntryMode::ReadWriteExecute => {
+                PteFlags::READ | PteFlags::WRITE | PteFlags::EXECUTE
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct PageTableEntry {
@@ -101,46 +78,65 @@ pub struct PageTableEntry {
 }
 
 impl PageTableEntry {
+    pub fn flags(&self) -> PteFlags {
+        PteFlags::from_bits_truncate(self.value & FLAG_BITS)
+    }
+
+    pub fn set_flags(&mut self, flags: PteFlags) {
+        self.value = (self.value & !FLAG_BITS) | flags.bits();
+    }
+
+    pub fn modify_flags(&mut self, f: impl FnOnce(&mut PteFlags)) {
+        let mut flags = self.flags();
+        f(&mut flags);
+        self.set_flags(flags);
+    }
+
     pub fn is_valid(&self) -> bool {
-        self.value & (1 << 0) == 1
+        self.flags().contains(PteFlags::VALID)
     }
 
     pub fn is_readable(&self) -> bool {
-        self.value & (1 << 1) == 1
+        self.flags().contains(PteFlags::READ)
     }
 
     pub fn is_writable(&self) -> bool {
-        self.value & (1 << 2) == 1
+        self.flags().contains(PteFlags::WRITE)
     }
 
     pub fn is_executable(&self) -> bool {
-        self.value & (1 << 3) == 1
+        self.flags().contains(PteFlags::EXECUTE)
     }
 
     pub fn is_leaf(&self) -> bool {
-        self.value & (0b1110) > 0
+        self.flags()
+            .intersects(PteFlags::READ | PteFlags::WRITE | PteFlags::EXECUTE)
     }
 
     pub fn is_user_accessible(&self) -> bool {
-        self.value & (1 << 4) == 1
+        self.flags().contains(PteFlags::USER)
     }
 
     pub fn is_global(&self) -> bool {
-        self.value & (1 << 5) == 1
+        self.flags().contains(PteFlags::GLOBAL)
     }
 
     pub fn has_been_accessed(&self) -> bool {
-        self.value & (1 << 6) == 1
+        self.flags().contains(PteFlags::ACCESSED)
     }
 
     pub fn is_dirty(&self) -> bool {
-        self.value & (1 << 7) == 1
+        self.flags().contains(PteFlags::DIRTY)
     }
 
     pub fn physical_page(&self) -> u64 {
         (self.value >> 10) & ((1 << 44) - 1)
     }
 
+    pub fn frame(&self) -> PhysFrame {
+        PhysFrame::from_number(self.physical_page())
+    }
+
     pub fn physical_page_number_0(&self) -> u64 {
         (self.value >> 10) & 0b1_1111_1111
     }
@@ -160,28 +156,13 @@ impl From<PageTableEntryBuilder> for PageTableEntry {
             return Self { value };
         }
 
-        let mut value = 1;
+        let mut flags = PteFlags::VALID | b.mode.flags();
+        flags.set(PteFlags::USER, b.user);
+        flags.set(PteFlags::GLOBAL, b.global);
 
-        match b.mode {
-            PageTableEntryMode::PageTablePointer => (),
-            PageTableEntryMode::ReadOnly => value |= 1 << 1,
-            PageTableEntryMode::ReadWrite => value |= (1 << 1) + (1 << 2),
-            PageTableEntryMode::ExecuteOnly => value |= 1 << 3,
-            PageTableEntryMode::ReadExecute => value |= (1 << 1) + (1 << 3),
-            PageTableEntryMode::ReadWriteExecute => value |= (1 << 1) + (1 << 2) + (1 << 3),
+        PageTableEntry {
+            value: (b.frame.number() << 10) | flags.bits(),
         }
-
-        if b.user {
-            value |= 1 << 4;
-        }
-
-        if b.global {
-            value |= 1 << 5;
-        }
-
-        value |= (b.page_number >> 12) << 10;
-
-        PageTableEntry { value }
     }
 }
 
@@ -189,17 +170,17 @@ struct PageTableEntryBuilder {
     mode: PageTableEntryMode,
     user: bool,
     global: bool,
-    page_number: u64,
+    frame: PhysFrame,
     invalid: Option<u64>,
 }
 
 impl PageTableEntryBuilder {
-    pub fn new(page_number: u64, mode: PageTableEntryMode) -> Self {
+    pub fn new(frame: PhysFrame, mode: PageTableEntryMode) -> Self {
         Self {
             mode,
             user: false,
             global: false,
-            page_number,
+            frame,
             invalid: None,
         }
     }
@@ -209,6 +190,7 @@ impl PageTableEntryBuilder {
         self
     }
 
+    #[allow(dead_code)]
     pub fn global_mapping(mut self) -> Self {
         self.global = true;
         self
@@ -219,7 +201,7 @@ impl PageTableEntryBuilder {
             mode: PageTableEntryMode::PageTablePointer,
             user: false,
             global: false,
-            page_number: 0,
+            frame: PhysFrame::from_number(0),
             invalid: Some(value & (u64::MAX - 1)),
         }
     }
@@ -233,55 +215,94 @@ pub struct PageTable {
     entries: [PageTableEntry; 512],
 }
 
+// Every table, each a page of its own, taken with the allocator it comes
+// from already held.
+pub static PAGE_TABLES: SpinLockIrq<SlabCache<PageTable>> =
+    SpinLockIrq::new(SlabCache::new("page tables", Some(clear_table), None));
+
+fn clear_table(table: *mut PageTable) {
+    unsafe {
+        table.write(PageTable {
+            entries: [PageTableEntryBuilder::invalid(0).build(); 512],
+        })
+    };
+}
+
 impl PageTable {
     pub fn new(allocator: &mut PageAllocator) -> Result<*mut Self, PageAllocationError> {
-        let page = allocator.alloc()?.address as *mut Self;
-        unsafe {
-            *page = Self {
-                entries: [PageTableEntryBuilder::invalid(0).build(); 512],
-            };
-        }
-        Ok(page)
+        PAGE_TABLES.lock().alloc(allocator)
     }
 
-    pub fn walk(&mut self, virt: VirtualAddress) -> Option<*mut PageTableEntry> {
-        self.do_walk(virt, 2)
+    pub fn walk(&mut self, virt: VirtAddr) -> Option<*mut PageTableEntry> {
+        self.do_walk(virt, 2).map(|(pte, _)| pte)
     }
 
-    fn do_walk(&mut self, virt: VirtualAddress, level: u64) -> Option<*mut PageTableEntry> {
+    // Also returns the level the walk stopped at, which gives the size of
+    // a leaf: 4 KiB at level 0, 2 MiB at 1 and 1 GiB at 2.
+    fn do_walk(&mut self, virt: VirtAddr, level: u64) -> Option<(*mut PageTableEntry, u64)> {
         let pte_idx = virt.page_table_index(level) as usize;
         let pte = self.entries[pte_idx];
         let pte_ptr =
             unsafe { (ptr::addr_of_mut!(self.entries) as *mut PageTableEntry).add(pte_idx) };
 
         if level == 0 {
-            return Some(pte_ptr);
+            return Some((pte_ptr, level));
         }
 
         if !pte.is_valid() {
             return None;
         }
 
-        let next: &mut PageTable = unsafe {
-            ((pte.physical_page() << 12) as *mut PageTable)
-                .as_mut()
-                .unwrap()
-        };
+        if pte.is_leaf() {
+            return Some((pte_ptr, level));
+        }
+
+        let next: &mut PageTable =
+            unsafe { pte.frame().as_mut_ptr::<PageTable>().as_mut().unwrap() };
         next.do_walk(virt, level - 1)
     }
 
+    fn visit_leaves(&self, level: u64, base: u64, f: &mut dyn FnMut(u64, u64, PageTableEntry)) {
+        for (index, pte) in self.entries.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let mut virt = base | ((index as u64) << (12 + level * 9));
+            // Addresses with bit 38 set are sign extended.
+            if virt & (1 << 38) != 0 {
+                virt |= !((1 << 39) - 1);
+            }
+            if pte.is_leaf() {
+                f(virt, 1 << (12 + level * 9), *pte);
+            } else if level > 0 {
+                let next = unsafe { &*pte.frame().as_mut_ptr::<PageTable>() };
+                next.visit_leaves(level - 1, virt, f);
+            }
+        }
+    }
+
     pub fn walk_and_map(
         &mut self,
-        virt: VirtualAddress,
+        virt: VirtAddr,
         allocator: &mut PageAllocator,
     ) -> Result<*mut PageTableEntry, PageAllocationError> {
-        self.do_walk_and_map(virt, 2, allocator)
+        self.do_walk_and_map(virt, 2, 0, allocator)
+    }
+
+    pub fn walk_and_map_level(
+        &mut self,
+        virt: VirtAddr,
+        target_level: u64,
+        allocator: &mut PageAllocator,
+    ) -> Result<*mut PageTableEntry, PageAllocationError> {
+        self.do_walk_and_map(virt, 2, target_level, allocator)
     }
 
     fn do_walk_and_map(
         &mut self,
-        virt: VirtualAddress,
+        virt: VirtAddr,
         level: u64,
+        target_level: u64,
         allocator: &mut PageAllocator,
     ) -> Result<*mut PageTableEntry, PageAllocationError> {
         // println!(
@@ -296,7 +317,7 @@ impl PageTable {
 
         // println!("  Getting entry {}: {:#064b}", pte_idx, pte.value);
 
-        if level == 0 {
+        if level == target_level {
             // println!("  Entry at {:#0x}", ptr::addr_of_mut!(pte) as u64);
             // println!(
             //     "  Or maybe it should be {:#0x}",
@@ -307,166 +328,352 @@ impl PageTable {
         }
 
         let next: &mut PageTable = if !pte.is_valid() {
-            let new_page = allocator.alloc()?;
+            let table = PageTable::new(allocator)?;
+            let frame = PhysFrame::containing_address(PhysAddr::new(table as u64));
             unsafe {
                 pte_ptr.write(
-                    PageTableEntryBuilder::new(
-                        new_page.address,
-                        PageTableEntryMode::PageTablePointer,
-                    )
-                    .build(),
+                    PageTableEntryBuilder::new(frame, PageTableEntryMode::PageTablePointer).build(),
                 );
             }
-            unsafe { (new_page.address as *mut PageTable).as_mut().unwrap() }
+            unsafe { table.as_mut().unwrap() }
+        } else if pte.is_leaf() {
+            panic!("Mapping {:?} inside an existing large page", virt);
         } else {
-            unsafe {
-                ((pte.physical_page() << 12) as *mut PageTable)
-                    .as_mut()
-                    .unwrap()
-            }
+            unsafe { pte.frame().as_mut_ptr::<PageTable>().as_mut().unwrap() }
         };
 
-        next.do_walk_and_map(virt, level - 1, allocator)
+        next.do_walk_and_map(virt, level - 1, target_level, allocator)
+    }
+}
+
+unsafe fn free_table(table: *mut PageTable, level: u64, allocator: &mut PageAllocator) {
+    if level > 0 {
+        for pte in (*table).entries.iter() {
+            if pte.is_valid() && !pte.is_leaf() {
+                free_table(pte.frame().as_mut_ptr(), level - 1, allocator);
+            }
+        }
     }
+    PAGE_TABLES.lock().free(table, allocator);
+}
+
+// The pages from `start` up to and including the one holding `end`.
+fn kernel_pages(start: u64, end: u64) -> PageRange {
+    PageRange::new(
+        PhysFrame::containing_address(PhysAddr::new(start)),
+        PhysFrame::containing_address(PhysAddr::new(end)),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingGranularity {
+    Pages,
+    Megapages,
 }
 
 #[derive(Debug)]
 pub struct VirtualMemory {
-    pub page_allocator: PageAllocator,
     pub root_table: *mut PageTable,
 }
 
 unsafe impl Send for VirtualMemory {}
+// Changing the tables takes &mut, so shared references only read them.
+unsafe impl Sync for VirtualMemory {}
 
 impl VirtualMemory {
-    pub fn new(mut page_allocator: PageAllocator) -> Result<Self, PageAllocationError> {
-        let root_table = PageTable::new(&mut page_allocator)?;
+    pub fn new(allocator: &mut PageAllocator) -> Result<Self, PageAllocationError> {
+        let root_table = PageTable::new(allocator)?;
 
-        Ok(Self {
-            page_allocator,
-            root_table,
-        })
+        Ok(Self { root_table })
     }
 
     unsafe fn map_to(
         &mut self,
-        virt: VirtualAddress,
-        phys: PageAddr,
+        virt: VirtPage,
+        phys: PhysFrame,
         mode: PageTableEntryMode,
+        allocator: &mut PageAllocator,
     ) -> Result<(), PageAllocationError> {
-        let pte = (*self.root_table).walk_and_map(virt, &mut self.page_allocator)?;
-        pte.write(PageTableEntryBuilder::new(phys.address, mode).build());
+        let pte = (*self.root_table).walk_and_map(virt.start_address(), allocator)?;
+        pte.write(PageTableEntryBuilder::new(phys, mode).build());
         Ok(())
     }
 
     pub fn map(
         &mut self,
-        virt: VirtualAddress,
+        virt: VirtPage,
         mode: PageTableEntryMode,
+        allocator: &mut PageAllocator,
     ) -> Result<(), PageAllocationError> {
-        let phys = self.page_allocator.alloc()?;
-        unsafe { self.map_to(virt, phys, mode) }
+        let phys = allocator.alloc_zeroed()?;
+        unsafe { self.map_to(virt, phys, mode, allocator) }
     }
 
     pub fn identity_map(
         &mut self,
-        phys: PageAddr,
+        phys: PhysFrame,
+        mode: PageTableEntryMode,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), PageAllocationError> {
+        let virt = VirtPage::identity(&phys).unwrap();
+        unsafe { self.map_to(virt, phys, mode, allocator) }
+    }
+
+    // Maps `phys` at `virt` so U-mode can reach it too.
+    pub fn map_user(
+        &mut self,
+        virt: VirtPage,
+        phys: PhysFrame,
+        mode: PageTableEntryMode,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), PageAllocationError> {
+        unsafe {
+            let pte = (*self.root_table).walk_and_map(virt.start_address(), allocator)?;
+            pte.write(
+                PageTableEntryBuilder::new(phys, mode)
+                    .user_accessible()
+                    .build(),
+            );
+        }
+        Ok(())
+    }
+
+    // Makes sure the root entry covering `virt` points at a table, so
+    // whatever shares the entry sees anything mapped below it later on.
+    pub fn prepare_root_entry(
+        &mut self,
+        virt: VirtAddr,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), PageAllocationError> {
+        unsafe { (*self.root_table).walk_and_map_level(virt, 1, allocator)? };
+        Ok(())
+    }
+
+    // Points the root entries in `indices` at the same tables as `other`'s,
+    // so whatever either maps below them shows up in both.
+    pub fn share_root_entries(&mut self, other: &VirtualMemory, indices: Range<usize>) {
+        unsafe {
+            for index in indices {
+                (*self.root_table).entries[index] = (*other.root_table).entries[index];
+            }
+        }
+    }
+
+    // Frees the root table and the tables below the root entries in
+    // `indices`, but none of the pages they map. The tables must not be in
+    // use on any hart.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn free_tables(self, indices: Range<usize>, allocator: &mut PageAllocator) {
+        for index in indices {
+            let pte = (*self.root_table).entries[index];
+            if pte.is_valid() && !pte.is_leaf() {
+                free_table(pte.frame().as_mut_ptr(), 1, allocator);
+            }
+        }
+        PAGE_TABLES.lock().free(self.root_table, allocator);
+    }
+
+    pub fn identity_map_megapage(
+        &mut self,
+        phys: PhysFrame,
         mode: PageTableEntryMode,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), PageAllocationError> {
+        if !phys.start_address().is_aligned(MEGAPAGE_SIZE) {
+            panic!(
+                "Megapage address {:#x} isn't 2 MiB aligned",
+                phys.start_address().as_u64()
+            );
+        }
+
+        unsafe {
+            let virt = VirtPage::identity(&phys).unwrap();
+            let pte = (*self.root_table).walk_and_map_level(virt.start_address(), 1, allocator)?;
+            pte.write(PageTableEntryBuilder::new(phys, mode).build());
+        }
+        Ok(())
+    }
+
+    fn identity_map_region(
+        &mut self,
+        region: &MemoryRegion,
+        granularity: MappingGranularity,
+        allocator: &mut PageAllocator,
     ) -> Result<(), PageAllocationError> {
-        unsafe { self.map_to(phys.clone().try_into().unwrap(), phys, mode) }
+        let mega_start = (region.start + MEGAPAGE_SIZE - 1) & !(MEGAPAGE_SIZE - 1);
+        let mega_end = region.end & !(MEGAPAGE_SIZE - 1);
+
+        if granularity == MappingGranularity::Pages || mega_start >= mega_end {
+            for page in region.pages() {
+                self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
+            }
+            return Ok(());
+        }
+
+        for page in MemoryRegion::new(region.start, mega_start).pages() {
+            self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
+        }
+        for address in (mega_start..mega_end).step_by(MEGAPAGE_SIZE as usize) {
+            self.identity_map_megapage(
+                PhysFrame::containing_address(PhysAddr::new(address)),
+                PageTableEntryMode::ReadWrite,
+                allocator,
+            )?
+        }
+        for page in MemoryRegion::new(mega_end, region.end).pages() {
+            self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
+        }
+        Ok(())
+    }
+
+    // A generous estimate of the page table pages init() will allocate.
+    pub fn identity_map_cost(memory_map: &MemoryMap, granularity: MappingGranularity) -> u64 {
+        let kernel_size = unsafe { BOOT_REGION_END - TEXT_START };
+        let kernel_cost = kernel_size / MEGAPAGE_SIZE + 4;
+
+        let region_cost: u64 = memory_map
+            .regions()
+            .iter()
+            .map(|region| match granularity {
+                MappingGranularity::Pages => region.size() / MEGAPAGE_SIZE + 2,
+                MappingGranularity::Megapages => region.size() / GIGAPAGE_SIZE + 4,
+            })
+            .sum();
+
+        // The root table plus the device mappings.
+        kernel_cost + region_cost + 1 + 4
+    }
+
+    fn leaf_entry(&self, virt: VirtAddr) -> Result<*mut PageTableEntry, VirtualAddressError> {
+        let pte = unsafe { (*self.root_table).walk(virt) };
+        match pte {
+            Some(pte) if unsafe { (*pte).is_valid() && (*pte).is_leaf() } => Ok(pte),
+            _ => Err(VirtualAddressError::NotMapped),
+        }
+    }
+
+    pub fn leaf(&self, virt: VirtAddr) -> Option<PageTableEntry> {
+        self.leaf_entry(virt).ok().map(|pte| unsafe { *pte })
+    }
+
+    // Hands back the page that was mapped so the caller can free it.
+    pub fn unmap(&mut self, virt: VirtPage) -> Result<PhysFrame, VirtualAddressError> {
+        let (phys, shootdown) = self.unmap_deferred(virt)?;
+        shootdown.send();
+        Ok(phys)
+    }
+
+    // As unmap, but leaves the other harts' TLBs to the caller.
+    pub fn unmap_deferred(
+        &mut self,
+        virt: VirtPage,
+    ) -> Result<(PhysFrame, tlb::Shootdown), VirtualAddressError> {
+        let pte = self.leaf_entry(virt.start_address())?;
+        let phys = unsafe { (*pte).frame() };
+        unsafe { pte.write(PageTableEntryBuilder::invalid(0).build()) };
+
+        let page = virt.start_address().as_u64();
+        Ok((phys, tlb::Shootdown::new(page, page + PAGE_SIZE)))
+    }
+
+    pub fn protect(
+        &mut self,
+        virt: VirtPage,
+        mode: PageTableEntryMode,
+    ) -> Result<(), VirtualAddressError> {
+        self.protect_deferred(virt, mode)?.send();
+        Ok(())
+    }
+
+    // As protect, but leaves the other harts' TLBs to the caller.
+    pub fn protect_deferred(
+        &mut self,
+        virt: VirtPage,
+        mode: PageTableEntryMode,
+    ) -> Result<tlb::Shootdown, VirtualAddressError> {
+        let pte = self.leaf_entry(virt.start_address())?;
+        let (phys, user) = unsafe { ((*pte).frame(), (*pte).is_user_accessible()) };
+        let mut entry = PageTableEntryBuilder::new(phys, mode);
+        if user {
+            entry = entry.user_accessible();
+        }
+        unsafe { pte.write(entry.build()) };
+
+        let page = virt.start_address().as_u64();
+        Ok(tlb::Shootdown::new(page, page + PAGE_SIZE))
     }
 
-    pub fn translate(&self, virt: VirtualAddress) -> Option<PhysicalAddress> {
-        let pte = unsafe { *(*self.root_table).walk(virt.clone())? };
+    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        let (pte, level) = unsafe { (*self.root_table).do_walk(virt, 2)? };
+        let pte = unsafe { *pte };
         if !(pte.is_leaf() && pte.is_valid()) {
             return None;
         }
-        Some(((pte.physical_page() << 12) | virt.offset()).into())
+        let size = 1 << (12 + level * 9);
+        pte.frame()
+            .start_address()
+            .add_offset(virt.as_u64() & (size - 1))
     }
 
-    pub fn init(&mut self) -> Result<(), PageAllocationError> {
+    pub fn init(
+        &mut self,
+        memory_map: &MemoryMap,
+        granularity: MappingGranularity,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), PageAllocationError> {
         unsafe {
-            for page in PageRange::new(
-                PageAddr {
-                    address: TEXT_START,
-                },
-                PageAddr { address: TEXT_END },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadExecute)?
+            for page in kernel_pages(TEXT_START, TEXT_END) {
+                self.identity_map(page, PageTableEntryMode::ReadExecute, allocator)?
             }
 
-            for page in PageRange::new(
-                PageAddr {
-                    address: RODATA_START,
-                },
-                PageAddr {
-                    address: RODATA_END,
-                },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadOnly)?
+            for page in kernel_pages(RODATA_START, RODATA_END) {
+                self.identity_map(page, PageTableEntryMode::ReadOnly, allocator)?
             }
 
-            for page in PageRange::new(
-                PageAddr {
-                    address: DATA_START,
-                },
-                PageAddr { address: DATA_END },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadWrite)?
+            for page in kernel_pages(DATA_START, DATA_END) {
+                self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
             }
 
-            for page in PageRange::new(
-                PageAddr { address: BSS_START },
-                PageAddr { address: BSS_END },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadWrite)?
+            for page in kernel_pages(BSS_START, BSS_END) {
+                self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
             }
 
-            for page in PageRange::new(
-                PageAddr {
-                    address: STACK_START,
-                },
-                PageAddr { address: STACK_END },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadWrite)?
+            for page in kernel_pages(STACK_START, STACK_END) {
+                self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
             }
 
-            for page in PageRange::new(
-                PageAddr {
-                    address: HEAP_START,
-                },
-                PageAddr { address: HEAP_END },
-            ) {
-                self.identity_map(page, PageTableEntryMode::ReadWrite)?
+            // Early allocations stay put; the unused tail is donated to the
+            // page allocator, which hands it out as identity mapped pages too.
+            for page in kernel_pages(BOOT_REGION_START, BOOT_REGION_END) {
+                self.identity_map(page, PageTableEntryMode::ReadWrite, allocator)?
             }
 
-            self.identity_map(
-                PageAddr {
-                    address: 0x1000_0000,
-                },
-                PageTableEntryMode::ReadWrite,
-            )?;
-
-            self.identity_map(
-                PageAddr {
-                    address: 0x0010_0000,
-                },
-                PageTableEntryMode::ReadWrite,
-            )?;
+            for region in memory_map.regions() {
+                self.identity_map_region(region, granularity, allocator)?
+            }
         }
         Ok(())
     }
 
-    pub fn satp(&self) -> u64 {
-        let addr = self.root_table as u64;
-        (8 << 60) | (addr >> 12)
+    // Calls `f` with the virtual address, size and entry of each mapping,
+    // in address order.
+    pub fn for_each_leaf(&self, mut f: impl FnMut(u64, u64, PageTableEntry)) {
+        unsafe { (*self.root_table).visit_leaves(2, 0, &mut f) }
+    }
+
+    pub fn satp(&self) -> Satp {
+        let root = PhysFrame::containing_address(PhysAddr::new(self.root_table as u64));
+        Satp::new(SatpMode::Sv39, 0, &root).unwrap()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bench;
     use crate::page_allocator::test::test_page_allocator;
+    use crate::prng::Xoshiro256StarStar;
+    use alloc::format;
 
     #[test_case]
     fn creating_a_new_page_table_reduces_free_page_count_by_1() {
@@ -475,6 +682,17 @@ mod test {
         assert_eq!(allocator.free_pages(), 9);
     }
 
+    #[test_case]
+    fn page_tables_are_counted_in_their_cache() {
+        let mut allocator = test_page_allocator(10);
+        let before = PAGE_TABLES.lock().stats().allocated;
+        let table = PageTable::new(&mut allocator).unwrap();
+        assert_eq!(PAGE_TABLES.lock().stats().allocated, before + 1);
+        unsafe { free_table(table, 0, &mut allocator) };
+        assert_eq!(PAGE_TABLES.lock().stats().allocated, before);
+        assert_eq!(allocator.free_pages(), 10);
+    }
+
     #[test_case]
     fn walking_a_fresh_table_returns_none() {
         let mut allocator = test_page_allocator(10);
@@ -515,8 +733,232 @@ mod test {
 
     #[test_case]
     fn initialising_virtual_memory_succeeds() {
-        let allocator = test_page_allocator(128);
-        let mut vm = VirtualMemory::new(allocator).unwrap();
-        assert!(vm.init().is_ok());
+        let mut allocator = test_page_allocator(128);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        assert!(vm
+            .init(&MemoryMap::new(), MappingGranularity::Pages, &mut allocator)
+            .is_ok());
+    }
+
+    #[test_case]
+    fn megapage_identity_maps_use_fewer_tables() {
+        let base = 0x1_0000_0000;
+        let mut memory_map = MemoryMap::new();
+        memory_map.add(MemoryRegion::new(
+            base + PAGE_SIZE,
+            base + 3 * MEGAPAGE_SIZE,
+        ));
+
+        let mut allocator = test_page_allocator(128);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        let before = allocator.free_pages();
+        for region in memory_map.regions() {
+            vm.identity_map_region(region, MappingGranularity::Megapages, &mut allocator)
+                .unwrap();
+        }
+
+        // One table each for levels 1 and 0; the two aligned megapages need none.
+        assert_eq!(before - allocator.free_pages(), 2);
+    }
+
+    #[test_case]
+    fn unmapping_returns_the_mapped_page() {
+        let address = 0x1_0000_0000;
+        let mut allocator = test_page_allocator(16);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        vm.identity_map(
+            PhysFrame::containing_address(PhysAddr::new(address)),
+            PageTableEntryMode::ReadWrite,
+            &mut allocator,
+        )
+        .unwrap();
+
+        let page = vm.unmap(address.try_into().unwrap()).unwrap();
+        assert_eq!(page.start_address().as_u64(), address);
+        assert!(vm.translate(address.try_into().unwrap()).is_none());
+        assert!(matches!(
+            vm.unmap(address.try_into().unwrap()),
+            Err(VirtualAddressError::NotMapped)
+        ));
+    }
+
+    #[test_case]
+    fn freeing_tables_returns_them_to_the_allocator() {
+        let address = 0x20_0000_0000;
+        let mut allocator = test_page_allocator(16);
+        let before = allocator.free_pages();
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        let page = allocator.alloc().unwrap();
+        vm.map_user(
+            address.try_into().unwrap(),
+            page.clone(),
+            PageTableEntryMode::ReadWrite,
+            &mut allocator,
+        )
+        .unwrap();
+
+        let pte = unsafe { *(*vm.root_table).walk(address.try_into().unwrap()).unwrap() };
+        assert!(pte.is_user_accessible());
+        unsafe { vm.free_tables(128..256, &mut allocator) };
+        allocator.dealloc(page);
+        assert_eq!(allocator.free_pages(), before);
+    }
+
+    #[test_case]
+    fn translation_keeps_the_offset_into_the_page() {
+        let mut allocator = test_page_allocator(16);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        let page = 0x1_0000_3000;
+        let megapage = 0x1_0020_0000;
+        for address in [page, megapage] {
+            let frame = PhysFrame::containing_address(PhysAddr::new(address));
+            if address == megapage {
+                vm.identity_map_megapage(frame, PageTableEntryMode::ReadWrite, &mut allocator)
+            } else {
+                vm.identity_map(frame, PageTableEntryMode::ReadWrite, &mut allocator)
+            }
+            .unwrap();
+        }
+
+        let mut rng = Xoshiro256StarStar::from_seed(849);
+        for _ in 0..100 {
+            let within_page = page + rng.next_below(PAGE_SIZE);
+            let within_megapage = megapage + rng.next_below(MEGAPAGE_SIZE);
+            for address in [within_page, within_megapage] {
+                let translated = vm.translate(address.try_into().unwrap());
+                assert_eq!(translated, Some(PhysAddr::new(address)));
+            }
+        }
+    }
+
+    #[test_case]
+    fn walking_to_a_megapage_returns_the_leaf() {
+        let address = 0x1_0020_0000;
+        let mut allocator = test_page_allocator(16);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        vm.identity_map_megapage(
+            PhysFrame::containing_address(PhysAddr::new(address)),
+            PageTableEntryMode::ReadWrite,
+            &mut allocator,
+        )
+        .unwrap();
+
+        let table = unsafe { &mut *vm.root_table };
+        let pte = table.walk((address + 0x1234).try_into().unwrap()).unwrap();
+        assert!(unsafe { (*pte).is_leaf() });
+    }
+
+    #[test_case]
+    fn page_table_entries_show_their_address_and_flags() {
+        let leaf = PageTableEntryBuilder::new(
+            PhysFrame::containing_address(PhysAddr::new(0x8020_0000)),
+            PageTableEntryMode::ReadExecute,
+        )
+        .build();
+        assert_eq!(format!("{}", leaf), "0x0080200000 r-x----");
+        let invalid = PageTableEntryBuilder::invalid(0).build();
+        assert_eq!(format!("{}", invalid), "invalid");
+    }
+
+    #[test_case]
+    fn every_flag_can_be_set_and_cleared_on_its_own() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8020_0000));
+        for flag in PteFlags::all().iter() {
+            let mut pte =
+                PageTableEntryBuilder::new(frame.clone(), PageTableEntryMode::PageTablePointer)
+                    .build();
+            pte.set_flags(flag);
+            assert_eq!(pte.flags(), flag);
+            assert_eq!(pte.frame(), frame);
+
+            pte.modify_flags(|flags| flags.remove(flag));
+            assert!(pte.flags().is_empty());
+            assert_eq!(pte.frame(), frame);
+        }
+    }
+
+    type Accessor = fn(&PageTableEntry) -> bool;
+
+    #[test_case]
+    fn flag_accessors_read_their_own_bits() {
+        let accessors: [(PteFlags, Accessor); 8] = [
+            (PteFlags::VALID, PageTableEntry::is_valid),
+            (PteFlags::READ, PageTableEntry::is_readable),
+            (PteFlags::WRITE, PageTableEntry::is_writable),
+            (PteFlags::EXECUTE, PageTableEntry::is_executable),
+            (PteFlags::USER, PageTableEntry::is_user_accessible),
+            (PteFlags::GLOBAL, PageTableEntry::is_global),
+            (PteFlags::ACCESSED, PageTableEntry::has_been_accessed),
+            (PteFlags::DIRTY, PageTableEntry::is_dirty),
+        ];
+        for (set, _) in accessors {
+            let mut pte = PageTableEntryBuilder::invalid(0).build();
+            pte.set_flags(set);
+            for (flag, accessor) in accessors {
+                assert_eq!(accessor(&pte), flag == set);
+            }
+        }
+    }
+
+    #[test_case]
+    fn modes_and_builder_options_set_the_matching_flags() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8020_0000));
+        let pte = PageTableEntryBuilder::new(frame.clone(), PageTableEntryMode::ReadWriteExecute)
+            .user_accessible()
+            .build();
+        assert_eq!(
+            pte.flags(),
+            PteFlags::VALID | PteFlags::READ | PteFlags::WRITE | PteFlags::EXECUTE | PteFlags::USER
+        );
+        assert!(pte.is_leaf());
+
+        let table = PageTableEntryBuilder::new(frame, PageTableEntryMode::PageTablePointer).build();
+        assert_eq!(table.flags(), PteFlags::VALID);
+        assert!(!table.is_leaf());
+    }
+
+    #[test_case]
+    fn leaves_are_visited_in_address_order_with_their_sizes() {
+        let mut allocator = test_page_allocator(16);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        vm.identity_map(
+            PhysFrame::containing_address(PhysAddr::new(0x8040_3000)),
+            PageTableEntryMode::ReadOnly,
+            &mut allocator,
+        )
+        .unwrap();
+        vm.identity_map_megapage(
+            PhysFrame::containing_address(PhysAddr::new(0x8020_0000)),
+            PageTableEntryMode::ReadWrite,
+            &mut allocator,
+        )
+        .unwrap();
+
+        let mut leaves = [(0, 0, false); 2];
+        let mut count = 0;
+        vm.for_each_leaf(|virt, size, pte| {
+            leaves[count] = (virt, size, pte.is_writable());
+            count += 1;
+        });
+        assert_eq!(count, 2);
+        assert_eq!(leaves[0], (0x8020_0000, MEGAPAGE_SIZE, true));
+        assert_eq!(leaves[1], (0x8040_3000, PAGE_SIZE, false));
+    }
+
+    #[test_case]
+    fn bench_map_and_unmap() {
+        let address = 0x1_0000_0000;
+        let mut allocator = test_page_allocator(16);
+        let mut vm = VirtualMemory::new(&mut allocator).unwrap();
+        bench::run("map and unmap a page", 1000, || {
+            vm.map(
+                address.try_into().unwrap(),
+                PageTableEntryMode::ReadWrite,
+                &mut allocator,
+            )
+            .unwrap();
+            let page = vm.unmap(address.try_into().unwrap()).unwrap();
+            allocator.dealloc(page);
+        });
     }
 }
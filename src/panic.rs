@@ -0,0 +1,90 @@
+use crate::backtrace::{self, Backtrace};
+use crate::hart::hart_id;
+use crate::page_table::{PageTable, VirtualMemory};
+use crate::satp::{Satp, SatpMode};
+use crate::style::{Color, Style};
+use crate::trap::{TrapCause, TrapFrame};
+use crate::{cmdline, monitor, power, print, println, serial, trap};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+pub fn report(info: &PanicInfo) {
+    trap::disable_interrupts();
+    serial::force_unlock();
+
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        println!("Panic while panicking: {}", info);
+        return;
+    }
+
+    println!(
+        "{} on hart {}: {}",
+        Style::PLAIN.fg(Color::Red).bold().paint("Kernel panic"),
+        hart_id(),
+        info
+    );
+
+    // Innermost first, since that's usually the one that failed.
+    for level in (0..trap::trap_depth()).rev() {
+        if let Some(frame) = trap::frame(level) {
+            println!("In trap (depth {}):", level + 1);
+            frame.print();
+            print_faulting_entry(&frame);
+        }
+    }
+
+    let sp: u64;
+    unsafe {
+        asm!("mv {}, sp", out(reg) sp);
+    }
+    println!("sp: {:#018x}", sp);
+
+    backtrace::print(Backtrace::here());
+}
+
+// The entry a page fault tripped over, in the tables the hart was using.
+fn print_faulting_entry(frame: &TrapFrame) {
+    let is_page_fault = matches!(
+        frame.cause(),
+        TrapCause::LoadPageFault | TrapCause::StorePageFault | TrapCause::InstructionPageFault
+    );
+    let satp = Satp::from_bits(frame.satp);
+    if !is_page_fault || !matches!(satp.mode(), Ok(SatpMode::Sv39)) {
+        return;
+    }
+    let tables = VirtualMemory {
+        root_table: satp.root().as_mut_ptr::<PageTable>(),
+    };
+    match frame
+        .stval
+        .try_into()
+        .ok()
+        .and_then(|virt| tables.leaf(virt))
+    {
+        Some(pte) => println!("  pte {}", pte),
+        None => println!("  pte: not mapped"),
+    }
+}
+
+// What to do once the report is out, from the panic= boot argument:
+// reboot, poweroff or monitor. By default the hart stops so the report
+// stays on screen.
+pub fn finish() -> ! {
+    match cmdline::get("panic") {
+        Some("reboot") => power::reboot(),
+        Some("poweroff") => power::shutdown_after_failure(),
+        Some("monitor") => monitor::panic_shell(),
+        _ => loop {
+            unsafe {
+                asm!("wfi");
+            }
+        },
+    }
+}
@@ -0,0 +1,107 @@
+use crate::csr;
+use crate::hart::MAX_HARTS;
+use crate::trap::InterruptGuard;
+use core::cell::UnsafeCell;
+use core::sync::atomic::AtomicU64;
+
+// Each hart keeps a pointer to its own area in sscratch from early boot, so
+// finding it doesn't depend on anything else being set up. The trap entry
+// in trap.S relies on the layout.
+#[repr(C)]
+#[derive(Debug)]
+pub struct HartArea {
+    pub hart_id: usize,
+    // The top of the running thread's kernel stack, where a trap from user
+    // mode saves its frame. Zero if the thread has no stack of its own.
+    pub kernel_sp: AtomicU64,
+    // Where the trap entry stashes a register before it has a stack.
+    pub scratch: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_AREA: HartArea = HartArea {
+    hart_id: 0,
+    kernel_sp: AtomicU64::new(0),
+    scratch: AtomicU64::new(0),
+};
+
+const fn areas() -> [HartArea; MAX_HARTS] {
+    let mut areas = [NO_AREA; MAX_HARTS];
+    let mut hart = 0;
+    while hart < MAX_HARTS {
+        areas[hart].hart_id = hart;
+        hart += 1;
+    }
+    areas
+}
+
+static AREAS: [HartArea; MAX_HARTS] = areas();
+
+pub fn init(hart: usize) {
+    let area = &AREAS[hart] as *const HartArea as u64;
+    unsafe {
+        csr::sscratch::write(area);
+    }
+}
+
+pub fn area() -> &'static HartArea {
+    let area = csr::sscratch::read();
+    unsafe { &*(area as *const HartArea) }
+}
+
+// One T for each hart. A hart only ever touches its own, so no lock is
+// needed; interrupts are held off while it does so a handler can't get in
+// halfway through.
+pub struct PerHart<T> {
+    values: UnsafeCell<[T; MAX_HARTS]>,
+}
+
+unsafe impl<T: Send> Sync for PerHart<T> {}
+
+impl<T> PerHart<T> {
+    pub const fn new(values: [T; MAX_HARTS]) -> Self {
+        Self {
+            values: UnsafeCell::new(values),
+        }
+    }
+
+    // `f` must not reach this same PerHart again.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _interrupts = InterruptGuard::disable();
+        let value = unsafe { &mut *(self.values.get() as *mut T).add(area().hart_id) };
+        f(value)
+    }
+}
+
+impl<T: Copy> PerHart<T> {
+    pub fn get(&self) -> T {
+        self.with(|value| *value)
+    }
+
+    pub fn set(&self, value: T) {
+        self.with(|slot| *slot = value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hart::hart_id;
+
+    #[test_case]
+    fn the_area_belongs_to_this_hart() {
+        assert_eq!(area().hart_id, hart_id());
+    }
+
+    #[test_case]
+    fn each_hart_has_its_own_value() {
+        let counters: PerHart<u64> = PerHart::new([0; MAX_HARTS]);
+        counters.with(|count| *count += 2);
+        counters.set(counters.get() + 1);
+
+        assert_eq!(counters.get(), 3);
+        let values = unsafe { &*counters.values.get() };
+        assert_eq!(values.iter().sum::<u64>(), 3);
+        assert_eq!(values[hart_id()], 3);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::{File, FileType, Stat};
+use crate::wait_queue::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+// As much as a writer can get ahead of the reader before it blocks.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+// A one-way byte channel between a reader and a writer end, each of which
+// can be shared, say by a process and the children it forks.
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+// Each end closes once the last handle to it is dropped.
+struct ReadEnd(Arc<Pipe>);
+struct WriteEnd(Arc<Pipe>);
+
+impl Drop for ReadEnd {
+    fn drop(&mut self) {
+        self.0.state.lock().reader_open = false;
+        self.0.writable.notify_all();
+    }
+}
+
+impl Drop for WriteEnd {
+    fn drop(&mut self) {
+        self.0.state.lock().writer_open = false;
+        self.0.readable.notify_all();
+    }
+}
+
+#[derive(Clone)]
+pub struct PipeReader(Arc<ReadEnd>);
+
+#[derive(Clone)]
+pub struct PipeWriter(Arc<WriteEnd>);
+
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (
+        PipeReader(Arc::new(ReadEnd(pipe.clone()))),
+        PipeWriter(Arc::new(WriteEnd(pipe))),
+    )
+}
+
+impl PipeReader {
+    // Blocks until there's something to read, then reads as much as there
+    // is that fits. Returns 0 once the writer has closed and it's empty, and
+    // fails with Interrupted if a signal comes first.
+    pub fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &(self.0).0;
+        let mut read = 0;
+        pipe.readable.wait_until_interruptible(|| {
+            let mut state = pipe.state.lock();
+            if state.buffer.is_empty() {
+                return !state.writer_open;
+            }
+            let (front, back) = state.buffer.as_slices();
+            for (to, from) in buffer.iter_mut().zip(front.iter().chain(back)) {
+                *to = *from;
+                read += 1;
+            }
+            state.buffer.drain(..read);
+            true
+        })?;
+        pipe.writable.notify_all();
+        Ok(read)
+    }
+}
+
+impl PipeWriter {
+    // Blocks until all of `bytes` is written. Fails with BrokenPipe if the
+    // reader closes before any of it is.
+    pub fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        let pipe = &(self.0).0;
+        let mut written = 0;
+        let mut broken = false;
+        while written < bytes.len() && !broken {
+            pipe.writable.wait_until(|| {
+                let mut state = pipe.state.lock();
+                if !state.reader_open {
+                    broken = true;
+                    return true;
+                }
+                let room = PIPE_CAPACITY - state.buffer.len();
+                let chunk = &bytes[written..][..room.min(bytes.len() - written)];
+                state.buffer.extend(chunk);
+                written += chunk.len();
+                !chunk.is_empty()
+            });
+            pipe.readable.notify_all();
+        }
+        match written {
+            0 if broken => Err(KernelError::BrokenPipe),
+            written => Ok(written),
+        }
+    }
+}
+
+fn stat(pipe: &Pipe) -> Stat {
+    Stat {
+        file_type: FileType::Pipe,
+        permissions: 0o600,
+        inode: 0,
+        size: pipe.state.lock().buffer.len() as u64,
+    }
+}
+
+impl File for PipeReader {
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        PipeReader::read(self, buffer)
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(stat(&(self.0).0))
+    }
+}
+
+impl File for PipeWriter {
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        PipeWriter::write(self, bytes)
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(stat(&(self.0).0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{self, test::yield_until};
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test_case]
+    fn bytes_written_can_be_read_back() {
+        let (reader, writer) = pipe();
+        assert_eq!(writer.write(b"hello"), Ok(5));
+
+        let mut buffer = [0; 8];
+        assert_eq!(reader.read(&mut buffer[..3]), Ok(3));
+        assert_eq!(&buffer[..3], b"hel");
+        assert_eq!(reader.read(&mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], b"lo");
+    }
+
+    #[test_case]
+    fn closing_one_end_is_seen_at_the_other() {
+        let (reader, writer) = pipe();
+        writer.write(b"x").unwrap();
+        drop(writer);
+        let mut buffer = [0; 4];
+        assert_eq!(reader.read(&mut buffer), Ok(1));
+        assert_eq!(reader.read(&mut buffer), Ok(0));
+
+        let (reader, writer) = pipe();
+        let copy = reader.clone();
+        drop(reader);
+        assert_eq!(writer.write(b"still open"), Ok(10));
+        drop(copy);
+        assert_eq!(writer.write(b"closed"), Err(KernelError::BrokenPipe));
+    }
+
+    #[test_case]
+    fn a_full_pipe_blocks_the_writer_until_it_is_read() {
+        let (reader, writer) = pipe();
+        let written = Arc::new(AtomicUsize::new(0));
+        let count = written.clone();
+        task::spawn(move || {
+            let bytes = vec![7; PIPE_CAPACITY + 100];
+            count.store(writer.write(&bytes).unwrap(), Ordering::Relaxed);
+        })
+        .unwrap();
+
+        let mut buffer = [0; PIPE_CAPACITY];
+        let mut read = 0;
+        while read < PIPE_CAPACITY + 100 {
+            read += reader.read(&mut buffer).unwrap();
+        }
+        yield_until(|| written.load(Ordering::Relaxed) == PIPE_CAPACITY + 100);
+        assert_eq!(reader.read(&mut buffer), Ok(0));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::csr::{self, Interrupts};
+use crate::devicetree::{DeviceTree, Node};
+use crate::driver::{Driver, DriverError, ProbeResult};
+use crate::hart::{self, hart_id, MAX_HARTS};
+use crate::memory_map::MemoryRegion;
+use crate::once::StaticOnce;
+use crate::trap::{self, TrapCause, TrapFrame};
+use crate::warn;
+use spin::Mutex;
+
+const COMPATIBLE: [&str; 2] = ["riscv,plic0", "sifive,plic-1.0.0"];
+
+pub const MAX_IRQS: usize = 128;
+
+const PRIORITY_OFFSET: u64 = 0x0;
+const ENABLE_OFFSET: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_OFFSET: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM: u64 = 0x4;
+
+static PLIC: StaticOnce<Plic> = StaticOnce::new();
+
+pub type IrqHandler = fn(u32);
+
+static HANDLERS: Mutex<[Option<IrqHandler>; MAX_IRQS]> = Mutex::new([None; MAX_IRQS]);
+
+pub fn mmio_region(tree: &DeviceTree) -> Option<MemoryRegion> {
+    let node = COMPATIBLE
+        .iter()
+        .find_map(|compatible| tree.find_compatible(compatible))?;
+    let (address, size) = node.reg()?.next()?;
+    Some(MemoryRegion::new(address, address + size))
+}
+
+// QEMU's virt machine gives each hart an M-mode context followed by an
+// S-mode one.
+pub fn supervisor_context(hart: usize) -> u64 {
+    2 * hart as u64 + 1
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Plic {
+    base: u64,
+}
+
+impl Plic {
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    fn register(&self, offset: u64) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        unsafe {
+            self.register(PRIORITY_OFFSET + 4 * irq as u64)
+                .write_volatile(priority);
+        }
+    }
+
+    pub fn priority(&self, irq: u32) -> u32 {
+        unsafe {
+            self.register(PRIORITY_OFFSET + 4 * irq as u64)
+                .read_volatile()
+        }
+    }
+
+    fn enable_register(&self, context: u64, irq: u32) -> *mut u32 {
+        self.register(ENABLE_OFFSET + ENABLE_STRIDE * context + 4 * (irq as u64 / 32))
+    }
+
+    pub fn enable(&self, context: u64, irq: u32) {
+        let register = self.enable_register(context, irq);
+        unsafe {
+            register.write_volatile(register.read_volatile() | 1 << (irq % 32));
+        }
+    }
+
+    pub fn disable(&self, context: u64, irq: u32) {
+        let register = self.enable_register(context, irq);
+        unsafe {
+            register.write_volatile(register.read_volatile() & !(1 << (irq % 32)));
+        }
+    }
+
+    pub fn is_enabled(&self, context: u64, irq: u32) -> bool {
+        let register = self.enable_register(context, irq);
+        unsafe { register.read_volatile() & 1 << (irq % 32) != 0 }
+    }
+
+    pub fn set_threshold(&self, context: u64, threshold: u32) {
+        unsafe {
+            self.register(CONTEXT_OFFSET + CONTEXT_STRIDE * context + THRESHOLD)
+                .write_volatile(threshold);
+        }
+    }
+
+    pub fn claim(&self, context: u64) -> Option<u32> {
+        let irq = unsafe {
+            self.register(CONTEXT_OFFSET + CONTEXT_STRIDE * context + CLAIM)
+                .read_volatile()
+        };
+        match irq {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    pub fn complete(&self, context: u64, irq: u32) {
+        unsafe {
+            self.register(CONTEXT_OFFSET + CONTEXT_STRIDE * context + CLAIM)
+                .write_volatile(irq);
+        }
+    }
+}
+
+pub fn get() -> Option<Plic> {
+    PLIC.get().copied()
+}
+
+fn handle_external_interrupt(_frame: &mut TrapFrame) -> bool {
+    let plic = match get() {
+        Some(plic) => plic,
+        None => return false,
+    };
+    let context = supervisor_context(hart_id());
+
+    while let Some(irq) = plic.claim(context) {
+        let handler = HANDLERS.lock().get(irq as usize).copied().flatten();
+        match handler {
+            Some(handler) => handler(irq),
+            None => warn!("Spurious interrupt from IRQ {}", irq),
+        }
+        plic.complete(context, irq);
+    }
+    true
+}
+
+// Routes `irq` to this hart and calls `handler` in interrupt context each
+// time it fires.
+pub fn enable_irq(irq: u32, priority: u32, handler: IrqHandler) {
+    let plic = get().expect("PLIC isn't initialised");
+    if irq == 0 || irq as usize >= MAX_IRQS {
+        panic!("IRQ {} out of range", irq);
+    }
+
+    trap::without_interrupts(|| {
+        HANDLERS.lock()[irq as usize] = Some(handler);
+        plic.set_priority(irq, priority);
+        plic.enable(supervisor_context(hart_id()), irq);
+    });
+}
+
+pub fn disable_irq(irq: u32) {
+    if let Some(plic) = get() {
+        trap::without_interrupts(|| {
+            plic.disable(supervisor_context(hart_id()), irq);
+            HANDLERS.lock()[irq as usize] = None;
+        });
+    }
+}
+
+// The harts `irq` is routed to, as a mask, so a thread that does the work
+// its interrupts bring can be pinned there with task::set_affinity.
+pub fn irq_harts(irq: u32) -> u64 {
+    let Some(plic) = get() else {
+        return 0;
+    };
+    let online = hart::online_harts();
+    (0..MAX_HARTS)
+        .filter(|&hart| online & 1 << hart != 0)
+        .filter(|&hart| plic.is_enabled(supervisor_context(hart), irq))
+        .fold(0, |mask, hart| mask | 1 << hart)
+}
+
+pub const DRIVER: Driver = Driver {
+    name: "plic",
+    compatible: &COMPATIBLE,
+    probe,
+};
+
+// Only one PLIC is driven; any others are left alone.
+fn probe(node: &Node) -> ProbeResult {
+    let (base, _) = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .ok_or(DriverError::MissingReg)?;
+    if get().is_some() {
+        return Err(DriverError::AlreadyProbed);
+    }
+
+    let plic = unsafe { Plic::new(base) };
+    plic.set_threshold(supervisor_context(hart_id()), 0);
+    let _ = PLIC.set(plic);
+
+    trap::register_handler(TrapCause::ExternalInterrupt, handle_external_interrupt);
+    unsafe {
+        csr::sie::set(Interrupts::EXTERNAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devicetree;
+
+    fn ignore_irq(_irq: u32) {}
+
+    #[test_case]
+    fn plic_is_found_in_the_device_tree() {
+        let region = mmio_region(&devicetree::get().unwrap()).unwrap();
+        assert_eq!(region.start, 0x0c00_0000);
+    }
+
+    #[test_case]
+    fn enabling_an_irq_sets_its_priority_and_enable_bit() {
+        let plic = get().unwrap();
+        let context = supervisor_context(hart_id());
+        // An unused virtio slot on QEMU's virt machine.
+        let irq = 8;
+
+        enable_irq(irq, 3, ignore_irq);
+        assert_eq!(plic.priority(irq), 3);
+        assert!(plic.is_enabled(context, irq));
+        assert_eq!(irq_harts(irq), 1 << hart_id());
+
+        disable_irq(irq);
+        assert!(!plic.is_enabled(context, irq));
+    }
+}
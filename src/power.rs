@@ -0,0 +1,73 @@
+use crate::devicetree::{self, DeviceTree};
+use crate::error;
+use crate::sbi::{self, ResetReason, ResetType};
+use core::arch::asm;
+
+const TEST_DEVICE_COMPATIBLE: &str = "sifive,test0";
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+// QEMU's test device, which powers off or resets the machine when a
+// finisher code is written to it.
+pub fn test_device_address(tree: &DeviceTree) -> Option<u64> {
+    let node = tree.find_compatible(TEST_DEVICE_COMPATIBLE)?;
+    let (address, _) = node.reg()?.next()?;
+    Some(address)
+}
+
+fn write_finisher(code: u32) {
+    if let Some(address) = devicetree::get().and_then(|tree| test_device_address(&tree)) {
+        unsafe {
+            (address as *mut u32).write_volatile(code);
+        }
+    }
+}
+
+fn reset(reset_type: ResetType, reason: ResetReason, finisher: u32) -> ! {
+    if sbi::probe_extension(sbi::EXTENSION_SRST) {
+        if let Err(error) = sbi::system_reset(reset_type, reason) {
+            error!("SBI system reset failed: {:?}", error);
+        }
+    }
+    write_finisher(finisher);
+
+    error!("Unable to {:?}, halting", reset_type);
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+pub fn shutdown() -> ! {
+    reset(ResetType::Shutdown, ResetReason::NoReason, FINISHER_PASS)
+}
+
+pub fn reboot() -> ! {
+    reset(ResetType::ColdReboot, ResetReason::NoReason, FINISHER_RESET)
+}
+
+// Firmware reports every shutdown to QEMU as a clean exit, so the test
+// device goes first when there is one: it's the only way to exit with a
+// failure status.
+pub fn shutdown_after_failure() -> ! {
+    write_finisher(FINISHER_FAIL);
+    reset(
+        ResetType::Shutdown,
+        ResetReason::SystemFailure,
+        FINISHER_FAIL,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_test_device_is_found_in_the_device_tree() {
+        let tree = devicetree::get().unwrap();
+        assert_eq!(test_device_address(&tree), Some(0x10_0000));
+    }
+}
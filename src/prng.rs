@@ -0,0 +1,121 @@
+use crate::{cmdline, csr, info};
+use spin::Mutex;
+
+static RNG: Mutex<Xoshiro256StarStar> = Mutex::new(Xoshiro256StarStar { s: [0; 4] });
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub struct Xoshiro256StarStar {
+    s: [u64; 4],
+}
+
+impl Xoshiro256StarStar {
+    pub fn from_seed(seed: u64) -> Self {
+        let mut state = seed;
+        Self {
+            s: [
+                splitmix64(&mut state),
+                splitmix64(&mut state),
+                splitmix64(&mut state),
+                splitmix64(&mut state),
+            ],
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+
+        result
+    }
+
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    pub fn next_bool(&mut self) -> bool {
+        self.next_u64() >> 63 == 1
+    }
+
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+fn boot_entropy() -> u64 {
+    csr::time::read()
+}
+
+pub fn init() -> u64 {
+    let seed = cmdline::get_u64("seed").unwrap_or_else(boot_entropy);
+    *RNG.lock() = Xoshiro256StarStar::from_seed(seed);
+    info!(
+        "Random seed: {:#x} (pass seed={:#x} to reproduce)",
+        seed, seed
+    );
+    seed
+}
+
+pub fn with_rng<R>(f: impl FnOnce(&mut Xoshiro256StarStar) -> R) -> R {
+    f(&mut RNG.lock())
+}
+
+pub fn next_u64() -> u64 {
+    with_rng(|rng| rng.next_u64())
+}
+
+pub fn next_below(bound: u64) -> u64 {
+    with_rng(|rng| rng.next_below(bound))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_same_seed_gives_the_same_sequence() {
+        let mut a = Xoshiro256StarStar::from_seed(1234);
+        let mut b = Xoshiro256StarStar::from_seed(1234);
+        for _ in 0..64 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test_case]
+    fn different_seeds_give_different_sequences() {
+        let mut a = Xoshiro256StarStar::from_seed(1);
+        let mut b = Xoshiro256StarStar::from_seed(2);
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    #[test_case]
+    fn next_below_stays_in_bounds() {
+        for _ in 0..1000 {
+            assert!(next_below(10) < 10);
+        }
+    }
+
+    #[test_case]
+    fn fill_bytes_handles_partial_chunks() {
+        let mut rng = Xoshiro256StarStar::from_seed(7);
+        let mut bytes = [0u8; 13];
+        rng.fill_bytes(&mut bytes);
+        assert!(bytes[8..].iter().any(|&b| b != 0));
+    }
+}
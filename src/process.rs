@@ -0,0 +1,736 @@
+use crate::address_space::AddressSpace;
+use crate::error::{KernelError, KernelResult};
+use crate::fd_table::FdTable;
+use crate::lock::{RwSpinLock, SpinLockIrq};
+use crate::rusage::{ExitRecord, Limits, PageCharge, ResourceUsage};
+use crate::satp::Satp;
+use crate::signal::Signals;
+use crate::slab::{SlabBox, SlabCache};
+use crate::task::{self, ThreadId};
+use crate::trap::{TrapFrame, TRAP_FRAMES};
+use crate::wait_queue::WaitQueue;
+use crate::{print, println, timer, user};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// As on Linux, PIDs wrap at this and are handed out again once reaped.
+const PID_LIMIT: u64 = 32768;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+// Adopts the children of processes that exit before them.
+pub const INIT_PID: Pid = Pid(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    // Its thread hasn't run yet.
+    Created,
+    // Running or ready to.
+    Running,
+    Blocked,
+    // Exited, but its exit code hasn't been collected.
+    Zombie,
+}
+
+// Each process runs on a kernel thread of its own, whose stack is the
+// process's kernel stack.
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    name: String,
+    thread: Option<ThreadId>,
+    satp: Satp,
+    // Dropped as soon as the process exits, so a zombie holds no memory.
+    address_space: Option<AddressSpace>,
+    // Emptied when it exits, closing everything it had open.
+    files: FdTable,
+    // The user context it starts from.
+    trap_frame: SlabBox<TrapFrame>,
+    signals: Signals,
+    limits: Limits,
+    // Pages it answers for beyond those it has mapped.
+    page_charge: PageCharge,
+    // Counted as it runs. Its CPU time and pages are filled in when it
+    // exits, and until then come from its thread and address space.
+    usage: ResourceUsage,
+    // What its children that it has waited for used, theirs included.
+    children_usage: ResourceUsage,
+    // Its thread's, once it has started.
+    cpu_time: Option<Arc<AtomicU64>>,
+    started: bool,
+    // Its parent exited first and init wasn't there to adopt it, so nothing
+    // will wait for it.
+    orphaned: bool,
+    exit_code: Option<i64>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ProcessState {
+        match (self.exit_code, self.started, self.thread) {
+            (Some(_), _, _) => ProcessState::Zombie,
+            (None, false, _) => ProcessState::Created,
+            (None, true, Some(thread)) if task::is_blocked(thread) => ProcessState::Blocked,
+            (None, true, _) => ProcessState::Running,
+        }
+    }
+
+    // None once it has exited.
+    pub fn address_space(&self) -> Option<&AddressSpace> {
+        self.address_space.as_ref()
+    }
+
+    pub fn address_space_mut(&mut self) -> Option<&mut AddressSpace> {
+        self.address_space.as_mut()
+    }
+
+    pub fn files(&self) -> &FdTable {
+        &self.files
+    }
+
+    pub fn files_mut(&mut self) -> &mut FdTable {
+        &mut self.files
+    }
+
+    pub fn trap_frame(&self) -> &TrapFrame {
+        &self.trap_frame
+    }
+
+    pub fn trap_frame_mut(&mut self) -> &mut TrapFrame {
+        &mut self.trap_frame
+    }
+
+    pub fn signals(&self) -> &Signals {
+        &self.signals
+    }
+
+    pub fn signals_mut(&mut self) -> &mut Signals {
+        &mut self.signals
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn page_charge(&self) -> &PageCharge {
+        &self.page_charge
+    }
+
+    // Holds back anything opened or mapped from now on, though not what
+    // the process has already. Fails with NotPermitted if it would
+    // raise a hard limit, and InvalidArgument if it would put a limit above
+    // its hard one.
+    pub fn set_limits(&mut self, limits: Limits) -> KernelResult<()> {
+        if limits.max_pages > self.limits.max_pages || limits.max_files > self.limits.max_files {
+            return Err(KernelError::NotPermitted);
+        }
+        if limits.pages > limits.max_pages || limits.files > limits.max_files {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.files.set_limit(limits.files)?;
+        if let Some(space) = self.address_space.as_mut() {
+            space.set_page_limit(limits.pages);
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        let mut usage = self.usage;
+        if self.exit_code.is_some() {
+            return usage;
+        }
+        if let Some(cpu_time) = &self.cpu_time {
+            usage.cpu_ticks = cpu_time.load(Ordering::Relaxed);
+            if self.thread.is_some() && self.thread == task::current_id() {
+                usage.cpu_ticks += task::running_time();
+            }
+        }
+        if let Some(space) = &self.address_space {
+            usage.resident_pages = space.page_count() as u64;
+            usage.peak_resident_pages = usage
+                .peak_resident_pages
+                .max(space.peak_page_count() as u64);
+        }
+        usage
+    }
+
+    // For counting syscalls and faults as they happen.
+    pub fn usage_mut(&mut self) -> &mut ResourceUsage {
+        &mut self.usage
+    }
+
+    pub fn children_usage(&self) -> ResourceUsage {
+        self.children_usage
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, SlabBox<Process>>,
+    next_pid: u64,
+}
+
+impl ProcessTable {
+    // The next unused PID after the last one handed out.
+    fn allocate_pid(&mut self) -> Option<Pid> {
+        for _ in 1..PID_LIMIT {
+            let pid = Pid(self.next_pid);
+            self.next_pid = if self.next_pid + 1 == PID_LIMIT {
+                1
+            } else {
+                self.next_pid + 1
+            };
+            if !self.processes.contains_key(&pid) {
+                return Some(pid);
+            }
+        }
+        None
+    }
+
+    fn current(&self) -> Option<&Process> {
+        let thread = task::current_id()?;
+        self.processes
+            .values()
+            .find(|process| process.thread == Some(thread))
+            .map(|process| &**process)
+    }
+
+    fn current_mut(&mut self) -> Option<&mut Process> {
+        let thread = task::current_id()?;
+        self.processes
+            .values_mut()
+            .find(|process| process.thread == Some(thread))
+            .map(|process| &mut **process)
+    }
+}
+
+// Where every process in the table is kept.
+pub static PROCESS_STRUCTS: SpinLockIrq<SlabCache<Process>> =
+    SpinLockIrq::new(SlabCache::new("processes", None, None));
+
+static PROCESSES: RwSpinLock<ProcessTable> = RwSpinLock::new(ProcessTable {
+    processes: BTreeMap::new(),
+    next_pid: 1,
+});
+
+// Notified whenever a process exits, for parents waiting on children.
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+// Starts `entry` on a new thread running in `address_space`. The process
+// exits with whatever it returns, if it doesn't exit first.
+pub fn spawn(
+    name: &str,
+    address_space: AddressSpace,
+    entry: impl FnOnce() -> i64 + Send + 'static,
+) -> KernelResult<Pid> {
+    create(
+        name,
+        address_space,
+        FdTable::with_console(),
+        TrapFrame::zeroed(),
+        Signals::new(),
+        Limits::from_cmdline(),
+        entry,
+    )
+}
+
+// Starts a process in user mode with `frame`'s registers, and standard
+// input, output and error on the console.
+pub fn spawn_user(name: &str, address_space: AddressSpace, frame: TrapFrame) -> KernelResult<Pid> {
+    create(
+        name,
+        address_space,
+        FdTable::with_console(),
+        frame,
+        Signals::new(),
+        Limits::from_cmdline(),
+        enter_user,
+    )
+}
+
+fn enter_user() -> i64 {
+    let frame = with_current(|process| process.trap_frame().clone())
+        .expect("user process missing from the table");
+    user::enter(&frame)
+}
+
+fn create(
+    name: &str,
+    mut address_space: AddressSpace,
+    mut files: FdTable,
+    trap_frame: TrapFrame,
+    signals: Signals,
+    limits: Limits,
+    entry: impl FnOnce() -> i64 + Send + 'static,
+) -> KernelResult<Pid> {
+    files.set_limit(limits.files)?;
+    let page_charge = PageCharge::new();
+    address_space.set_page_limit(limits.pages);
+    address_space.set_page_charge(page_charge.clone());
+    let trap_frame = SlabBox::new(&TRAP_FRAMES, trap_frame)?;
+    let pid = {
+        let mut table = PROCESSES.write();
+        let pid = table.allocate_pid().ok_or(KernelError::WouldBlock)?;
+        let parent = table.current().map(|process| process.pid);
+        let process = SlabBox::new(
+            &PROCESS_STRUCTS,
+            Process {
+                pid,
+                parent,
+                name: String::from(name),
+                thread: None,
+                satp: address_space.satp(),
+                address_space: Some(address_space),
+                files,
+                trap_frame,
+                signals,
+                limits,
+                page_charge,
+                usage: ResourceUsage::new(),
+                children_usage: ResourceUsage::new(),
+                cpu_time: None,
+                started: false,
+                orphaned: false,
+                exit_code: None,
+            },
+        )?;
+        table.processes.insert(pid, process);
+        pid
+    };
+
+    let thread = task::spawn_named("process", move || {
+        start(pid);
+        let code = entry();
+        exit(code)
+    });
+    let mut table = PROCESSES.write();
+    match thread {
+        Ok(thread) => {
+            if let Some(process) = table.processes.get_mut(&pid) {
+                process.thread = Some(thread);
+            }
+            Ok(pid)
+        }
+        Err(e) => {
+            table.processes.remove(&pid);
+            Err(e.into())
+        }
+    }
+}
+
+fn start(pid: Pid) {
+    let satp = {
+        let mut table = PROCESSES.write();
+        let process = table
+            .processes
+            .get_mut(&pid)
+            .expect("started a missing process");
+        process.started = true;
+        // The spawner may not have got round to recording this yet.
+        process.thread = task::current_id();
+        process.cpu_time = task::cpu_time_counter();
+        process.satp
+    };
+    task::set_address_space(Some(satp));
+}
+
+pub fn current_pid() -> Option<Pid> {
+    PROCESSES.read().current().map(|process| process.pid)
+}
+
+// Runs `f` on the current process, if the running thread belongs to one.
+// Any TLB shootdown its page table changes need goes out after the table is
+// unlocked, as the harts it waits on may be spinning on the lock.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let (result, shootdown) = {
+        let mut table = PROCESSES.write();
+        let process = table.current_mut()?;
+        let result = f(process);
+        let shootdown = process
+            .address_space
+            .as_mut()
+            .and_then(AddressSpace::take_shootdown);
+        (result, shootdown)
+    };
+    if let Some(shootdown) = shootdown {
+        shootdown.send();
+    }
+    Some(result)
+}
+
+// Starts a copy of the current process, sharing its memory copy-on-write
+// and its open files, signal handlers and limits, that carries on from
+// `frame` but sees a return value of 0.
+pub fn fork(frame: &TrapFrame) -> KernelResult<Pid> {
+    let (name, address_space, files, signals, limits) = with_current(|process| {
+        let address_space = process
+            .address_space
+            .as_mut()
+            .ok_or(KernelError::NotFound)?
+            .fork()?;
+        Ok::<_, KernelError>((
+            process.name.clone(),
+            address_space,
+            process.files.clone(),
+            process.signals.fork(),
+            process.limits,
+        ))
+    })
+    .ok_or(KernelError::NotFound)??;
+
+    let mut frame = frame.clone();
+    frame.set_return_value(0);
+    create(
+        &name,
+        address_space,
+        files,
+        frame,
+        signals,
+        limits,
+        enter_user,
+    )
+}
+
+// Swaps the current process's memory for `address_space` and frees the
+// old. Starting the new program is up to the caller.
+pub fn exec(name: &str, mut address_space: AddressSpace) -> KernelResult<()> {
+    let satp = address_space.satp();
+    let old = with_current(|process| {
+        process.name = String::from(name);
+        process.satp = satp;
+        process.signals.exec();
+        // Keeps the old program's peak.
+        process.usage = process.usage();
+        address_space.set_page_limit(process.limits.pages);
+        address_space.set_page_charge(process.page_charge.clone());
+        process.address_space.replace(address_space)
+    })
+    .ok_or(KernelError::NotFound)?;
+    // Onto the new page tables before the old ones are freed.
+    task::set_address_space(Some(satp));
+    drop(old);
+    Ok(())
+}
+
+// Ends the current process, leaving a zombie for its parent to reap.
+pub fn exit(code: i64) -> ! {
+    let (pid, usage, address_space, files) = with_current(|process| {
+        process.usage = process.usage();
+        process.usage.resident_pages = 0;
+        (
+            process.pid,
+            process.usage,
+            process.address_space.take(),
+            core::mem::take(&mut process.files),
+        )
+    })
+    .expect("exit called outside a process");
+    // Off its page tables before they're freed.
+    task::set_address_space(None);
+    drop(address_space);
+    drop(files);
+    {
+        let mut table = PROCESSES.write();
+        let process = table.current_mut().expect("exit called outside a process");
+        let (pid, orphaned) = (process.pid, process.orphaned);
+        if orphaned {
+            table.processes.remove(&pid);
+        } else {
+            process.exit_code = Some(code);
+        }
+
+        // Its children that have already exited are reaped now. Init
+        // adopts the rest if it's still running, and otherwise they're
+        // reaped as they exit.
+        table
+            .processes
+            .retain(|_, child| child.parent != Some(pid) || child.exit_code.is_none());
+        let adopter = table
+            .processes
+            .get(&INIT_PID)
+            .filter(|init| init.pid != pid && init.exit_code.is_none())
+            .map(|init| init.pid);
+        for child in table.processes.values_mut() {
+            if child.parent == Some(pid) {
+                child.parent = adopter;
+                child.orphaned = adopter.is_none();
+            }
+        }
+    }
+    CHILD_EXITED.notify_all();
+    ExitRecord {
+        pid: pid.0,
+        exit_code: code,
+        usage,
+    }
+    .report();
+    task::exit()
+}
+
+// Waits for a child of the current process to exit, any child or the one
+// with `pid`, then reaps it. Its usage comes back with its own reaped
+// children's added in. Fails with NoChildren if there's none to wait for,
+// except that init waiting for any child sleeps until an orphan is handed
+// to it.
+pub fn wait_child(pid: Option<Pid>) -> KernelResult<(Pid, i64, ResourceUsage)> {
+    let parent = current_pid().ok_or(KernelError::NotFound)?;
+    let mut result = Err(KernelError::NoChildren);
+    CHILD_EXITED.wait_until_interruptible(|| {
+        let mut table = PROCESSES.write();
+        let mut children = table
+            .processes
+            .values()
+            .filter(|process| {
+                process.parent == Some(parent) && pid.is_none_or(|pid| process.pid == pid)
+            })
+            .peekable();
+        if children.peek().is_none() {
+            return parent != INIT_PID || pid.is_some();
+        }
+        match children.find_map(|process| process.exit_code.map(|code| (process.pid, code))) {
+            Some((child, code)) => {
+                let child_process = table.processes.remove(&child).unwrap();
+                let mut usage = child_process.usage;
+                usage.accumulate(&child_process.children_usage);
+                if let Some(parent) = table.processes.get_mut(&parent) {
+                    parent.children_usage.accumulate(&usage);
+                }
+                result = Ok((child, code, usage));
+                true
+            }
+            None => false,
+        }
+    })?;
+    result
+}
+
+// Leaves `signal` pending for the process, which acts on it the next time
+// it returns to user mode. If it's blocked in an interruptible wait, that
+// wakes and fails with Interrupted so it can.
+pub fn send_signal(pid: Pid, signal: u32) -> KernelResult<()> {
+    let thread = {
+        let mut table = PROCESSES.write();
+        let process = table
+            .processes
+            .get_mut(&pid)
+            .filter(|process| process.exit_code.is_none())
+            .ok_or(KernelError::NoSuchProcess)?;
+        process.signals.raise(signal)?;
+        process.thread.filter(|_| process.signals.has_deliverable())
+    };
+    if let Some(thread) = thread {
+        task::unblock(thread);
+    }
+    Ok(())
+}
+
+// Whether the current process has a signal to act on, which interruptible
+// waits give up for.
+pub fn signal_pending() -> bool {
+    PROCESSES
+        .read()
+        .current()
+        .is_some_and(|process| process.signals.has_deliverable())
+}
+
+pub fn state(pid: Pid) -> Option<ProcessState> {
+    PROCESSES
+        .read()
+        .processes
+        .get(&pid)
+        .map(|process| process.state())
+}
+
+// Collects a zombie's exit code, freeing its PID. None if it hasn't exited.
+pub fn reap(pid: Pid) -> Option<i64> {
+    let mut table = PROCESSES.write();
+    let code = table.processes.get(&pid)?.exit_code?;
+    table.processes.remove(&pid);
+    Some(code)
+}
+
+// CPU time is in milliseconds, and pages are those mapped now and the most
+// there have been.
+pub fn report() {
+    let processes: Vec<_> = PROCESSES
+        .read()
+        .processes
+        .values()
+        .map(|process| {
+            (
+                process.pid,
+                process.parent,
+                process.state(),
+                process.usage(),
+                process.files.open_count(),
+                process.name.clone(),
+            )
+        })
+        .collect();
+
+    println!(
+        "{:>6} {:>6} {:<8} {:>8} {:>6} {:>6} {:>4} name",
+        "pid", "ppid", "state", "cpu", "pages", "peak", "fds"
+    );
+    for (pid, parent, state, usage, files, name) in processes {
+        println!(
+            "{:>6} {:>6} {:<8} {:>8} {:>6} {:>6} {:>4} {}",
+            pid.0,
+            parent.map_or(0, |parent| parent.0),
+            match state {
+                ProcessState::Created => "created",
+                ProcessState::Running => "running",
+                ProcessState::Blocked => "blocked",
+                ProcessState::Zombie => "zombie",
+            },
+            usage.cpu_ticks * 1000 / timer::timebase_frequency(),
+            usage.resident_pages,
+            usage.peak_resident_pages,
+            files,
+            name
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::address_space::{self, USER_START};
+    use crate::page_allocator::PAGE_SIZE;
+    use crate::page_table::PageTableEntryMode;
+    use crate::task::test::yield_until;
+    use crate::{pipe, signal};
+
+    pub fn wait_for(pid: Pid) -> i64 {
+        yield_until(|| state(pid) == Some(ProcessState::Zombie));
+        reap(pid).unwrap()
+    }
+
+    #[test_case]
+    fn a_process_runs_on_its_own_page_tables() {
+        let space = AddressSpace::new().unwrap();
+        let satp = space.satp();
+        let pid = spawn("satp", space, || {
+            address_space::current_satp().bits() as i64
+        })
+        .unwrap();
+
+        assert_eq!(wait_for(pid), satp.bits() as i64);
+        assert_eq!(state(pid), None);
+        assert_eq!(address_space::current_satp(), address_space::kernel_satp());
+    }
+
+    #[test_case]
+    fn a_signal_kills_a_process_blocked_on_an_empty_pipe() {
+        let (reader, writer) = pipe::pipe();
+        let pid = spawn("blocked", AddressSpace::new().unwrap(), move || {
+            let mut buffer = [0; 4];
+            if reader.read(&mut buffer) == Err(KernelError::Interrupted) {
+                // As it would be on its way back to user mode.
+                signal::deliver(&mut TrapFrame::zeroed());
+            }
+            0
+        })
+        .unwrap();
+
+        yield_until(|| state(pid) == Some(ProcessState::Blocked));
+        send_signal(pid, signal::SIGKILL).unwrap();
+        assert_eq!(wait_for(pid), signal::exit_code(signal::SIGKILL));
+        drop(writer);
+    }
+
+    #[test_case]
+    fn live_processes_have_distinct_pids() {
+        let first = spawn("first", AddressSpace::new().unwrap(), || 1).unwrap();
+        let second = spawn("second", AddressSpace::new().unwrap(), || 2).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(wait_for(first), 1);
+        assert_eq!(wait_for(second), 2);
+    }
+
+    #[test_case]
+    fn usage_counts_the_pages_mapped_and_time_run() {
+        let pid = spawn("usage", AddressSpace::new().unwrap(), || {
+            with_current(|process| {
+                let space = process.address_space_mut().unwrap();
+                space
+                    .map(USER_START, PageTableEntryMode::ReadWrite)
+                    .unwrap();
+                let usage = process.usage();
+                (usage.resident_pages == 1 && usage.cpu_ticks > 0) as i64
+            })
+            .unwrap()
+        })
+        .unwrap();
+        assert_eq!(wait_for(pid), 1);
+    }
+
+    #[test_case]
+    fn limits_hold_back_pages_and_descriptors() {
+        let pid = spawn("limits", AddressSpace::new().unwrap(), || {
+            with_current(|process| {
+                let limits = Limits {
+                    pages: 1,
+                    files: 3,
+                    ..process.limits()
+                };
+                process.set_limits(limits).unwrap();
+                let space = process.address_space_mut().unwrap();
+                space
+                    .map(USER_START, PageTableEntryMode::ReadWrite)
+                    .unwrap();
+                let page = space.map(USER_START + PAGE_SIZE, PageTableEntryMode::ReadWrite);
+                let fd = process.files_mut().dup(0);
+                (page.is_err() && fd == Err(KernelError::TooManyFiles)) as i64
+            })
+            .unwrap()
+        })
+        .unwrap();
+        assert_eq!(wait_for(pid), 1);
+    }
+
+    #[test_case]
+    fn hard_limits_can_be_lowered_but_not_raised() {
+        let pid = spawn("hard-limits", AddressSpace::new().unwrap(), || {
+            with_current(|process| {
+                let lowered = Limits {
+                    pages: 4,
+                    files: 3,
+                    max_pages: 8,
+                    max_files: 3,
+                };
+                process.set_limits(lowered).unwrap();
+                let raised = process.set_limits(Limits {
+                    max_pages: 9,
+                    ..lowered
+                });
+                let above_hard = process.set_limits(Limits {
+                    pages: 9,
+                    ..lowered
+                });
+                (raised == Err(KernelError::NotPermitted)
+                    && above_hard == Err(KernelError::InvalidArgument)
+                    && process.limits() == lowered) as i64
+            })
+            .unwrap()
+        })
+        .unwrap();
+        assert_eq!(wait_for(pid), 1);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::error::KernelResult;
+use crate::info;
+use crate::prng::Xoshiro256StarStar;
+use crate::vfs::{File, OpenFile, Stat};
+use crate::{cmdline, devfs, timer, virtio_rng};
+use spin::Mutex;
+
+// Used when there's no entropy device. Seeded once at boot, so its output
+// is only as unpredictable as the seed.
+static FALLBACK: Mutex<Option<Xoshiro256StarStar>> = Mutex::new(None);
+
+fn fallback_seed() -> u64 {
+    let mut seed = [0; 8];
+    if virtio_rng::fill(&mut seed) {
+        return u64::from_le_bytes(seed);
+    }
+    timer::read_time()
+}
+
+pub fn has_hardware_entropy() -> bool {
+    virtio_rng::is_present()
+}
+
+pub fn random_bytes(bytes: &mut [u8]) {
+    if virtio_rng::fill(bytes) {
+        return;
+    }
+    FALLBACK
+        .lock()
+        .get_or_insert_with(|| Xoshiro256StarStar::from_seed(fallback_seed()))
+        .fill_bytes(bytes);
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+// /dev/random and /dev/urandom, which are the same here. What's written
+// is accepted but not mixed in.
+struct RandomDevice;
+
+impl File for RandomDevice {
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        random_bytes(buffer);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        Ok(bytes.len())
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(devfs::device_stat(0))
+    }
+}
+
+pub fn init() {
+    let seed = cmdline::get_u64("rand_seed").unwrap_or_else(fallback_seed);
+    *FALLBACK.lock() = Some(Xoshiro256StarStar::from_seed(seed));
+    if !has_hardware_entropy() {
+        info!("No entropy device, random numbers come from a seeded PRNG");
+    }
+    for name in ["random", "urandom"] {
+        devfs::register(name, || Ok(OpenFile::new(RandomDevice))).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::{self, OpenOptions};
+
+    #[test_case]
+    fn random_bytes_differ_between_calls() {
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        random_bytes(&mut a);
+        random_bytes(&mut b);
+        assert_ne!(a, b);
+    }
+
+    #[test_case]
+    fn dev_random_reads_random_bytes() {
+        let options = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        let random = vfs::open("/dev/urandom", options).unwrap();
+        let mut bytes = [0u8; 32];
+        assert_eq!(random.read(&mut bytes), Ok(32));
+        assert_ne!(bytes, [0; 32]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::fd_table::MAX_FILES;
+use crate::{cmdline, print, println};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub cpu_ticks: u64,
+    pub peak_resident_pages: u64,
+    pub resident_pages: u64,
+    pub syscalls: u64,
+    pub faults: u64,
+}
+
+impl ResourceUsage {
+    pub const fn new() -> Self {
+        Self {
+            cpu_ticks: 0,
+            peak_resident_pages: 0,
+            resident_pages: 0,
+            syscalls: 0,
+            faults: 0,
+        }
+    }
+
+    pub fn record_syscall(&mut self) {
+        self.syscalls += 1;
+    }
+
+    pub fn record_fault(&mut self) {
+        self.faults += 1;
+    }
+
+    // Fold a reaped child's usage into its parent's children total.
+    pub fn accumulate(&mut self, child: &ResourceUsage) {
+        self.cpu_ticks += child.cpu_ticks;
+        self.peak_resident_pages = self.peak_resident_pages.max(child.peak_resident_pages);
+        self.syscalls += child.syscalls;
+        self.faults += child.faults;
+    }
+}
+
+// The most a process may hold at once. A new process gets the limits from
+// maxpages= and maxfds= on the command line, and a forked one its parent's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Pages mapped into its address space, shared ones included, plus the
+    // shared memory it has created.
+    pub pages: usize,
+    // One more than the highest descriptor it can have.
+    pub files: usize,
+    // The hard limits, which the ones above can be raised as far as. The
+    // process can lower these too, but never raise them again.
+    pub max_pages: usize,
+    pub max_files: usize,
+}
+
+impl Limits {
+    pub fn from_cmdline() -> Self {
+        let pages = cmdline::get_u64("maxpages").map_or(usize::MAX, |pages| pages as usize);
+        let files =
+            cmdline::get_u64("maxfds").map_or(MAX_FILES, |files| (files as usize).min(MAX_FILES));
+        Self {
+            pages,
+            files,
+            max_pages: pages,
+            max_files: files,
+        }
+    }
+}
+
+// Pages a process answers for without having them mapped, such as shared
+// memory it created. They count against its page limit, and stay charged
+// until they're freed, whether or not the process is still around.
+#[derive(Debug, Clone, Default)]
+pub struct PageCharge(Arc<AtomicUsize>);
+
+impl PageCharge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pages(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, pages: usize) {
+        self.0.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, pages: usize) {
+        self.0.fetch_sub(pages, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExitRecord {
+    pub pid: u64,
+    pub exit_code: i64,
+    pub usage: ResourceUsage,
+}
+
+impl ExitRecord {
+    pub fn report(&self) {
+        if !cmdline::has("rusage") {
+            return;
+        }
+
+        println!(
+            "pid {} exited with {}: cpu {} ticks, peak {} pages, {} syscalls, {} faults",
+            self.pid,
+            self.exit_code,
+            self.usage.cpu_ticks,
+            self.usage.peak_resident_pages,
+            self.usage.syscalls,
+            self.usage.faults
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn accumulating_a_child_sums_counters_and_keeps_the_larger_peak() {
+        let mut parent = ResourceUsage::new();
+        parent.record_syscall();
+        parent.peak_resident_pages = 2;
+
+        let mut child = ResourceUsage::new();
+        child.record_syscall();
+        child.record_fault();
+        child.peak_resident_pages = 5;
+
+        parent.accumulate(&child);
+        assert_eq!(parent.syscalls, 2);
+        assert_eq!(parent.faults, 1);
+        assert_eq!(parent.peak_resident_pages, 5);
+    }
+}
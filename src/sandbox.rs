@@ -0,0 +1,128 @@
+use crate::address::{PhysFrame, VirtPage};
+use crate::address_space::AddressSpace;
+use crate::heap;
+use crate::page_allocator::test::test_page_allocator;
+use crate::page_allocator::{PageAllocator, PAGE_ALLOCATOR};
+use crate::page_table::PageTableEntryMode;
+use crate::satp::Satp;
+use crate::task;
+use crate::VIRTUAL_MEMORY;
+use alloc::vec::Vec;
+
+// Somewhere for a test to make changes that the tests after it won't see:
+// an allocator over pages of the scratch region, which nothing else hands
+// out, and, if it asks for one, an address space of its own. Dropping it
+// puts back the address space the thread was on, undoes the kernel
+// mappings made through it and shrinks the heap back to its old size, as
+// far as what's still allocated allows.
+pub struct Sandbox {
+    pub allocator: PageAllocator,
+    space: Option<AddressSpace>,
+    satp: Option<Satp>,
+    heap_size: usize,
+    // Mapped in the kernel's page tables, which every address space shares.
+    kernel_pages: Vec<VirtPage>,
+    // Unmapped, but kept from PAGE_ALLOCATOR until the sandbox goes.
+    frames: Vec<PhysFrame>,
+}
+
+impl Sandbox {
+    pub fn new(pages: u64) -> Self {
+        Self {
+            allocator: test_page_allocator(pages),
+            space: None,
+            satp: task::address_space(),
+            heap_size: heap::stats().size,
+            kernel_pages: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    // Switches the thread to the sandbox's address space, creating it the
+    // first time. Its own pages come from PAGE_ALLOCATOR and go back there.
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        let space = self
+            .space
+            .get_or_insert_with(|| AddressSpace::new().expect("no address space for the sandbox"));
+        task::set_address_space(Some(space.satp()));
+        space
+    }
+
+    // Maps a fresh page from PAGE_ALLOCATOR at `page` in the kernel's page
+    // tables.
+    pub fn map_kernel(&mut self, page: VirtPage, mode: PageTableEntryMode) {
+        VIRTUAL_MEMORY
+            .lock()
+            .get_mut()
+            .unwrap()
+            .map(page, mode, &mut PAGE_ALLOCATOR.lock())
+            .expect("no page for the sandbox");
+        self.kernel_pages.push(page);
+    }
+
+    // The page that was mapped stays allocated, so mapping `page` again
+    // can't get it back.
+    pub fn unmap_kernel(&mut self, page: VirtPage) {
+        let frame = VIRTUAL_MEMORY
+            .lock()
+            .get_mut()
+            .unwrap()
+            .unmap(page)
+            .unwrap();
+        self.kernel_pages.retain(|&mapped| mapped != page);
+        self.frames.push(frame);
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        task::set_address_space(self.satp);
+        self.space = None;
+        while let Some(page) = self.kernel_pages.pop() {
+            self.unmap_kernel(page);
+        }
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        for frame in core::mem::take(&mut self.frames) {
+            allocator.dealloc(frame);
+        }
+        drop(allocator);
+        heap::shrink_to(self.heap_size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address_space::{self, USER_START};
+
+    #[test_case]
+    fn the_address_space_is_private_until_dropped() {
+        let mut sandbox = Sandbox::new(1);
+        assert!(sandbox.allocator.alloc().is_ok());
+
+        let space = sandbox.address_space();
+        space
+            .map(USER_START, PageTableEntryMode::ReadWrite)
+            .unwrap();
+        let satp = space.satp();
+        assert_eq!(address_space::current_satp(), satp);
+
+        drop(sandbox);
+        assert_eq!(address_space::current_satp(), address_space::kernel_satp());
+    }
+
+    #[test_case]
+    fn kernel_mappings_and_heap_growth_are_undone() {
+        let page: VirtPage = 0x9100_0000.try_into().unwrap();
+        let heap_size = heap::stats().size;
+        let mut sandbox = Sandbox::new(1);
+        sandbox.map_kernel(page, PageTableEntryMode::ReadWrite);
+        let big = Vec::<u8>::with_capacity(heap_size + 4096);
+        drop(big);
+
+        drop(sandbox);
+        let vm = VIRTUAL_MEMORY.lock();
+        assert_eq!(vm.get().unwrap().translate(page.start_address()), None);
+        assert_eq!(heap::stats().size, heap_size);
+    }
+}
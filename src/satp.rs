@@ -0,0 +1,150 @@
+use crate::address::PhysFrame;
+use crate::{csr, tlb};
+use core::fmt;
+
+const MODE_SHIFT: u64 = 60;
+const ASID_SHIFT: u64 = 44;
+const PPN_BITS: u64 = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SatpMode {
+    Bare = 0,
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+#[derive(Debug)]
+pub enum SatpError {
+    BareWithTables,
+    UnknownMode(u64),
+    Unsupported(SatpMode),
+}
+
+// The supervisor address translation register: which page tables are in
+// use, and the address space ID the TLB tags their entries with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Satp(u64);
+
+impl Satp {
+    // Bare means no translation, so has neither tables nor an ASID.
+    pub fn new(mode: SatpMode, asid: u16, root: &PhysFrame) -> Result<Self, SatpError> {
+        if mode == SatpMode::Bare {
+            return Err(SatpError::BareWithTables);
+        }
+        Ok(Self(
+            (mode as u64) << MODE_SHIFT | (asid as u64) << ASID_SHIFT | root.number(),
+        ))
+    }
+
+    pub const fn bare() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn mode(self) -> Result<SatpMode, SatpError> {
+        match self.0 >> MODE_SHIFT {
+            0 => Ok(SatpMode::Bare),
+            8 => Ok(SatpMode::Sv39),
+            9 => Ok(SatpMode::Sv48),
+            10 => Ok(SatpMode::Sv57),
+            mode => Err(SatpError::UnknownMode(mode)),
+        }
+    }
+
+    pub fn asid(self) -> u16 {
+        (self.0 >> ASID_SHIFT) as u16
+    }
+
+    pub fn root(self) -> PhysFrame {
+        PhysFrame::from_number(self.0 & ((1 << PPN_BITS) - 1))
+    }
+}
+
+pub fn read_satp() -> Satp {
+    csr::satp::read()
+}
+
+// A mode that isn't one at all is refused before the CSR is touched. A
+// hart ignores the whole write if it doesn't implement the mode. The fence
+// flushes whatever the TLB cached from the old tables, which without ASIDs
+// in use means all of it.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn write_satp(satp: Satp) -> Result<(), SatpError> {
+    let mode = satp.mode()?;
+    csr::satp::write(satp);
+    if read_satp().mode()? != mode {
+        return Err(SatpError::Unsupported(mode));
+    }
+    tlb::flush_local_all();
+    Ok(())
+}
+
+impl fmt::Display for Satp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let root = self.root().start_address().as_u64();
+        match self.mode() {
+            Ok(SatpMode::Bare) => f.write_str("bare"),
+            Ok(mode) => write!(f, "{:?} asid {} root {:#012x}", mode, self.asid(), root),
+            Err(_) => write!(f, "mode {} ({:#018x})", self.0 >> MODE_SHIFT, self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address::PhysAddr;
+    use crate::trap;
+    use alloc::format;
+
+    #[test_case]
+    fn satp_fields_round_trip() {
+        let root = PhysFrame::containing_address(PhysAddr::new(0x8020_1000));
+        let satp = Satp::new(SatpMode::Sv39, 3, &root).unwrap();
+        assert_eq!(satp.bits(), (8 << 60) | (3 << 44) | 0x80201);
+        assert_eq!(satp.mode().unwrap(), SatpMode::Sv39);
+        assert_eq!(satp.asid(), 3);
+        assert_eq!(satp.root(), root);
+        assert!(matches!(
+            Satp::from_bits(5 << 60).mode(),
+            Err(SatpError::UnknownMode(5))
+        ));
+        assert!(matches!(
+            Satp::new(SatpMode::Bare, 0, &root),
+            Err(SatpError::BareWithTables)
+        ));
+    }
+
+    #[test_case]
+    fn rewriting_the_current_satp_succeeds() {
+        let current = read_satp();
+        trap::without_interrupts(|| unsafe { write_satp(current) }).unwrap();
+        assert_eq!(read_satp(), current);
+    }
+
+    #[test_case]
+    fn unknown_modes_are_never_written() {
+        let current = read_satp();
+        let unknown = Satp::from_bits(5 << 60 | current.root().number());
+        let written = trap::without_interrupts(|| unsafe { write_satp(unknown) });
+        assert!(matches!(written, Err(SatpError::UnknownMode(5))));
+        assert_eq!(read_satp(), current);
+    }
+
+    #[test_case]
+    fn satp_shows_the_mode_and_root_table() {
+        assert_eq!(format!("{}", Satp::bare()), "bare");
+        assert_eq!(
+            format!("{}", Satp::from_bits((8 << 60) | (3 << 44) | 0x80201)),
+            "Sv39 asid 3 root 0x0080201000"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use core::arch::asm;
+
+pub const EXTENSION_BASE: u64 = 0x10;
+pub const EXTENSION_TIME: u64 = 0x5449_4d45;
+pub const EXTENSION_IPI: u64 = 0x0073_5049;
+pub const EXTENSION_RFENCE: u64 = 0x5246_4e43;
+pub const EXTENSION_SRST: u64 = 0x5352_5354;
+pub const EXTENSION_HSM: u64 = 0x0048_534d;
+pub const EXTENSION_DBCN: u64 = 0x4442_434e;
+
+const BASE_PROBE_EXTENSION: u64 = 3;
+const TIME_SET_TIMER: u64 = 0;
+const IPI_SEND_IPI: u64 = 0;
+const RFENCE_REMOTE_FENCE_I: u64 = 0;
+const RFENCE_REMOTE_SFENCE_VMA: u64 = 1;
+const RFENCE_REMOTE_SFENCE_VMA_ASID: u64 = 2;
+const SRST_SYSTEM_RESET: u64 = 0;
+const HSM_HART_START: u64 = 0;
+const HSM_HART_STOP: u64 = 1;
+const HSM_HART_GET_STATUS: u64 = 2;
+const DBCN_CONSOLE_WRITE: u64 = 0;
+const DBCN_CONSOLE_READ: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(i64),
+}
+
+impl From<i64> for SbiError {
+    fn from(code: i64) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+pub fn call(extension: u64, function: u64, args: [u64; 5]) -> SbiResult<u64> {
+    let error: i64;
+    let value: u64;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+
+    match error {
+        0 => Ok(value),
+        code => Err(code.into()),
+    }
+}
+
+pub fn probe_extension(extension: u64) -> bool {
+    matches!(
+        call(EXTENSION_BASE, BASE_PROBE_EXTENSION, [extension, 0, 0, 0, 0]),
+        Ok(value) if value != 0
+    )
+}
+
+// Deadline is in `time` ticks; it also clears any pending timer interrupt.
+pub fn set_timer(deadline: u64) -> SbiResult<()> {
+    call(EXTENSION_TIME, TIME_SET_TIMER, [deadline, 0, 0, 0, 0]).map(|_| ())
+}
+
+// Bit n of the mask selects hart base + n.
+pub fn send_ipi(hart_mask: u64, hart_mask_base: u64) -> SbiResult<()> {
+    call(
+        EXTENSION_IPI,
+        IPI_SEND_IPI,
+        [hart_mask, hart_mask_base, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+pub fn remote_fence_i(hart_mask: u64, hart_mask_base: u64) -> SbiResult<()> {
+    call(
+        EXTENSION_RFENCE,
+        RFENCE_REMOTE_FENCE_I,
+        [hart_mask, hart_mask_base, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+// A zero start and size flushes the whole address space.
+pub fn remote_sfence_vma(
+    hart_mask: u64,
+    hart_mask_base: u64,
+    start: u64,
+    size: u64,
+) -> SbiResult<()> {
+    call(
+        EXTENSION_RFENCE,
+        RFENCE_REMOTE_SFENCE_VMA,
+        [hart_mask, hart_mask_base, start, size, 0],
+    )
+    .map(|_| ())
+}
+
+pub fn remote_sfence_vma_asid(
+    hart_mask: u64,
+    hart_mask_base: u64,
+    start: u64,
+    size: u64,
+    asid: u64,
+) -> SbiResult<()> {
+    call(
+        EXTENSION_RFENCE,
+        RFENCE_REMOTE_SFENCE_VMA_ASID,
+        [hart_mask, hart_mask_base, start, size, asid],
+    )
+    .map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(u64),
+}
+
+impl From<u64> for HartState {
+    fn from(state: u64) -> Self {
+        match state {
+            0 => HartState::Started,
+            1 => HartState::Stopped,
+            2 => HartState::StartPending,
+            3 => HartState::StopPending,
+            4 => HartState::Suspended,
+            5 => HartState::SuspendPending,
+            6 => HartState::ResumePending,
+            state => HartState::Unknown(state),
+        }
+    }
+}
+
+// The hart enters `start_address` in S-mode with the MMU off, its hart id
+// in a0 and `opaque` in a1.
+pub fn hart_start(hart: usize, start_address: u64, opaque: u64) -> SbiResult<()> {
+    call(
+        EXTENSION_HSM,
+        HSM_HART_START,
+        [hart as u64, start_address, opaque, 0, 0],
+    )
+    .map(|_| ())
+}
+
+// Only returns if the hart couldn't be stopped.
+pub fn hart_stop() -> SbiResult<()> {
+    call(EXTENSION_HSM, HSM_HART_STOP, [0; 5]).map(|_| ())
+}
+
+pub fn hart_get_status(hart: usize) -> SbiResult<HartState> {
+    call(
+        EXTENSION_HSM,
+        HSM_HART_GET_STATUS,
+        [hart as u64, 0, 0, 0, 0],
+    )
+    .map(HartState::from)
+}
+
+// Only returns if the reset couldn't be carried out.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiResult<()> {
+    call(
+        EXTENSION_SRST,
+        SRST_SYSTEM_RESET,
+        [reset_type as u64, reason as u64, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+// The firmware takes physical addresses. Only the kernel image and the
+// page allocator's pages are identity mapped, so buffers in the heap, thread
+// stacks included, or in user memory have to be copied somewhere that is
+// first. Either call may handle fewer bytes than asked, and returns how many
+// it did.
+pub fn debug_console_write(bytes: &[u8]) -> SbiResult<usize> {
+    call(
+        EXTENSION_DBCN,
+        DBCN_CONSOLE_WRITE,
+        [bytes.len() as u64, bytes.as_ptr() as u64, 0, 0, 0],
+    )
+    .map(|written| written as usize)
+}
+
+pub fn debug_console_read(buffer: &mut [u8]) -> SbiResult<usize> {
+    call(
+        EXTENSION_DBCN,
+        DBCN_CONSOLE_READ,
+        [buffer.len() as u64, buffer.as_mut_ptr() as u64, 0, 0, 0],
+    )
+    .map(|read| read as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn base_extension_is_always_present() {
+        assert!(probe_extension(EXTENSION_BASE));
+    }
+
+    #[test_case]
+    fn time_extension_is_present() {
+        assert!(probe_extension(EXTENSION_TIME));
+    }
+
+    #[test_case]
+    fn system_reset_extension_is_present() {
+        assert!(probe_extension(EXTENSION_SRST));
+    }
+
+    #[test_case]
+    fn this_hart_is_started() {
+        assert_eq!(
+            hart_get_status(crate::hart::hart_id()),
+            Ok(HartState::Started)
+        );
+    }
+
+    #[test_case]
+    fn the_debug_console_writes_when_present() {
+        if probe_extension(EXTENSION_DBCN) {
+            assert_eq!(debug_console_write(b" "), Ok(1));
+        }
+    }
+
+    #[test_case]
+    fn unknown_extensions_are_not_supported() {
+        assert!(!probe_extension(0x0bad_cafe));
+        assert_eq!(call(0x0bad_cafe, 0, [0; 5]), Err(SbiError::NotSupported));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::hart::{self, MAX_HARTS};
+use crate::timer;
+use crate::{print, println};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// What the scheduler keeps count of on each hart, and how loaded each is:
+// the threads running or ready there, averaged over the last few jiffies
+// in units of 1/LOAD_SCALE of a thread. Every so often each hart compares
+// its load with the others' and pulls a thread over from the busiest.
+
+pub const LOAD_SCALE: u64 = 1024;
+// Each jiffy's sample makes up 1/2^LOAD_SHIFT of the average.
+const LOAD_SHIFT: u32 = 3;
+// In jiffies.
+const BALANCE_INTERVAL: u64 = 4;
+
+struct HartStats {
+    switches: AtomicU64,
+    // Threads moved onto this hart from another.
+    migrations: AtomicU64,
+    // Timer ticks spent in the idle thread.
+    idle: AtomicU64,
+    load: AtomicU64,
+    samples: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STATS: HartStats = HartStats {
+    switches: AtomicU64::new(0),
+    migrations: AtomicU64::new(0),
+    idle: AtomicU64::new(0),
+    load: AtomicU64::new(0),
+    samples: AtomicU64::new(0),
+};
+
+static STATS: [HartStats; MAX_HARTS] = [NO_STATS; MAX_HARTS];
+
+fn averaged(load: u64, runnable: usize) -> u64 {
+    load - (load >> LOAD_SHIFT) + ((runnable as u64 * LOAD_SCALE) >> LOAD_SHIFT)
+}
+
+// The load after `jiffies` of nothing runnable.
+fn decayed(load: u64, jiffies: u64) -> u64 {
+    // Past this the load has long since decayed as far as it will.
+    (0..jiffies.min(64)).fold(load, |load, _| averaged(load, 0))
+}
+
+// The hart with the most load, if it has more than a thread's worth more
+// than `hart`.
+fn busiest_of(hart: usize, loads: &[u64; MAX_HARTS], online: u64) -> Option<usize> {
+    (0..MAX_HARTS)
+        .filter(|&other| other != hart && online & 1 << other != 0)
+        .max_by_key(|&other| loads[other])
+        .filter(|&other| loads[other] > loads[hart] + LOAD_SCALE)
+}
+
+pub fn record_switch(hart: usize) {
+    STATS[hart].switches.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_migration(hart: usize) {
+    STATS[hart].migrations.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_idle(hart: usize, ticks: u64) {
+    STATS[hart].idle.fetch_add(ticks, Ordering::Relaxed);
+}
+
+// Called on every hart each jiffy with how many threads it has running or
+// ready. Returns whether it's time for the hart to balance.
+pub fn sample_load(hart: usize, runnable: usize) -> bool {
+    let stats = &STATS[hart];
+    let load = stats.load.load(Ordering::Relaxed);
+    stats
+        .load
+        .store(averaged(load, runnable), Ordering::Relaxed);
+    stats
+        .samples
+        .fetch_add(1, Ordering::Relaxed)
+        .is_multiple_of(BALANCE_INTERVAL)
+}
+
+// Catches up on the samples a hart missed while idle with its tick
+// stopped, all of them with nothing runnable.
+pub fn sample_idle(hart: usize, jiffies: u64) {
+    let stats = &STATS[hart];
+    let load = stats.load.load(Ordering::Relaxed);
+    stats.load.store(decayed(load, jiffies), Ordering::Relaxed);
+}
+
+pub fn load(hart: usize) -> u64 {
+    STATS[hart].load.load(Ordering::Relaxed)
+}
+
+// The hart `hart` should take a thread from, if any.
+pub fn busiest(hart: usize) -> Option<usize> {
+    let loads = core::array::from_fn(load);
+    busiest_of(hart, &loads, hart::online_harts())
+}
+
+// Times are in milliseconds, and loads in threads.
+pub fn stats() {
+    let frequency = timer::timebase_frequency();
+    println!(
+        "{:>4} {:>10} {:>10} {:>10} {:>6}",
+        "hart", "switches", "migrations", "idle", "load"
+    );
+    let online = hart::online_harts();
+    for (hart, stats) in STATS.iter().enumerate() {
+        if online & 1 << hart == 0 {
+            continue;
+        }
+        let load = stats.load.load(Ordering::Relaxed);
+        println!(
+            "{:>4} {:>10} {:>10} {:>10} {:>3}.{:02}",
+            hart,
+            stats.switches.load(Ordering::Relaxed),
+            stats.migrations.load(Ordering::Relaxed),
+            stats.idle.load(Ordering::Relaxed) * 1000 / frequency,
+            load / LOAD_SCALE,
+            load % LOAD_SCALE * 100 / LOAD_SCALE
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn load_follows_the_threads_runnable() {
+        let mut load = 0;
+        for _ in 0..64 {
+            load = averaged(load, 2);
+        }
+        assert!(load > 2 * LOAD_SCALE - LOAD_SCALE / 8 && load <= 2 * LOAD_SCALE);
+        for _ in 0..64 {
+            load = averaged(load, 0);
+        }
+        assert!(load < LOAD_SCALE / 8);
+    }
+
+    #[test_case]
+    fn idle_jiffies_decay_the_load_as_if_sampled() {
+        let load = 3 * LOAD_SCALE;
+        assert_eq!(decayed(load, 0), load);
+        assert_eq!(decayed(load, 1), averaged(load, 0));
+        assert_eq!(
+            decayed(load, 3),
+            averaged(averaged(averaged(load, 0), 0), 0)
+        );
+        // However long the hart slept, it costs no more than this.
+        assert_eq!(decayed(load, u64::MAX), decayed(load, 64));
+        assert!(decayed(load, 64) < LOAD_SCALE / 8);
+    }
+
+    #[test_case]
+    fn only_a_hart_busier_by_more_than_a_thread_is_taken_from() {
+        let mut loads = [0; MAX_HARTS];
+        loads[1] = 3 * LOAD_SCALE;
+        loads[2] = LOAD_SCALE / 2;
+        loads[3] = 5 * LOAD_SCALE;
+        // Hart 3 is offline.
+        let online = 0b111;
+
+        assert_eq!(busiest_of(0, &loads, online), Some(1));
+        assert_eq!(busiest_of(2, &loads, online), Some(1));
+        assert_eq!(busiest_of(1, &loads, online), None);
+        loads[0] = 2 * LOAD_SCALE;
+        assert_eq!(busiest_of(0, &loads, online), None);
+    }
+}
@@ -1,24 +1,580 @@
+use crate::devicetree::{self, DeviceTree, Node};
+use crate::driver::{Driver, DriverError, ProbeResult};
+use crate::error::{KernelError, KernelResult};
+use crate::lock::SpinLockIrq;
+use crate::sync::CondVar;
+use crate::trap::LockIrqSave;
+use crate::{cmdline, early_console, gdb_stub, plic, sbi, task};
 use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use lazy_static::lazy_static;
 use spin::Mutex;
-use uart_16550::MmioSerialPort;
 
-const QEMU_UART0_ADDRESS: u64 = 0x1000_0000;
+const COMPATIBLE: &str = "ns16550a";
+
+// Where QEMU's virt machine puts it, for output before the device tree is
+// available.
+pub const QEMU_UART0_ADDRESS: u64 = 0x1000_0000;
+const QEMU_UART0_CLOCK: u32 = 3_686_400;
+
+const DEFAULT_BAUD: u32 = 115_200;
+// How long to wait for room in the transmitter before sending anyway, so a
+// wedged UART slows output down rather than hanging the kernel.
+const TX_TIMEOUT_SPINS: usize = 100_000;
+
+// Register indices, before reg-shift is applied.
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const LCR_EIGHT_BITS: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+const RX_BUFFER_SIZE: usize = 256;
+
+// How long a panicking hart waits for another to finish printing before
+// taking the console from it.
+const FORCE_UNLOCK_SPINS: usize = 1_000_000;
+
+// The console is the UART /chosen/stdout-path names, or else the first one.
+pub fn uart_node(tree: &DeviceTree) -> Option<Node> {
+    let stdout = tree
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("stdout-path"))
+        .and_then(|path| path.as_str())
+        .and_then(|path| path.split(':').next());
+
+    stdout
+        .and_then(|path| tree.find_node(path))
+        .filter(|node| node.is_compatible(COMPATIBLE))
+        .or_else(|| tree.find_compatible(COMPATIBLE))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub base: u64,
+    // Registers are 1 << reg_shift bytes apart and reg_io_width bytes wide.
+    pub reg_shift: u32,
+    pub reg_io_width: u32,
+    // Zero if unknown, in which case the firmware's baud rate is kept.
+    pub clock_frequency: u32,
+}
+
+impl UartConfig {
+    const QEMU: UartConfig = UartConfig {
+        base: QEMU_UART0_ADDRESS,
+        reg_shift: 0,
+        reg_io_width: 1,
+        clock_frequency: QEMU_UART0_CLOCK,
+    };
+
+    pub fn from_node(node: &Node) -> Option<UartConfig> {
+        let (base, _) = node.reg()?.next()?;
+        let cell = |name| node.property(name).and_then(|p| p.as_u32());
+        Some(UartConfig {
+            base,
+            reg_shift: cell("reg-shift").unwrap_or(0),
+            reg_io_width: cell("reg-io-width").unwrap_or(1),
+            clock_frequency: cell("clock-frequency").unwrap_or(0),
+        })
+    }
+}
+
+pub fn console_config() -> UartConfig {
+    devicetree::get()
+        .and_then(|tree| UartConfig::from_node(&uart_node(&tree)?))
+        .unwrap_or(UartConfig::QEMU)
+}
+
+pub fn base_address() -> u64 {
+    console_config().base
+}
+
+// A UART other than the console, named by the boot argument `key` as a
+// device tree path or an alias such as serial1.
+fn named_uart_config(tree: &DeviceTree, key: &str) -> Option<UartConfig> {
+    let name = cmdline::get(key)?;
+    let path = if name.starts_with('/') {
+        name
+    } else {
+        tree.find_node("/aliases")?.property(name)?.as_str()?
+    };
+    let node = tree
+        .find_node(path)
+        .filter(|node| node.is_compatible(COMPATIBLE))?;
+    UartConfig::from_node(&node).filter(|config| config.base != base_address())
+}
+
+// A second UART that log records go to instead of the console.
+pub fn log_uart_config(tree: &DeviceTree) -> Option<UartConfig> {
+    named_uart_config(tree, "log_uart")
+}
+
+// The UART gdb_uart names, which the GDB stub talks over. The log UART
+// keeps it if both name the same one.
+pub fn gdb_uart_config(tree: &DeviceTree) -> Option<UartConfig> {
+    named_uart_config(tree, "gdb_uart")
+        .filter(|config| log_uart_config(tree).is_none_or(|log| log.base != config.base))
+}
+
+pub struct Uart {
+    config: UartConfig,
+}
+
+impl Uart {
+    // `config` must describe a 16550-compatible UART that nothing else drives.
+    #[allow(clippy::missing_safety_doc)]
+    pub const unsafe fn new(config: UartConfig) -> Uart {
+        Uart { config }
+    }
+
+    fn register(&self, index: u64) -> u64 {
+        self.config.base + (index << self.config.reg_shift)
+    }
+
+    fn read(&self, index: u64) -> u8 {
+        let address = self.register(index);
+        unsafe {
+            match self.config.reg_io_width {
+                4 => ptr::read_volatile(address as *const u32) as u8,
+                _ => ptr::read_volatile(address as *const u8),
+            }
+        }
+    }
+
+    fn write(&mut self, index: u64, value: u8) {
+        let address = self.register(index);
+        unsafe {
+            match self.config.reg_io_width {
+                4 => ptr::write_volatile(address as *mut u32, value as u32),
+                _ => ptr::write_volatile(address as *mut u8, value),
+            }
+        }
+    }
+
+    // 8N1 at `baud` if the clock is known, with FIFOs on and an interrupt
+    // whenever a byte arrives.
+    pub fn init(&mut self, baud: u32) {
+        self.write(IER_DLM, 0);
+        self.write(LCR, LCR_EIGHT_BITS);
+        if self.set_baud(baud).is_err() {
+            let _ = self.set_baud(DEFAULT_BAUD);
+        }
+        self.write(FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+        self.write(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+        self.write(IER_DLM, IER_RX_AVAILABLE);
+    }
+
+    fn divisor(&self, baud: u32) -> Option<u16> {
+        if baud == 0 {
+            return None;
+        }
+        let divisor = self.config.clock_frequency as u64 / (16 * baud as u64);
+        u16::try_from(divisor).ok().filter(|&divisor| divisor != 0)
+    }
+
+    pub fn set_baud(&mut self, baud: u32) -> KernelResult<()> {
+        let divisor = self.divisor(baud).ok_or(KernelError::InvalidArgument)?;
+        let lcr = self.read(LCR);
+        self.write(LCR, lcr | LCR_DLAB);
+        self.write(RBR_THR_DLL, divisor as u8);
+        self.write(IER_DLM, (divisor >> 8) as u8);
+        self.write(LCR, lcr & !LCR_DLAB);
+        Ok(())
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        for _ in 0..TX_TIMEOUT_SPINS {
+            if self.read(LSR) & LSR_THR_EMPTY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.write(RBR_THR_DLL, byte);
+    }
+
+    // Waits, up to the same timeout as sending, for everything written to
+    // have gone out on the wire.
+    pub fn wait_until_idle(&self) {
+        for _ in 0..TX_TIMEOUT_SPINS {
+            if self.read(LSR) & LSR_TX_IDLE != 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(LSR) & LSR_DATA_READY == 0 {
+            return None;
+        }
+        Some(self.read(RBR_THR_DLL))
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+// From the baud= boot argument, where the clock is known.
+fn boot_baud() -> u32 {
+    cmdline::get_u64("baud")
+        .and_then(|baud| u32::try_from(baud).ok())
+        .unwrap_or(DEFAULT_BAUD)
+}
 
 lazy_static! {
-    pub static ref QEMU_SERIAL: Mutex<MmioSerialPort> = {
-        let mut port = unsafe { MmioSerialPort::new(QEMU_UART0_ADDRESS as usize) };
-        port.init();
-        Mutex::new(port)
+    pub static ref QEMU_SERIAL: SpinLockIrq<Uart> = {
+        let mut uart = unsafe { Uart::new(console_config()) };
+        uart.init(boot_baud());
+        SpinLockIrq::new(uart)
     };
 }
 
+// How much of a write goes through the bounce buffer at a time.
+const SBI_CHUNK: usize = 64;
+
+static USE_SBI: AtomicBool = AtomicBool::new(false);
+static SBI_CONSOLE: SpinLockIrq<SbiConsole> = SpinLockIrq::new(SbiConsole::new());
+
+// Without a UART in the device tree, or with console=sbi, the console is
+// the SBI debug console if the firmware has one, rather than a UART that
+// may not be there.
+pub fn choose_backend() {
+    let has_uart = devicetree::get().is_some_and(|tree| uart_node(&tree).is_some());
+    let wants_sbi = !has_uart || cmdline::get("console") == Some("sbi");
+    if wants_sbi && sbi::probe_extension(sbi::EXTENSION_DBCN) {
+        USE_SBI.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn uses_sbi() -> bool {
+    USE_SBI.load(Ordering::Relaxed)
+}
+
+// The firmware wants a physical address, which a caller's buffer needn't
+// be at: it may be user memory or on a thread's stack in the heap. Bytes go
+// through a buffer of the console's own instead, which as the console is
+// only ever the static above is in the identity mapped kernel image.
+pub struct SbiConsole {
+    buffer: [u8; SBI_CHUNK],
+}
+
+impl SbiConsole {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; SBI_CHUNK],
+        }
+    }
+
+    // Returns how many bytes the firmware took, which is fewer than asked
+    // only if it failed.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> usize {
+        let mut total = 0;
+        for chunk in bytes.chunks(SBI_CHUNK) {
+            self.buffer[..chunk.len()].copy_from_slice(chunk);
+            let mut written = 0;
+            while written < chunk.len() {
+                match sbi::debug_console_write(&self.buffer[written..chunk.len()]) {
+                    Ok(n) if n > 0 => written += n,
+                    _ => return total + written,
+                }
+            }
+            total += written;
+        }
+        total
+    }
+
+    fn try_receive(&mut self) -> Option<u8> {
+        match sbi::debug_console_read(&mut self.buffer[..1]) {
+            Ok(1) => Some(self.buffer[0]),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Write for SbiConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+// Set once the driver finds the UART log_uart names.
+static LOG_UART: Mutex<Option<Uart>> = Mutex::new(None);
+
+pub struct RingBuffer<const N: usize> {
+    bytes: [u8; N],
+    head: usize,
+    len: usize,
+    dropped: u64,
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    // Drops the byte if the buffer is full: old input beats new input.
+    pub fn push(&mut self, byte: u8) {
+        if self.len == N {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes[(self.head + self.len) % N] = byte;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+static RX_BUFFER: Mutex<RingBuffer<RX_BUFFER_SIZE>> = Mutex::new(RingBuffer::new());
+static RX_READY: CondVar = CondVar::new();
+
+fn handle_uart_interrupt(_irq: u32) {
+    {
+        let mut serial = QEMU_SERIAL.lock();
+        let mut buffer = RX_BUFFER.lock();
+        while let Some(byte) = serial.try_receive() {
+            buffer.push(byte);
+        }
+    }
+    RX_READY.notify_all_from_irq();
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    if uses_sbi() {
+        return SBI_CONSOLE.lock().try_receive();
+    }
+    RX_BUFFER.lock_irqsave().pop()
+}
+
+// Blocks until the UART interrupt has buffered some input. The SBI console
+// has no interrupt, so it's polled, letting other threads run in between.
+pub fn read_byte() -> u8 {
+    if !uses_sbi() {
+        let mut buffer = RX_READY.wait_while(&RX_BUFFER, |buffer| buffer.is_empty());
+        return buffer.pop().unwrap();
+    }
+    loop {
+        if let Some(byte) = try_read_byte() {
+            return byte;
+        }
+        task::yield_now();
+    }
+}
+
+// Spins on the UART itself, for callers running with interrupts disabled.
+pub fn poll_byte() -> u8 {
+    loop {
+        if let Some(byte) = try_read_byte() {
+            return byte;
+        }
+        if uses_sbi() {
+            continue;
+        }
+        if let Some(byte) = QEMU_SERIAL.lock().try_receive() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Reads an echoed, editable line into `buffer` and returns its length. The
+// line ending isn't stored; input past the end of the buffer is discarded.
+pub fn read_line(buffer: &mut [u8]) -> usize {
+    read_line_from(buffer, read_byte)
+}
+
+pub fn poll_line(buffer: &mut [u8]) -> usize {
+    read_line_from(buffer, poll_byte)
+}
+
+fn read_line_from(buffer: &mut [u8], mut next_byte: impl FnMut() -> u8) -> usize {
+    let mut len = 0;
+    loop {
+        match next_byte() {
+            b'\r' | b'\n' => {
+                _print(format_args!("\n"));
+                return len;
+            }
+            8 | 0x7f if len > 0 => {
+                len -= 1;
+                // Back over the character, blank it and back again.
+                echo(b"\x08 \x08");
+            }
+            8 | 0x7f => {}
+            byte if len < buffer.len() => {
+                buffer[len] = byte;
+                len += 1;
+                echo(&[byte]);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn echo(bytes: &[u8]) {
+    write_bytes(bytes);
+}
+
+pub const DRIVER: Driver = Driver {
+    name: "ns16550",
+    compatible: &[COMPATIBLE],
+    probe,
+};
+
+// Drives the console UART, and the log and GDB UARTs if there are any. The
+// log UART is only written to, so it goes without an interrupt; the GDB
+// stub takes its UART's interrupt if it has one.
+fn probe(node: &Node) -> ProbeResult {
+    let (base, _) = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .ok_or(DriverError::MissingReg)?;
+    let log_uart = devicetree::get().and_then(|tree| log_uart_config(&tree));
+    if let Some(config) = log_uart.filter(|config| config.base == base) {
+        let mut uart = unsafe { Uart::new(config) };
+        uart.init(boot_baud());
+        // It comes up too late for the start of the boot, which the early
+        // console kept.
+        for &byte in early_console::transcript() {
+            uart.send(byte);
+        }
+        *LOG_UART.lock_irqsave() = Some(uart);
+        return Ok(());
+    }
+    let gdb_uart = devicetree::get().and_then(|tree| gdb_uart_config(&tree));
+    if let Some(config) = gdb_uart.filter(|config| config.base == base) {
+        let mut uart = unsafe { Uart::new(config) };
+        uart.init(boot_baud());
+        gdb_stub::attach(uart, node.interrupts().next());
+        return Ok(());
+    }
+    if base != base_address() {
+        return Err(DriverError::Unsupported);
+    }
+
+    let irq = node
+        .interrupts()
+        .next()
+        .ok_or(DriverError::MissingInterrupt)?;
+    if plic::get().is_none() {
+        return Err(DriverError::NoInterruptController);
+    }
+    plic::enable_irq(irq, 1, handle_uart_interrupt);
+    Ok(())
+}
+
+// Raw bytes, which needn't be UTF-8, such as a user program's output.
+pub fn write_bytes(bytes: &[u8]) {
+    if uses_sbi() {
+        SBI_CONSOLE.lock().write_bytes(bytes);
+        return;
+    }
+    if early_console::is_active() {
+        return early_console::write_bytes(bytes);
+    }
+    let mut serial = QEMU_SERIAL.lock();
+    for &byte in bytes {
+        serial.send(byte);
+    }
+}
+
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    if uses_sbi() {
+        return SBI_CONSOLE.lock().write_fmt(args).unwrap();
+    }
+    if early_console::is_active() {
+        return early_console::print(args);
+    }
+    // The UART interrupt handler takes the same lock.
     QEMU_SERIAL.lock().write_fmt(args).unwrap();
 }
 
+// To the log UART if there is one, or else the console. The log UART is
+// only set up after the early console has handed off.
+pub fn print_log(args: fmt::Arguments) {
+    use core::fmt::Write;
+    match LOG_UART.lock_irqsave().as_mut() {
+        Some(uart) => uart.write_fmt(args).unwrap(),
+        None => _print(args),
+    }
+}
+
+// Only for the panic handler. The console lock may be held by the hart
+// that panicked, which will never release it, so after a short wait the
+// lock is broken rather than waited on forever.
+pub fn force_unlock() {
+    if uses_sbi() {
+        break_lock(&SBI_CONSOLE);
+    } else if !early_console::is_active() {
+        break_lock(&QEMU_SERIAL);
+    }
+}
+
+fn break_lock<T>(lock: &SpinLockIrq<T>) {
+    for _ in 0..FORCE_UNLOCK_SPINS {
+        if !lock.is_locked() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { lock.force_unlock() };
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
@@ -29,3 +585,94 @@ macro_rules! println {
     () => (print!("\n"));
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::test::yield_until;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn ring_buffer_is_first_in_first_out() {
+        let mut buffer = RingBuffer::<4>::new();
+        buffer.push(1);
+        buffer.push(2);
+        assert_eq!(buffer.pop(), Some(1));
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn ring_buffer_wraps_around() {
+        let mut buffer = RingBuffer::<2>::new();
+        for byte in 0..5 {
+            buffer.push(byte);
+            assert_eq!(buffer.pop(), Some(byte));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test_case]
+    fn a_full_ring_buffer_drops_new_bytes() {
+        let mut buffer = RingBuffer::<2>::new();
+        buffer.push(1);
+        buffer.push(2);
+        buffer.push(3);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(buffer.pop(), Some(1));
+    }
+
+    #[test_case]
+    fn a_uart_is_programmed_through_its_shifted_registers() {
+        let mut registers = [0u32; 8];
+        registers[LSR as usize] = (LSR_THR_EMPTY | LSR_DATA_READY) as u32;
+        registers[RBR_THR_DLL as usize] = b'x' as u32;
+        let mut uart = unsafe {
+            Uart::new(UartConfig {
+                base: registers.as_mut_ptr() as u64,
+                reg_shift: 2,
+                reg_io_width: 4,
+                clock_frequency: QEMU_UART0_CLOCK,
+            })
+        };
+
+        assert_eq!(uart.try_receive(), Some(b'x'));
+        uart.write(LCR, LCR_EIGHT_BITS);
+        uart.set_baud(9600).unwrap();
+        assert_eq!(registers[RBR_THR_DLL as usize], 24);
+        assert_eq!(registers[IER_DLM as usize], 0);
+        assert_eq!(registers[LCR as usize], LCR_EIGHT_BITS as u32);
+        assert_eq!(uart.set_baud(1_000_000), Err(KernelError::InvalidArgument));
+
+        uart.send(b'y');
+        assert_eq!(registers[RBR_THR_DLL as usize], b'y' as u32);
+    }
+
+    #[test_case]
+    fn force_unlock_frees_an_abandoned_console() {
+        core::mem::forget(QEMU_SERIAL.lock());
+        force_unlock();
+        println!("still printing");
+    }
+
+    #[test_case]
+    fn the_sbi_console_writes_from_a_thread_stack() {
+        if !sbi::probe_extension(sbi::EXTENSION_DBCN) {
+            return;
+        }
+        let written = Arc::new(AtomicUsize::new(usize::MAX));
+        let result = written.clone();
+        task::spawn(move || {
+            // Thread stacks come from the heap, which isn't identity mapped.
+            let bytes = core::hint::black_box([b' '; 8]);
+            let count = SBI_CONSOLE.lock().write_bytes(&bytes);
+            result.store(count, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| written.load(Ordering::Relaxed) != usize::MAX);
+        assert_eq!(written.load(Ordering::Relaxed), 8);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::address::PhysFrame;
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::PAGE_SIZE;
+use crate::page_cache;
+use crate::rusage::PageCharge;
+use crate::trap::LockIrqSave;
+use crate::vfs::{File, FileType, Stat};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Named shared memory, for processes to map into their address spaces with
+// MAP_SHARED. A segment's pages are held by every descriptor for it and
+// every page of it that's mapped, and freed once none of them are left;
+// until then its name finds it.
+
+// So that one segment can't take every free page.
+pub const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+// Zeroed pages, allocated when the segment is created.
+pub struct Segment {
+    frames: Vec<PhysFrame>,
+    // Whoever created it, who answers for its pages until they're freed.
+    charge: PageCharge,
+}
+
+impl Segment {
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * PAGE_SIZE
+    }
+
+    pub fn frame(&self, page: usize) -> Option<&PhysFrame> {
+        self.frames.get(page)
+    }
+}
+
+// Mustn't happen with the page allocator locked, as an address space
+// dropping its last mapping might.
+impl Drop for Segment {
+    fn drop(&mut self) {
+        self.charge.remove(self.frames.len());
+        for frame in self.frames.drain(..) {
+            page_cache::free_page(frame);
+        }
+    }
+}
+
+static SEGMENTS: Mutex<BTreeMap<String, Weak<Segment>>> = Mutex::new(BTreeMap::new());
+
+fn find(name: &str) -> Option<Arc<Segment>> {
+    let mut segments = SEGMENTS.lock_irqsave();
+    segments.retain(|_, segment| segment.strong_count() > 0);
+    segments.get(name).and_then(Weak::upgrade)
+}
+
+// Finds the segment called `name`, or creates one `size` bytes long if
+// there isn't one and `create` is set. A new one's pages are charged to
+// `charge`, and it fails with OutOfMemory if there are more than
+// `allowance` of them. The size only matters to creating it.
+pub fn open(
+    name: &str,
+    size: u64,
+    create: bool,
+    exclusive: bool,
+    charge: &PageCharge,
+    allowance: usize,
+) -> KernelResult<Arc<Segment>> {
+    if let Some(segment) = find(name) {
+        if create && exclusive {
+            return Err(KernelError::AlreadyExists);
+        }
+        return Ok(segment);
+    }
+    if !create {
+        return Err(KernelError::NotFound);
+    }
+    if name.is_empty() || size == 0 || size > MAX_SIZE {
+        return Err(KernelError::InvalidArgument);
+    }
+    let pages = size.div_ceil(PAGE_SIZE) as usize;
+    if pages > allowance {
+        return Err(KernelError::OutOfMemory);
+    }
+
+    let mut segment = Segment {
+        frames: Vec::with_capacity(pages),
+        charge: charge.clone(),
+    };
+    for _ in 0..pages {
+        // Anything allocated so far goes back when the segment is dropped.
+        segment.frames.push(page_cache::alloc_page_zeroed()?);
+        segment.charge.add(1);
+    }
+    let segment = Arc::new(segment);
+
+    // Someone else may have made one by the same name while the pages were
+    // allocated, in which case this one is freed, outside the lock.
+    let existing = {
+        let mut segments = SEGMENTS.lock_irqsave();
+        let existing = segments.get(name).and_then(Weak::upgrade);
+        if existing.is_none() {
+            segments.insert(String::from(name), Arc::downgrade(&segment));
+        }
+        existing
+    };
+    match existing {
+        Some(_) if exclusive => Err(KernelError::AlreadyExists),
+        Some(existing) => Ok(existing),
+        None => Ok(segment),
+    }
+}
+
+// What shm_open gives a descriptor for. It can only be mapped, and only
+// written through a mapping if it was opened for writing.
+pub struct SharedMemory {
+    segment: Arc<Segment>,
+    writable: bool,
+}
+
+impl SharedMemory {
+    pub fn new(segment: Arc<Segment>, writable: bool) -> Self {
+        Self { segment, writable }
+    }
+}
+
+impl File for SharedMemory {
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(Stat {
+            file_type: FileType::Regular,
+            permissions: 0o600,
+            inode: 0,
+            size: self.segment.size(),
+        })
+    }
+
+    fn shared_memory(&self) -> Option<(Arc<Segment>, bool)> {
+        Some((self.segment.clone(), self.writable))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(name: &str, size: u64, create: bool, exclusive: bool) -> KernelResult<Arc<Segment>> {
+        super::open(
+            name,
+            size,
+            create,
+            exclusive,
+            &PageCharge::new(),
+            usize::MAX,
+        )
+    }
+
+    #[test_case]
+    fn segments_are_found_by_name_until_their_last_user_goes() {
+        let segment = open("test-shm", PAGE_SIZE + 1, true, false).unwrap();
+        assert_eq!(segment.size(), 2 * PAGE_SIZE);
+        assert_eq!(
+            open("test-shm", PAGE_SIZE, true, true).err(),
+            Some(KernelError::AlreadyExists)
+        );
+
+        let again = open("test-shm", 0, false, false).unwrap();
+        assert!(Arc::ptr_eq(&segment, &again));
+        drop(segment);
+        drop(again);
+        assert_eq!(
+            open("test-shm", 0, false, false).err(),
+            Some(KernelError::NotFound)
+        );
+    }
+
+    #[test_case]
+    fn pages_are_charged_to_the_creator_until_freed() {
+        let charge = PageCharge::new();
+        let create =
+            |size, allowance| super::open("test-shm-charge", size, true, false, &charge, allowance);
+        assert_eq!(
+            create(3 * PAGE_SIZE, 2).err(),
+            Some(KernelError::OutOfMemory)
+        );
+        assert_eq!(
+            create(MAX_SIZE + 1, usize::MAX).err(),
+            Some(KernelError::InvalidArgument)
+        );
+        assert_eq!(charge.pages(), 0);
+
+        let segment = create(2 * PAGE_SIZE, 2).unwrap();
+        assert_eq!(charge.pages(), 2);
+        // Finding it again charges nothing more.
+        let again = create(PAGE_SIZE, 0).unwrap();
+        assert!(Arc::ptr_eq(&segment, &again));
+        assert_eq!(charge.pages(), 2);
+        drop(segment);
+        drop(again);
+        assert_eq!(charge.pages(), 0);
+    }
+}
@@ -0,0 +1,421 @@
+use crate::error::{KernelError, KernelResult};
+use crate::trap::{self, TrapFrame};
+use crate::{process, uaccess, user};
+
+// Linux's numbers, as user space knows them. Signal n is bit n - 1 of a
+// mask.
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGBUS: u32 = 7;
+pub const SIGKILL: u32 = 9;
+pub const SIGSEGV: u32 = 11;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+pub const NSIG: u32 = 64;
+
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+// Accepted, though interrupted calls fail with EINTR rather than restart.
+pub const SA_RESTART: u64 = 0x1000_0000;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+// The registers a handler interrupted, then the mask to go back to, saved
+// on the user stack for sigreturn.
+const FRAME_WORDS: usize = 33;
+const FRAME_SIZE: usize = FRAME_WORDS * 8;
+
+fn bit(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+// Neither can be caught, blocked or ignored.
+const UNBLOCKABLE: u64 = 1 << (SIGKILL - 1) | 1 << (SIGSTOP - 1);
+
+// There's no job control, so stopping and continuing are ignored too.
+fn ignored_by_default(signal: u32) -> bool {
+    matches!(
+        signal,
+        SIGCHLD | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU | SIGURG | SIGWINCH
+    )
+}
+
+fn check(signal: u32) -> KernelResult<()> {
+    match signal {
+        1..=NSIG => Ok(()),
+        _ => Err(KernelError::InvalidArgument),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Default,
+    Ignore,
+    Function(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Action {
+    pub handler: Handler,
+    pub flags: u64,
+    // Blocked as well while the handler runs.
+    pub mask: u64,
+}
+
+impl Action {
+    pub const DEFAULT: Action = Action {
+        handler: Handler::Default,
+        flags: 0,
+        mask: 0,
+    };
+
+    // From the sa_handler, sa_flags and sa_mask of a struct sigaction.
+    pub fn from_words(handler: u64, flags: u64, mask: u64) -> KernelResult<Self> {
+        if flags & !(SA_NODEFER | SA_RESETHAND | SA_RESTART) != 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        let handler = match handler {
+            SIG_DFL => Handler::Default,
+            SIG_IGN => Handler::Ignore,
+            address => Handler::Function(address),
+        };
+        Ok(Action {
+            handler,
+            flags,
+            mask,
+        })
+    }
+
+    pub fn to_words(self) -> [u64; 3] {
+        let handler = match self.handler {
+            Handler::Default => SIG_DFL,
+            Handler::Ignore => SIG_IGN,
+            Handler::Function(address) => address,
+        };
+        [handler, self.flags, self.mask]
+    }
+}
+
+// What to do on the way back to user mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Terminate(u32),
+    // Run the handler, and have sigreturn put the mask back afterwards.
+    Handle {
+        signal: u32,
+        handler: u64,
+        blocked: u64,
+    },
+}
+
+// A process's signals: those sent but not yet acted on, those it's holding
+// back, and what it does with each.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    actions: [Action; NSIG as usize],
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Signals {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::DEFAULT; NSIG as usize],
+        }
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, mask: u64) {
+        self.blocked = mask & !UNBLOCKABLE;
+    }
+
+    pub fn raise(&mut self, signal: u32) -> KernelResult<()> {
+        check(signal)?;
+        self.pending |= bit(signal);
+        Ok(())
+    }
+
+    // Whether a signal is waiting that take would act on, which cuts short
+    // a wait in the kernel.
+    pub fn has_deliverable(&self) -> bool {
+        let ready = self.pending & !self.blocked;
+        (1..=NSIG).any(|signal| {
+            ready & bit(signal) != 0
+                && match self.actions[signal as usize - 1].handler {
+                    Handler::Ignore => false,
+                    Handler::Default => !ignored_by_default(signal),
+                    Handler::Function(_) => true,
+                }
+        })
+    }
+
+    // For faults, which can't be carried on from: a signal that's blocked
+    // or ignored kills the process instead.
+    pub fn force(&mut self, signal: u32) {
+        let action = &mut self.actions[signal as usize - 1];
+        if self.blocked & bit(signal) != 0 || action.handler == Handler::Ignore {
+            self.blocked &= !bit(signal);
+            *action = Action::DEFAULT;
+        }
+        self.pending |= bit(signal);
+    }
+
+    pub fn action(&self, signal: u32) -> KernelResult<Action> {
+        check(signal)?;
+        Ok(self.actions[signal as usize - 1])
+    }
+
+    // Returns the action it replaces. Ignoring a signal discards any of it
+    // that's pending.
+    pub fn set_action(&mut self, signal: u32, action: Action) -> KernelResult<Action> {
+        check(signal)?;
+        if UNBLOCKABLE & bit(signal) != 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        if action.handler == Handler::Ignore {
+            self.pending &= !bit(signal);
+        }
+        Ok(core::mem::replace(
+            &mut self.actions[signal as usize - 1],
+            action,
+        ))
+    }
+
+    // A forked child keeps the handlers and mask, but none of the pending
+    // signals.
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    // Handlers don't survive exec, which replaces the code they were in.
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let Handler::Function(_) = action.handler {
+                *action = Action::DEFAULT;
+            }
+        }
+    }
+
+    // Takes the lowest pending signal that isn't blocked, skipping those
+    // that are ignored, and blocks what its handler asks to while it runs.
+    pub fn take(&mut self) -> Option<Delivery> {
+        loop {
+            let ready = self.pending & !self.blocked;
+            if ready == 0 {
+                return None;
+            }
+            let signal = ready.trailing_zeros() + 1;
+            self.pending &= !bit(signal);
+
+            let action = self.actions[signal as usize - 1];
+            match action.handler {
+                Handler::Ignore => (),
+                Handler::Default if ignored_by_default(signal) => (),
+                Handler::Default => return Some(Delivery::Terminate(signal)),
+                Handler::Function(handler) => {
+                    let blocked = self.blocked;
+                    let deferred = if action.flags & SA_NODEFER != 0 {
+                        0
+                    } else {
+                        bit(signal)
+                    };
+                    self.set_blocked(blocked | action.mask | deferred);
+                    if action.flags & SA_RESETHAND != 0 {
+                        self.actions[signal as usize - 1] = Action::DEFAULT;
+                    }
+                    return Some(Delivery::Handle {
+                        signal,
+                        handler,
+                        blocked,
+                    });
+                }
+            }
+        }
+    }
+}
+
+// What a process killed by `signal` exits with, as a shell would report it.
+pub const fn exit_code(signal: u32) -> i64 {
+    128 + signal as i64
+}
+
+// Saves the interrupted registers below the user stack and starts the
+// handler there, returning through the trampoline to sigreturn.
+fn start_handler(frame: &mut TrapFrame, signal: u32, handler: u64, blocked: u64) -> bool {
+    let mut words = [0; FRAME_WORDS];
+    for (n, word) in words[..31].iter_mut().enumerate() {
+        *word = frame.reg(n + 1);
+    }
+    words[31] = frame.sepc;
+    words[32] = blocked;
+
+    let mut bytes = [0; FRAME_SIZE];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    let sp = (frame.sp().wrapping_sub(FRAME_SIZE as u64)) & !0xf;
+    if uaccess::copy_to_user(sp, &bytes).is_err() {
+        return false;
+    }
+
+    frame.set_reg(1, user::SIGRETURN_TRAMPOLINE);
+    frame.set_reg(2, sp);
+    frame.set_reg(10, signal as u64);
+    frame.sepc = handler;
+    true
+}
+
+// Called on the way back to user mode, to act on a signal if one is ready.
+// A process with nowhere to put a handler's frame gets a SIGSEGV instead.
+pub fn deliver(frame: &mut TrapFrame) {
+    let delivery = match process::with_current(|process| process.signals_mut().take()) {
+        Some(Some(delivery)) => delivery,
+        _ => return,
+    };
+
+    trap::enable_interrupts();
+    match delivery {
+        Delivery::Terminate(signal) => process::exit(exit_code(signal)),
+        Delivery::Handle {
+            signal,
+            handler,
+            blocked,
+        } => {
+            if !start_handler(frame, signal, handler, blocked) {
+                process::exit(exit_code(SIGSEGV));
+            }
+        }
+    }
+    trap::disable_interrupts();
+}
+
+// Puts back what start_handler saved, from the stack as the handler left
+// it. Returns a0 as it was, since the syscall's result goes there.
+pub fn sigreturn(frame: &mut TrapFrame) -> KernelResult<u64> {
+    let mut bytes = [0; FRAME_SIZE];
+    if let Err(e) = uaccess::copy_from_user(&mut bytes, frame.sp()) {
+        process::with_current(|process| process.signals_mut().force(SIGSEGV));
+        return Err(e);
+    }
+    let mut words = [0; FRAME_WORDS];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+
+    for (n, &word) in words[..31].iter().enumerate() {
+        frame.set_reg(n + 1, word);
+    }
+    frame.sepc = words[31];
+    process::with_current(|process| process.signals_mut().set_blocked(words[32]));
+    Ok(frame.reg(10))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIGUSR1: u32 = 10;
+    const SIGUSR2: u32 = 12;
+
+    fn handled_by(handler: u64, flags: u64) -> Action {
+        Action::from_words(handler, flags, 0).unwrap()
+    }
+
+    #[test_case]
+    fn unhandled_signals_terminate_unless_ignored_by_default() {
+        let mut signals = Signals::new();
+        signals.raise(SIGCHLD).unwrap();
+        signals.raise(SIGUSR1).unwrap();
+        assert_eq!(signals.take(), Some(Delivery::Terminate(SIGUSR1)));
+        assert_eq!(signals.take(), None);
+    }
+
+    #[test_case]
+    fn blocked_signals_wait_until_unblocked() {
+        let mut signals = Signals::new();
+        signals.set_blocked(bit(SIGUSR1) | bit(SIGKILL));
+        assert_eq!(signals.blocked(), bit(SIGUSR1));
+
+        signals.raise(SIGUSR1).unwrap();
+        assert_eq!(signals.take(), None);
+        signals.set_blocked(0);
+        assert_eq!(signals.take(), Some(Delivery::Terminate(SIGUSR1)));
+    }
+
+    #[test_case]
+    fn handlers_block_their_own_signal_while_they_run() {
+        let mut signals = Signals::new();
+        signals
+            .set_action(SIGUSR1, handled_by(0x1000, SA_RESETHAND))
+            .unwrap();
+        signals.set_action(SIGUSR2, handled_by(0x2000, 0)).unwrap();
+        signals.raise(SIGUSR1).unwrap();
+
+        assert_eq!(
+            signals.take(),
+            Some(Delivery::Handle {
+                signal: SIGUSR1,
+                handler: 0x1000,
+                blocked: 0
+            })
+        );
+        assert_eq!(signals.blocked(), bit(SIGUSR1));
+        assert_eq!(signals.action(SIGUSR1), Ok(Action::DEFAULT));
+    }
+
+    #[test_case]
+    fn only_signals_that_would_be_acted_on_are_deliverable() {
+        let mut signals = Signals::new();
+        signals.raise(SIGCHLD).unwrap();
+        assert!(!signals.has_deliverable());
+        signals.set_blocked(bit(SIGUSR1));
+        signals.raise(SIGUSR1).unwrap();
+        assert!(!signals.has_deliverable());
+        signals.raise(SIGKILL).unwrap();
+        assert!(signals.has_deliverable());
+    }
+
+    #[test_case]
+    fn kill_cannot_be_caught() {
+        let mut signals = Signals::new();
+        assert_eq!(
+            signals.set_action(SIGKILL, handled_by(0x1000, 0)),
+            Err(KernelError::InvalidArgument)
+        );
+        assert_eq!(signals.raise(NSIG + 1), Err(KernelError::InvalidArgument));
+    }
+
+    #[test_case]
+    fn forced_signals_override_blocking_and_ignoring() {
+        let mut signals = Signals::new();
+        signals.set_action(SIGSEGV, handled_by(SIG_IGN, 0)).unwrap();
+        signals.set_blocked(bit(SIGSEGV));
+        signals.force(SIGSEGV);
+        assert_eq!(signals.take(), Some(Delivery::Terminate(SIGSEGV)));
+    }
+}
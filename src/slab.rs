@@ -0,0 +1,463 @@
+use crate::address::{PhysAddr, PhysFrame};
+use crate::lock::SpinLockIrq;
+use crate::page_allocator::{PageAllocationError, PageAllocator, PAGE_ALLOCATOR, PAGE_SIZE};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Each slab is a single page: this header followed by the objects.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub slabs: usize,
+    pub objects_per_slab: usize,
+    pub allocated: usize,
+    pub peak_allocated: usize,
+}
+
+impl SlabStats {
+    pub fn capacity(&self) -> usize {
+        self.slabs * self.objects_per_slab
+    }
+}
+
+pub type ObjectHook<T> = fn(*mut T);
+
+pub struct SlabCache<T> {
+    name: &'static str,
+    // Slabs with at least one free object; full slabs are only reachable
+    // through the objects they hold.
+    partial: *mut Slab,
+    empty_slabs: usize,
+    constructor: Option<ObjectHook<T>>,
+    destructor: Option<ObjectHook<T>>,
+    stats: SlabStats,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = max(align_of::<T>(), align_of::<FreeObject>());
+    const OBJECT_SIZE: usize = align_up(
+        max(size_of::<T>(), size_of::<FreeObject>()),
+        Self::OBJECT_ALIGN,
+    );
+    const FIRST_OBJECT: usize = align_up(size_of::<Slab>(), Self::OBJECT_ALIGN);
+    const OBJECTS_PER_SLAB: usize =
+        (PAGE_SIZE as usize).saturating_sub(Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+    // Objects with no room beside a slab header, page tables among them,
+    // get a page each. It goes straight back to the allocator when they're
+    // freed, as callers don't always hand over the same allocator.
+    const WHOLE_PAGES: bool = Self::OBJECTS_PER_SLAB == 0;
+
+    pub const fn new(
+        name: &'static str,
+        constructor: Option<ObjectHook<T>>,
+        destructor: Option<ObjectHook<T>>,
+    ) -> Self {
+        assert!(
+            size_of::<T>() <= PAGE_SIZE as usize && align_of::<T>() <= PAGE_SIZE as usize,
+            "Object is too large for a slab cache"
+        );
+
+        Self {
+            name,
+            partial: ptr::null_mut(),
+            empty_slabs: 0,
+            constructor,
+            destructor,
+            stats: SlabStats {
+                name,
+                slabs: 0,
+                objects_per_slab: if Self::WHOLE_PAGES {
+                    1
+                } else {
+                    Self::OBJECTS_PER_SLAB
+                },
+                allocated: 0,
+                peak_allocated: 0,
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    pub fn alloc(&mut self, allocator: &mut PageAllocator) -> Result<*mut T, PageAllocationError> {
+        let object = if Self::WHOLE_PAGES {
+            let object = allocator.alloc()?.as_mut_ptr();
+            self.stats.slabs += 1;
+            object
+        } else {
+            self.take(allocator)?
+        };
+
+        self.stats.allocated += 1;
+        self.stats.peak_allocated = self.stats.peak_allocated.max(self.stats.allocated);
+
+        if let Some(constructor) = self.constructor {
+            constructor(object);
+        }
+        Ok(object)
+    }
+
+    fn take(&mut self, allocator: &mut PageAllocator) -> Result<*mut T, PageAllocationError> {
+        if self.partial.is_null() {
+            self.grow(allocator)?;
+        }
+
+        Ok(unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            if (*slab).in_use == 0 {
+                self.empty_slabs -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+            object as *mut T
+        })
+    }
+
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn free(&mut self, object: *mut T, allocator: &mut PageAllocator) {
+        if let Some(destructor) = self.destructor {
+            destructor(object);
+        }
+        self.stats.allocated -= 1;
+
+        if Self::WHOLE_PAGES {
+            allocator.dealloc(PhysFrame::containing_address(PhysAddr::new(object as u64)));
+            self.stats.slabs -= 1;
+            return;
+        }
+
+        let slab = (object as u64 & !(PAGE_SIZE - 1)) as *mut Slab;
+        let was_full = (*slab).free.is_null();
+
+        let free = object as *mut FreeObject;
+        (*free).next = (*slab).free;
+        (*slab).free = free;
+        (*slab).in_use -= 1;
+
+        if was_full {
+            self.push(slab);
+        }
+
+        if (*slab).in_use == 0 {
+            // Keep one empty slab around so alloc/free churn doesn't bounce
+            // pages through the page allocator.
+            if self.empty_slabs > 0 {
+                self.release(slab, allocator);
+            } else {
+                self.empty_slabs += 1;
+            }
+        }
+    }
+
+    // Returns every empty slab to the page allocator.
+    pub fn shrink(&mut self, allocator: &mut PageAllocator) -> usize {
+        let mut released = 0;
+        let mut slab = self.partial;
+        while !slab.is_null() {
+            unsafe {
+                let next = (*slab).next;
+                if (*slab).in_use == 0 {
+                    self.release(slab, allocator);
+                    self.empty_slabs -= 1;
+                    released += 1;
+                }
+                slab = next;
+            }
+        }
+        released
+    }
+
+    fn grow(&mut self, allocator: &mut PageAllocator) -> Result<(), PageAllocationError> {
+        let base = allocator.alloc()?.start_address().as_u64() as usize;
+
+        // Thread the free list backwards so objects are handed out in address order.
+        let mut free = ptr::null_mut();
+        for idx in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = (base + Self::FIRST_OBJECT + idx * Self::OBJECT_SIZE) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        let slab = base as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+            self.push(slab);
+        }
+        self.empty_slabs += 1;
+        self.stats.slabs += 1;
+        Ok(())
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+
+    unsafe fn release(&mut self, slab: *mut Slab, allocator: &mut PageAllocator) {
+        self.unlink(slab);
+        allocator.dealloc(PhysFrame::containing_address(PhysAddr::new(slab as u64)));
+        self.stats.slabs -= 1;
+    }
+}
+
+// An object from a cache that goes back to it when dropped, as a Box does
+// to the heap. Its slabs come from PAGE_ALLOCATOR, which is taken after
+// the cache's lock.
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static SpinLockIrq<SlabCache<T>>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    pub fn new(
+        cache: &'static SpinLockIrq<SlabCache<T>>,
+        value: T,
+    ) -> Result<Self, PageAllocationError> {
+        let object = cache.lock().alloc(&mut PAGE_ALLOCATOR.lock())?;
+        unsafe { object.write(value) };
+        Ok(Self {
+            object: NonNull::new(object).unwrap(),
+            cache,
+        })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        let object = self.object.as_ptr();
+        unsafe {
+            ptr::drop_in_place(object);
+            self.cache.lock().free(object, &mut PAGE_ALLOCATOR.lock());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::page_allocator::test::test_page_allocator;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Object {
+        value: u64,
+        _padding: [u64; 7],
+    }
+
+    fn new_cache() -> SlabCache<Object> {
+        SlabCache::new("test", None, None)
+    }
+
+    #[test_case]
+    fn small_objects_share_a_page() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = new_cache();
+
+        let a = cache.alloc(&mut allocator).unwrap();
+        let b = cache.alloc(&mut allocator).unwrap();
+
+        assert_eq!(a as u64 & !(PAGE_SIZE - 1), b as u64 & !(PAGE_SIZE - 1));
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(allocator.free_pages(), 3);
+    }
+
+    #[test_case]
+    fn a_full_slab_grows_the_cache() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = new_cache();
+        let per_slab = cache.stats().objects_per_slab;
+
+        for _ in 0..per_slab + 1 {
+            cache.alloc(&mut allocator).unwrap();
+        }
+
+        assert_eq!(cache.stats().slabs, 2);
+        assert_eq!(cache.stats().allocated, per_slab + 1);
+    }
+
+    #[test_case]
+    fn freed_objects_are_reused() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = new_cache();
+
+        let a = cache.alloc(&mut allocator).unwrap();
+        cache.alloc(&mut allocator).unwrap();
+        unsafe { cache.free(a, &mut allocator) };
+
+        assert_eq!(cache.alloc(&mut allocator).unwrap(), a);
+    }
+
+    #[test_case]
+    fn only_one_empty_slab_is_kept() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = new_cache();
+        let per_slab = cache.stats().objects_per_slab;
+
+        let mut objects = [ptr::null_mut(); 2];
+        objects[0] = cache.alloc(&mut allocator).unwrap();
+        for _ in 1..per_slab {
+            cache.alloc(&mut allocator).unwrap();
+        }
+        objects[1] = cache.alloc(&mut allocator).unwrap();
+        assert_eq!(cache.stats().slabs, 2);
+
+        unsafe { cache.free(objects[1], &mut allocator) };
+        assert_eq!(cache.stats().slabs, 2);
+        assert_eq!(cache.shrink(&mut allocator), 1);
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(allocator.free_pages(), 3);
+    }
+
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    fn construct(object: *mut Object) {
+        CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+        unsafe { (*object).value = 42 };
+    }
+
+    fn destroy(_object: *mut Object) {
+        DESTROYED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    struct Page {
+        _words: [u64; 512],
+    }
+
+    #[test_case]
+    fn page_sized_objects_get_a_page_each() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = SlabCache::<Page>::new("pages", None, None);
+        assert_eq!(cache.stats().objects_per_slab, 1);
+
+        let a = cache.alloc(&mut allocator).unwrap();
+        let b = cache.alloc(&mut allocator).unwrap();
+        assert_eq!(a as u64 % PAGE_SIZE, 0);
+        assert_ne!(a, b);
+        assert_eq!(allocator.free_pages(), 2);
+
+        // Nothing is kept back, whichever allocator it came from.
+        unsafe { cache.free(a, &mut allocator) };
+        assert_eq!(allocator.free_pages(), 3);
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(cache.stats().allocated, 1);
+    }
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(u64);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static COUNTED: SpinLockIrq<SlabCache<Counted>> =
+        SpinLockIrq::new(SlabCache::new("counted", None, None));
+
+    #[test_case]
+    fn a_slab_box_drops_its_object_back_into_the_cache() {
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        let mut object = SlabBox::new(&COUNTED, Counted(1)).unwrap();
+        object.0 += 1;
+        assert_eq!(object.0, 2);
+        assert_eq!(COUNTED.lock().stats().allocated, 1);
+
+        drop(object);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 1);
+        assert_eq!(COUNTED.lock().stats().allocated, 0);
+    }
+
+    #[test_case]
+    fn hooks_run_on_alloc_and_free() {
+        let mut allocator = test_page_allocator(4);
+        let mut cache = SlabCache::new("hooks", Some(construct), Some(destroy));
+        let constructed = CONSTRUCTED.load(Ordering::Relaxed);
+        let destroyed = DESTROYED.load(Ordering::Relaxed);
+
+        let object = cache.alloc(&mut allocator).unwrap();
+        assert_eq!(unsafe { (*object).value }, 42);
+        unsafe { cache.free(object, &mut allocator) };
+
+        assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), constructed + 1);
+        assert_eq!(DESTROYED.load(Ordering::Relaxed), destroyed + 1);
+        assert_eq!(cache.stats().peak_allocated, 1);
+        assert_eq!(cache.stats().allocated, 0);
+    }
+}
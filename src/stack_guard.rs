@@ -0,0 +1,121 @@
+use crate::backtrace::StackBounds;
+use core::arch::asm;
+use core::mem::size_of;
+
+pub const CANARY: u64 = 0x57ac_c0de_57ac_c0de;
+// The rest of a fresh stack, so the deepest a stack has reached is the
+// lowest word that's no longer this.
+pub const FILL: u64 = 0x5a5a_5a5a_5a5a_5a5a;
+
+const CANARY_WORDS: usize = 4;
+const CANARY_SIZE: u64 = (CANARY_WORDS * size_of::<u64>()) as u64;
+
+fn current_sp() -> u64 {
+    let sp: u64;
+    unsafe {
+        asm!("mv {}, sp", out(reg) sp);
+    }
+    sp
+}
+
+// Lays the canary at the bottom of the stack and the fill above it. On the
+// stack we're running on, only what's below sp is free to overwrite.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(bounds: &StackBounds) {
+    let top = match current_sp() {
+        sp if bounds.contains(sp) => sp & !(size_of::<u64>() as u64 - 1),
+        _ => bounds.high,
+    };
+    let canary = bounds.low as *mut u64;
+    for i in 0..CANARY_WORDS {
+        canary.add(i).write_volatile(CANARY);
+    }
+    let mut word = bounds.low + CANARY_SIZE;
+    while word < top {
+        (word as *mut u64).write_volatile(FILL);
+        word += size_of::<u64>() as u64;
+    }
+}
+
+pub fn is_intact(bounds: &StackBounds) -> bool {
+    let canary = bounds.low as *const u64;
+    (0..CANARY_WORDS).all(|i| unsafe { canary.add(i).read_volatile() } == CANARY)
+}
+
+// The most of the stack that has ever been in use, in bytes.
+pub fn high_water(bounds: &StackBounds) -> u64 {
+    if !is_intact(bounds) {
+        return bounds.high - bounds.low;
+    }
+    let mut word = bounds.low + CANARY_SIZE;
+    while word < bounds.high && unsafe { (word as *const u64).read_volatile() } == FILL {
+        word += size_of::<u64>() as u64;
+    }
+    bounds.high - word
+}
+
+// Carrying on past an overflow would mean running on whatever it trampled.
+pub fn verify(bounds: &StackBounds, owner: &str, tid: u64) {
+    if !is_intact(bounds) {
+        panic!(
+            "Kernel stack overflow in thread {} ({}): canary at {:#x} overwritten, {} byte stack",
+            tid,
+            owner,
+            bounds.low,
+            bounds.high - bounds.low
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn bounds_of(words: &mut [u64]) -> StackBounds {
+        let low = words.as_mut_ptr() as u64;
+        StackBounds {
+            low,
+            high: low + core::mem::size_of_val(words) as u64,
+        }
+    }
+
+    #[test_case]
+    fn a_fresh_stack_is_intact_and_unused() {
+        let mut words = vec![0u64; 64];
+        let bounds = bounds_of(&mut words);
+        unsafe { install(&bounds) };
+        assert!(is_intact(&bounds));
+        assert_eq!(high_water(&bounds), 0);
+    }
+
+    #[test_case]
+    fn the_high_water_mark_is_the_deepest_write() {
+        let mut words = vec![0u64; 64];
+        let bounds = bounds_of(&mut words);
+        unsafe { install(&bounds) };
+        words[60] = 1;
+        words[20] = 1;
+        words[62] = FILL;
+        assert_eq!(high_water(&bounds), 44 * 8);
+        verify(&bounds, "test", 0);
+    }
+
+    #[test_case]
+    fn an_overwritten_canary_is_caught() {
+        let mut words = vec![0u64; 64];
+        let bounds = bounds_of(&mut words);
+        unsafe { install(&bounds) };
+        words[CANARY_WORDS - 1] = 0;
+        assert!(!is_intact(&bounds));
+        assert_eq!(high_water(&bounds), 64 * 8);
+    }
+
+    #[test_case]
+    fn the_running_stack_is_intact() {
+        let bounds = StackBounds::current();
+        assert!(is_intact(&bounds));
+        let used = high_water(&bounds);
+        assert!(used > 0 && used < bounds.high - bounds.low);
+    }
+}
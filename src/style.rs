@@ -0,0 +1,115 @@
+use crate::cmdline;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Off with the nocolor boot argument, or from the monitor, for when the
+// console isn't a terminal and escapes would only get in the way.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    foreground: Option<Color>,
+    bold: bool,
+}
+
+impl Style {
+    pub const PLAIN: Style = Style {
+        foreground: None,
+        bold: false,
+    };
+
+    pub const fn fg(self, color: Color) -> Style {
+        Style {
+            foreground: Some(color),
+            ..self
+        }
+    }
+
+    pub const fn bold(self) -> Style {
+        Style { bold: true, ..self }
+    }
+
+    pub fn paint<T: fmt::Display>(self, value: T) -> Styled<T> {
+        Styled { style: self, value }
+    }
+
+    // The SGR escape that turns the style on.
+    fn write_start(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\x1b[")?;
+        let mut first = true;
+        let mut code = |f: &mut fmt::Formatter, code: u8| {
+            let separator = if first { "" } else { ";" };
+            first = false;
+            write!(f, "{}{}", separator, code)
+        };
+        if self.bold {
+            code(f, 1)?;
+        }
+        if let Some(color) = self.foreground {
+            code(f, 30 + color as u8)?;
+        }
+        f.write_str("m")
+    }
+}
+
+// A value shown in a style. Width and alignment apply to the value, not the
+// escapes around it.
+pub struct Styled<T> {
+    style: Style,
+    value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !is_enabled() || self.style == Style::PLAIN {
+            return self.value.fmt(f);
+        }
+        self.style.write_start(f)?;
+        self.value.fmt(f)?;
+        f.write_str("\x1b[0m")
+    }
+}
+
+pub fn init() {
+    if cmdline::has("nocolor") {
+        set_enabled(false);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn styled_values_are_wrapped_in_escapes() {
+        set_enabled(true);
+        let style = Style::PLAIN.fg(Color::Red).bold();
+        assert_eq!(format!("{}", style.paint("no")), "\x1b[1;31mno\x1b[0m");
+        assert_eq!(
+            format!("[{:<4}]", Style::PLAIN.fg(Color::Green).paint("ok")),
+            "[\x1b[32mok  \x1b[0m]"
+        );
+        assert_eq!(format!("{}", Style::PLAIN.paint(42)), "42");
+    }
+}
@@ -0,0 +1,225 @@
+use core::fmt;
+use core::ptr;
+
+// Zeroed at link time and filled in by tools/symbolize.py. Layout:
+//   header:  magic "RVSYMTAB", symbol count (u64), strings offset (u64)
+//   entries: address (u64), name offset (u32), size (u32), sorted by address
+//   strings: NUL-terminated names
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+const MAGIC: &[u8; 8] = b"RVSYMTAB";
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 16;
+
+// Mutable so the compiler can't assume the zeros it was linked with.
+#[used]
+#[link_section = ".symbols"]
+static mut SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub address: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    bytes: &'a [u8],
+    count: usize,
+    strings_offset: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.get(..8)? != MAGIC {
+            return None;
+        }
+
+        let count = read_u64(bytes, 8)? as usize;
+        let strings_offset = read_u64(bytes, 16)? as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > strings_offset || strings_offset > bytes.len() {
+            return None;
+        }
+
+        Some(Self {
+            bytes,
+            count,
+            strings_offset,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn address(&self, idx: usize) -> u64 {
+        read_u64(self.bytes, HEADER_SIZE + idx * ENTRY_SIZE).unwrap()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Symbol<'a>> {
+        if idx >= self.count {
+            return None;
+        }
+
+        let entry = HEADER_SIZE + idx * ENTRY_SIZE;
+        let name_offset = self.strings_offset + read_u32(self.bytes, entry + 8)? as usize;
+        let name = self.bytes.get(name_offset..)?;
+        let length = name.iter().position(|&b| b == 0)?;
+
+        Some(Symbol {
+            name: core::str::from_utf8(&name[..length]).ok()?,
+            address: self.address(idx),
+            size: read_u32(self.bytes, entry + 12)? as u64,
+        })
+    }
+
+    fn search(&self, address: u64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.address(mid) {
+                a if a < address => low = mid + 1,
+                a if a > address => high = mid,
+                _ => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    // The symbol containing `address` and the offset into it. Symbols
+    // without a size extend to the next one.
+    pub fn resolve(&self, address: u64) -> Option<(Symbol<'a>, u64)> {
+        let idx = match self.search(address) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let symbol = self.get(idx)?;
+        let offset = address - symbol.address;
+        let end = match symbol.size {
+            0 => self.get(idx + 1).map_or(u64::MAX, |next| next.address),
+            size => symbol.address + size,
+        };
+
+        if address >= end {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+}
+
+pub fn table() -> Option<SymbolTable<'static>> {
+    let bytes = unsafe {
+        let base = ptr::addr_of!(SYMBOL_TABLE) as *const u8;
+        core::slice::from_raw_parts(base, SYMBOL_TABLE_SIZE)
+    };
+    SymbolTable::from_bytes(bytes)
+}
+
+pub fn resolve(address: u64) -> Option<(Symbol<'static>, u64)> {
+    table()?.resolve(address)
+}
+
+// Formats as the address followed by <symbol+offset> when it's known.
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((symbol, offset)) = resolve(self.0) {
+            write!(f, " <{}+{:#x}>", symbol.name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_table(buffer: &mut [u8; 128]) -> SymbolTable<'_> {
+        let symbols: [(u64, u32, &[u8]); 3] = [
+            (0x1000, 0x10, b"first"),
+            (0x2000, 0, b"second"),
+            (0x3000, 0x8, b"third"),
+        ];
+        let strings_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+
+        buffer[..8].copy_from_slice(MAGIC);
+        buffer[8..16].copy_from_slice(&(symbols.len() as u64).to_le_bytes());
+        buffer[16..24].copy_from_slice(&(strings_offset as u64).to_le_bytes());
+
+        let mut string = strings_offset;
+        for (idx, (address, size, name)) in symbols.iter().enumerate() {
+            let entry = HEADER_SIZE + idx * ENTRY_SIZE;
+            buffer[entry..entry + 8].copy_from_slice(&address.to_le_bytes());
+            buffer[entry + 8..entry + 12]
+                .copy_from_slice(&((string - strings_offset) as u32).to_le_bytes());
+            buffer[entry + 12..entry + 16].copy_from_slice(&size.to_le_bytes());
+            buffer[string..string + name.len()].copy_from_slice(name);
+            string += name.len() + 1;
+        }
+
+        SymbolTable::from_bytes(buffer).unwrap()
+    }
+
+    #[test_case]
+    fn a_zeroed_table_is_absent() {
+        assert!(SymbolTable::from_bytes(&[0; 64]).is_none());
+    }
+
+    #[test_case]
+    fn addresses_resolve_to_the_containing_symbol() {
+        let mut buffer = [0; 128];
+        let table = test_table(&mut buffer);
+
+        let (symbol, offset) = table.resolve(0x1004).unwrap();
+        assert_eq!(symbol.name, "first");
+        assert_eq!(offset, 4);
+        assert_eq!(table.resolve(0x3000).unwrap().0.name, "third");
+    }
+
+    #[test_case]
+    fn addresses_outside_every_symbol_do_not_resolve() {
+        let mut buffer = [0; 128];
+        let table = test_table(&mut buffer);
+
+        assert!(table.resolve(0x0fff).is_none());
+        assert!(table.resolve(0x1010).is_none());
+        assert!(table.resolve(0x3008).is_none());
+    }
+
+    #[test_case]
+    fn unsized_symbols_extend_to_the_next_one() {
+        let mut buffer = [0; 128];
+        let table = test_table(&mut buffer);
+
+        assert_eq!(table.resolve(0x2fff).unwrap().0.name, "second");
+    }
+
+    #[test_case]
+    fn the_trap_handler_resolves_when_symbols_are_embedded() {
+        if let Some(table) = table() {
+            let (symbol, _) = table
+                .resolve(crate::trap::kernel_trap as *const () as u64)
+                .unwrap();
+            assert!(symbol.name.ends_with("kernel_trap"));
+        }
+    }
+}
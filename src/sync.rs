@@ -0,0 +1,169 @@
+use crate::task;
+use crate::trap::{self, IrqSave, LockIrqSave};
+use crate::wait_queue::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// After waking a thread from thread context, so that one which outranks
+// this thread runs now rather than at the next tick. An interrupt handler
+// can't switch away, but the return from the interrupt does the same.
+fn give_way() {
+    assert!(
+        trap::trap_depth() == 0,
+        "Interrupt handlers must use the _from_irq variants"
+    );
+    trap::without_interrupts(task::preempt);
+}
+
+// Counts permits, blocking a thread that wants one until one is released.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.release_from_irq();
+        give_way();
+    }
+
+    pub fn release_from_irq(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+// Threads waiting for a change to state behind a lock. The lock is taken
+// with interrupts off, so a handler can change the state and notify.
+pub struct CondVar {
+    waiters: WaitQueue,
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Blocks while `condition` holds for the state behind `lock`, without
+    // holding the lock, and returns it held once the condition is false.
+    pub fn wait_while<'a, T, L: LockIrqSave<T>>(
+        &self,
+        lock: &'a L,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> IrqSave<'a, T> {
+        loop {
+            let mut state = lock.lock_irqsave();
+            if !condition(&mut state) {
+                return state;
+            }
+            drop(state);
+            // Checked again once it's queued, so a notification in between
+            // isn't missed.
+            self.waiters
+                .wait_until(|| !condition(&mut lock.lock_irqsave()));
+        }
+    }
+
+    pub fn notify_one(&self) {
+        self.notify_one_from_irq();
+        give_way();
+    }
+
+    pub fn notify_all(&self) {
+        self.notify_all_from_irq();
+        give_way();
+    }
+
+    pub fn notify_one_from_irq(&self) {
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all_from_irq(&self) {
+        self.waiters.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lock::Lock;
+    use crate::task::test::yield_until;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    #[test_case]
+    fn permits_are_counted() {
+        let semaphore = Semaphore::new(1);
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+        semaphore.release();
+        assert_eq!(semaphore.permits(), 1);
+    }
+
+    #[test_case]
+    fn acquire_blocks_until_a_release() {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let acquired = Arc::new(AtomicBool::new(false));
+        let (waiting, done) = (semaphore.clone(), acquired.clone());
+        task::spawn(move || {
+            waiting.acquire();
+            done.store(true, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| semaphore.waiters.len() == 1);
+        assert!(!acquired.load(Ordering::Relaxed));
+        semaphore.release();
+        yield_until(|| acquired.load(Ordering::Relaxed));
+        assert_eq!(semaphore.permits(), 0);
+    }
+
+    #[test_case]
+    fn wait_while_returns_the_changed_state() {
+        let state = Arc::new((Lock::new(0), CondVar::new()));
+        let waiter = state.clone();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let result = seen.clone();
+        task::spawn(move || {
+            let (lock, changed) = &*waiter;
+            let value = changed.wait_while(lock, |value| *value == 0);
+            result.store(*value, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| state.1.waiters.len() == 1);
+        *state.0.lock() = 7;
+        state.1.notify_all();
+        yield_until(|| seen.load(Ordering::Relaxed) == 7);
+    }
+}
@@ -0,0 +1,945 @@
+use crate::address_space::{self, AddressSpace, USER_START};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::PAGE_SIZE;
+use crate::pipe;
+use crate::process::{Pid, ProcessState};
+use crate::rusage::ResourceUsage;
+use crate::shm::{self, SharedMemory};
+use crate::signal::{self, Action};
+use crate::trap::TrapFrame;
+use crate::vfs::{self, FileType, OpenFile, OpenOptions, SeekFrom, Stat};
+use crate::{buffer_cache, futex, process, task, timer, uaccess, user};
+use alloc::string::String;
+
+// The RISC-V Linux numbers, so ordinary toolchains can target the kernel.
+pub const DUP: u64 = 23;
+pub const DUP3: u64 = 24;
+pub const MKDIRAT: u64 = 34;
+pub const UNLINKAT: u64 = 35;
+pub const OPENAT: u64 = 56;
+pub const CLOSE: u64 = 57;
+pub const PIPE2: u64 = 59;
+pub const LSEEK: u64 = 62;
+pub const READ: u64 = 63;
+pub const WRITE: u64 = 64;
+pub const FSTAT: u64 = 80;
+pub const SYNC: u64 = 81;
+pub const EXIT: u64 = 93;
+pub const FUTEX: u64 = 98;
+pub const SCHED_YIELD: u64 = 124;
+pub const KILL: u64 = 129;
+pub const RT_SIGACTION: u64 = 134;
+pub const RT_SIGPROCMASK: u64 = 135;
+pub const RT_SIGRETURN: u64 = 139;
+pub const GETRUSAGE: u64 = 165;
+pub const GETPID: u64 = 172;
+pub const BRK: u64 = 214;
+pub const MUNMAP: u64 = 215;
+// Only in its fork form.
+pub const CLONE: u64 = 220;
+pub const EXECVE: u64 = 221;
+pub const MMAP: u64 = 222;
+pub const WAIT4: u64 = 260;
+pub const PRLIMIT64: u64 = 261;
+// Not Linux's, which has none and opens files in /dev/shm instead. It
+// takes the name, open flags and, to create one, a size.
+pub const SHM_OPEN: u64 = 1024;
+
+// Only relative to the working directory, which is always the root for now.
+const AT_FDCWD: u64 = -100i64 as u64;
+const AT_REMOVEDIR: u64 = 0x200;
+
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+// Accepted but not acted on, as exec keeps every descriptor for now.
+const O_CLOEXEC: u64 = 0o2000000;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// The generic struct stat RISC-V Linux uses.
+const STAT_SIZE: usize = 128;
+
+const SIGCHLD: u64 = 17;
+// The only sigset_t size there is, a bit for each of 64 signals.
+const SIGSET_SIZE: u64 = 8;
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+const PATH_MAX: usize = 4096;
+
+const PROT_READ: u64 = 1 << 0;
+const PROT_WRITE: u64 = 1 << 1;
+const PROT_EXEC: u64 = 1 << 2;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+// Accepted and ignored, as every futex is found by its physical address.
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const RUSAGE_SELF: u64 = 0;
+const RUSAGE_CHILDREN: u64 = -1i64 as u64;
+const RUSAGE_THREAD: u64 = 1;
+const RUSAGE_SIZE: usize = 144;
+
+const RLIMIT_RSS: u64 = 5;
+const RLIMIT_NOFILE: u64 = 7;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// User buffers are copied in through this much kernel stack at a time.
+const CHUNK_SIZE: usize = 256;
+
+// Handlers get a0 to a5 and return what goes back in a0.
+type SyscallHandler = fn(&mut TrapFrame, [u64; 6]) -> KernelResult<u64>;
+
+struct Syscall {
+    number: u64,
+    name: &'static str,
+    handler: SyscallHandler,
+}
+
+const SYSCALLS: &[Syscall] = &[
+    Syscall {
+        number: DUP,
+        name: "dup",
+        handler: dup,
+    },
+    Syscall {
+        number: DUP3,
+        name: "dup3",
+        handler: dup3,
+    },
+    Syscall {
+        number: MKDIRAT,
+        name: "mkdirat",
+        handler: mkdirat,
+    },
+    Syscall {
+        number: UNLINKAT,
+        name: "unlinkat",
+        handler: unlinkat,
+    },
+    Syscall {
+        number: OPENAT,
+        name: "openat",
+        handler: openat,
+    },
+    Syscall {
+        number: CLOSE,
+        name: "close",
+        handler: close,
+    },
+    Syscall {
+        number: PIPE2,
+        name: "pipe2",
+        handler: pipe2,
+    },
+    Syscall {
+        number: LSEEK,
+        name: "lseek",
+        handler: lseek,
+    },
+    Syscall {
+        number: READ,
+        name: "read",
+        handler: read,
+    },
+    Syscall {
+        number: WRITE,
+        name: "write",
+        handler: write,
+    },
+    Syscall {
+        number: FSTAT,
+        name: "fstat",
+        handler: fstat,
+    },
+    Syscall {
+        number: SYNC,
+        name: "sync",
+        handler: sync,
+    },
+    Syscall {
+        number: EXIT,
+        name: "exit",
+        handler: exit,
+    },
+    Syscall {
+        number: FUTEX,
+        name: "futex",
+        handler: futex,
+    },
+    Syscall {
+        number: SCHED_YIELD,
+        name: "sched_yield",
+        handler: sched_yield,
+    },
+    Syscall {
+        number: KILL,
+        name: "kill",
+        handler: kill,
+    },
+    Syscall {
+        number: RT_SIGACTION,
+        name: "rt_sigaction",
+        handler: sigaction,
+    },
+    Syscall {
+        number: RT_SIGPROCMASK,
+        name: "rt_sigprocmask",
+        handler: sigprocmask,
+    },
+    Syscall {
+        number: RT_SIGRETURN,
+        name: "rt_sigreturn",
+        handler: sigreturn,
+    },
+    Syscall {
+        number: GETRUSAGE,
+        name: "getrusage",
+        handler: getrusage,
+    },
+    Syscall {
+        number: GETPID,
+        name: "getpid",
+        handler: getpid,
+    },
+    Syscall {
+        number: BRK,
+        name: "brk",
+        handler: brk,
+    },
+    Syscall {
+        number: MUNMAP,
+        name: "munmap",
+        handler: munmap,
+    },
+    Syscall {
+        number: CLONE,
+        name: "clone",
+        handler: fork,
+    },
+    Syscall {
+        number: EXECVE,
+        name: "execve",
+        handler: execve,
+    },
+    Syscall {
+        number: MMAP,
+        name: "mmap",
+        handler: mmap,
+    },
+    Syscall {
+        number: WAIT4,
+        name: "wait4",
+        handler: wait4,
+    },
+    Syscall {
+        number: PRLIMIT64,
+        name: "prlimit64",
+        handler: prlimit64,
+    },
+    Syscall {
+        number: SHM_OPEN,
+        name: "shm_open",
+        handler: shm_open,
+    },
+];
+
+pub fn name(number: u64) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|syscall| syscall.number == number)
+        .map(|syscall| syscall.name)
+}
+
+// The number is in a7. Errors go back as negative errnos, as on Linux.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let number = frame.reg(17);
+    let args = [
+        frame.arg(0),
+        frame.arg(1),
+        frame.arg(2),
+        frame.arg(3),
+        frame.arg(4),
+        frame.arg(5),
+    ];
+    // Before the handler runs, so one that starts the process somewhere
+    // new isn't undone. ecall is never compressed.
+    frame.sepc += 4;
+    process::with_current(|process| process.usage_mut().record_syscall());
+
+    let result = match SYSCALLS.iter().find(|syscall| syscall.number == number) {
+        Some(syscall) => (syscall.handler)(frame, args),
+        None => Err(KernelError::NotSupported),
+    };
+    frame.set_return_value(match result {
+        Ok(value) => value,
+        Err(e) => e.errno() as u64,
+    });
+}
+
+fn file(fd: u64) -> KernelResult<OpenFile> {
+    process::with_current(|process| process.files().get(fd as usize))
+        .ok_or(KernelError::NotFound)?
+}
+
+// Reads at most a chunk, which a file can cut shorter still.
+fn read(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fd, buffer, length, ..] = args;
+    let file = file(fd)?;
+    // Only the part of the buffer this read can fill is faulted in, but all
+    // of that before the file gives up any data.
+    let size = length.min(CHUNK_SIZE as u64) as usize;
+    if !uaccess::can_access(buffer, size as u64, true) {
+        return Err(KernelError::InvalidAddress);
+    }
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let read = file.read(&mut chunk[..size])?;
+    uaccess::copy_to_user(buffer, &chunk[..read])?;
+    Ok(read as u64)
+}
+
+// Stops early if the file takes less than it was given, returning what was
+// written so far.
+fn write(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fd, buffer, length, ..] = args;
+    let file = file(fd)?;
+
+    // Checked up front, so nothing is written if any of it is bad.
+    if !uaccess::can_access(buffer, length, false) {
+        return Err(KernelError::InvalidAddress);
+    }
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut written = 0;
+    while written < length {
+        let size = (length - written).min(CHUNK_SIZE as u64) as usize;
+        uaccess::copy_from_user(&mut chunk[..size], buffer + written)?;
+        match file.write(&chunk[..size]) {
+            Ok(count) => {
+                written += count as u64;
+                if count < size {
+                    break;
+                }
+            }
+            Err(e) if written == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(written)
+}
+
+fn lseek(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fd, offset, whence, ..] = args;
+    let position = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(KernelError::InvalidArgument),
+    };
+    file(fd)?.seek(position)
+}
+
+fn stat_bytes(stat: &Stat) -> [u8; STAT_SIZE] {
+    let kind = match stat.file_type {
+        FileType::Regular => S_IFREG,
+        FileType::Directory => S_IFDIR,
+        FileType::CharDevice => S_IFCHR,
+        FileType::Pipe => S_IFIFO,
+    };
+    let mut bytes = [0; STAT_SIZE];
+    bytes[8..16].copy_from_slice(&stat.inode.to_le_bytes());
+    bytes[16..20].copy_from_slice(&(kind | stat.permissions as u32).to_le_bytes());
+    // One link.
+    bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+    bytes[48..56].copy_from_slice(&stat.size.to_le_bytes());
+    bytes[56..60].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    bytes[64..72].copy_from_slice(&stat.size.div_ceil(512).to_le_bytes());
+    bytes
+}
+
+fn fstat(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fd, buffer, ..] = args;
+    let stat = file(fd)?.stat()?;
+    uaccess::copy_to_user(buffer, &stat_bytes(&stat))?;
+    Ok(0)
+}
+
+// Linux's can't fail, but this says if a disk did.
+fn sync(_frame: &mut TrapFrame, _args: [u64; 6]) -> KernelResult<u64> {
+    buffer_cache::sync_all()?;
+    Ok(0)
+}
+
+fn dup(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    process::with_current(|process| process.files_mut().dup(args[0] as usize))
+        .ok_or(KernelError::NotFound)?
+        .map(|fd| fd as u64)
+}
+
+// No flags are supported, and unlike dup2 it won't dup onto itself.
+fn dup3(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fd, new_fd, flags, ..] = args;
+    if flags != 0 || fd == new_fd {
+        return Err(KernelError::InvalidArgument);
+    }
+    process::with_current(|process| process.files_mut().dup_to(fd as usize, new_fd as usize))
+        .ok_or(KernelError::NotFound)?
+        .map(|fd| fd as u64)
+}
+
+// The path at `address`, so long as it's from the working directory.
+fn path_from_user(dirfd: u64, address: u64) -> KernelResult<String> {
+    if dirfd != AT_FDCWD {
+        return Err(KernelError::NotSupported);
+    }
+    uaccess::copy_string_from_user(address, PATH_MAX)
+}
+
+// Modes are ignored, there being no owners or permissions to check.
+fn openat(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [dirfd, path, flags, ..] = args;
+    let path = path_from_user(dirfd, path)?;
+    let known = O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_DIRECTORY | O_CLOEXEC;
+    if flags & !known != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(KernelError::InvalidArgument),
+    };
+
+    let file = vfs::open(
+        &path,
+        OpenOptions {
+            read,
+            write,
+            create: flags & O_CREAT != 0,
+            exclusive: flags & O_EXCL != 0,
+            truncate: flags & O_TRUNC != 0,
+            append: flags & O_APPEND != 0,
+            directory: flags & O_DIRECTORY != 0,
+        },
+    )?;
+    process::with_current(|process| process.files_mut().insert(file))
+        .ok_or(KernelError::NotFound)?
+        .map(|fd| fd as u64)
+}
+
+fn mkdirat(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [dirfd, path, ..] = args;
+    vfs::mkdir(&path_from_user(dirfd, path)?)?;
+    Ok(0)
+}
+
+// Removes a directory with AT_REMOVEDIR, and anything else without.
+fn unlinkat(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [dirfd, path, flags, ..] = args;
+    let path = path_from_user(dirfd, path)?;
+    match flags {
+        0 => vfs::unlink(&path)?,
+        AT_REMOVEDIR => vfs::rmdir(&path)?,
+        _ => return Err(KernelError::InvalidArgument),
+    }
+    Ok(0)
+}
+
+fn close(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    process::with_current(|process| process.files_mut().close(args[0] as usize))
+        .ok_or(KernelError::NotFound)??;
+    Ok(0)
+}
+
+// Stores the read end's descriptor and then the write end's, as ints.
+fn pipe2(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [fds, flags, ..] = args;
+    if flags != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    if !uaccess::can_access(fds, 8, true) {
+        return Err(KernelError::InvalidAddress);
+    }
+
+    let (reader, writer) = pipe::pipe();
+    let (read_fd, write_fd) = process::with_current(|process| {
+        let files = process.files_mut();
+        let read_fd = files.insert(OpenFile::new(reader))?;
+        match files.insert(OpenFile::new(writer)) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                files.close(read_fd)?;
+                Err(e)
+            }
+        }
+    })
+    .ok_or(KernelError::NotFound)??;
+
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+    uaccess::copy_to_user(fds, &bytes)?;
+    Ok(0)
+}
+
+fn exit(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    process::exit(args[0] as i64)
+}
+
+// WAIT sleeps while the word at `address` holds `value`, and WAKE wakes
+// up to `value` of the threads waiting on it. WAIT has no timeout.
+fn futex(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [address, op, value, timeout, ..] = args;
+    if address % 4 != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    // For writing, so a copy-on-write page is copied now rather than the
+    // word moving out from under its key later.
+    if !uaccess::can_access(address, 4, true) {
+        return Err(KernelError::InvalidAddress);
+    }
+    let key = process::with_current(|process| {
+        process
+            .address_space()
+            .and_then(|space| space.translate(address))
+    })
+    .flatten()
+    .ok_or(KernelError::InvalidAddress)?
+    .as_u64();
+
+    match op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT if timeout != 0 => Err(KernelError::NotSupported),
+        FUTEX_WAIT => futex::wait(key, value as u32).map(|()| 0),
+        FUTEX_WAKE => Ok(futex::wake(key, value as usize) as u64),
+        _ => Err(KernelError::NotSupported),
+    }
+}
+
+fn sched_yield(_frame: &mut TrapFrame, _args: [u64; 6]) -> KernelResult<u64> {
+    task::yield_now();
+    Ok(0)
+}
+
+// Only to a single process, by PID. Signal 0 just checks it's there.
+fn kill(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [pid, signal, ..] = args;
+    let pid = match pid as i64 {
+        pid if pid > 0 => Pid(pid as u64),
+        _ => return Err(KernelError::NotSupported),
+    };
+    match signal {
+        0 if process::state(pid).is_some_and(|state| state != ProcessState::Zombie) => Ok(0),
+        0 => Err(KernelError::NoSuchProcess),
+        signal => process::send_signal(pid, signal as u32).map(|()| 0),
+    }
+}
+
+fn u64_from_user(address: u64) -> KernelResult<u64> {
+    let mut bytes = [0; 8];
+    uaccess::copy_from_user(&mut bytes, address)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// The struct sigaction is the handler, the flags and then the mask, there
+// being no sa_restorer on RISC-V.
+fn sigaction(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [signal, action, old_action, size, ..] = args;
+    if size != SIGSET_SIZE {
+        return Err(KernelError::InvalidArgument);
+    }
+    let action = match action {
+        0 => None,
+        address => {
+            let mut bytes = [0; 24];
+            uaccess::copy_from_user(&mut bytes, address)?;
+            let word = |n: usize| u64::from_le_bytes(bytes[n * 8..][..8].try_into().unwrap());
+            Some(Action::from_words(word(0), word(1), word(2))?)
+        }
+    };
+
+    let old = process::with_current(|process| {
+        let signals = process.signals_mut();
+        match action {
+            Some(action) => signals.set_action(signal as u32, action),
+            None => signals.action(signal as u32),
+        }
+    })
+    .ok_or(KernelError::NotFound)??;
+    if old_action != 0 {
+        let mut bytes = [0; 24];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(old.to_words()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        uaccess::copy_to_user(old_action, &bytes)?;
+    }
+    Ok(0)
+}
+
+fn sigprocmask(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [how, set, old_set, size, ..] = args;
+    if size != SIGSET_SIZE {
+        return Err(KernelError::InvalidArgument);
+    }
+    let set = match set {
+        0 => None,
+        address => Some(u64_from_user(address)?),
+    };
+
+    let old = process::with_current(|process| {
+        let signals = process.signals_mut();
+        let old = signals.blocked();
+        match (how, set) {
+            (_, None) => (),
+            (SIG_BLOCK, Some(set)) => signals.set_blocked(old | set),
+            (SIG_UNBLOCK, Some(set)) => signals.set_blocked(old & !set),
+            (SIG_SETMASK, Some(set)) => signals.set_blocked(set),
+            _ => return Err(KernelError::InvalidArgument),
+        }
+        Ok(old)
+    })
+    .ok_or(KernelError::NotFound)??;
+    if old_set != 0 {
+        uaccess::copy_to_user(old_set, &old.to_le_bytes())?;
+    }
+    Ok(0)
+}
+
+// Returns to wherever the handler interrupted, rather than to the caller.
+fn sigreturn(frame: &mut TrapFrame, _args: [u64; 6]) -> KernelResult<u64> {
+    signal::sigreturn(frame)
+}
+
+fn getpid(_frame: &mut TrapFrame, _args: [u64; 6]) -> KernelResult<u64> {
+    process::current_pid()
+        .map(|pid| pid.0)
+        .ok_or(KernelError::NotFound)
+}
+
+// The struct rusage RISC-V Linux uses. All the CPU time counts as user
+// time, as time spent in the kernel isn't told apart, and the faults are
+// the minor ones, there being no swap to make them major.
+fn rusage_bytes(usage: &ResourceUsage) -> [u8; RUSAGE_SIZE] {
+    let frequency = timer::timebase_frequency();
+    let seconds = usage.cpu_ticks / frequency;
+    let micros = usage.cpu_ticks % frequency * 1_000_000 / frequency;
+    let max_rss = usage.peak_resident_pages * PAGE_SIZE / 1024;
+    let mut bytes = [0; RUSAGE_SIZE];
+    bytes[0..8].copy_from_slice(&seconds.to_le_bytes());
+    bytes[8..16].copy_from_slice(&micros.to_le_bytes());
+    bytes[32..40].copy_from_slice(&max_rss.to_le_bytes());
+    bytes[64..72].copy_from_slice(&usage.faults.to_le_bytes());
+    bytes
+}
+
+// A thread's usage is its process's, each process having just the one.
+fn getrusage(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [who, buffer, ..] = args;
+    let usage = process::with_current(|process| match who {
+        RUSAGE_SELF | RUSAGE_THREAD => Ok(process.usage()),
+        RUSAGE_CHILDREN => Ok(process.children_usage()),
+        _ => Err(KernelError::InvalidArgument),
+    })
+    .ok_or(KernelError::NotFound)??;
+    uaccess::copy_to_user(buffer, &rusage_bytes(&usage))?;
+    Ok(0)
+}
+
+// Gets and sets the calling process's limits, with a PID of 0 or its own.
+// RLIMIT_NOFILE is the one Linux has, but RLIMIT_RSS, which Linux ignores,
+// limits how many bytes of pages it has mapped. Either hard limit can be
+// lowered but, there being no privileged processes, never raised.
+fn prlimit64(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [pid, resource, new, old, ..] = args;
+    if pid != 0 && process::current_pid() != Some(Pid(pid)) {
+        return Err(KernelError::NotSupported);
+    }
+    let new = if new != 0 {
+        let mut bytes = [0; 16];
+        uaccess::copy_from_user(&mut bytes, new)?;
+        let soft = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let hard = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+        if soft > hard {
+            return Err(KernelError::InvalidArgument);
+        }
+        Some((soft, hard))
+    } else {
+        None
+    };
+
+    let (soft, hard) = process::with_current(|process| {
+        let mut limits = process.limits();
+        let current = match resource {
+            RLIMIT_NOFILE => (limits.files as u64, limits.max_files as u64),
+            RLIMIT_RSS => (rss_bytes(limits.pages), rss_bytes(limits.max_pages)),
+            _ => return Err(KernelError::InvalidArgument),
+        };
+        if let Some((soft, hard)) = new {
+            match resource {
+                RLIMIT_NOFILE => {
+                    limits.files = soft.min(usize::MAX as u64) as usize;
+                    limits.max_files = hard.min(usize::MAX as u64) as usize;
+                }
+                _ => {
+                    limits.pages = rss_pages(soft);
+                    limits.max_pages = rss_pages(hard);
+                }
+            }
+            process.set_limits(limits)?;
+        }
+        Ok(current)
+    })
+    .ok_or(KernelError::NotFound)??;
+
+    if old != 0 {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&soft.to_le_bytes());
+        bytes[8..].copy_from_slice(&hard.to_le_bytes());
+        uaccess::copy_to_user(old, &bytes)?;
+    }
+    Ok(0)
+}
+
+// A page limit as an RLIMIT_RSS in bytes, usize::MAX being no limit at all.
+fn rss_bytes(pages: usize) -> u64 {
+    if pages == usize::MAX {
+        RLIM_INFINITY
+    } else {
+        pages as u64 * PAGE_SIZE
+    }
+}
+
+fn rss_pages(bytes: u64) -> usize {
+    if bytes == RLIM_INFINITY {
+        usize::MAX
+    } else {
+        (bytes / PAGE_SIZE) as usize
+    }
+}
+
+// Returns the break, moved to the address given if it could be, as on
+// Linux. sbrk is left to the C library to build on this.
+fn brk(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    process::with_current(|process| {
+        let space = process.address_space_mut().ok_or(KernelError::NotFound)?;
+        if args[0] != 0 {
+            // A failure just leaves the break where it was.
+            let _ = space.set_brk(args[0]);
+        }
+        Ok(space.brk())
+    })
+    .ok_or(KernelError::NotFound)?
+}
+
+// Private anonymous mappings are faulted in a page at a time. Shared ones
+// are of shared memory from shm_open, and map all of it there is at once.
+fn mmap(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [address, length, prot, flags, fd, offset] = args;
+    let kind = MAP_SHARED | MAP_PRIVATE | MAP_ANONYMOUS;
+    if length == 0 || flags & !(kind | MAP_FIXED) != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let segment = match flags & kind {
+        MAP_SHARED => {
+            let (segment, writable) = file(fd)?.shared_memory().ok_or(KernelError::NotSupported)?;
+            if prot & PROT_WRITE != 0 && !writable {
+                return Err(KernelError::PermissionDenied);
+            }
+            Some(segment)
+        }
+        kind if kind == MAP_PRIVATE | MAP_ANONYMOUS => None,
+        _ => return Err(KernelError::InvalidArgument),
+    };
+    let mode = address_space::mode_for(
+        prot & PROT_READ != 0,
+        prot & PROT_WRITE != 0,
+        prot & PROT_EXEC != 0,
+    )
+    .ok_or(KernelError::InvalidArgument)?;
+
+    process::with_current(|process| {
+        let space = process.address_space_mut().ok_or(KernelError::NotFound)?;
+        let start = if flags & MAP_FIXED != 0 {
+            space.release_range(address, length)?;
+            address
+        } else {
+            // Anywhere will do if the address hinted at isn't free.
+            let hint = if address != 0 {
+                address
+            } else {
+                user::MMAP_BASE
+            };
+            space
+                .find_free(hint, user::STACK_BOTTOM, length)
+                .or_else(|| space.find_free(USER_START, user::STACK_BOTTOM, length))
+                .ok_or(KernelError::OutOfMemory)?
+        };
+        match &segment {
+            Some(segment) => space.map_shared(start, length, segment, offset, mode)?,
+            None => space.reserve(start, length, mode)?,
+        }
+        Ok(start)
+    })
+    .ok_or(KernelError::NotFound)?
+}
+
+fn munmap(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [address, length, ..] = args;
+    if length == 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    process::with_current(|process| {
+        let space = process.address_space_mut().ok_or(KernelError::NotFound)?;
+        space.release_range(address, length)?;
+        Ok(0)
+    })
+    .ok_or(KernelError::NotFound)?
+}
+
+// The child returns 0 from here and the parent gets its PID.
+fn fork(frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [flags, stack, ..] = args;
+    if (flags != SIGCHLD && flags != 0) || stack != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    process::fork(frame).map(|pid| pid.0)
+}
+
+// Arguments and the environment aren't passed on yet. On success it starts
+// the new program rather than returning.
+fn execve(frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let path = uaccess::copy_string_from_user(args[0], PATH_MAX)?;
+    let image = vfs::read_file(&path)?;
+    let mut address_space = AddressSpace::new()?;
+    let start = user::load(&mut address_space, &image)?;
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    process::exec(name, address_space)?;
+    *frame = start;
+    Ok(0)
+}
+
+// Waits for any child with a PID of -1, or for the given one. The status
+// has the exit code in bits 8 to 15, as from a normal exit on Linux, and
+// the usage is the child's and its reaped children's. As on Linux, the
+// child is reaped even if they can't be written.
+fn wait4(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [pid, status, options, rusage, ..] = args;
+    let pid = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid(pid as u64)),
+        _ => return Err(KernelError::InvalidArgument),
+    };
+    if options != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let (child, code, usage) = process::wait_child(pid)?;
+    if status != 0 {
+        let value = ((code & 0xff) << 8) as i32;
+        uaccess::copy_to_user(status, &value.to_le_bytes())?;
+    }
+    if rusage != 0 {
+        uaccess::copy_to_user(rusage, &rusage_bytes(&usage))?;
+    }
+    Ok(child.0)
+}
+
+// Opens the shared memory called `name`, creating it with O_CREAT. A
+// leading slash, as POSIX wants, is ignored. Mapping it writable needs it
+// opened for writing.
+fn shm_open(_frame: &mut TrapFrame, args: [u64; 6]) -> KernelResult<u64> {
+    let [name, flags, size, ..] = args;
+    let name = uaccess::copy_string_from_user(name, PATH_MAX)?;
+    if flags & !(O_ACCMODE | O_CREAT | O_EXCL | O_CLOEXEC) != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let writable = match flags & O_ACCMODE {
+        O_RDONLY => false,
+        O_RDWR => true,
+        _ => return Err(KernelError::InvalidArgument),
+    };
+
+    let name = name.strip_prefix('/').unwrap_or(&name);
+    // A new segment's pages are charged to the caller, within what its page
+    // limit has room for.
+    let (charge, allowance) = process::with_current(|process| {
+        let allowance = process.address_space().map_or(0, AddressSpace::pages_left);
+        (process.page_charge().clone(), allowance)
+    })
+    .ok_or(KernelError::NotFound)?;
+    let (create, exclusive) = (flags & O_CREAT != 0, flags & O_EXCL != 0);
+    let segment = shm::open(name, size, create, exclusive, &charge, allowance)?;
+    let file = OpenFile::new(SharedMemory::new(segment, writable));
+    process::with_current(|process| process.files_mut().insert(file))
+        .ok_or(KernelError::NotFound)?
+        .map(|fd| fd as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process::test::wait_for;
+    use crate::user;
+
+    #[test_case]
+    fn write_returns_the_length_written() {
+        let pid = user::spawn_program("hello", user::hello_program()).unwrap();
+        assert_eq!(wait_for(pid), "Hello from user mode\n".len() as i64);
+    }
+
+    #[test_case]
+    fn writing_from_a_bad_pointer_fails_with_efault() {
+        let pid = user::spawn_program("bad-write", user::bad_write_program()).unwrap();
+        assert_eq!(wait_for(pid), KernelError::InvalidAddress.errno());
+    }
+
+    #[test_case]
+    fn getpid_returns_the_callers_pid() {
+        let pid = user::spawn_program("getpid", user::getpid_program()).unwrap();
+        assert_eq!(wait_for(pid), pid.0 as i64);
+    }
+
+    #[test_case]
+    fn unknown_syscalls_fail_with_enosys() {
+        let pid = user::spawn_program("bad", user::bad_syscall_program()).unwrap();
+        assert_eq!(wait_for(pid), KernelError::NotSupported.errno());
+    }
+
+    #[test_case]
+    fn forked_children_can_be_waited_for() {
+        let pid = user::spawn_program("fork", user::fork_program()).unwrap();
+        assert_eq!(wait_for(pid), 7);
+    }
+
+    #[test_case]
+    fn memory_from_brk_and_mmap_is_faulted_in() {
+        let pid = user::spawn_program("heap", user::heap_program()).unwrap();
+        assert_eq!(wait_for(pid), 42);
+    }
+
+    #[test_case]
+    fn exec_replaces_the_running_program() {
+        let pid = user::spawn_program("exec", user::exec_program()).unwrap();
+        assert_eq!(wait_for(pid), 55);
+    }
+}
@@ -0,0 +1,1109 @@
+use crate::address_space;
+use crate::backtrace::StackBounds;
+use crate::hart::{self, hart_id, MAX_HARTS};
+use crate::ipi::{self, IpiMessage};
+use crate::page_allocator::PAGE_SIZE;
+use crate::per_hart::{self, PerHart};
+use crate::satp::Satp;
+use crate::sched;
+use crate::stack_guard;
+use crate::timer;
+use crate::trap::{self, InterruptGuard, LockIrqSave, TrapFrame};
+use crate::{print, println};
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+const STACK_SIZE: usize = 16 * 1024;
+
+// In timer ticks, so a jiffy each.
+const SLICE_TICKS: u64 = 2;
+
+// How long, in jiffies, a thread must have been waiting on a busy hart
+// before another takes it, so one about to run where it is stays put.
+const MIGRATION_DELAY: u64 = 1;
+
+extern "C" {
+    fn _switch_context(from: *mut Context, to: *const Context);
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    OutOfMemory,
+    // No hart in the mask is online, or the thread can't leave its hart.
+    InvalidAffinity,
+    // No such thread, or it has exited.
+    NotFound,
+}
+
+// The registers _switch_context saves; see switch.S. Everything else is
+// caller-saved, so already on the stack by the time it's called.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Context {
+    ra: u64,
+    sp: u64,
+    s: [u64; 12],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+// A ready thread only runs once nothing of higher priority is ready, and
+// only threads of the same priority share the hart in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+const PRIORITY_LEVELS: usize = 3;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl ThreadId {
+    fn next() -> Self {
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct Stack {
+    base: *mut u8,
+}
+
+impl Stack {
+    fn layout() -> Layout {
+        Layout::from_size_align(STACK_SIZE, PAGE_SIZE as usize).unwrap()
+    }
+
+    fn new() -> Result<Self, TaskError> {
+        let base = unsafe { alloc(Self::layout()) };
+        if base.is_null() {
+            return Err(TaskError::OutOfMemory);
+        }
+        let stack = Self { base };
+        unsafe { stack_guard::install(&stack.bounds()) };
+        Ok(stack)
+    }
+
+    fn bounds(&self) -> StackBounds {
+        let low = self.base as u64;
+        StackBounds {
+            low,
+            high: low + STACK_SIZE as u64,
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base, Self::layout()) };
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    context: Context,
+    // None for the thread a hart booted on, which keeps its boot stack.
+    stack: Option<Stack>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    // The hart it last ran on, which it goes back to when unblocked.
+    hart: usize,
+    priority: Priority,
+    // The page tables it runs on, if not the kernel's own.
+    satp: Option<Satp>,
+    is_idle: bool,
+    // Timer ticks spent running, up to when it was last switched away
+    // from, shared with whatever else accounts for it.
+    cpu_time: Arc<AtomicU64>,
+    // When it was last switched to.
+    switched_in: u64,
+    // When it was last put on a run queue.
+    queued_at: u64,
+    // Bit n is set if it may run on hart n.
+    affinity: u64,
+}
+
+// Only the hart running a thread, or the queue holding it, touches it.
+unsafe impl Send for Thread {}
+
+impl Thread {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    // Only right for a running thread: one without a stack of its own is
+    // on the stack its hart booted on.
+    fn running_stack(&self) -> StackBounds {
+        self.stack.as_ref().map_or_else(
+            || hart::stack_bounds(self.hart).unwrap_or_else(StackBounds::boot_stack),
+            Stack::bounds,
+        )
+    }
+
+    // A thread without a stack of its own is on its hart's boot stack, so
+    // can't run anywhere else.
+    fn can_migrate(&self) -> bool {
+        self.stack.is_some() && !self.is_idle
+    }
+
+    fn allows(&self, hart: usize) -> bool {
+        self.affinity & 1 << hart != 0
+    }
+
+    // Where it should be queued, given it was last on `hart`: there if it
+    // may still run there, and otherwise on the first online hart it may.
+    fn home(&self, hart: usize) -> usize {
+        match self.affinity & hart::online_harts() {
+            _ if self.allows(hart) => hart,
+            0 => hart,
+            allowed => allowed.trailing_zeros() as usize,
+        }
+    }
+}
+
+struct HartTasks {
+    current: Option<Box<Thread>>,
+    // The thread switched away from, until the one switched to has finished
+    // putting it wherever it belongs.
+    previous: Option<Box<Thread>>,
+    // Runs when nothing else is ready; never queued.
+    idle: Option<Box<Thread>>,
+    // Ticks left before the current thread is preempted.
+    slice: u64,
+    need_reschedule: bool,
+}
+
+const NO_TASKS: HartTasks = HartTasks {
+    current: None,
+    previous: None,
+    idle: None,
+    slice: 0,
+    need_reschedule: false,
+};
+
+// A queue per priority, each served in turn.
+struct RunQueue {
+    levels: [VecDeque<Box<Thread>>; PRIORITY_LEVELS],
+}
+
+const EMPTY_LEVEL: VecDeque<Box<Thread>> = VecDeque::new();
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            levels: [EMPTY_LEVEL; PRIORITY_LEVELS],
+        }
+    }
+
+    fn push(&mut self, mut thread: Box<Thread>) {
+        thread.queued_at = timer::read_time();
+        self.levels[thread.priority as usize].push_back(thread);
+    }
+
+    // The longest-waiting of the highest-priority threads, as long as it's
+    // at least `minimum`.
+    fn pop(&mut self, minimum: Priority) -> Option<Box<Thread>> {
+        self.levels[minimum as usize..]
+            .iter_mut()
+            .rev()
+            .find_map(|level| level.pop_front())
+    }
+
+    // The longest-waiting of the highest-priority threads that can move to
+    // `hart` and have been waiting since `before`.
+    fn steal(&mut self, hart: usize, before: u64) -> Option<Box<Thread>> {
+        self.levels.iter_mut().rev().find_map(|level| {
+            let n = level.iter().position(|thread| {
+                thread.can_migrate() && thread.allows(hart) && thread.queued_at <= before
+            })?;
+            level.remove(n)
+        })
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        self.levels.iter_mut().find_map(|level| {
+            let n = level.iter().position(|thread| thread.id == id)?;
+            level.remove(n)
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: Mutex<RunQueue> = Mutex::new(RunQueue::new());
+
+static TASKS: PerHart<HartTasks> = PerHart::new([NO_TASKS; MAX_HARTS]);
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_HARTS] = [EMPTY_QUEUE; MAX_HARTS];
+
+// Blocked threads, and wakeups that arrived before the thread they were for
+// got round to blocking.
+struct Parked {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    wakeups: BTreeSet<ThreadId>,
+}
+
+static PARKED: Mutex<Parked> = Mutex::new(Parked {
+    threads: BTreeMap::new(),
+    wakeups: BTreeSet::new(),
+});
+
+// What set_affinity needs to know about a thread without finding it, for
+// every thread that hasn't exited.
+struct Registered {
+    // The hart it can't leave, if it has no stack of its own or idles.
+    pinned: Option<usize>,
+    // An affinity set while it was running on another hart, which only that
+    // hart can change, left for it to pick up when next interrupted.
+    new_affinity: Option<u64>,
+}
+
+static THREADS: Mutex<BTreeMap<ThreadId, Registered>> = Mutex::new(BTreeMap::new());
+// How many threads have a new affinity waiting, so preempt only takes the
+// lock when one might be its own.
+static NEW_AFFINITY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn register(thread: &Thread) {
+    let registered = Registered {
+        pinned: (!thread.can_migrate()).then_some(thread.hart),
+        new_affinity: None,
+    };
+    THREADS.lock_irqsave().insert(thread.id, registered);
+}
+
+fn unregister(id: ThreadId) {
+    let registered = THREADS.lock_irqsave().remove(&id);
+    if registered.is_some_and(|registered| registered.new_affinity.is_some()) {
+        NEW_AFFINITY_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Puts a ready thread on `hart`'s queue and makes sure that hart notices
+// if it's sitting idle or running something less important.
+fn enqueue(hart: usize, thread: Box<Thread>) {
+    let priority = thread.priority;
+    RUN_QUEUES[hart].lock_irqsave().push(thread);
+    if hart == hart_id() {
+        TASKS.with(|tasks| {
+            if tasks
+                .current
+                .as_ref()
+                .is_some_and(|thread| thread.is_idle || thread.priority < priority)
+            {
+                tasks.need_reschedule = true;
+            }
+        });
+    } else {
+        let _ = ipi::send(hart, IpiMessage::Reschedule);
+    }
+}
+
+// Runs on the new thread straight after every switch. Until now the old
+// thread's registers weren't saved, so it couldn't be queued for another
+// hart to pick up, and if it has exited its stack was still in use.
+fn finish_switch() {
+    let previous = TASKS.with(|tasks| tasks.previous.take());
+    let thread = match previous {
+        Some(thread) => thread,
+        None => return,
+    };
+
+    let hart = hart_id();
+    match thread.state {
+        _ if thread.is_idle => TASKS.with(|tasks| tasks.idle = Some(thread)),
+        ThreadState::Exited => {
+            unregister(thread.id);
+            // An unblock that raced with the exit would otherwise leave a
+            // wakeup behind that nothing will ever consume.
+            PARKED.lock_irqsave().wakeups.remove(&thread.id);
+            drop(thread)
+        }
+        ThreadState::Blocked => {
+            let mut parked = PARKED.lock_irqsave();
+            if parked.wakeups.remove(&thread.id) {
+                drop(parked);
+                enqueue(thread.home(hart), thread);
+            } else {
+                parked.threads.insert(thread.id, thread);
+            }
+        }
+        _ if !thread.allows(hart) => enqueue(thread.home(hart), thread),
+        _ => RUN_QUEUES[hart].lock_irqsave().push(thread),
+    }
+}
+
+// Outside TASKS.with, as a panic looks up the current thread too.
+fn verify_current_stack() {
+    let current = TASKS.with(|tasks| {
+        tasks
+            .current
+            .as_ref()
+            .map(|thread| (thread.running_stack(), thread.name, thread.id))
+    });
+    if let Some((bounds, name, id)) = current {
+        stack_guard::verify(&bounds, name, id.0);
+    }
+}
+
+// Interrupts must be off, and stay off until the switch is finished.
+fn switch_to(mut next: Box<Thread>, state: ThreadState) {
+    verify_current_stack();
+    let now = timer::read_time();
+    let hart = hart_id();
+    next.state = ThreadState::Running;
+    next.hart = hart;
+    next.switched_in = now;
+    let to = &next.context as *const Context;
+    address_space::activate(next.satp.unwrap_or_else(address_space::kernel_satp));
+    let kernel_sp = next.stack.as_ref().map_or(0, |stack| stack.bounds().high);
+    per_hart::area()
+        .kernel_sp
+        .store(kernel_sp, Ordering::Relaxed);
+    let from = TASKS.with(|tasks| {
+        let mut current = tasks.current.replace(next).expect("no thread is running");
+        current.state = state;
+        current
+            .cpu_time
+            .fetch_add(now - current.switched_in, Ordering::Relaxed);
+        if current.is_idle {
+            sched::record_idle(hart, now - current.switched_in);
+        }
+        let from = &mut current.context as *mut Context;
+        tasks.previous = Some(current);
+        tasks.slice = SLICE_TICKS;
+        tasks.need_reschedule = false;
+        from
+    });
+    sched::record_switch(hart);
+
+    unsafe { _switch_context(from, to) };
+    finish_switch();
+}
+
+// Moves this hart on to the next ready thread. A thread that's still
+// runnable keeps going unless something of at least its priority is
+// waiting; one that isn't, or that may no longer run here, hands over to
+// the idle thread.
+fn schedule(state: ThreadState) {
+    let hart = hart_id();
+    let (stays, minimum) = TASKS.with(|tasks| {
+        let current = tasks.current.as_ref();
+        let stays = state == ThreadState::Ready && current.is_none_or(|thread| thread.allows(hart));
+        let minimum = current
+            .filter(|thread| stays && !thread.is_idle)
+            .map_or(Priority::Low, |thread| thread.priority);
+        (stays, minimum)
+    });
+    let next = RUN_QUEUES[hart].lock_irqsave().pop(minimum);
+    let next = match next {
+        Some(next) => next,
+        None if stays => return,
+        None => TASKS
+            .with(|tasks| tasks.idle.take())
+            .expect("no idle thread to switch to"),
+    };
+    switch_to(next, state);
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let entry = TASKS.with(|tasks| {
+        tasks
+            .current
+            .as_mut()
+            .and_then(|thread| thread.entry.take())
+    });
+    trap::enable_interrupts();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+fn new_thread(
+    name: &'static str,
+    priority: Priority,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<Box<Thread>, TaskError> {
+    let stack = Stack::new()?;
+    let start: extern "C" fn() -> ! = thread_start;
+    // The top of the stack is kept for the frame of a trap from user mode.
+    let context = Context {
+        ra: start as usize as u64,
+        sp: stack.bounds().high - size_of::<TrapFrame>() as u64,
+        ..Context::default()
+    };
+    Ok(Box::new(Thread {
+        id: ThreadId::next(),
+        name,
+        state: ThreadState::Ready,
+        context,
+        stack: Some(stack),
+        entry: Some(entry),
+        hart: hart_id(),
+        priority,
+        satp: None,
+        is_idle: false,
+        cpu_time: Arc::new(AtomicU64::new(0)),
+        switched_in: 0,
+        queued_at: 0,
+        affinity: u64::MAX,
+    }))
+}
+
+// New threads start on the spawning hart.
+pub fn spawn_with_priority(
+    name: &'static str,
+    priority: Priority,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, TaskError> {
+    spawn_with_affinity(name, priority, u64::MAX, entry)
+}
+
+// As spawn_with_priority, but starting on the first online hart in
+// `affinity` if the spawning hart isn't one, so it never runs anywhere else.
+pub fn spawn_with_affinity(
+    name: &'static str,
+    priority: Priority,
+    affinity: u64,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, TaskError> {
+    if affinity & hart::online_harts() == 0 {
+        return Err(TaskError::InvalidAffinity);
+    }
+    let mut thread = new_thread(name, priority, Box::new(entry))?;
+    thread.affinity = affinity;
+    let id = thread.id;
+    register(&thread);
+    enqueue(thread.home(hart_id()), thread);
+    Ok(id)
+}
+
+pub fn spawn_named(
+    name: &'static str,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, TaskError> {
+    spawn_with_priority(name, Priority::Normal, entry)
+}
+
+pub fn spawn(entry: impl FnOnce() + Send + 'static) -> Result<ThreadId, TaskError> {
+    spawn_named("thread", entry)
+}
+
+fn is_running_threads() -> bool {
+    TASKS.with(|tasks| tasks.current.is_some())
+}
+
+// Lets the next ready thread of at least this one's priority run, if there
+// is one; this thread goes to the back of its queue.
+pub fn yield_now() {
+    let _interrupts = InterruptGuard::disable();
+    if is_running_threads() {
+        schedule(ThreadState::Ready);
+    }
+}
+
+// Sleeps until unblock is called for this thread. As with park and unpark,
+// a wakeup that comes first isn't lost: the next block returns at once.
+pub fn block() {
+    assert!(trap::trap_depth() == 0, "Interrupt handlers can't block");
+    let _interrupts = InterruptGuard::disable();
+    schedule(ThreadState::Blocked);
+}
+
+// Returns whether the thread was blocked; if not, its next block won't be.
+pub fn unblock(id: ThreadId) -> bool {
+    let mut parked = PARKED.lock_irqsave();
+    match parked.threads.remove(&id) {
+        Some(mut thread) => {
+            drop(parked);
+            thread.state = ThreadState::Ready;
+            enqueue(thread.home(thread.hart), thread);
+            true
+        }
+        None => {
+            parked.wakeups.insert(id);
+            false
+        }
+    }
+}
+
+pub fn is_blocked(id: ThreadId) -> bool {
+    PARKED.lock_irqsave().threads.contains_key(&id)
+}
+
+pub fn exit() -> ! {
+    trap::disable_interrupts();
+    schedule(ThreadState::Exited);
+    unreachable!("an exited thread was resumed");
+}
+
+// Pulls over the thread that's waited longest on the busiest hart, if
+// that's busier than this one by more than a thread.
+fn balance(hart: usize) {
+    let Some(busiest) = sched::busiest(hart) else {
+        return;
+    };
+    let before = timer::read_time().saturating_sub(MIGRATION_DELAY * timer::ticks_per_jiffy());
+    let thread = RUN_QUEUES[busiest].lock_irqsave().steal(hart, before);
+    if let Some(mut thread) = thread {
+        thread.hart = hart;
+        sched::record_migration(hart);
+        enqueue(hart, thread);
+    }
+}
+
+// Called from the timer interrupt on every hart, once a jiffy.
+pub fn tick() {
+    verify_current_stack();
+    let running = TASKS.with(|tasks| {
+        tasks.slice = tasks.slice.saturating_sub(1);
+        if tasks.slice == 0 {
+            tasks.need_reschedule = true;
+        }
+        tasks.current.as_ref().is_some_and(|thread| !thread.is_idle)
+    });
+    let hart = hart_id();
+    if sched::sample_load(hart, ready_count(hart) + running as usize) {
+        balance(hart);
+        kick_idle(hart);
+    }
+}
+
+// Harts with their tick stopped don't balance, so one with threads waiting
+// wakes one of them to come and take some.
+fn kick_idle(hart: usize) {
+    let idle = timer::tickless_harts() & hart::online_harts() & !(1 << hart);
+    if idle != 0 && ready_count(hart) > 0 {
+        let _ = ipi::send(idle.trailing_zeros() as usize, IpiMessage::Reschedule);
+    }
+}
+
+fn take_new_affinity(id: ThreadId) -> Option<u64> {
+    let mask = THREADS.lock_irqsave().get_mut(&id)?.new_affinity.take()?;
+    NEW_AFFINITY_COUNT.fetch_sub(1, Ordering::Relaxed);
+    Some(mask)
+}
+
+// Picks up an affinity set while the running thread was running, returning
+// whether it now has to leave this hart.
+fn apply_new_affinity() -> bool {
+    if NEW_AFFINITY_COUNT.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let Some(mask) = current_id().and_then(take_new_affinity) else {
+        return false;
+    };
+    // set_affinity checked it against the thread already.
+    let hart = hart_id();
+    TASKS.with(|tasks| match tasks.current.as_mut() {
+        Some(thread) => {
+            thread.affinity = mask;
+            !thread.allows(hart)
+        }
+        None => false,
+    })
+}
+
+// Called on the way out of an interrupt that didn't interrupt another
+// handler, so the thread being switched away from isn't partway through
+// anything that has interrupts off.
+pub fn preempt() {
+    let requested = ipi::take_reschedule();
+    let due = TASKS.with(|tasks| core::mem::take(&mut tasks.need_reschedule));
+    let moved = apply_new_affinity();
+    if (requested || due || moved) && is_running_threads() {
+        schedule(ThreadState::Ready);
+    }
+}
+
+// Limits the thread to the harts set in `mask`, moving it off this one
+// if need be. A thread running on another hart moves the next time that
+// hart is interrupted, which this makes happen soon. Threads without a
+// stack of their own can't leave the hart they booted on.
+pub fn set_affinity(id: ThreadId, mask: u64) -> Result<(), TaskError> {
+    let _interrupts = InterruptGuard::disable();
+    let pinned = THREADS
+        .lock_irqsave()
+        .get(&id)
+        .ok_or(TaskError::NotFound)?
+        .pinned;
+    if mask & hart::online_harts() == 0 || pinned.is_some_and(|hart| mask & 1 << hart == 0) {
+        return Err(TaskError::InvalidAffinity);
+    }
+    let hart = hart_id();
+
+    let current = TASKS.with(|tasks| match tasks.current.as_mut() {
+        Some(thread) if thread.id == id => {
+            thread.affinity = mask;
+            Some(!thread.allows(hart))
+        }
+        _ => None,
+    });
+    if let Some(moved) = current {
+        if moved {
+            schedule(ThreadState::Ready);
+        }
+        return Ok(());
+    }
+
+    for (queued_on, queue) in RUN_QUEUES.iter().enumerate() {
+        let thread = queue.lock_irqsave().remove(id);
+        if let Some(mut thread) = thread {
+            thread.affinity = mask;
+            enqueue(thread.home(queued_on), thread);
+            return Ok(());
+        }
+    }
+
+    if let Some(thread) = PARKED.lock_irqsave().threads.get_mut(&id) {
+        thread.affinity = mask;
+        return Ok(());
+    }
+
+    // Not found anywhere, so running elsewhere, unless it has exited since.
+    {
+        let mut threads = THREADS.lock_irqsave();
+        let registered = threads.get_mut(&id).ok_or(TaskError::NotFound)?;
+        if registered.new_affinity.replace(mask).is_none() {
+            NEW_AFFINITY_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    for other in
+        (0..MAX_HARTS).filter(|&other| other != hart && hart::online_harts() & 1 << other != 0)
+    {
+        let _ = ipi::send(other, IpiMessage::Reschedule);
+    }
+    Ok(())
+}
+
+pub fn current_affinity() -> Option<u64> {
+    TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.affinity))
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.id))
+}
+
+pub fn current_name() -> Option<&'static str> {
+    TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.name))
+}
+
+// The page tables the running thread is on, unless they're the kernel's.
+pub fn address_space() -> Option<Satp> {
+    TASKS.with(|tasks| tasks.current.as_ref().and_then(|thread| thread.satp))
+}
+
+// Switches the running thread onto `satp`'s page tables, or back to the
+// kernel's, for as long as it runs.
+pub fn set_address_space(satp: Option<Satp>) {
+    TASKS.with(|tasks| {
+        if let Some(thread) = tasks.current.as_mut() {
+            thread.satp = satp;
+        }
+        address_space::activate(satp.unwrap_or_else(address_space::kernel_satp));
+    });
+}
+
+// The running thread's CPU time, which only goes up when it's switched
+// away from.
+pub fn cpu_time_counter() -> Option<Arc<AtomicU64>> {
+    TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.cpu_time.clone()))
+}
+
+// How long the running thread has run since it was last switched to, which
+// its counter has yet to count.
+pub fn running_time() -> u64 {
+    let switched_in = TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.switched_in));
+    switched_in.map_or(0, |switched_in| timer::read_time() - switched_in)
+}
+
+pub fn current_priority() -> Option<Priority> {
+    TASKS.with(|tasks| tasks.current.as_ref().map(|thread| thread.priority))
+}
+
+// Lowering the priority gives way at once to anything it now ranks below.
+pub fn set_priority(priority: Priority) {
+    let lowered = TASKS.with(|tasks| match tasks.current.as_mut() {
+        Some(thread) => {
+            let lowered = priority < thread.priority;
+            thread.priority = priority;
+            lowered
+        }
+        None => false,
+    });
+    if lowered {
+        yield_now();
+    }
+}
+
+// The stack of the running thread, if it has one of its own.
+pub fn stack_bounds() -> Option<StackBounds> {
+    TASKS.with(|tasks| {
+        tasks
+            .current
+            .as_ref()
+            .and_then(|thread| thread.stack.as_ref())
+            .map(Stack::bounds)
+    })
+}
+
+pub fn ready_count(hart: usize) -> usize {
+    RUN_QUEUES[hart].lock_irqsave().len()
+}
+
+// For reports from interrupt context, which can't wait for the lock.
+pub fn try_ready_count(hart: usize) -> Option<usize> {
+    RUN_QUEUES[hart].try_lock().map(|queue| queue.len())
+}
+
+// Threads running on other harts are missing, as only their own hart can
+// see them.
+pub fn report() {
+    let summary = |thread: &Thread| {
+        (
+            thread.id,
+            thread.hart,
+            thread.affinity,
+            thread.state,
+            thread.priority,
+            thread
+                .stack
+                .as_ref()
+                .map(|stack| stack_guard::high_water(&stack.bounds())),
+            thread.name,
+        )
+    };
+    let mut threads = Vec::new();
+    TASKS.with(|tasks| threads.extend(tasks.current.as_deref().map(summary)));
+    for queue in &RUN_QUEUES {
+        let queue = queue.lock_irqsave();
+        threads.extend(queue.levels.iter().flatten().map(|thread| summary(thread)));
+    }
+    threads.extend(
+        PARKED
+            .lock_irqsave()
+            .threads
+            .values()
+            .map(|thread| summary(thread)),
+    );
+    threads.sort_by_key(|&(id, ..)| id);
+
+    println!(
+        "{:>6} {:>4} {:>6} {:<8} {:<6} {:>6} name",
+        "tid", "hart", "harts", "state", "prio", "stack"
+    );
+    for (id, hart, affinity, state, priority, stack, name) in threads {
+        // The most of its stack the thread has used.
+        let stack = match stack {
+            Some(used) => format!("{}", used),
+            None => String::from("-"),
+        };
+        // The harts it may run on, as a mask.
+        let affinity = match affinity {
+            u64::MAX => String::from("all"),
+            mask => format!("{:#x}", mask),
+        };
+        println!(
+            "{:>6} {:>4} {:>6} {:<8} {:<6} {:>6} {}",
+            id.0,
+            hart,
+            affinity,
+            match state {
+                ThreadState::Ready => "ready",
+                ThreadState::Running => "running",
+                ThreadState::Blocked => "blocked",
+                ThreadState::Exited => "exited",
+            },
+            match priority {
+                Priority::Low => "low",
+                Priority::Normal => "normal",
+                Priority::High => "high",
+            },
+            stack,
+            name
+        );
+    }
+}
+
+// With nothing to run, and nothing worth pulling over from another hart,
+// the hart waits for an interrupt with its tick stopped where it can be.
+fn idle_loop() -> ! {
+    loop {
+        // Anything made ready after the check raises an interrupt, which
+        // wakes the wfi even with interrupts off.
+        trap::disable_interrupts();
+        let hart = hart_id();
+        if ready_count(hart) == 0 {
+            balance(hart);
+        }
+        if ready_count(hart) > 0 {
+            schedule(ThreadState::Ready);
+        } else if timer::stop_tick() {
+            let stopped = timer::read_time();
+            unsafe { asm!("wfi") };
+            timer::restart_tick();
+            let skipped = (timer::read_time() - stopped) / timer::ticks_per_jiffy();
+            sched::sample_idle(hart, skipped);
+        } else {
+            unsafe { asm!("wfi") };
+        }
+        trap::enable_interrupts();
+    }
+}
+
+fn boot_thread(name: &'static str, is_idle: bool) -> Box<Thread> {
+    let thread = Box::new(Thread {
+        id: ThreadId::next(),
+        name,
+        state: ThreadState::Running,
+        context: Context::default(),
+        stack: None,
+        entry: None,
+        hart: hart_id(),
+        priority: if is_idle {
+            Priority::Low
+        } else {
+            Priority::Normal
+        },
+        satp: None,
+        is_idle,
+        cpu_time: Arc::new(AtomicU64::new(0)),
+        switched_in: timer::read_time(),
+        queued_at: 0,
+        affinity: u64::MAX,
+    });
+    register(&thread);
+    thread
+}
+
+// Turns whatever the boot hart is running into a thread, so it can yield,
+// and gives the hart an idle thread for when it blocks.
+pub fn init() {
+    let idle = match new_thread("idle", Priority::Low, Box::new(|| idle_loop())) {
+        Ok(mut idle) => {
+            idle.is_idle = true;
+            register(&idle);
+            idle
+        }
+        Err(e) => panic!("Failed to create the idle thread: {:?}", e),
+    };
+    TASKS.with(|tasks| {
+        tasks.current = Some(boot_thread("main", false));
+        tasks.idle = Some(idle);
+        tasks.slice = SLICE_TICKS;
+    });
+}
+
+// Secondary harts have nothing else to do, so their boot flow becomes the
+// idle thread.
+pub fn run_idle() -> ! {
+    TASKS.with(|tasks| tasks.current = Some(boot_thread("idle", true)));
+    idle_loop()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+
+    pub fn yield_until(done: impl Fn() -> bool) {
+        for _ in 0..1000 {
+            if done() {
+                return;
+            }
+            yield_now();
+        }
+        panic!("gave up waiting for other threads");
+    }
+
+    #[test_case]
+    fn a_spawned_thread_runs_when_yielded_to() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        spawn(move || flag.store(true, Ordering::Relaxed)).unwrap();
+
+        yield_until(|| ran.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn threads_take_turns() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for n in 0..2 {
+            let log = log.clone();
+            spawn(move || {
+                for _ in 0..3 {
+                    log.lock().push(n);
+                    yield_now();
+                }
+            })
+            .unwrap();
+        }
+
+        // A tick can preempt either thread, so only the totals are fixed.
+        yield_until(|| log.lock().len() == 6);
+        assert_eq!(log.lock().iter().filter(|&&n| n == 0).count(), 3);
+    }
+
+    #[test_case]
+    fn threads_run_on_their_own_stacks() {
+        let inside = Arc::new(AtomicBool::new(false));
+        let flag = inside.clone();
+        spawn(move || {
+            let here = &flag as *const _ as u64;
+            let bounds = stack_bounds().unwrap();
+            flag.store(bounds.contains(here), Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| inside.load(Ordering::Relaxed));
+        assert!(stack_bounds().is_none());
+    }
+
+    #[test_case]
+    fn a_thread_that_never_yields_is_preempted() {
+        let started = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (flag, done) = (started.clone(), stop.clone());
+        spawn(move || {
+            flag.store(true, Ordering::Relaxed);
+            while !done.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        })
+        .unwrap();
+
+        // Neither thread yields, so only the timer can switch between them.
+        let deadline = crate::timer::read_time() + crate::timer::timebase_frequency();
+        while !started.load(Ordering::Relaxed) && crate::timer::read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert!(started.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn higher_priority_threads_run_first() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for priority in [Priority::Low, Priority::High] {
+            let log = log.clone();
+            // So no other hart runs either first.
+            spawn_with_affinity("thread", priority, 1 << hart_id(), move || {
+                log.lock().push(priority)
+            })
+            .unwrap();
+        }
+
+        // Otherwise the low priority thread wouldn't get a turn.
+        set_priority(Priority::Low);
+        yield_until(|| log.lock().len() == 2);
+        set_priority(Priority::Normal);
+        assert_eq!(*log.lock(), [Priority::High, Priority::Low]);
+    }
+
+    #[test_case]
+    fn a_blocked_thread_runs_again_once_unblocked() {
+        let woken = Arc::new(AtomicBool::new(false));
+        let flag = woken.clone();
+        let id = spawn(move || {
+            block();
+            flag.store(true, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        // Whether or not it has blocked yet, the wakeup isn't lost.
+        yield_now();
+        unblock(id);
+        yield_until(|| woken.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn a_pinned_thread_only_runs_on_its_hart() {
+        let harts = Arc::new(AtomicU64::new(0));
+        let seen = harts.clone();
+        spawn_with_affinity("thread", Priority::Normal, 1 << hart_id(), move || {
+            for _ in 0..5 {
+                seen.fetch_or(1 << hart_id(), Ordering::Relaxed);
+                yield_now();
+            }
+            seen.fetch_or(1 << 63, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| harts.load(Ordering::Relaxed) & 1 << 63 != 0);
+        assert_eq!(harts.load(Ordering::Relaxed), 1 << 63 | 1 << hart_id());
+    }
+
+    #[test_case]
+    fn affinity_must_allow_an_online_hart_the_thread_can_reach() {
+        let main = current_id().unwrap();
+        assert!(matches!(
+            set_affinity(main, 0),
+            Err(TaskError::InvalidAffinity)
+        ));
+        // The boot thread can't leave its stack behind.
+        let elsewhere = !(1 << hart_id());
+        assert!(matches!(
+            set_affinity(main, elsewhere),
+            Err(TaskError::InvalidAffinity)
+        ));
+        assert_eq!(current_affinity(), Some(u64::MAX));
+        assert!(matches!(
+            spawn_with_affinity("thread", Priority::Normal, 0, || {}),
+            Err(TaskError::InvalidAffinity)
+        ));
+    }
+
+    #[test_case]
+    fn exited_and_unknown_threads_have_no_affinity_to_set() {
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        let id = spawn(move || flag.store(true, Ordering::Relaxed)).unwrap();
+        yield_until(|| done.load(Ordering::Relaxed) && !THREADS.lock_irqsave().contains_key(&id));
+
+        assert!(matches!(
+            set_affinity(id, u64::MAX),
+            Err(TaskError::NotFound)
+        ));
+        assert!(matches!(
+            set_affinity(ThreadId(u64::MAX), u64::MAX),
+            Err(TaskError::NotFound)
+        ));
+    }
+}
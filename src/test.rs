@@ -1,49 +1,272 @@
+use crate::csr::Sstatus;
+use crate::style::{Color, Style};
+use crate::task::{self, ThreadId};
+use crate::time::Instant;
+use crate::timer::TimerId;
+use crate::trap::TrapCause;
+use crate::{cmdline, power, serial, timer, trap};
 use crate::{print, println};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
 
-const SIFIVE_TEST_ADDR: u64 = 0x100000;
+// How long a test may run before it's abandoned, unless test_timeout=
+// gives a number of milliseconds.
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
 
-#[repr(u32)]
-enum QemuExitCode {
-    Failure = 0x3333,
-    Success = 0x5555,
-    Reset = 0x7777,
+extern "C" {
+    static STACK_END: u64;
 }
 
-pub trait Testable {
-    fn run(&self) -> ();
+pub trait Testable: Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self);
 }
 
 impl<T> Testable for T
 where
-    T: Fn(),
+    T: Fn() + Sync,
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        print!("{}...\t", core::any::type_name::<T>());
         self();
-        println!("[ok]");
     }
 }
 
+// How a test is meant to end, if not by returning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    Return,
+    Panic,
+    Trap(TrapCause),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Return => f.write_str("to return"),
+            Expected::Panic => f.write_str("a panic"),
+            Expected::Trap(cause) => write!(f, "an unhandled {:?}", cause),
+        }
+    }
+}
+
+struct Runner {
+    tests: Vec<&'static dyn Testable>,
+    next: usize,
+    timed_out: usize,
+    thread: Option<ThreadId>,
+    expected: Expected,
+    timeout: Option<TimerId>,
+    started: Option<Instant>,
+}
+
+// Taken from the timer interrupt too, so always with interrupts off.
+static RUNNER: Mutex<Runner> = Mutex::new(Runner {
+    tests: Vec::new(),
+    next: 0,
+    timed_out: 0,
+    thread: None,
+    expected: Expected::Return,
+    timeout: None,
+    started: None,
+});
+// Set while a test runs, so a timeout that fires just as one finishes is
+// ignored.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
 pub fn test_runner(tests: &[&dyn Testable]) {
-    println!("Running {} tests", tests.len());
-    for test in tests {
+    // Only the tests whose paths contain test=, if it's given.
+    let filter = cmdline::get("test").unwrap_or("");
+    // Tests are functions, so the references are to statics.
+    let matching: Vec<&'static dyn Testable> = tests
+        .iter()
+        .filter(|test| test.name().contains(filter))
+        .map(|&test| unsafe { core::mem::transmute::<&dyn Testable, &'static dyn Testable>(test) })
+        .collect();
+    if filter.is_empty() {
+        println!("Running {} tests", tests.len());
+    } else {
+        println!(
+            "Running {} of {} tests, matching {}",
+            matching.len(),
+            tests.len(),
+            filter
+        );
+    }
+    // Alongside the text, the results are TAP for scripts to pick out.
+    println!("1..{}", matching.len());
+    let tests = matching;
+    {
+        let mut runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+        runner.tests = tests;
+        runner.thread = task::current_id();
+    }
+    run_remaining();
+}
+
+fn run_remaining() -> ! {
+    let timeout_ms = cmdline::get_u64("test_timeout").unwrap_or(DEFAULT_TIMEOUT_MS);
+    loop {
+        let test = {
+            let mut runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+            match runner.tests.get(runner.next) {
+                Some(&test) => {
+                    runner.next += 1;
+                    test
+                }
+                None => break,
+            }
+        };
+        // Whatever the last test left it on, if it was abandoned.
+        task::set_address_space(None);
+        print!("{}...\t", test.name());
+        let timeout = timer::after(timeout_ms, timed_out);
+        {
+            let mut runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+            runner.timeout = Some(timeout);
+            runner.started = Some(Instant::now());
+        }
+        RUNNING.store(true, Ordering::Relaxed);
         test.run();
+        RUNNING.store(false, Ordering::Relaxed);
+        let expected = finish_test();
+        if expected != Expected::Return {
+            fail(format_args!("Expected {}, but the test returned", expected));
+        }
+        pass();
     }
-    exit_qemu(QemuExitCode::Success);
+
+    println!("exiting...");
+    let timed_out = trap::LockIrqSave::lock_irqsave(&RUNNER).timed_out;
+    if timed_out > 0 {
+        println!("{} test(s) timed out", timed_out);
+        power::shutdown_after_failure();
+    }
+    power::shutdown();
 }
 
-pub fn panic_handler(info: &core::panic::PanicInfo) {
-    println!("[failed]");
-    println!("Error: {}", info);
-    exit_qemu(QemuExitCode::Failure);
+// The TAP line for the test that just finished: its number, name and how
+// long it took, with anything in `note` after that.
+fn report(status: &str, note: &str) {
+    let runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+    // Nothing to report for a panic before the first test.
+    let Some(test) = runner.next.checked_sub(1).map(|n| runner.tests[n]) else {
+        return;
+    };
+    let elapsed = runner
+        .started
+        .map_or(0, |started| started.elapsed().as_micros());
+    println!(
+        "{} {} - {} # {}.{:03} ms{}",
+        status,
+        runner.next,
+        test.name(),
+        elapsed / 1000,
+        elapsed % 1000,
+        note
+    );
 }
 
-fn exit_qemu(exit_code: QemuExitCode) {
-    let ptr: *mut u32 = SIFIVE_TEST_ADDR as *mut u32;
+// Entered on a fresh stack, since the one the abandoned test was using
+// can't be trusted.
+extern "C" fn resume() -> ! {
+    run_remaining()
+}
 
+// Declares that the rest of the current test should end in a panic, which
+// then counts as passing.
+pub fn should_panic() {
+    trap::LockIrqSave::lock_irqsave(&RUNNER).expected = Expected::Panic;
+}
+
+// Declares that the rest of the current test should end in a trap of this
+// cause that no handler deals with, which then counts as passing.
+pub fn expect_trap(cause: TrapCause) {
+    trap::LockIrqSave::lock_irqsave(&RUNNER).expected = Expected::Trap(cause);
+}
+
+// Cancels the current test's timeout and takes what it was expected to do.
+fn finish_test() -> Expected {
+    let mut runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+    if let Some(timeout) = runner.timeout.take() {
+        timer::cancel(timeout);
+    }
+    core::mem::replace(&mut runner.expected, Expected::Return)
+}
+
+fn pass() {
+    println!("[{}]", Style::PLAIN.fg(Color::Green).paint("ok"));
+    report("ok", "");
+}
+
+fn fail(error: impl fmt::Display) -> ! {
+    println!("[{}]", Style::PLAIN.fg(Color::Red).bold().paint("failed"));
+    println!("Error: {}", error);
+    report("not ok", "");
     println!("exiting...");
+    power::shutdown_after_failure();
+}
+
+// Runs in the timer interrupt. A test stuck on the runner's thread is
+// abandoned by making the interrupt return into the runner instead; one
+// stuck elsewhere, such as blocked waiting for something, can't be, so the
+// run ends there.
+fn timed_out() {
+    if !RUNNING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    println!("[{}]", Style::PLAIN.fg(Color::Red).bold().paint("timeout"));
+    report("not ok", " timeout");
+    let mut runner = trap::LockIrqSave::lock_irqsave(&RUNNER);
+    runner.timed_out += 1;
+    // What finish_test does, but the timer has already fired.
+    runner.timeout = None;
+    runner.expected = Expected::Return;
+    let entry: extern "C" fn() -> ! = resume;
+    let resumed = task::current_id() == runner.thread
+        && trap::redirect_outermost(entry as usize as u64, unsafe { STACK_END });
+    if !resumed {
+        println!("The test can't be abandoned, exiting...");
+        power::shutdown_after_failure();
+    }
+}
+
+pub fn panic_handler(info: &core::panic::PanicInfo) {
+    serial::force_unlock();
+    RUNNING.store(false, Ordering::Relaxed);
+    let on_runner = task::current_id() == trap::LockIrqSave::lock_irqsave(&RUNNER).thread;
+    let passed = match finish_test() {
+        Expected::Return => false,
+        Expected::Panic => true,
+        Expected::Trap(cause) => trap::current_frame().map(|frame| frame.cause()) == Some(cause),
+    };
+    if !passed || !on_runner {
+        fail(info);
+    }
+    pass();
+    restart();
+}
 
+// Carries on with the next test, abandoning the panicked one along with
+// any traps it was in the middle of.
+fn restart() -> ! {
+    trap::abandon_traps();
+    let entry: extern "C" fn() -> ! = resume;
     unsafe {
-        ptr.write_volatile(exit_code as u32);
+        asm!(
+            "mv sp, {stack}",
+            "csrs sstatus, {sie}",
+            "jr {entry}",
+            stack = in(reg) STACK_END,
+            sie = in(reg) Sstatus::SIE.bits(),
+            entry = in(reg) entry,
+            options(noreturn)
+        );
     }
 }
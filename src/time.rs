@@ -0,0 +1,160 @@
+use crate::timer::{read_time, timebase_frequency};
+use crate::trap;
+use core::arch::asm;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+pub use core::time::Duration;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+// Rounded down to the nanosecond.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SECOND / timebase_frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SECOND) as u64,
+        (nanos % NANOS_PER_SECOND) as u32,
+    )
+}
+
+// Rounded up, so waiting this many ticks waits at least `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = timebase_frequency() as u128;
+    let ticks = (duration.as_nanos() * frequency).div_ceil(NANOS_PER_SECOND);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+// A reading of the time CSR, which counts up from reset at the same rate
+// on every hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Self { ticks: read_time() }
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // Zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.ticks
+            .checked_add(duration_to_ticks(duration))
+            .map(Instant::from_ticks)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_boot = ticks_to_duration(self.ticks);
+        write!(
+            f,
+            "{}.{:06}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros()
+        )
+    }
+}
+
+pub fn uptime() -> Duration {
+    ticks_to_duration(read_time())
+}
+
+// Spins without giving up the hart, so it's usable with interrupts off.
+pub fn busy_sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+// Waits for interrupts between checks, which with the timer running means
+// waking at least once a jiffy. Until there are other threads to run this
+// still holds the hart.
+pub fn sleep(duration: Duration) {
+    if !trap::interrupts_enabled() {
+        return busy_sleep(duration);
+    }
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ticks_and_durations_round_trip() {
+        let ticks = timebase_frequency() * 3 / 2;
+        assert_eq!(ticks_to_duration(ticks), Duration::from_millis(1500));
+        assert_eq!(duration_to_ticks(Duration::from_millis(1500)), ticks);
+    }
+
+    #[test_case]
+    fn durations_round_up_to_a_whole_tick() {
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+        assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    }
+
+    #[test_case]
+    fn instants_subtract_to_durations() {
+        let start = Instant::from_ticks(1000);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(later - start, Duration::from_secs(1));
+        assert_eq!(start - later, Duration::ZERO);
+    }
+
+    #[test_case]
+    fn sleeping_waits_at_least_as_long_as_asked() {
+        let start = Instant::now();
+        busy_sleep(Duration::from_millis(2));
+        assert!(start.elapsed() >= Duration::from_millis(2));
+
+        let start = Instant::now();
+        sleep(Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
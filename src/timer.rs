@@ -0,0 +1,474 @@
+use crate::csr::{self, Interrupts};
+use crate::hart::{self, hart_id, MAX_HARTS};
+use crate::ipi::{self, IpiMessage};
+use crate::time::{duration_to_ticks, Duration};
+use crate::trap::{self, LockIrqSave, TrapCause, TrapFrame};
+use crate::{devicetree, latency, sbi, task, watchdog};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+pub const HZ: u64 = 100;
+
+// QEMU's virt machine; used if the device tree doesn't say otherwise.
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// Each slot of the wheel covers one jiffy.
+const WHEEL_SLOTS: usize = 64;
+
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQUENCY);
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+static NEXT_JIFFY: AtomicU64 = AtomicU64::new(0);
+
+// Only the hart that ran init takes timer interrupts.
+static TIMER_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// Harts sitting idle with their tick stopped. The timer hart only stops
+// once all the others have, since they count on it for jiffies, and then
+// wakes for nothing but its earliest timer.
+static TICKLESS: AtomicU64 = AtomicU64::new(0);
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+pub type TimerCallback = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: u64,
+    period: Option<u64>,
+    callback: TimerCallback,
+}
+
+// A hashed wheel: timers sit in the slot for their deadline's jiffy, and
+// those more than a turn away wait there until their turn comes round.
+struct TimerWheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    ticks_per_slot: u64,
+    // The slot number expiry has reached; it may still hold later timers.
+    current: u64,
+    next_id: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Timer> = Vec::new();
+        Self {
+            slots: [EMPTY; WHEEL_SLOTS],
+            ticks_per_slot: DEFAULT_TIMEBASE_FREQUENCY / HZ,
+            current: 0,
+            next_id: 1,
+        }
+    }
+
+    fn slot_number(&self, deadline: u64) -> u64 {
+        deadline / self.ticks_per_slot
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        // Anything already due goes in the slot expiry looks at next.
+        let number = self.slot_number(timer.deadline).max(self.current);
+        self.slots[number as usize % WHEEL_SLOTS].push(timer);
+    }
+
+    fn add(&mut self, deadline: u64, period: Option<u64>, callback: TimerCallback) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.insert(Timer {
+            id,
+            deadline,
+            period,
+            callback,
+        });
+        id
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(n) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(n);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Takes out every timer due by `now`, putting periodic ones back for
+    // their next deadline.
+    fn expire(&mut self, now: u64, due: &mut Vec<Timer>) {
+        let last = self.slot_number(now);
+        // After a long gap every slot needs looking at, but only once.
+        let first = self
+            .current
+            .max(last.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for number in first..=last {
+            let slot = &mut self.slots[number as usize % WHEEL_SLOTS];
+            let mut n = 0;
+            while n < slot.len() {
+                if slot[n].deadline <= now {
+                    due.push(slot.swap_remove(n));
+                } else {
+                    n += 1;
+                }
+            }
+        }
+        self.current = self.current.max(last);
+
+        for timer in due.iter() {
+            if let Some(period) = timer.period {
+                let missed = (now - timer.deadline) / period;
+                self.insert(Timer {
+                    deadline: timer.deadline + (missed + 1) * period,
+                    ..*timer
+                });
+            }
+        }
+    }
+
+    // Searches outwards from the current slot, so the scan usually stops at
+    // the first non-empty one.
+    fn earliest(&self) -> Option<u64> {
+        let mut earliest = None;
+        for number in self.current..self.current + WHEEL_SLOTS as u64 {
+            for timer in self.slots[number as usize % WHEEL_SLOTS].iter() {
+                if self.slot_number(timer.deadline).max(self.current) == number {
+                    earliest =
+                        Some(earliest.map_or(timer.deadline, |e: u64| e.min(timer.deadline)));
+                }
+            }
+            if earliest.is_some() {
+                return earliest;
+            }
+        }
+        // Everything left is more than a turn away.
+        self.slots
+            .iter()
+            .flat_map(|slot| slot.iter())
+            .map(|timer| timer.deadline)
+            .min()
+    }
+}
+
+pub fn read_time() -> u64 {
+    csr::time::read()
+}
+
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn ticks_per_jiffy() -> u64 {
+    timebase_frequency() / HZ
+}
+
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+pub fn set_next_event(deadline: u64) {
+    latency::timer_armed(deadline);
+    if let Err(e) = sbi::set_timer(deadline) {
+        panic!("Failed to program the timer: {:?}", e);
+    }
+}
+
+// The next interrupt is for whichever comes first, the next jiffy or the
+// earliest timer.
+fn reprogram() {
+    let next_jiffy = NEXT_JIFFY.load(Ordering::Relaxed);
+    let deadline = WHEEL
+        .lock_irqsave()
+        .earliest()
+        .map_or(next_jiffy, |earliest| earliest.min(next_jiffy));
+    set_next_event(deadline);
+}
+
+fn run_expired(now: u64) {
+    let mut due = Vec::new();
+    WHEEL.lock_irqsave().expire(now, &mut due);
+    // Called without the lock held, so callbacks can add and cancel timers.
+    for timer in due {
+        (timer.callback)();
+    }
+}
+
+// Other harts only tick for the scheduler; jiffies, timers and the latency
+// figures belong to the timer hart.
+fn arm_local_tick() {
+    if let Err(e) = sbi::set_timer(read_time() + ticks_per_jiffy()) {
+        panic!("Failed to program the timer: {:?}", e);
+    }
+}
+
+pub fn tickless_harts() -> u64 {
+    TICKLESS.load(Ordering::SeqCst)
+}
+
+// Called by the idle thread with interrupts off and nothing to run, just
+// before it waits. Returns whether the tick stopped; if so, it must be
+// restarted once the wait is over.
+pub fn stop_tick() -> bool {
+    let hart = hart_id();
+    let timer_hart = TIMER_HART.load(Ordering::Relaxed);
+    // Set before looking at the others, and cleared by them before they
+    // look at this, so a hart waking up either stops this or wakes it.
+    TICKLESS.fetch_or(1 << hart, Ordering::SeqCst);
+    if hart != timer_hart {
+        if let Err(e) = sbi::set_timer(u64::MAX) {
+            panic!("Failed to stop the timer: {:?}", e);
+        }
+        return true;
+    }
+
+    let others = hart::online_harts() & !(1 << hart);
+    if tickless_harts() & others != others {
+        TICKLESS.fetch_and(!(1 << hart), Ordering::SeqCst);
+        return false;
+    }
+    let earliest = WHEEL.lock_irqsave().earliest();
+    set_next_event(earliest.unwrap_or(u64::MAX));
+    true
+}
+
+// Goes back to ticking once a jiffy, waking the timer hart to do the same
+// if this hart is back at work without it.
+pub fn restart_tick() {
+    let hart = hart_id();
+    if tickless_harts() & 1 << hart == 0 {
+        return;
+    }
+    watchdog::woken(hart);
+    TICKLESS.fetch_and(!(1 << hart), Ordering::SeqCst);
+
+    let timer_hart = TIMER_HART.load(Ordering::Relaxed);
+    if hart == timer_hart {
+        // A jiffy already past raises the interrupt straight away, which
+        // catches up on those missed.
+        reprogram();
+        return;
+    }
+    arm_local_tick();
+    if timer_hart < MAX_HARTS && tickless_harts() & 1 << timer_hart != 0 {
+        let _ = ipi::send(timer_hart, IpiMessage::Reschedule);
+    }
+}
+
+// How many jiffies have gone by at `now`, the next being due at
+// `next_jiffy`: none before then, and otherwise that one plus any skipped
+// while the tick was stopped.
+fn jiffies_elapsed(now: u64, next_jiffy: u64, per_jiffy: u64) -> u64 {
+    if now < next_jiffy {
+        0
+    } else {
+        1 + (now - next_jiffy) / per_jiffy
+    }
+}
+
+fn handle_timer_interrupt(frame: &mut TrapFrame) -> bool {
+    watchdog::beat(frame);
+    if hart_id() != TIMER_HART.load(Ordering::Relaxed) {
+        arm_local_tick();
+        task::tick();
+        return true;
+    }
+
+    latency::handler_entry();
+    let now = read_time();
+    let next_jiffy = NEXT_JIFFY.load(Ordering::Relaxed);
+    let elapsed = jiffies_elapsed(now, next_jiffy, ticks_per_jiffy());
+    if elapsed > 0 {
+        JIFFIES.fetch_add(elapsed, Ordering::Relaxed);
+        NEXT_JIFFY.store(now + ticks_per_jiffy(), Ordering::Relaxed);
+        task::tick();
+    }
+    run_expired(now);
+    reprogram();
+    latency::handler_exit();
+    true
+}
+
+fn add_timer(delay: Duration, period: Option<Duration>, callback: TimerCallback) -> TimerId {
+    let deadline = read_time() + duration_to_ticks(delay);
+    let period = period.map(|period| duration_to_ticks(period).max(1));
+    let id = WHEEL.lock_irqsave().add(deadline, period, callback);
+    // Other harts leave it to the next jiffy, which is soon enough.
+    if hart_id() == TIMER_HART.load(Ordering::Relaxed) {
+        reprogram();
+    }
+    id
+}
+
+// Callbacks run in interrupt context on the timer hart, so they mustn't
+// block.
+pub fn after(ms: u64, callback: TimerCallback) -> TimerId {
+    add_timer(Duration::from_millis(ms), None, callback)
+}
+
+pub fn every(ms: u64, callback: TimerCallback) -> TimerId {
+    let period = Duration::from_millis(ms);
+    add_timer(period, Some(period), callback)
+}
+
+// False if the timer had already fired, or never existed.
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock_irqsave().cancel(id)
+}
+
+pub fn init() {
+    let frequency = devicetree::get()
+        .and_then(|tree| tree.find_node("/cpus"))
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|p| p.as_u64());
+    if let Some(frequency) = frequency {
+        TIMEBASE_FREQUENCY.store(frequency, Ordering::Relaxed);
+    }
+
+    WHEEL.lock_irqsave().ticks_per_slot = ticks_per_jiffy();
+    TIMER_HART.store(hart_id(), Ordering::Relaxed);
+
+    trap::register_handler(TrapCause::TimerInterrupt, handle_timer_interrupt);
+    NEXT_JIFFY.store(read_time() + ticks_per_jiffy(), Ordering::Relaxed);
+    reprogram();
+
+    unsafe {
+        csr::sie::set(Interrupts::TIMER);
+    }
+    trap::enable_interrupts();
+}
+
+pub fn init_hart() {
+    arm_local_tick();
+    unsafe {
+        csr::sie::set(Interrupts::TIMER);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+
+    #[test_case]
+    fn timebase_frequency_comes_from_the_device_tree() {
+        let cpus = devicetree::get().unwrap().find_node("/cpus").unwrap();
+        let frequency = cpus.property("timebase-frequency").unwrap().as_u64();
+        assert_eq!(Some(timebase_frequency()), frequency);
+    }
+
+    #[test_case]
+    fn jiffies_advance_while_waiting() {
+        let start = jiffies();
+        let until = read_time() + 3 * ticks_per_jiffy();
+        while read_time() < until {
+            core::hint::spin_loop();
+        }
+        assert!(jiffies() > start);
+    }
+
+    #[test_case]
+    fn jiffies_skipped_while_tickless_are_counted() {
+        assert_eq!(jiffies_elapsed(999, 1000, 100), 0);
+        assert_eq!(jiffies_elapsed(1000, 1000, 100), 1);
+        assert_eq!(jiffies_elapsed(1099, 1000, 100), 1);
+        assert_eq!(jiffies_elapsed(1100, 1000, 100), 2);
+        assert_eq!(jiffies_elapsed(1550, 1000, 100), 6);
+    }
+
+    #[test_case]
+    fn a_stopped_tick_starts_again() {
+        // The timer hart only stops once every other hart has, but any
+        // other hart stops whenever it's asked to.
+        let others = hart::online_harts() & !(1 << TIMER_HART.load(Ordering::Relaxed));
+        assert_ne!(others, 0);
+        let pinned = 1 << others.trailing_zeros();
+
+        let result = Arc::new(Mutex::new(None));
+        let outcome = result.clone();
+        task::spawn_with_affinity("tickless", task::Priority::Normal, pinned, move || {
+            let hart = hart_id();
+            let (stopped, restarted) = {
+                let _interrupts = trap::InterruptGuard::disable();
+                let stopped = stop_tick() && tickless_harts() & 1 << hart != 0;
+                restart_tick();
+                (stopped, tickless_harts() & 1 << hart == 0)
+            };
+
+            let start = read_time();
+            while read_time() < start + 3 * ticks_per_jiffy() {
+                core::hint::spin_loop();
+            }
+            let ticked = watchdog::last_beat(hart) > start;
+            *outcome.lock() = Some((stopped, restarted, ticked));
+        })
+        .unwrap();
+
+        let deadline = read_time() + timebase_frequency();
+        while result.lock().is_none() && read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        assert_eq!(*result.lock(), Some((true, true, true)));
+    }
+
+    fn ignore() {}
+
+    fn wheel() -> TimerWheel {
+        let mut wheel = TimerWheel::new();
+        wheel.ticks_per_slot = 100;
+        wheel
+    }
+
+    #[test_case]
+    fn the_wheel_expires_only_due_timers() {
+        let mut wheel = wheel();
+        let soon = wheel.add(150, None, ignore);
+        let later = wheel.add(250, None, ignore);
+        // A whole turn later, in the same slot as `soon`.
+        wheel.add(150 + 100 * WHEEL_SLOTS as u64, None, ignore);
+
+        let mut due = Vec::new();
+        wheel.expire(200, &mut due);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, soon);
+        assert_eq!(wheel.earliest(), Some(250));
+
+        assert!(wheel.cancel(later));
+        assert!(!wheel.cancel(later));
+        assert_eq!(wheel.earliest(), Some(150 + 100 * WHEEL_SLOTS as u64));
+    }
+
+    #[test_case]
+    fn periodic_timers_are_put_back() {
+        let mut wheel = wheel();
+        wheel.add(100, Some(100), ignore);
+
+        let mut due = Vec::new();
+        wheel.expire(120, &mut due);
+        assert_eq!(due.len(), 1);
+        assert_eq!(wheel.earliest(), Some(200));
+
+        // Missed periods are skipped rather than run back to back.
+        due.clear();
+        wheel.expire(1050, &mut due);
+        assert_eq!(due.len(), 1);
+        assert_eq!(wheel.earliest(), Some(1100));
+    }
+
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+
+    fn count_firing() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn timers_fire_from_the_interrupt() {
+        FIRED.store(0, Ordering::Relaxed);
+        after(1, count_firing);
+        let periodic = every(2, count_firing);
+        crate::time::sleep(Duration::from_millis(30));
+        cancel(periodic);
+        assert!(FIRED.load(Ordering::Relaxed) >= 3);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::hart::{hart_id, online_harts, MAX_HARTS};
+use crate::ipi::{self, IpiMessage};
+use crate::once::StaticOnce;
+use crate::page_allocator::PAGE_SIZE;
+use crate::sbi;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+// Past this many pages a full flush is cheaper than flushing page by page.
+const MAX_PAGE_FLUSHES: u64 = 64;
+
+static HAS_RFENCE: StaticOnce<bool> = StaticOnce::new();
+
+// IPI shootdowns are serialised so acknowledgements can't be mixed up.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn flush_local_all() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+// An empty range flushes everything.
+pub fn flush_local(start: u64, end: u64) {
+    if start >= end || (end - start) / PAGE_SIZE > MAX_PAGE_FLUSHES {
+        return flush_local_all();
+    }
+
+    for page in (start..end).step_by(PAGE_SIZE as usize) {
+        unsafe {
+            asm!("sfence.vma {}, zero", in(reg) page);
+        }
+    }
+}
+
+pub fn flush_local_asid(start: u64, end: u64, asid: u64) {
+    if start >= end || (end - start) / PAGE_SIZE > MAX_PAGE_FLUSHES {
+        unsafe { asm!("sfence.vma zero, {}", in(reg) asid) };
+        return;
+    }
+
+    for page in (start..end).step_by(PAGE_SIZE as usize) {
+        unsafe {
+            asm!("sfence.vma {}, {}", in(reg) page, in(reg) asid);
+        }
+    }
+}
+
+fn other_harts() -> u64 {
+    online_harts() & !(1 << hart_id())
+}
+
+fn has_rfence() -> bool {
+    *HAS_RFENCE.get_or_init(|| sbi::probe_extension(sbi::EXTENSION_RFENCE))
+}
+
+// Called by each hart once it has handled a shootdown IPI.
+pub fn acknowledge() {
+    PENDING_ACKS.fetch_sub(1, Ordering::Release);
+}
+
+// Shootdowns are often sent with interrupts off, so a hart waiting for
+// its turn answers the one in flight itself; otherwise the sender would
+// wait forever on its acknowledgement.
+fn lock_shootdown() -> MutexGuard<'static, ()> {
+    loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            return guard;
+        }
+        ipi::handle_pending_shootdowns();
+        core::hint::spin_loop();
+    }
+}
+
+fn shootdown_by_ipi(others: u64, start: u64, end: u64) {
+    let _shootdown = lock_shootdown();
+
+    PENDING_ACKS.store(others.count_ones() as usize, Ordering::Relaxed);
+    for hart in (0..MAX_HARTS).filter(|hart| others & 1 << hart != 0) {
+        if let Err(e) = ipi::send(hart, IpiMessage::TlbShootdown { start, end }) {
+            panic!("Failed to send a TLB shootdown to hart {}: {:?}", hart, e);
+        }
+    }
+
+    while PENDING_ACKS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// A page table change to [start, end) that this hart has already flushed,
+// with the harts that still need telling. Sending it waits on every one of
+// them, so callers holding a lock those harts might spin on send it once
+// they've dropped the lock.
+#[must_use]
+pub struct Shootdown {
+    harts: u64,
+    start: u64,
+    end: u64,
+}
+
+impl Shootdown {
+    pub fn new(start: u64, end: u64) -> Self {
+        flush_local(start, end);
+        Self {
+            harts: other_harts(),
+            start,
+            end,
+        }
+    }
+
+    // Covers both changes, flushing everything in between too.
+    pub fn merge(&mut self, other: Shootdown) {
+        self.harts |= other.harts;
+        self.start = self.start.min(other.start);
+        self.end = self.end.max(other.end);
+    }
+
+    // Returns once no hart can still be using the old translations.
+    pub fn send(self) {
+        let Self { harts, start, end } = self;
+        if harts == 0 {
+            return;
+        }
+
+        if !has_rfence() {
+            return shootdown_by_ipi(harts, start, end);
+        }
+        let size = end.saturating_sub(start);
+        if let Err(e) = sbi::remote_sfence_vma(harts, 0, start, size) {
+            panic!("Remote sfence.vma failed: {:?}", e);
+        }
+    }
+}
+
+// Makes a page table change to [start, end) visible on every hart. Returns
+// once no hart can still be using the old translations.
+pub fn shootdown(start: u64, end: u64) {
+    Shootdown::new(start, end).send()
+}
+
+pub fn shootdown_asid(start: u64, end: u64, asid: u64) {
+    flush_local_asid(start, end, asid);
+
+    let others = other_harts();
+    if others == 0 {
+        return;
+    }
+
+    // The IPI fallback flushes every address space, which is still correct.
+    if !has_rfence() {
+        return shootdown_by_ipi(others, start, end);
+    }
+    let size = end.saturating_sub(start);
+    if let Err(e) = sbi::remote_sfence_vma_asid(others, 0, start, size, asid) {
+        panic!("Remote sfence.vma failed: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address::VirtPage;
+    use crate::page_table::PageTableEntryMode;
+    use crate::sandbox::Sandbox;
+
+    #[test_case]
+    fn rfence_is_available_under_opensbi() {
+        assert!(has_rfence());
+    }
+
+    #[test_case]
+    fn remapping_a_live_page_is_seen_immediately() {
+        let mut sandbox = Sandbox::new(1);
+        let page: VirtPage = 0x9100_0000.try_into().unwrap();
+        sandbox.map_kernel(page, PageTableEntryMode::ReadWrite);
+        let ptr = page.start_address().as_u64() as *mut u64;
+        unsafe { ptr.write_volatile(0xdead_beef) };
+        // Loads the translation into the TLB.
+        assert_eq!(unsafe { ptr.read_volatile() }, 0xdead_beef);
+
+        // The old frame stays allocated, so the new mapping gets a different
+        // one and a stale entry would still read 0xdead_beef.
+        sandbox.unmap_kernel(page);
+        sandbox.map_kernel(page, PageTableEntryMode::ReadWrite);
+        assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::error;
+use crate::error::{KernelError, KernelResult};
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Stat};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Shared by every tmpfs, which is harmless as they don't hand out device
+// numbers to tell them apart by.
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+// Files live in the heap, so one can't be allowed to take all of it.
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpfsInode>>),
+}
+
+struct TmpfsInode {
+    number: u64,
+    contents: Mutex<Contents>,
+}
+
+impl TmpfsInode {
+    fn new(file_type: FileType) -> KernelResult<Arc<Self>> {
+        let contents = match file_type {
+            FileType::Regular => Contents::File(Vec::new()),
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            _ => return Err(KernelError::NotSupported),
+        };
+        Ok(Arc::new(Self {
+            number: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            contents: Mutex::new(contents),
+        }))
+    }
+
+    fn file_type(&self) -> FileType {
+        match *self.contents.lock() {
+            Contents::File(_) => FileType::Regular,
+            Contents::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+// Zero-fills `data` out to `size` bytes, failing rather than panicking if
+// the heap can't hold it.
+fn grow(data: &mut Vec<u8>, size: usize) -> KernelResult<()> {
+    if size > data.len() {
+        data.try_reserve(size - data.len())
+            .map_err(|_| KernelError::OutOfMemory)?;
+        data.resize(size, 0);
+    }
+    Ok(())
+}
+
+impl Inode for TmpfsInode {
+    fn stat(&self) -> Stat {
+        let (file_type, permissions, size) = match &*self.contents.lock() {
+            Contents::File(data) => (FileType::Regular, 0o644, data.len() as u64),
+            Contents::Directory(entries) => (FileType::Directory, 0o755, entries.len() as u64),
+        };
+        Stat {
+            file_type,
+            permissions,
+            inode: self.number,
+            size,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+        match &*self.contents.lock() {
+            Contents::File(data) => {
+                let available = data.get(offset as usize..).unwrap_or(&[]);
+                let count = available.len().min(buffer.len());
+                buffer[..count].copy_from_slice(&available[..count]);
+                Ok(count)
+            }
+            Contents::Directory(_) => Err(KernelError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> KernelResult<usize> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => {
+                let end = offset
+                    .checked_add(bytes.len() as u64)
+                    .filter(|&end| end <= MAX_FILE_SIZE)
+                    .ok_or(KernelError::FileTooLarge)? as usize;
+                grow(data, end)?;
+                data[offset as usize..end].copy_from_slice(bytes);
+                Ok(bytes.len())
+            }
+            Contents::Directory(_) => Err(KernelError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, size: u64) -> KernelResult<()> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => {
+                if size > MAX_FILE_SIZE {
+                    return Err(KernelError::FileTooLarge);
+                }
+                grow(data, size as usize)?;
+                data.truncate(size as usize);
+                Ok(())
+            }
+            Contents::Directory(_) => Err(KernelError::IsADirectory),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> KernelResult<Arc<dyn Inode>> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => entries
+                .get(name)
+                .map(|inode| inode.clone() as Arc<dyn Inode>)
+                .ok_or(KernelError::NotFound),
+            Contents::File(_) => Err(KernelError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> KernelResult<Arc<dyn Inode>> {
+        match &mut *self.contents.lock() {
+            Contents::Directory(entries) => {
+                if entries.contains_key(name) {
+                    return Err(KernelError::AlreadyExists);
+                }
+                let inode = TmpfsInode::new(file_type)?;
+                entries.insert(name.to_string(), inode.clone());
+                Ok(inode)
+            }
+            Contents::File(_) => Err(KernelError::NotADirectory),
+        }
+    }
+
+    // Anyone with the file open keeps it until they close it.
+    fn unlink(&self, name: &str) -> KernelResult<()> {
+        match &mut *self.contents.lock() {
+            Contents::Directory(entries) => {
+                let inode = entries.get(name).ok_or(KernelError::NotFound)?;
+                if inode.file_type() == FileType::Directory {
+                    return Err(KernelError::IsADirectory);
+                }
+                entries.remove(name);
+                Ok(())
+            }
+            Contents::File(_) => Err(KernelError::NotADirectory),
+        }
+    }
+
+    fn rmdir(&self, name: &str) -> KernelResult<()> {
+        match &mut *self.contents.lock() {
+            Contents::Directory(entries) => {
+                let inode = entries.get(name).ok_or(KernelError::NotFound)?;
+                match &*inode.contents.lock() {
+                    Contents::Directory(children) if !children.is_empty() => {
+                        return Err(KernelError::DirectoryNotEmpty)
+                    }
+                    Contents::Directory(_) => (),
+                    Contents::File(_) => return Err(KernelError::NotADirectory),
+                }
+                entries.remove(name);
+                Ok(())
+            }
+            Contents::File(_) => Err(KernelError::NotADirectory),
+        }
+    }
+
+    fn entries(&self) -> KernelResult<Vec<DirEntry>> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => Ok(entries
+                .iter()
+                .map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    inode: inode.number,
+                    file_type: inode.file_type(),
+                })
+                .collect()),
+            Contents::File(_) => Err(KernelError::NotADirectory),
+        }
+    }
+}
+
+// A file system that lives entirely on the kernel heap and is gone at the
+// next boot.
+pub struct Tmpfs {
+    root: Arc<TmpfsInode>,
+}
+
+impl Tmpfs {
+    pub fn new() -> KernelResult<Arc<Self>> {
+        Ok(Arc::new(Self {
+            root: TmpfsInode::new(FileType::Directory)?,
+        }))
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// Gives us a root file system to put things in until there are block
+// devices to mount.
+pub fn init() {
+    if let Err(e) = Tmpfs::new().and_then(|fs| vfs::mount("/", fs)) {
+        error!("Failed to mount tmpfs as root: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn files_hold_what_is_written_to_them() {
+        let fs = Tmpfs::new().unwrap();
+        let file = fs.root().create("file", FileType::Regular).unwrap();
+        assert_eq!(file.write_at(4, b"data"), Ok(4));
+        assert_eq!(file.stat().size, 8);
+
+        let mut buffer = [0xff; 10];
+        assert_eq!(file.read_at(0, &mut buffer), Ok(8));
+        assert_eq!(&buffer[..8], b"\0\0\0\0data");
+        assert_eq!(file.read_at(8, &mut buffer), Ok(0));
+
+        file.truncate(2).unwrap();
+        assert_eq!(file.stat().size, 2);
+    }
+
+    #[test_case]
+    fn files_cannot_grow_past_the_limit() {
+        let fs = Tmpfs::new().unwrap();
+        let file = fs.root().create("file", FileType::Regular).unwrap();
+        assert_eq!(
+            file.write_at(u64::MAX, b"data"),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(
+            file.write_at(MAX_FILE_SIZE, b"data"),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(
+            file.truncate(MAX_FILE_SIZE + 1),
+            Err(KernelError::FileTooLarge)
+        );
+        assert_eq!(file.stat().size, 0);
+    }
+
+    #[test_case]
+    fn directories_name_their_entries() {
+        let fs = Tmpfs::new().unwrap();
+        let root = fs.root();
+        let directory = root.create("dir", FileType::Directory).unwrap();
+        directory.create("file", FileType::Regular).unwrap();
+        assert!(matches!(
+            root.create("dir", FileType::Regular),
+            Err(KernelError::AlreadyExists)
+        ));
+
+        let entries = root.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "dir");
+        assert_eq!(entries[0].file_type, FileType::Directory);
+        assert_eq!(root.lookup("dir").unwrap().stat().inode, entries[0].inode);
+        assert!(matches!(root.lookup("file"), Err(KernelError::NotFound)));
+    }
+
+    #[test_case]
+    fn only_empty_directories_can_be_removed() {
+        let fs = Tmpfs::new().unwrap();
+        let root = fs.root();
+        let directory = root.create("dir", FileType::Directory).unwrap();
+        directory.create("file", FileType::Regular).unwrap();
+
+        assert_eq!(root.unlink("dir"), Err(KernelError::IsADirectory));
+        assert_eq!(root.rmdir("dir"), Err(KernelError::DirectoryNotEmpty));
+        assert_eq!(directory.rmdir("file"), Err(KernelError::NotADirectory));
+        directory.unlink("file").unwrap();
+        root.rmdir("dir").unwrap();
+        assert!(root.entries().unwrap().is_empty());
+    }
+
+    // The tests run with tmpfs mounted as the root.
+    #[test_case]
+    fn paths_lead_through_the_root() {
+        vfs::mkdir("/tmpfs-test").unwrap();
+        vfs::write_file("/tmpfs-test/file", b"contents").unwrap();
+        assert_eq!(
+            vfs::read_file("tmpfs-test/../tmpfs-test/./file").unwrap(),
+            b"contents"
+        );
+        assert_eq!(
+            vfs::rmdir("/tmpfs-test"),
+            Err(KernelError::DirectoryNotEmpty)
+        );
+        vfs::unlink("/tmpfs-test/file").unwrap();
+        vfs::rmdir("/tmpfs-test").unwrap();
+        assert!(matches!(
+            vfs::lookup("/tmpfs-test"),
+            Err(KernelError::NotFound)
+        ));
+    }
+}
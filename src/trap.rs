@@ -1,6 +1,223 @@
+use crate::csr::{self, PrivilegeMode, Sstatus, Stvec};
+use crate::hart::{hart_id, online_harts, MAX_HARTS};
+use crate::lock::{LockGuard, SpinLockIrq};
+use crate::per_hart::PerHart;
+use crate::slab::SlabCache;
+use crate::symbols::Symbolized;
+use crate::{debugger, misaligned, signal, task, timer, uaccess, user};
 use crate::{print, println};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
 
-#[derive(Debug)]
+pub fn enable_interrupts() {
+    unsafe {
+        csr::sstatus::set(Sstatus::SIE);
+    }
+}
+
+pub fn disable_interrupts() {
+    unsafe {
+        csr::sstatus::clear(Sstatus::SIE);
+    }
+}
+
+pub fn interrupts_enabled() -> bool {
+    csr::sstatus::read().sie()
+}
+
+// For handlers that run long enough to hold up other interrupts; the source
+// of the trap must already be acknowledged. Interrupts stay off if the
+// interrupted code had them off, since it may hold locks a nested handler
+// would spin on.
+pub fn with_nested_interrupts<R>(frame: &TrapFrame, f: impl FnOnce() -> R) -> R {
+    if !Sstatus::from_bits_retain(frame.sstatus).spie() {
+        return f();
+    }
+
+    enable_interrupts();
+    let result = f();
+    disable_interrupts();
+    result
+}
+
+// Holds interrupts off until dropped, then puts them back as they were, so
+// guards nest.
+#[must_use = "interrupts are restored as soon as the guard is dropped"]
+pub struct InterruptGuard {
+    was_enabled: bool,
+    // The interrupt state belongs to the hart that made the guard.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    pub fn disable() -> Self {
+        let sstatus = unsafe { csr::sstatus::read_and_clear(Sstatus::SIE) };
+        Self {
+            was_enabled: sstatus.sie(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _interrupts = InterruptGuard::disable();
+    f()
+}
+
+// A held lock that also keeps interrupts off, so a handler can't interrupt
+// the holder and then spin on the same lock. The lock is released before
+// interrupts come back on.
+pub struct IrqSave<'a, T> {
+    guard: LockGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<'a, T> IrqSave<'a, T> {
+    pub fn new(guard: LockGuard<'a, T>, interrupts: InterruptGuard) -> Self {
+        Self {
+            guard,
+            _interrupts: interrupts,
+        }
+    }
+}
+
+impl<T> Deref for IrqSave<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSave<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+// For any lock that an interrupt handler might also take.
+pub trait LockIrqSave<T> {
+    fn lock_irqsave(&self) -> IrqSave<'_, T>;
+}
+
+impl<T> LockIrqSave<T> for Mutex<T> {
+    // Interrupts stay as they were while waiting, so a hart spinning here
+    // still answers IPIs, such as a TLB shootdown from the lock's holder.
+    fn lock_irqsave(&self) -> IrqSave<'_, T> {
+        loop {
+            let interrupts = InterruptGuard::disable();
+            if let Some(guard) = self.try_lock() {
+                return IrqSave::new(guard.into(), interrupts);
+            }
+            drop(interrupts);
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Must match the frame layout in trap.S.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    // x1 to x31; x0 is hardwired to zero and isn't saved.
+    pub regs: [u64; 31],
+    pub sepc: u64,
+    pub sstatus: u64,
+    pub stval: u64,
+    pub satp: u64,
+    pub scause: u64,
+}
+
+const _: () = assert!(size_of::<TrapFrame>() == 288);
+
+// Frames kept off the stack, such as the one each process starts from.
+pub static TRAP_FRAMES: SpinLockIrq<SlabCache<TrapFrame>> =
+    SpinLockIrq::new(SlabCache::new("trap frames", None, None));
+
+// ABI names of x0 to x31.
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl TrapFrame {
+    pub const fn zeroed() -> Self {
+        Self {
+            regs: [0; 31],
+            sepc: 0,
+            sstatus: 0,
+            stval: 0,
+            satp: 0,
+            scause: 0,
+        }
+    }
+
+    pub fn reg(&self, n: usize) -> u64 {
+        match n {
+            0 => 0,
+            n => self.regs[n - 1],
+        }
+    }
+
+    pub fn set_reg(&mut self, n: usize, value: u64) {
+        if n != 0 {
+            self.regs[n - 1] = value;
+        }
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.reg(2)
+    }
+
+    pub fn arg(&self, n: usize) -> u64 {
+        self.reg(10 + n)
+    }
+
+    pub fn set_return_value(&mut self, value: u64) {
+        self.set_reg(10, value);
+    }
+
+    pub fn cause(&self) -> TrapCause {
+        self.scause.into()
+    }
+
+    pub fn is_from_user(&self) -> bool {
+        Sstatus::from_bits_retain(self.sstatus).spp() == PrivilegeMode::User
+    }
+
+    // Laid out by crate::fmt.
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+
+    // Compressed instructions are 16 bits; everything else we run is 32.
+    pub fn instruction_length(&self) -> u64 {
+        let low_half = unsafe { (self.sepc as *const u16).read_volatile() };
+        if low_half & 0b11 == 0b11 {
+            4
+        } else {
+            2
+        }
+    }
+
+    pub fn skip_instruction(&mut self) {
+        self.sepc += self.instruction_length();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrapCause {
     SoftwareInterrupt,
     TimerInterrupt,
@@ -26,9 +243,48 @@ pub enum TrapCause {
     CustomException,
 }
 
+pub const TRAP_CAUSES: usize = TrapCause::CustomException as usize + 1;
+
+impl TrapCause {
+    pub fn is_interrupt(&self) -> bool {
+        matches!(
+            self,
+            TrapCause::SoftwareInterrupt
+                | TrapCause::TimerInterrupt
+                | TrapCause::ExternalInterrupt
+                | TrapCause::ReservedInterrupt
+                | TrapCause::PlatformInterrupt
+        )
+    }
+
+    // Indexed by discriminant, like the handler table.
+    pub const ALL: [TrapCause; TRAP_CAUSES] = [
+        TrapCause::SoftwareInterrupt,
+        TrapCause::TimerInterrupt,
+        TrapCause::ExternalInterrupt,
+        TrapCause::InstructionAddressMisaligned,
+        TrapCause::InstructionAccessFault,
+        TrapCause::IllegalInstruction,
+        TrapCause::Breakpoint,
+        TrapCause::LoadAddressMisaligned,
+        TrapCause::LoadAccessFault,
+        TrapCause::StoreAddressMisaligned,
+        TrapCause::StoreAccessFault,
+        TrapCause::UserEnvironmentCall,
+        TrapCause::SupervisorEnvironmentCall,
+        TrapCause::InstructionPageFault,
+        TrapCause::LoadPageFault,
+        TrapCause::StorePageFault,
+        TrapCause::ReservedInterrupt,
+        TrapCause::PlatformInterrupt,
+        TrapCause::ReservedException,
+        TrapCause::CustomException,
+    ];
+}
+
 impl From<u64> for TrapCause {
     fn from(val: u64) -> TrapCause {
-        let interrupt_bit = val & (1 << 63);
+        let interrupt_bit = val >> 63;
         let exception_code = val & ((1 << 63) - 1);
 
         match (interrupt_bit, exception_code) {
@@ -52,8 +308,8 @@ impl From<u64> for TrapCause {
             (0, 13) => TrapCause::LoadPageFault,
             (0, 15) => TrapCause::StorePageFault,
 
-            (0, c) if c >= 24 && c <= 31 => TrapCause::CustomException,
-            (0, c) if c >= 48 && c <= 63 => TrapCause::CustomException,
+            (0, c) if (24..=31).contains(&c) => TrapCause::CustomException,
+            (0, c) if (48..=63).contains(&c) => TrapCause::CustomException,
             (0, _) => TrapCause::ReservedException,
 
             (_, _) => panic!("Interrupt bit > 1 in when decoding trap cause?")
@@ -61,8 +317,534 @@ impl From<u64> for TrapCause {
     }
 }
 
+extern "C" {
+    static TRAP: u64;
+    static TRAP_VECTOR: u64;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    // Every trap enters at one address and is dispatched on scause.
+    Direct,
+    // Software, timer and external interrupts get their own entry points.
+    Vectored,
+}
+
+// Harts started later install whatever the boot hart chose.
+static VECTORED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_mode(mode: TrapMode) {
+    VECTORED.store(mode == TrapMode::Vectored, Ordering::Relaxed);
+    let stvec = unsafe {
+        match mode {
+            TrapMode::Direct => Stvec::direct(TRAP),
+            TrapMode::Vectored => Stvec::vectored(TRAP_VECTOR),
+        }
+    };
+    unsafe {
+        csr::stvec::write(stvec);
+    }
+}
+
+pub fn init_hart() {
+    set_mode(if VECTORED.load(Ordering::Relaxed) {
+        TrapMode::Vectored
+    } else {
+        TrapMode::Direct
+    });
+}
+
+pub fn mode() -> TrapMode {
+    if csr::stvec::read().is_vectored() {
+        TrapMode::Vectored
+    } else {
+        TrapMode::Direct
+    }
+}
+
+// Returning from kernel_trap resumes at frame.sepc, so a handler that can
+// recover must leave sepc pointing at the next instruction to run. Returning
+// false hands the trap on to the default handler, which panics.
+pub type TrapHandler = fn(&mut TrapFrame) -> bool;
+
+static HANDLERS: Mutex<[Option<TrapHandler>; TRAP_CAUSES]> = Mutex::new(default_handlers());
+
+const fn default_handlers() -> [Option<TrapHandler>; TRAP_CAUSES] {
+    let mut handlers: [Option<TrapHandler>; TRAP_CAUSES] = [None; TRAP_CAUSES];
+    handlers[TrapCause::Breakpoint as usize] = Some(debugger::handle_breakpoint);
+    handlers[TrapCause::LoadAddressMisaligned as usize] = Some(misaligned::handle_misaligned);
+    handlers[TrapCause::StoreAddressMisaligned as usize] = Some(misaligned::handle_misaligned);
+    handlers[TrapCause::LoadPageFault as usize] = Some(uaccess::handle_fault);
+    handlers[TrapCause::StorePageFault as usize] = Some(uaccess::handle_fault);
+    handlers[TrapCause::LoadAccessFault as usize] = Some(uaccess::handle_fault);
+    handlers[TrapCause::StoreAccessFault as usize] = Some(uaccess::handle_fault);
+    handlers
+}
+
+// The table is locked while a trap is dispatched, so it must not be held
+// with interrupts enabled.
+pub fn register_handler(cause: TrapCause, handler: TrapHandler) -> Option<TrapHandler> {
+    without_interrupts(|| HANDLERS.lock()[cause as usize].replace(handler))
+}
+
+pub fn unregister_handler(cause: TrapCause) -> Option<TrapHandler> {
+    without_interrupts(|| HANDLERS.lock()[cause as usize].take())
+}
+
+// Deeper than this, a handler is almost certainly faulting on itself.
+const MAX_TRAP_DEPTH: usize = 8;
+
+// The frames a hart is handling, outermost first. The frames themselves
+// live on the stack below the code they interrupted.
+#[derive(Clone, Copy)]
+struct TrapStack {
+    depth: usize,
+    frames: [u64; MAX_TRAP_DEPTH],
+}
+
+const NOT_TRAPPED: TrapStack = TrapStack {
+    depth: 0,
+    frames: [0; MAX_TRAP_DEPTH],
+};
+
+static TRAP_STACKS: PerHart<TrapStack> = PerHart::new([NOT_TRAPPED; MAX_HARTS]);
+
+pub fn trap_depth() -> usize {
+    TRAP_STACKS.with(|stack| stack.depth)
+}
+
+// Level 0 is the outermost trap.
+pub fn frame(level: usize) -> Option<TrapFrame> {
+    let stack = TRAP_STACKS.get();
+    if level >= stack.depth {
+        return None;
+    }
+    Some(unsafe { (*(stack.frames[level] as *const TrapFrame)).clone() })
+}
+
+// Makes the trap being handled return to `pc` on the stack `sp`, in S-mode
+// with interrupts on, rather than to the kernel code it interrupted. It has
+// to be the only trap being handled. For abandoning code that's stuck.
+pub fn redirect_outermost(pc: u64, sp: u64) -> bool {
+    let stack = TRAP_STACKS.get();
+    if stack.depth != 1 {
+        return false;
+    }
+    let frame = unsafe { &mut *(stack.frames[0] as *mut TrapFrame) };
+    if frame.is_from_user() {
+        return false;
+    }
+    frame.sepc = pc;
+    frame.set_reg(2, sp);
+    frame.sstatus |= (Sstatus::SPP | Sstatus::SPIE).bits();
+    true
+}
+
+// Forgets the traps being handled, when the code that took them is being
+// abandoned and won't return through them.
+pub fn abandon_traps() {
+    TRAP_STACKS.with(|stack| stack.depth = 0);
+}
+
+pub fn current_frame() -> Option<TrapFrame> {
+    frame(trap_depth().checked_sub(1)?)
+}
+
+// The frame of the innermost trap being handled, for an interrupt handler
+// that has to change the registers of the code it interrupted.
+pub fn with_current_frame<R>(f: impl FnOnce(&mut TrapFrame) -> R) -> Option<R> {
+    let stack = TRAP_STACKS.get();
+    let frame = stack.frames[stack.depth.checked_sub(1)?];
+    Some(f(unsafe { &mut *(frame as *mut TrapFrame) }))
+}
+
+const HISTORY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapRecord {
+    pub cause: TrapCause,
+    pub sepc: u64,
+    pub stval: u64,
+    // In `time` ticks.
+    pub time: u64,
+}
+
+// The most recent traps taken by one hart, overwriting the oldest.
+#[derive(Debug, Clone, Copy)]
+pub struct TrapHistory {
+    records: [Option<TrapRecord>; HISTORY_SIZE],
+    next: usize,
+}
+
+impl Default for TrapHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrapHistory {
+    pub const fn new() -> Self {
+        Self {
+            records: [None; HISTORY_SIZE],
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, record: TrapRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % HISTORY_SIZE;
+    }
+
+    // Oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = TrapRecord> + '_ {
+        (0..HISTORY_SIZE).filter_map(move |n| self.records[(self.next + n) % HISTORY_SIZE])
+    }
+
+    pub fn latest(&self) -> Option<TrapRecord> {
+        self.records[(self.next + HISTORY_SIZE - 1) % HISTORY_SIZE]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrapStats {
+    // Totals across all harts, indexed by cause.
+    pub counts: [u64; TRAP_CAUSES],
+}
+
+impl TrapStats {
+    pub fn count(&self, cause: TrapCause) -> u64 {
+        self.counts[cause as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_HISTORY: Mutex<TrapHistory> = Mutex::new(TrapHistory::new());
+
+static TRAP_COUNTS: [AtomicU64; TRAP_CAUSES] = [ZERO; TRAP_CAUSES];
+static HISTORIES: [Mutex<TrapHistory>; MAX_HARTS] = [EMPTY_HISTORY; MAX_HARTS];
+
+pub fn stats() -> TrapStats {
+    let mut counts = [0; TRAP_CAUSES];
+    for (count, counter) in counts.iter_mut().zip(&TRAP_COUNTS) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    TrapStats { counts }
+}
+
+pub fn history(hart: usize) -> TrapHistory {
+    without_interrupts(|| *HISTORIES[hart].lock())
+}
+
+fn record_trap(frame: &TrapFrame, cause: TrapCause) {
+    TRAP_COUNTS[cause as usize].fetch_add(1, Ordering::Relaxed);
+
+    // Only this hart records into its history, so the lock is free unless
+    // another hart is reading it.
+    HISTORIES[hart_id()].lock().record(TrapRecord {
+        cause,
+        sepc: frame.sepc,
+        stval: frame.stval,
+        time: timer::read_time(),
+    });
+}
+
+pub fn report() {
+    let stats = stats();
+    println!("{} traps", stats.total());
+    for cause in TrapCause::ALL {
+        if stats.count(cause) != 0 {
+            println!("  {:>10} {:?}", stats.count(cause), cause);
+        }
+    }
+
+    for hart in (0..MAX_HARTS).filter(|hart| online_harts() & 1 << hart != 0) {
+        println!("Recent traps on hart {}:", hart);
+        for record in history(hart).iter() {
+            println!(
+                "  {:>12} {:?} at {}, stval {:#x}",
+                record.time,
+                record.cause,
+                Symbolized(record.sepc),
+                record.stval
+            );
+        }
+    }
+}
+
+// An exception the handler can't recover from, taken while another trap
+// was being handled. The outer handler's state is as suspect as its own.
+fn double_fault(frame: &TrapFrame, outer: &TrapFrame) -> ! {
+    panic!(
+        "Double fault: {:?} at {}, stval {:#x}, while handling {:?} at {}",
+        frame.cause(),
+        Symbolized(frame.sepc),
+        frame.stval,
+        outer.cause(),
+        Symbolized(outer.sepc)
+    );
+}
+
+fn handle_trap(frame: &mut TrapFrame, cause: TrapCause) {
+    let depth = trap_depth();
+    if depth == MAX_TRAP_DEPTH {
+        panic!(
+            "Traps nested too deeply: {:?} at {}",
+            cause,
+            Symbolized(frame.sepc)
+        );
+    }
+
+    TRAP_STACKS.with(|stack| {
+        stack.frames[depth] = frame as *mut TrapFrame as u64;
+        stack.depth = depth + 1;
+    });
+    record_trap(frame, cause);
+
+    let handler = HANDLERS.lock()[cause as usize];
+    if !handler.is_some_and(|handler| handler(frame)) {
+        if depth > 0 && !cause.is_interrupt() {
+            let outer = TRAP_STACKS.with(|stack| stack.frames[depth - 1]);
+            double_fault(frame, unsafe { &*(outer as *const TrapFrame) });
+        }
+        panic!(
+            "Unhandled trap: {:?} at {}, stval {:#x}",
+            cause,
+            Symbolized(frame.sepc),
+            frame.stval
+        );
+    }
+
+    TRAP_STACKS.with(|stack| stack.depth = depth);
+}
+
+// Exceptions from user mode aren't nested in anything, so they're handled
+// outside the trap stack, where the thread can block or be preempted.
 #[no_mangle]
-pub extern "C" fn kernel_trap(cause: u64) {
-    let cause: TrapCause = cause.into();
-    panic!("Unhandled trap: {:?}", cause);
+pub extern "C" fn kernel_trap(frame: &mut TrapFrame) {
+    let cause = frame.cause();
+    let from_user = frame.is_from_user();
+    if from_user && !cause.is_interrupt() {
+        record_trap(frame, cause);
+        user::handle_exception(frame, cause);
+    } else {
+        handle_trap(frame, cause);
+    }
+    if (cause.is_interrupt() || from_user) && trap_depth() == 0 {
+        task::preempt();
+    }
+    if from_user {
+        signal::deliver(frame);
+    }
+}
+
+// Entered from the dedicated interrupt entries in vectored mode, which
+// already know the cause's TrapCause discriminant.
+#[no_mangle]
+pub extern "C" fn kernel_interrupt(frame: &mut TrapFrame, cause: usize) {
+    handle_trap(frame, TrapCause::ALL[cause]);
+    if trap_depth() == 0 {
+        task::preempt();
+    }
+    if frame.is_from_user() {
+        signal::deliver(frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn register_zero_is_always_zero() {
+        let mut frame = TrapFrame::zeroed();
+        frame.set_reg(0, 42);
+        assert_eq!(frame.reg(0), 0);
+    }
+
+    #[test_case]
+    fn arguments_are_the_a_registers() {
+        let mut frame = TrapFrame::zeroed();
+        frame.set_reg(10, 1);
+        frame.set_reg(17, 8);
+        assert_eq!(frame.arg(0), 1);
+        assert_eq!(frame.arg(7), 8);
+    }
+
+    #[test_case]
+    fn interrupt_causes_are_decoded() {
+        assert_eq!(TrapCause::from((1 << 63) | 5), TrapCause::TimerInterrupt);
+        assert_eq!(TrapCause::from(5), TrapCause::LoadAccessFault);
+    }
+
+    #[test_case]
+    fn execution_resumes_after_a_breakpoint() {
+        let resumed: u64;
+        unsafe {
+            core::arch::asm!("ebreak", "li {0}, 1", out(reg) resumed);
+        }
+        assert_eq!(resumed, 1);
+    }
+
+    #[test_case]
+    fn breakpoints_are_counted_and_recorded() {
+        // Otherwise a timer interrupt could land between the two.
+        let (before, latest) = without_interrupts(|| {
+            let before = stats().count(TrapCause::Breakpoint);
+            unsafe {
+                core::arch::asm!("ebreak");
+            }
+            (before, history(hart_id()).latest().unwrap())
+        });
+
+        assert!(stats().count(TrapCause::Breakpoint) > before);
+        assert_eq!(latest.cause, TrapCause::Breakpoint);
+    }
+
+    #[test_case]
+    fn the_history_keeps_the_most_recent_traps() {
+        let mut history = TrapHistory::new();
+        for sepc in 0..HISTORY_SIZE as u64 + 3 {
+            history.record(TrapRecord {
+                cause: TrapCause::Breakpoint,
+                sepc,
+                stval: 0,
+                time: 0,
+            });
+        }
+
+        assert_eq!(history.iter().count(), HISTORY_SIZE);
+        assert_eq!(history.iter().next().unwrap().sepc, 3);
+        assert_eq!(history.latest().unwrap().sepc, HISTORY_SIZE as u64 + 2);
+    }
+
+    #[test_case]
+    fn causes_are_listed_in_discriminant_order() {
+        for (n, cause) in TrapCause::ALL.iter().enumerate() {
+            assert_eq!(*cause as usize, n);
+        }
+    }
+
+    fn skip_illegal_instruction(frame: &mut TrapFrame) -> bool {
+        frame.skip_instruction();
+        true
+    }
+
+    #[test_case]
+    fn registered_handlers_are_dispatched_by_cause() {
+        let previous = register_handler(TrapCause::IllegalInstruction, skip_illegal_instruction);
+        let resumed: u64;
+        unsafe {
+            core::arch::asm!("unimp", "li {0}, 1", out(reg) resumed);
+        }
+        unregister_handler(TrapCause::IllegalInstruction);
+
+        assert!(previous.is_none());
+        assert_eq!(resumed, 1);
+    }
+
+    static OBSERVED_DEPTH: AtomicUsize = AtomicUsize::new(0);
+    static JIFFIES_ADVANCED: AtomicBool = AtomicBool::new(false);
+
+    fn wait_for_a_tick(frame: &mut TrapFrame) -> bool {
+        OBSERVED_DEPTH.store(trap_depth(), Ordering::Relaxed);
+        frame.skip_instruction();
+
+        let start = crate::timer::jiffies();
+        let deadline = crate::timer::read_time() + 4 * crate::timer::ticks_per_jiffy();
+        with_nested_interrupts(frame, || {
+            while crate::timer::jiffies() == start && crate::timer::read_time() < deadline {
+                core::hint::spin_loop();
+            }
+        });
+        JIFFIES_ADVANCED.store(crate::timer::jiffies() != start, Ordering::Relaxed);
+        true
+    }
+
+    #[test_case]
+    fn interrupts_nest_inside_handlers_that_allow_it() {
+        let previous = register_handler(TrapCause::IllegalInstruction, wait_for_a_tick);
+        unsafe {
+            core::arch::asm!("unimp");
+        }
+        match previous {
+            Some(previous) => register_handler(TrapCause::IllegalInstruction, previous),
+            None => unregister_handler(TrapCause::IllegalInstruction),
+        };
+
+        assert_eq!(OBSERVED_DEPTH.load(Ordering::Relaxed), 1);
+        assert!(JIFFIES_ADVANCED.load(Ordering::Relaxed));
+        assert_eq!(trap_depth(), 0);
+    }
+
+    #[test_case]
+    fn interrupts_are_delivered_in_vectored_mode() {
+        let previous = mode();
+        set_mode(TrapMode::Vectored);
+        assert_eq!(mode(), TrapMode::Vectored);
+
+        let start = crate::timer::jiffies();
+        let deadline = crate::timer::read_time() + 4 * crate::timer::ticks_per_jiffy();
+        while crate::timer::jiffies() == start && crate::timer::read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        let advanced = crate::timer::jiffies() != start;
+
+        // Exceptions still come through the common entry.
+        let resumed: u64;
+        unsafe {
+            core::arch::asm!("ebreak", "li {0}, 1", out(reg) resumed);
+        }
+        set_mode(previous);
+
+        assert!(advanced);
+        assert_eq!(resumed, 1);
+    }
+
+    #[test_case]
+    fn interrupt_guards_nest_and_restore() {
+        assert!(interrupts_enabled());
+        {
+            let _outer = InterruptGuard::disable();
+            {
+                let _inner = InterruptGuard::disable();
+                assert!(!interrupts_enabled());
+            }
+            assert!(!interrupts_enabled());
+        }
+        assert!(interrupts_enabled());
+    }
+
+    #[test_case]
+    fn interrupts_are_off_while_an_irqsave_lock_is_held() {
+        let lock = Mutex::new(0);
+        {
+            let mut value = lock.lock_irqsave();
+            *value += 1;
+            assert!(!interrupts_enabled());
+        }
+        assert!(interrupts_enabled());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test_case]
+    fn interrupt_causes_are_recognised() {
+        assert!(TrapCause::TimerInterrupt.is_interrupt());
+        assert!(!TrapCause::LoadPageFault.is_interrupt());
+    }
+
+    #[test_case]
+    fn skipping_a_compressed_instruction_advances_two_bytes() {
+        let nop: u16 = 0x0001;
+        let mut frame = TrapFrame::zeroed();
+        frame.sepc = &nop as *const u16 as u64;
+        frame.skip_instruction();
+        assert_eq!(frame.sepc, &nop as *const u16 as u64 + 2);
+    }
 }
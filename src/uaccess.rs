@@ -0,0 +1,132 @@
+use crate::address_space::{is_user_range, Access};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::PAGE_SIZE;
+use crate::process;
+use crate::trap::{self, TrapFrame};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+extern "C" {
+    fn _copy_user(dst: *mut u8, src: *const u8, length: usize) -> usize;
+
+    static COPY_USER_START: u64;
+    static COPY_USER_FAULT: u64;
+    static COPY_USER_END: u64;
+}
+
+// Registered for kernel page and access faults. One inside _copy_user
+// means the user memory went away after it was checked, so the copy gives
+// up rather than the kernel panicking.
+pub fn handle_fault(frame: &mut TrapFrame) -> bool {
+    let (start, end) = unsafe { (COPY_USER_START, COPY_USER_END) };
+    if frame.is_from_user() || !(start..end).contains(&frame.sepc) {
+        return false;
+    }
+    frame.sepc = unsafe { COPY_USER_FAULT };
+    true
+}
+
+// Whether the current process can reach all of [start, start + length)
+// from user mode, for reading or, if `write`, for writing. Pages that
+// haven't been faulted in yet are, and copy-on-write ones are copied for
+// writing.
+pub fn can_access(start: u64, length: u64, write: bool) -> bool {
+    if length == 0 {
+        return true;
+    }
+    if !is_user_range(start, length) {
+        return false;
+    }
+
+    let first_page = start & !(PAGE_SIZE - 1);
+    let last_page = (start + length - 1) & !(PAGE_SIZE - 1);
+    process::with_current(|process| {
+        let space = match process.address_space_mut() {
+            Some(space) => space,
+            None => return false,
+        };
+        (first_page..=last_page)
+            .step_by(PAGE_SIZE as usize)
+            .all(|page| {
+                let access = if write { Access::Write } else { Access::Read };
+                space.fault_in(page, access).is_ok()
+            })
+    })
+    .unwrap_or(false)
+}
+
+// A page at a time with interrupts off, so the thread isn't switched away
+// from with SUM set.
+unsafe fn copy(dst: *mut u8, src: *const u8, length: usize) -> KernelResult<()> {
+    let mut copied = 0;
+    while copied < length {
+        let chunk = (length - copied).min(PAGE_SIZE as usize);
+        let left = trap::without_interrupts(|| _copy_user(dst.add(copied), src.add(copied), chunk));
+        if left != 0 {
+            return Err(KernelError::InvalidAddress);
+        }
+        copied += chunk;
+    }
+    Ok(())
+}
+
+// Fails with InvalidAddress, EFAULT to user space, if any of the source
+// isn't readable user memory of the current process.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> KernelResult<()> {
+    if !can_access(src, dst.len() as u64, false) {
+        return Err(KernelError::InvalidAddress);
+    }
+    unsafe { copy(dst.as_mut_ptr(), src as *const u8, dst.len()) }
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> KernelResult<()> {
+    if !can_access(dst, src.len() as u64, true) {
+        return Err(KernelError::InvalidAddress);
+    }
+    unsafe { copy(dst as *mut u8, src.as_ptr(), src.len()) }
+}
+
+// Copies a NUL-terminated string of at most `limit` bytes, failing with
+// InvalidArgument if it's longer or isn't UTF-8.
+pub fn copy_string_from_user(src: u64, limit: usize) -> KernelResult<String> {
+    let mut bytes = Vec::new();
+    let mut address = src;
+    while bytes.len() < limit {
+        // Up to the end of the page, which can't fault if the start doesn't.
+        let chunk = ((PAGE_SIZE - address % PAGE_SIZE) as usize).min(limit - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + chunk, 0);
+        copy_from_user(&mut bytes[start..], address)?;
+        if let Some(end) = bytes[start..].iter().position(|&byte| byte == 0) {
+            bytes.truncate(start + end);
+            return String::from_utf8(bytes).map_err(|_| KernelError::InvalidArgument);
+        }
+        address += chunk as u64;
+    }
+    Err(KernelError::InvalidArgument)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address_space::USER_START;
+
+    #[test_case]
+    fn kernel_threads_have_no_user_memory() {
+        let mut buffer = [0; 8];
+        assert_eq!(
+            copy_from_user(&mut buffer, USER_START),
+            Err(KernelError::InvalidAddress)
+        );
+        assert!(!can_access(USER_START, 8, false));
+        assert!(can_access(USER_START, 0, true));
+    }
+
+    #[test_case]
+    fn a_fault_during_a_copy_is_recovered() {
+        let mut buffer = [0; 8];
+        // Never mapped, so it faults despite skipping the checks.
+        let result = unsafe { copy(buffer.as_mut_ptr(), 0x1000 as *const u8, buffer.len()) };
+        assert_eq!(result, Err(KernelError::InvalidAddress));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::address_space::{is_user_range, mode_for, Access, AddressSpace, USER_END, USER_START};
+use crate::csr::{self, Sstatus};
+use crate::elf::{self, Elf, Segment};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::PAGE_SIZE;
+use crate::page_table::PageTableEntryMode;
+use crate::per_hart;
+use crate::process::{self, Pid};
+use crate::signal::{self, SIGBUS, SIGILL, SIGSEGV, SIGTRAP};
+use crate::syscall;
+use crate::trap::{self, TrapCause, TrapFrame};
+use crate::vfs;
+use crate::warn;
+use alloc::format;
+use core::arch::asm;
+use core::mem::size_of;
+use core::sync::atomic::Ordering;
+
+pub const CODE_START: u64 = USER_START;
+pub const STACK_TOP: u64 = USER_END;
+const STACK_PAGES: u64 = 4;
+pub const STACK_BOTTOM: u64 = STACK_TOP - STACK_PAGES * PAGE_SIZE;
+
+// The page below the stack, where signal handlers return to.
+pub const SIGRETURN_TRAMPOLINE: u64 = STACK_BOTTOM - PAGE_SIZE;
+
+// Where mmap starts looking for room, well clear of the heap.
+pub const MMAP_BASE: u64 = USER_START + (USER_END - USER_START) / 2;
+
+// What a shell reports for a process killed by SIGSEGV.
+pub const FAULT_EXIT_CODE: i64 = signal::exit_code(SIGSEGV);
+
+extern "C" {
+    fn _return_to_user(frame: *const TrapFrame) -> !;
+
+    static USER_SUM_START: u8;
+    static USER_SUM_END: u8;
+    static USER_FAULT_START: u8;
+    static USER_FAULT_END: u8;
+    static USER_HELLO_START: u8;
+    static USER_HELLO_END: u8;
+    static USER_GETPID_START: u8;
+    static USER_GETPID_END: u8;
+    static USER_BAD_SYSCALL_START: u8;
+    static USER_BAD_SYSCALL_END: u8;
+    static USER_BAD_WRITE_START: u8;
+    static USER_BAD_WRITE_END: u8;
+    static USER_FORK_START: u8;
+    static USER_FORK_END: u8;
+    static USER_EXEC_START: u8;
+    static USER_EXEC_END: u8;
+    static USER_HEAP_START: u8;
+    static USER_HEAP_END: u8;
+    static USER_SIGRETURN_START: u8;
+    static USER_SIGRETURN_END: u8;
+}
+
+unsafe fn program(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    let length = end as *const u8 as usize - start as usize;
+    core::slice::from_raw_parts(start, length)
+}
+
+// Exits with the sum of 1 to 10.
+pub fn sum_program() -> &'static [u8] {
+    unsafe { program(&USER_SUM_START, &USER_SUM_END) }
+}
+
+// Faults trying to read kernel memory.
+pub fn fault_program() -> &'static [u8] {
+    unsafe { program(&USER_FAULT_START, &USER_FAULT_END) }
+}
+
+// Writes a greeting and exits with the byte count.
+pub fn hello_program() -> &'static [u8] {
+    unsafe { program(&USER_HELLO_START, &USER_HELLO_END) }
+}
+
+// Exits with its own PID.
+pub fn getpid_program() -> &'static [u8] {
+    unsafe { program(&USER_GETPID_START, &USER_GETPID_END) }
+}
+
+// Exits with the error from a system call that doesn't exist.
+pub fn bad_syscall_program() -> &'static [u8] {
+    unsafe { program(&USER_BAD_SYSCALL_START, &USER_BAD_SYSCALL_END) }
+}
+
+// Exits with the error from writing out kernel memory.
+pub fn bad_write_program() -> &'static [u8] {
+    unsafe { program(&USER_BAD_WRITE_START, &USER_BAD_WRITE_END) }
+}
+
+// Forks a child that exits with a value it stored on its stack, then waits
+// for it and exits with the child's exit code.
+pub fn fork_program() -> &'static [u8] {
+    unsafe { program(&USER_FORK_START, &USER_FORK_END) }
+}
+
+// Replaces itself with /bin/sum, exiting with the error if that fails.
+pub fn exec_program() -> &'static [u8] {
+    unsafe { program(&USER_EXEC_START, &USER_EXEC_END) }
+}
+
+// The ELF executable built from user/init by build.rs.
+static INIT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/init"));
+
+// Checks that the system calls behave, exiting with 0 if they do.
+pub fn init_program() -> &'static [u8] {
+    INIT
+}
+
+// Stores to memory from both brk and mmap, and exits with the sum of what
+// it reads back from them, 42.
+pub fn heap_program() -> &'static [u8] {
+    unsafe { program(&USER_HEAP_START, &USER_HEAP_END) }
+}
+
+// Calls rt_sigreturn, for handlers to return into.
+fn sigreturn_trampoline() -> &'static [u8] {
+    unsafe { program(&USER_SIGRETURN_START, &USER_SIGRETURN_END) }
+}
+
+struct Program {
+    name: &'static str,
+    image: fn() -> &'static [u8],
+}
+
+// Installed in /bin at boot, for exec to find.
+const PROGRAMS: &[Program] = &[
+    Program {
+        name: "init",
+        image: init_program,
+    },
+    Program {
+        name: "sum",
+        image: sum_program,
+    },
+    Program {
+        name: "hello",
+        image: hello_program,
+    },
+    Program {
+        name: "getpid",
+        image: getpid_program,
+    },
+    Program {
+        name: "fork",
+        image: fork_program,
+    },
+    Program {
+        name: "heap",
+        image: heap_program,
+    },
+];
+
+pub fn install_programs() -> KernelResult<()> {
+    match vfs::mkdir("/bin") {
+        Ok(()) | Err(KernelError::AlreadyExists) => (),
+        Err(e) => return Err(e),
+    }
+    for program in PROGRAMS {
+        vfs::write_file(&format!("/bin/{}", program.name), (program.image)())?;
+    }
+    Ok(())
+}
+
+// A frame that sret's into user mode at `entry` with interrupts enabled,
+// and with the FPU and access to user memory from the kernel both off.
+pub fn initial_frame(entry: u64, stack_top: u64) -> TrapFrame {
+    let sstatus = csr::sstatus::read();
+    let mut frame = TrapFrame::zeroed();
+    frame.sepc = entry;
+    frame.sstatus =
+        ((sstatus | Sstatus::SPIE) - (Sstatus::SPP | Sstatus::FS | Sstatus::SUM)).bits();
+    frame.set_reg(2, stack_top);
+    frame
+}
+
+// Loads an ELF executable, or failing that a flat binary to run from the
+// start of user space, and maps a stack below the top with the signal
+// trampoline under it. The heap starts after the highest page loaded.
+// Returns the frame to start it with.
+pub fn load(space: &mut AddressSpace, image: &[u8]) -> KernelResult<TrapFrame> {
+    let (entry, end) = if elf::is_elf(image) {
+        load_elf(space, image)?
+    } else {
+        load_flat(space, image)?
+    };
+    space.set_heap_start(end);
+    for page in (STACK_BOTTOM..STACK_TOP).step_by(PAGE_SIZE as usize) {
+        space.map(page, PageTableEntryMode::ReadWrite)?;
+    }
+    let trampoline = space.map(SIGRETURN_TRAMPOLINE, PageTableEntryMode::ReadExecute)?;
+    let code = sigreturn_trampoline();
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), trampoline.as_mut_ptr::<u8>(), code.len());
+    }
+    Ok(initial_frame(entry, STACK_TOP))
+}
+
+// These return the entry point and the end of what they loaded.
+fn load_flat(space: &mut AddressSpace, code: &[u8]) -> KernelResult<(u64, u64)> {
+    if code.len() as u64 > STACK_BOTTOM - CODE_START {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    for (n, chunk) in code.chunks(PAGE_SIZE as usize).enumerate() {
+        let address = CODE_START + n as u64 * PAGE_SIZE;
+        let page = space.map(address, PageTableEntryMode::ReadExecute)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(chunk.as_ptr(), page.as_mut_ptr::<u8>(), chunk.len());
+        }
+    }
+    Ok((CODE_START, CODE_START + code.len() as u64))
+}
+
+fn segment_mode(segment: &Segment) -> PageTableEntryMode {
+    mode_for(segment.readable, segment.writable, segment.executable)
+        .unwrap_or(PageTableEntryMode::ReadOnly)
+}
+
+// A page shared by two segments keeps the permissions of the first.
+fn load_elf(space: &mut AddressSpace, image: &[u8]) -> KernelResult<(u64, u64)> {
+    let elf = Elf::parse(image)?;
+    let mut loaded_end = CODE_START;
+    for segment in elf.segments() {
+        if !is_user_range(segment.address, segment.memory_size) {
+            return Err(KernelError::NotExecutable);
+        }
+        let start = segment.address & !(PAGE_SIZE - 1);
+        let end = (segment.address + segment.memory_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let data_end = segment.address + segment.data.len() as u64;
+        loaded_end = loaded_end.max(end);
+
+        for page_address in (start..end).step_by(PAGE_SIZE as usize) {
+            let page = match space.translate(page_address) {
+                Some(page) => page,
+                None => space
+                    .map(page_address, segment_mode(segment))?
+                    .start_address(),
+            };
+            let from = page_address.max(segment.address);
+            let to = (page_address + PAGE_SIZE).min(data_end);
+            if from < to {
+                let data =
+                    &segment.data[(from - segment.address) as usize..][..(to - from) as usize];
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        data.as_ptr(),
+                        (page.as_u64() + (from - page_address)) as *mut u8,
+                        data.len(),
+                    );
+                }
+            }
+        }
+    }
+    Ok((elf.entry(), loaded_end))
+}
+
+// Runs `code` in a new process of its own.
+pub fn spawn_program(name: &str, code: &[u8]) -> KernelResult<Pid> {
+    let mut space = AddressSpace::new()?;
+    let frame = load(&mut space, code)?;
+    process::spawn_user(name, space, frame)
+}
+
+// As the first process started, init gets PID 1.
+pub fn start_init() -> KernelResult<Pid> {
+    let pid = spawn_program("init", init_program())?;
+    if pid != process::INIT_PID {
+        warn!(
+            "init started as PID {} rather than {}",
+            pid.0,
+            process::INIT_PID.0
+        );
+    }
+    Ok(pid)
+}
+
+// Drops into user mode with `frame`'s registers, from a thread with a
+// kernel stack whose top is free for the frame of the next trap.
+pub fn enter(frame: &TrapFrame) -> ! {
+    trap::disable_interrupts();
+    let kernel_sp = per_hart::area().kernel_sp.load(Ordering::Relaxed);
+    assert!(kernel_sp != 0, "Entering user mode without a kernel stack");
+
+    let saved = (kernel_sp - size_of::<TrapFrame>() as u64) as *mut TrapFrame;
+    unsafe {
+        // The code may only just have been copied in.
+        asm!("fence.i");
+        saved.write(frame.clone());
+        _return_to_user(saved)
+    }
+}
+
+// Demand paging: a fault on a page that's reserved but not yet mapped, or
+// a store to one that's copy-on-write, is fixed up and the access retried.
+fn handle_page_fault(address: u64, access: Access) -> bool {
+    process::with_current(|process| {
+        let handled = process
+            .address_space_mut()
+            .is_some_and(|space| space.fault_in(address, access).is_ok());
+        if handled {
+            process.usage_mut().record_fault();
+        }
+        handled
+    })
+    .unwrap_or(false)
+}
+
+fn fault_signal(cause: TrapCause) -> u32 {
+    match cause {
+        TrapCause::IllegalInstruction => SIGILL,
+        TrapCause::Breakpoint => SIGTRAP,
+        TrapCause::InstructionAddressMisaligned
+        | TrapCause::LoadAddressMisaligned
+        | TrapCause::StoreAddressMisaligned => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+// Runs with interrupts on, so a system call can block. They're off again
+// by the time this returns. A fault that can't be fixed up raises a
+// signal, which kills the process unless it has a handler for it.
+pub fn handle_exception(frame: &mut TrapFrame, cause: TrapCause) {
+    trap::enable_interrupts();
+    match cause {
+        TrapCause::UserEnvironmentCall => syscall::dispatch(frame),
+        TrapCause::LoadPageFault if handle_page_fault(frame.stval, Access::Read) => {}
+        TrapCause::StorePageFault if handle_page_fault(frame.stval, Access::Write) => {}
+        TrapCause::InstructionPageFault if handle_page_fault(frame.stval, Access::Execute) => {}
+        _ => {
+            let signal = fault_signal(cause);
+            warn!(
+                "Process {}: {:?} at {:#x}, stval {:#x}, raising signal {}",
+                process::current_pid().map_or(0, |pid| pid.0),
+                cause,
+                frame.sepc,
+                frame.stval,
+                signal
+            );
+            process::with_current(|process| process.signals_mut().force(signal));
+        }
+    }
+    trap::disable_interrupts();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process::test::wait_for;
+
+    #[test_case]
+    fn user_code_runs_and_traps_back() {
+        let pid = spawn_program("sum", sum_program()).unwrap();
+        assert_eq!(wait_for(pid), 55);
+    }
+
+    #[test_case]
+    fn a_fault_in_user_mode_only_kills_the_process() {
+        let pid = spawn_program("fault", fault_program()).unwrap();
+        assert_eq!(wait_for(pid), FAULT_EXIT_CODE);
+    }
+
+    #[test_case]
+    fn init_passes_its_checks() {
+        let pid = spawn_program("init", init_program()).unwrap();
+        assert_eq!(wait_for(pid), 0);
+    }
+
+    #[test_case]
+    fn elf_executables_are_loaded() {
+        let image = elf::test::executable(CODE_START + PAGE_SIZE, sum_program(), PAGE_SIZE);
+        let pid = spawn_program("sum.elf", &image).unwrap();
+        assert_eq!(wait_for(pid), 55);
+    }
+}
@@ -0,0 +1,506 @@
+use crate::error::{KernelError, KernelResult};
+use crate::lock::RwSpinLock;
+use crate::shm::Segment;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Deref;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    Pipe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub file_type: FileType,
+    // The rwx bits for owner, group and others.
+    pub permissions: u16,
+    pub inode: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+// Anything a file descriptor can refer to. Implementations that keep a
+// position keep it themselves, so it's shared by every descriptor for the
+// same opening. Only what a file supports need be implemented.
+pub trait File: Send + Sync {
+    fn read(&self, _buffer: &mut [u8]) -> KernelResult<usize> {
+        Err(KernelError::BadDescriptor)
+    }
+
+    fn write(&self, _bytes: &[u8]) -> KernelResult<usize> {
+        Err(KernelError::BadDescriptor)
+    }
+
+    // Returns the new position.
+    fn seek(&self, _position: SeekFrom) -> KernelResult<u64> {
+        Err(KernelError::IllegalSeek)
+    }
+
+    fn stat(&self) -> KernelResult<Stat>;
+
+    // The memory a MAP_SHARED mapping of it maps, and whether it was opened
+    // for writing, if it's backed by any.
+    fn shared_memory(&self) -> Option<(Arc<Segment>, bool)> {
+        None
+    }
+
+    // Called once, when the last descriptor for it is closed.
+    fn close(&self) {}
+}
+
+// Works out where a seek lands for something `size` bytes long, currently
+// at `current`. As on Linux, offsets have to fit in an off_t, so anywhere
+// past i64::MAX fails with InvalidArgument.
+pub fn seek_position(position: SeekFrom, current: u64, size: u64) -> KernelResult<u64> {
+    let position = match position {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::Current(offset) => current.checked_add_signed(offset),
+        SeekFrom::End(offset) => size.checked_add_signed(offset),
+    };
+    position
+        .filter(|&position| position <= i64::MAX as u64)
+        .ok_or(KernelError::InvalidArgument)
+}
+
+struct Opened(Box<dyn File>);
+
+impl Drop for Opened {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// One opening of a file, shared by the descriptors dup'd from it and by
+// forked children.
+#[derive(Clone)]
+pub struct OpenFile(Arc<Opened>);
+
+impl OpenFile {
+    pub fn new(file: impl File + 'static) -> Self {
+        Self(Arc::new(Opened(Box::new(file))))
+    }
+
+    pub fn same_as(&self, other: &OpenFile) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Deref for OpenFile {
+    type Target = dyn File;
+
+    fn deref(&self) -> &Self::Target {
+        &*(self.0).0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+// A file or directory in some file system. Files only need the data
+// operations and directories only the naming ones.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> KernelResult<usize> {
+        Err(KernelError::IsADirectory)
+    }
+
+    // Writing past the end fills the gap with zeros.
+    fn write_at(&self, _offset: u64, _bytes: &[u8]) -> KernelResult<usize> {
+        Err(KernelError::IsADirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> KernelResult<()> {
+        Err(KernelError::IsADirectory)
+    }
+
+    fn lookup(&self, _name: &str) -> KernelResult<Arc<dyn Inode>> {
+        Err(KernelError::NotADirectory)
+    }
+
+    // Fails with AlreadyExists if there's something called `name` already.
+    fn create(&self, _name: &str, _file_type: FileType) -> KernelResult<Arc<dyn Inode>> {
+        Err(KernelError::NotADirectory)
+    }
+
+    // Removes anything but a directory.
+    fn unlink(&self, _name: &str) -> KernelResult<()> {
+        Err(KernelError::NotADirectory)
+    }
+
+    // Removes an empty directory.
+    fn rmdir(&self, _name: &str) -> KernelResult<()> {
+        Err(KernelError::NotADirectory)
+    }
+
+    fn entries(&self) -> KernelResult<Vec<DirEntry>> {
+        Err(KernelError::NotADirectory)
+    }
+
+    // Device nodes open as a file of their driver's, rather than one that
+    // reads and writes the inode.
+    fn open_device(&self) -> Option<KernelResult<OpenFile>> {
+        None
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+// File systems by the absolute path they're mounted on, "/" for the root.
+static MOUNTS: RwSpinLock<BTreeMap<String, Arc<dyn FileSystem>>> = RwSpinLock::new(BTreeMap::new());
+
+// The components of `path`, with "." and ".." resolved. Relative paths are
+// taken from the root, as there's no working directory yet.
+pub fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    components
+}
+
+fn mounted_at(path: &str) -> Option<Arc<dyn FileSystem>> {
+    MOUNTS.read().get(path).cloned()
+}
+
+fn walk(components: &[&str]) -> KernelResult<Arc<dyn Inode>> {
+    let mut inode = mounted_at("/").ok_or(KernelError::NotFound)?.root();
+    let mut path = String::new();
+    for component in components {
+        inode = inode.lookup(component)?;
+        path.push('/');
+        path.push_str(component);
+        if let Some(fs) = mounted_at(&path) {
+            inode = fs.root();
+        }
+    }
+    Ok(inode)
+}
+
+pub fn lookup(path: &str) -> KernelResult<Arc<dyn Inode>> {
+    walk(&components(path))
+}
+
+// The directory `path` is in and its last component.
+fn lookup_parent(path: &str) -> KernelResult<(Arc<dyn Inode>, String)> {
+    let components = components(path);
+    let (name, parent) = components
+        .split_last()
+        .ok_or(KernelError::InvalidArgument)?;
+    Ok((walk(parent)?, name.to_string()))
+}
+
+// Mounts `fs` over the directory at `path`, or as the root if `path` is
+// "/" and nothing is mounted there yet.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> KernelResult<()> {
+    let components = components(path);
+    let key = if components.is_empty() {
+        String::from("/")
+    } else {
+        let directory = walk(&components)?;
+        if directory.stat().file_type != FileType::Directory {
+            return Err(KernelError::NotADirectory);
+        }
+        components.iter().flat_map(|c| ["/", *c]).collect()
+    };
+
+    let mut mounts = MOUNTS.write();
+    if mounts.contains_key(&key) {
+        return Err(KernelError::AlreadyExists);
+    }
+    mounts.insert(key, fs);
+    Ok(())
+}
+
+// Mount points and the file systems on them.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .read()
+        .iter()
+        .map(|(path, fs)| (path.clone(), fs.name()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    // With create, fail if it already exists.
+    pub exclusive: bool,
+    pub truncate: bool,
+    pub append: bool,
+    // Fail unless it's a directory.
+    pub directory: bool,
+}
+
+// An opened inode, reading and writing from a position of its own.
+struct InodeFile {
+    inode: Arc<dyn Inode>,
+    position: Mutex<u64>,
+    options: OpenOptions,
+}
+
+impl File for InodeFile {
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        if !self.options.read {
+            return Err(KernelError::BadDescriptor);
+        }
+        let mut position = self.position.lock();
+        let read = self.inode.read_at(*position, buffer)?;
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        if !self.options.write {
+            return Err(KernelError::BadDescriptor);
+        }
+        let mut position = self.position.lock();
+        if self.options.append {
+            *position = self.inode.stat().size;
+        }
+        let written = self.inode.write_at(*position, bytes)?;
+        *position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, position: SeekFrom) -> KernelResult<u64> {
+        let mut current = self.position.lock();
+        *current = seek_position(position, *current, self.inode.stat().size)?;
+        Ok(*current)
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        Ok(self.inode.stat())
+    }
+}
+
+// A driver's file, limited to the access it was opened for.
+struct DeviceFile {
+    file: OpenFile,
+    options: OpenOptions,
+}
+
+impl File for DeviceFile {
+    fn read(&self, buffer: &mut [u8]) -> KernelResult<usize> {
+        if !self.options.read {
+            return Err(KernelError::BadDescriptor);
+        }
+        self.file.read(buffer)
+    }
+
+    fn write(&self, bytes: &[u8]) -> KernelResult<usize> {
+        if !self.options.write {
+            return Err(KernelError::BadDescriptor);
+        }
+        self.file.write(bytes)
+    }
+
+    fn seek(&self, position: SeekFrom) -> KernelResult<u64> {
+        self.file.seek(position)
+    }
+
+    fn stat(&self) -> KernelResult<Stat> {
+        self.file.stat()
+    }
+
+    fn shared_memory(&self) -> Option<(Arc<Segment>, bool)> {
+        self.file
+            .shared_memory()
+            .map(|(segment, writable)| (segment, writable && self.options.write))
+    }
+}
+
+pub fn open(path: &str, options: OpenOptions) -> KernelResult<OpenFile> {
+    let inode = match lookup(path) {
+        Ok(_) if options.create && options.exclusive => return Err(KernelError::AlreadyExists),
+        Ok(inode) => inode,
+        Err(KernelError::NotFound) if options.create => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.create(&name, FileType::Regular)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let file_type = inode.stat().file_type;
+    if options.directory && file_type != FileType::Directory {
+        return Err(KernelError::NotADirectory);
+    }
+    if file_type == FileType::Directory && options.write {
+        return Err(KernelError::IsADirectory);
+    }
+    if let Some(file) = inode.open_device() {
+        return Ok(OpenFile::new(DeviceFile {
+            file: file?,
+            options,
+        }));
+    }
+    if options.truncate && options.write {
+        inode.truncate(0)?;
+    }
+    Ok(OpenFile::new(InodeFile {
+        inode,
+        position: Mutex::new(0),
+        options,
+    }))
+}
+
+pub fn mkdir(path: &str) -> KernelResult<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, FileType::Directory).map(drop)
+}
+
+pub fn unlink(path: &str) -> KernelResult<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.unlink(&name)
+}
+
+pub fn rmdir(path: &str) -> KernelResult<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.rmdir(&name)
+}
+
+// Makes the directory at `path` and any it's in that don't exist yet.
+pub fn mkdir_all(path: &str) -> KernelResult<()> {
+    let mut prefix = String::new();
+    for component in components(path) {
+        prefix.push('/');
+        prefix.push_str(component);
+        match mkdir(&prefix) {
+            Ok(()) | Err(KernelError::AlreadyExists) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// The whole of the file at `path`.
+pub fn read_file(path: &str) -> KernelResult<Vec<u8>> {
+    let inode = lookup(path)?;
+    let mut contents = alloc::vec![0; inode.stat().size as usize];
+    let mut read = 0;
+    while read < contents.len() {
+        match inode.read_at(read as u64, &mut contents[read..])? {
+            0 => break,
+            count => read += count,
+        }
+    }
+    contents.truncate(read);
+    Ok(contents)
+}
+
+// Creates or replaces the file at `path` with `contents`.
+pub fn write_file(path: &str, contents: &[u8]) -> KernelResult<()> {
+    let file = open(
+        path,
+        OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        },
+    )?;
+    let mut written = 0;
+    while written < contents.len() {
+        written += file.write(&contents[written..])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static CLOSED: AtomicBool = AtomicBool::new(false);
+
+    struct Probe;
+
+    impl File for Probe {
+        fn stat(&self) -> KernelResult<Stat> {
+            Ok(Stat {
+                file_type: FileType::CharDevice,
+                permissions: 0o666,
+                inode: 0,
+                size: 0,
+            })
+        }
+
+        fn close(&self) {
+            CLOSED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn files_close_with_their_last_reference() {
+        let file = OpenFile::new(Probe);
+        let copy = file.clone();
+        assert!(file.same_as(&copy));
+        assert_eq!(copy.read(&mut [0; 4]), Err(KernelError::BadDescriptor));
+
+        drop(file);
+        assert!(!CLOSED.load(Ordering::Relaxed));
+        drop(copy);
+        assert!(CLOSED.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn paths_are_resolved_lexically() {
+        assert_eq!(components("/a/./b//c/"), ["a", "b", "c"]);
+        assert_eq!(components("a/../../b"), ["b"]);
+        assert!(components("/").is_empty());
+    }
+
+    #[test_case]
+    fn seeks_are_relative_to_where_they_say() {
+        assert_eq!(seek_position(SeekFrom::Start(5), 10, 20), Ok(5));
+        assert_eq!(seek_position(SeekFrom::Current(-4), 10, 20), Ok(6));
+        assert_eq!(seek_position(SeekFrom::End(2), 10, 20), Ok(22));
+        assert_eq!(
+            seek_position(SeekFrom::End(-21), 10, 20),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+
+    #[test_case]
+    fn seeks_past_the_largest_offset_fail() {
+        let max = i64::MAX as u64;
+        assert_eq!(seek_position(SeekFrom::Start(max), 0, 0), Ok(max));
+        assert_eq!(
+            seek_position(SeekFrom::Start(max + 1), 0, 0),
+            Err(KernelError::InvalidArgument)
+        );
+        assert_eq!(
+            seek_position(SeekFrom::Current(1), max, 0),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+}
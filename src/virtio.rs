@@ -0,0 +1,545 @@
+use crate::address::PhysFrame;
+use crate::devicetree::{DeviceTree, Node};
+use crate::driver::{Driver, DriverError, ProbeResult};
+use crate::memory_map::MemoryRegion;
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::{info, warn};
+use crate::{virtio_blk, virtio_rng};
+use core::arch::asm;
+
+const COMPATIBLE: &str = "virtio,mmio";
+
+const MAGIC: u32 = 0x7472_6976;
+
+const MAGIC_OFFSET: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const LEGACY_GUEST_PAGE_SIZE: u64 = 0x028;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const LEGACY_QUEUE_ALIGN: u64 = 0x03c;
+const LEGACY_QUEUE_PFN: u64 = 0x040;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG: u64 = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+// Big enough for any device here while keeping a whole queue in one page.
+pub const MAX_QUEUE_SIZE: u16 = 128;
+
+// The used ring's alignment. At 8 the legacy layout, which leaves room for
+// avail's used_event field, and QEMU's, which doesn't, agree.
+const USED_ALIGN: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    NoDevice,
+    FeaturesRejected,
+    QueueUnavailable,
+    QueueInUse,
+    QueueFull,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Gpu,
+    Input,
+    Unknown(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            id => DeviceType::Unknown(id),
+        }
+    }
+}
+
+// The register window of one virtio-mmio slot. Version 1 is the legacy
+// interface QEMU still defaults to; version 2 is the one the spec describes.
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    base: u64,
+    version: u32,
+}
+
+impl Transport {
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn new(base: u64) -> Result<Self, VirtioError> {
+        let transport = Self { base, version: 0 };
+        match transport.read(MAGIC_OFFSET) {
+            MAGIC => (),
+            magic => return Err(VirtioError::BadMagic(magic)),
+        }
+        let version = match transport.read(VERSION) {
+            version @ (1 | 2) => version,
+            version => return Err(VirtioError::UnsupportedVersion(version)),
+        };
+        // An empty slot still answers, with a device id of zero.
+        if transport.read(DEVICE_ID) == 0 {
+            return Err(VirtioError::NoDevice);
+        }
+        Ok(Self { base, version })
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.read(DEVICE_ID))
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.read(VENDOR_ID)
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES);
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    pub fn status(&self) -> u32 {
+        self.read(STATUS)
+    }
+
+    fn add_status(&self, bits: u32) {
+        self.write(STATUS, self.status() | bits);
+    }
+
+    // Resets the device and agrees on the features both sides support,
+    // returning them. Queues are set up after this and before driver_ok.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.write(STATUS, 0);
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let supported = match self.is_legacy() {
+            true => supported & !FEATURE_VERSION_1,
+            false => supported | FEATURE_VERSION_1,
+        };
+        let features = self.device_features() & supported;
+        self.set_driver_features(features);
+
+        if self.is_legacy() {
+            self.write(LEGACY_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    pub fn read_config_u8(&self, offset: u64) -> u8 {
+        unsafe { ((self.base + CONFIG + offset) as *const u8).read_volatile() }
+    }
+
+    pub fn read_config_u32(&self, offset: u64) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    pub fn notify(&self, queue: u16) {
+        barrier();
+        self.write(QUEUE_NOTIFY, queue as u32);
+    }
+
+    // Returns the causes that were pending, having acknowledged them.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+
+    fn queue_size(&self, index: u16, wanted: u16) -> Result<u16, VirtioError> {
+        self.write(QUEUE_SEL, index as u32);
+        let in_use = match self.is_legacy() {
+            true => self.read(LEGACY_QUEUE_PFN) != 0,
+            false => self.read(QUEUE_READY) != 0,
+        };
+        if in_use {
+            return Err(VirtioError::QueueInUse);
+        }
+        match self.read(QUEUE_NUM_MAX) {
+            0 => Err(VirtioError::QueueUnavailable),
+            max => Ok(wanted.min(max.min(MAX_QUEUE_SIZE as u32) as u16)),
+        }
+    }
+
+    fn enable_queue(&self, index: u16, size: u16, page: &PhysFrame, layout: &QueueLayout) {
+        self.write(QUEUE_SEL, index as u32);
+        self.write(QUEUE_NUM, size as u32);
+        if self.is_legacy() {
+            self.write(LEGACY_QUEUE_ALIGN, USED_ALIGN as u32);
+            self.write(LEGACY_QUEUE_PFN, page.number() as u32);
+            return;
+        }
+
+        let write_address = |low, high, address: u64| {
+            self.write(low, address as u32);
+            self.write(high, (address >> 32) as u32);
+        };
+        let base = page.start_address().as_u64();
+        write_address(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, base);
+        write_address(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, base + layout.avail);
+        write_address(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, base + layout.used);
+        self.write(QUEUE_READY, 1);
+    }
+}
+
+// Orders ring updates in memory against the device's view of them.
+fn barrier() {
+    unsafe { asm!("fence iorw, iorw") };
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+// Where each part of a split virtqueue sits within its page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QueueLayout {
+    avail: u64,
+    used: u64,
+    size: u64,
+}
+
+impl QueueLayout {
+    fn new(queue_size: u16) -> Self {
+        let n = queue_size as u64;
+        let avail = 16 * n;
+        // flags, idx, ring and used_event.
+        let used = (avail + 6 + 2 * n + USED_ALIGN - 1) & !(USED_ALIGN - 1);
+        Self {
+            avail,
+            used,
+            size: used + 6 + 8 * n,
+        }
+    }
+}
+
+// A part of a request: memory the device reads from, or writes into if
+// `writable`.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub length: u32,
+    pub writable: bool,
+}
+
+// A split virtqueue in a single page from the page allocator. Descriptors
+// not in flight are kept on a free list threaded through their `next`.
+pub struct VirtQueue {
+    transport: Transport,
+    index: u16,
+    size: u16,
+    page: PhysFrame,
+    layout: QueueLayout,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+// The queue's page belongs to it alone.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    pub fn new(transport: &Transport, index: u16, size: u16) -> Result<Self, VirtioError> {
+        let size = transport.queue_size(index, size)?;
+        let layout = QueueLayout::new(size);
+        let page = PAGE_ALLOCATOR
+            .lock()
+            .alloc_zeroed()
+            .map_err(|_| VirtioError::OutOfMemory)?;
+
+        let mut queue = Self {
+            transport: *transport,
+            index,
+            size,
+            page,
+            layout,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        for n in 0..size {
+            queue.descriptor(n).next = n + 1;
+        }
+        transport.enable_queue(index, size, &queue.page, &layout);
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    fn at<T>(&self, offset: u64) -> *mut T {
+        (self.page.start_address().as_u64() + offset) as *mut T
+    }
+
+    fn descriptor(&mut self, n: u16) -> &mut Descriptor {
+        unsafe { &mut *self.at::<Descriptor>(0).add(n as usize) }
+    }
+
+    fn avail_index(&self) -> *mut u16 {
+        self.at(self.layout.avail + 2)
+    }
+
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        self.at(self.layout.avail + 4 + 2 * (slot % self.size) as u64)
+    }
+
+    fn used_index(&self) -> *const u16 {
+        self.at(self.layout.used + 2)
+    }
+
+    fn used_ring(&self, slot: u16) -> *const UsedElement {
+        self.at(self.layout.used + 4 + 8 * (slot % self.size) as u64)
+    }
+
+    // Chains `buffers` into one request and makes it available to the
+    // device, returning the head descriptor that identifies it. The device
+    // isn't told until notify.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        for (n, buffer) in buffers.iter().enumerate() {
+            let current = self.free_head;
+            let descriptor = self.descriptor(current);
+            let next = descriptor.next;
+            descriptor.address = buffer.address;
+            descriptor.length = buffer.length;
+            descriptor.flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if n + 1 < buffers.len() {
+                descriptor.flags |= DESCRIPTOR_NEXT;
+            }
+            self.free_head = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let index = self.avail_index().read_volatile();
+            self.avail_ring(index).write_volatile(head);
+            barrier();
+            self.avail_index().write_volatile(index.wrapping_add(1));
+        }
+        Ok(head)
+    }
+
+    pub fn notify(&self) {
+        self.transport.notify(self.index);
+    }
+
+    // Takes the next request the device has finished with, returning its
+    // head descriptor and how many bytes were written into it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        barrier();
+        if unsafe { self.used_index().read_volatile() } == self.last_used {
+            return None;
+        }
+        let element = unsafe { self.used_ring(self.last_used).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        self.free_chain(head);
+        Some((head, element.length))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let free_head = self.free_head;
+        let mut current = head;
+        loop {
+            self.free_count += 1;
+            let descriptor = self.descriptor(current);
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                descriptor.next = free_head;
+                break;
+            }
+            current = descriptor.next;
+        }
+        self.free_head = head;
+    }
+
+    pub fn has_used(&self) -> bool {
+        barrier();
+        unsafe { self.used_index().read_volatile() != self.last_used }
+    }
+}
+
+// Drivers for what sits behind the transport, chosen by device id.
+pub struct DeviceDriver {
+    pub name: &'static str,
+    pub device_type: DeviceType,
+    pub probe: fn(Transport, &Node) -> ProbeResult,
+}
+
+const DEVICE_DRIVERS: &[&DeviceDriver] = &[&virtio_blk::DRIVER, &virtio_rng::DRIVER];
+
+pub fn mmio_regions(tree: &DeviceTree) -> impl Iterator<Item = MemoryRegion> {
+    tree.compatible_nodes(COMPATIBLE).filter_map(|node| {
+        let (address, size) = node.reg()?.next()?;
+        Some(MemoryRegion::new(address, address + size))
+    })
+}
+
+pub const DRIVER: Driver = Driver {
+    name: "virtio",
+    compatible: &[COMPATIBLE],
+    probe,
+};
+
+fn probe(node: &Node) -> ProbeResult {
+    let (base, _) = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .ok_or(DriverError::MissingReg)?;
+    let transport = match unsafe { Transport::new(base) } {
+        Ok(transport) => transport,
+        Err(VirtioError::NoDevice) => return Err(DriverError::Unsupported),
+        Err(error) => {
+            warn!("{}: {:?}", node.name, error);
+            return Err(DriverError::Unsupported);
+        }
+    };
+
+    let device_type = transport.device_type();
+    match DEVICE_DRIVERS
+        .iter()
+        .find(|driver| driver.device_type == device_type)
+    {
+        Some(driver) => (driver.probe)(transport, node),
+        None => {
+            info!("{}: no driver for virtio {:?}", node.name, device_type);
+            Err(DriverError::Unsupported)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devicetree;
+
+    #[test_case]
+    fn queue_layout_fits_in_a_page() {
+        let layout = QueueLayout::new(MAX_QUEUE_SIZE);
+        assert_eq!(layout.avail, 2048);
+        assert_eq!(layout.used % USED_ALIGN, 0);
+        assert!(layout.size <= PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn queue_layout_matches_the_legacy_formula() {
+        for size in [8u64, 16, 64, 128] {
+            let layout = QueueLayout::new(size as u16);
+            // QEMU places used after avail's ring, without used_event.
+            let qemu_used = (16 * size + 4 + 2 * size + USED_ALIGN - 1) & !(USED_ALIGN - 1);
+            assert_eq!(layout.used, qemu_used);
+        }
+    }
+
+    #[test_case]
+    fn every_slot_has_the_virtio_magic() {
+        let tree = devicetree::get().unwrap();
+        let mut slots = 0;
+        for region in mmio_regions(&tree) {
+            let result = unsafe { Transport::new(region.start) };
+            assert!(result.is_ok() || result.unwrap_err() == VirtioError::NoDevice);
+            slots += 1;
+        }
+        assert_eq!(slots, 8);
+    }
+
+    #[test_case]
+    fn device_types_come_from_the_device_id() {
+        assert_eq!(DeviceType::from(4), DeviceType::Entropy);
+        assert_eq!(DeviceType::from(99), DeviceType::Unknown(99));
+    }
+}
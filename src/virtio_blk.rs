@@ -0,0 +1,240 @@
+use crate::address::PhysFrame;
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::devicetree::Node;
+use crate::driver::{DriverError, ProbeResult};
+use crate::error::{KernelError, KernelResult};
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::virtio::{Buffer, DeviceDriver, DeviceType, Transport, VirtQueue, VirtioError};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+
+const CAPACITY_OFFSET: u64 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+
+const HEADER_SIZE: u32 = 16;
+const STATUS_OK: u8 = 0;
+
+// Each request moves at most a page.
+const SECTORS_PER_REQUEST: usize = PAGE_SIZE as usize / SECTOR_SIZE;
+
+// Disks are named vda, vdb and so on in the order they're probed.
+static DISKS_PROBED: AtomicUsize = AtomicUsize::new(0);
+
+struct Queue {
+    transport: Transport,
+    queue: VirtQueue,
+    // The request header goes at the start and the status byte after it.
+    request: PhysFrame,
+    // As with virtio-rng, data goes through here rather than the caller's
+    // buffer.
+    bounce: PhysFrame,
+}
+
+impl Queue {
+    // Polled, like the entropy device, so there's no interrupt to route.
+    fn transfer(&mut self, kind: u32, sector: u64, length: usize) -> KernelResult<()> {
+        let header: *mut u8 = self.request.as_mut_ptr();
+        let status = self.request.start_address().as_u64() + HEADER_SIZE as u64;
+        unsafe {
+            (header as *mut u32).write_volatile(kind);
+            (header.add(4) as *mut u32).write_volatile(0);
+            (header.add(8) as *mut u64).write_volatile(sector);
+            (status as *mut u8).write_volatile(0xff);
+        }
+
+        let queued = self.queue.add(&[
+            Buffer {
+                address: self.request.start_address().as_u64(),
+                length: HEADER_SIZE,
+                writable: false,
+            },
+            Buffer {
+                address: self.bounce.start_address().as_u64(),
+                length: length as u32,
+                writable: kind == REQUEST_IN,
+            },
+            Buffer {
+                address: status,
+                length: 1,
+                writable: true,
+            },
+        ]);
+        if queued.is_err() {
+            return Err(KernelError::Io);
+        }
+        self.queue.notify();
+        while self.queue.pop_used().is_none() {
+            core::hint::spin_loop();
+        }
+        self.transport.ack_interrupt();
+
+        match unsafe { (status as *const u8).read_volatile() } {
+            STATUS_OK => Ok(()),
+            _ => Err(KernelError::Io),
+        }
+    }
+}
+
+pub struct VirtioBlock {
+    name: String,
+    sectors: u64,
+    read_only: bool,
+    queue: Mutex<Queue>,
+}
+
+impl VirtioBlock {
+    fn new(transport: Transport, name: String) -> Result<Self, VirtioError> {
+        let features = transport.negotiate(FEATURE_READ_ONLY)?;
+        let queue = VirtQueue::new(&transport, REQUEST_QUEUE, QUEUE_SIZE)?;
+        let (request, bounce) = {
+            let mut allocator = PAGE_ALLOCATOR.lock();
+            let request = allocator.alloc_zeroed();
+            let bounce = allocator.alloc();
+            match (request, bounce) {
+                (Ok(request), Ok(bounce)) => (request, bounce),
+                (request, bounce) => {
+                    request
+                        .into_iter()
+                        .chain(bounce)
+                        .for_each(|page| allocator.dealloc(page));
+                    return Err(VirtioError::OutOfMemory);
+                }
+            }
+        };
+        let sectors = transport.read_config_u32(CAPACITY_OFFSET) as u64
+            | (transport.read_config_u32(CAPACITY_OFFSET + 4) as u64) << 32;
+        transport.driver_ok();
+        Ok(Self {
+            name,
+            sectors,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            queue: Mutex::new(Queue {
+                transport,
+                queue,
+                request,
+                bounce,
+            }),
+        })
+    }
+
+    fn check(&self, sector: u64, length: usize) -> KernelResult<()> {
+        if !length.is_multiple_of(SECTOR_SIZE)
+            || sector + (length / SECTOR_SIZE) as u64 > self.sectors
+        {
+            return Err(KernelError::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        self.check(sector, buffer.len())?;
+        let mut queue = self.queue.lock();
+        for (n, chunk) in buffer
+            .chunks_mut(SECTORS_PER_REQUEST * SECTOR_SIZE)
+            .enumerate()
+        {
+            let first = sector + (n * SECTORS_PER_REQUEST) as u64;
+            queue.transfer(REQUEST_IN, first, chunk.len())?;
+            let source: *const u8 = queue.bounce.as_mut_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(source, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, bytes: &[u8]) -> KernelResult<()> {
+        if self.read_only {
+            return Err(KernelError::ReadOnly);
+        }
+        self.check(sector, bytes.len())?;
+        let mut queue = self.queue.lock();
+        for (n, chunk) in bytes.chunks(SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let first = sector + (n * SECTORS_PER_REQUEST) as u64;
+            let destination: *mut u8 = queue.bounce.as_mut_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), destination, chunk.len()) };
+            queue.transfer(REQUEST_OUT, first, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+pub const DRIVER: DeviceDriver = DeviceDriver {
+    name: "virtio-blk",
+    device_type: DeviceType::Block,
+    probe,
+};
+
+fn probe(transport: Transport, _node: &Node) -> ProbeResult {
+    let index = DISKS_PROBED.load(Ordering::Relaxed);
+    let name = format!("vd{}", (b'a' + index as u8) as char);
+    match VirtioBlock::new(transport, name) {
+        Ok(disk) => {
+            DISKS_PROBED.fetch_add(1, Ordering::Relaxed);
+            block::register(Arc::new(disk));
+            Ok(())
+        }
+        Err(_) => {
+            transport.fail();
+            Err(DriverError::Unsupported)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    // tools/run.sh gives QEMU a scratch disk if it isn't given one.
+    #[test_case]
+    fn the_scratch_disk_is_probed() {
+        let disk = block::find("vda").unwrap();
+        assert!(disk.sector_count() > 0);
+    }
+
+    #[test_case]
+    fn sectors_written_can_be_read_back() {
+        let disk = block::find("vda").unwrap();
+        // More than a request's worth, at the end of the disk, and put back
+        // afterwards in case it's one that matters.
+        let count = SECTORS_PER_REQUEST + 3;
+        let first = disk.sector_count() - count as u64;
+        let mut original = vec![0; count * SECTOR_SIZE];
+        disk.read_sectors(first, &mut original).unwrap();
+        let bytes: Vec<u8> = (0..count * SECTOR_SIZE).map(|n| (n / 7) as u8).collect();
+        disk.write_sectors(first, &bytes).unwrap();
+
+        let mut read = vec![0; bytes.len()];
+        disk.read_sectors(first, &mut read).unwrap();
+        disk.write_sectors(first, &original).unwrap();
+        assert_eq!(read, bytes);
+        assert_eq!(
+            disk.read_sectors(disk.sector_count(), &mut read[..SECTOR_SIZE]),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+}
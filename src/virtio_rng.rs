@@ -0,0 +1,126 @@
+use crate::address::PhysFrame;
+use crate::devicetree::Node;
+use crate::driver::{DriverError, ProbeResult};
+use crate::once::InitCell;
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::virtio::{Buffer, DeviceDriver, DeviceType, Transport, VirtQueue, VirtioError};
+use spin::Mutex;
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+
+struct EntropyDevice {
+    transport: Transport,
+    queue: VirtQueue,
+    // Requests go through a page of our own rather than the caller's buffer,
+    // which may not be identity mapped.
+    bounce: PhysFrame,
+}
+
+static DEVICE: Mutex<InitCell<EntropyDevice>> = Mutex::new(InitCell::new());
+
+impl EntropyDevice {
+    fn new(transport: Transport) -> Result<Self, VirtioError> {
+        transport.negotiate(0)?;
+        let queue = VirtQueue::new(&transport, REQUEST_QUEUE, QUEUE_SIZE)?;
+        let bounce = PAGE_ALLOCATOR
+            .lock()
+            .alloc()
+            .map_err(|_| VirtioError::OutOfMemory)?;
+        transport.driver_ok();
+        Ok(Self {
+            transport,
+            queue,
+            bounce,
+        })
+    }
+
+    // The device may hand back fewer bytes than asked for.
+    fn read(&mut self, bytes: &mut [u8]) -> Result<usize, VirtioError> {
+        let length = bytes.len().min(PAGE_SIZE as usize);
+        self.queue.add(&[Buffer {
+            address: self.bounce.start_address().as_u64(),
+            length: length as u32,
+            writable: true,
+        }])?;
+        self.queue.notify();
+
+        // Polled, so entropy can be had with interrupts off.
+        let written = loop {
+            if let Some((_, written)) = self.queue.pop_used() {
+                break written as usize;
+            }
+            core::hint::spin_loop();
+        };
+        self.transport.ack_interrupt();
+
+        let written = written.min(length);
+        let source: *const u8 = self.bounce.as_mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(source, bytes.as_mut_ptr(), written);
+        }
+        Ok(written)
+    }
+}
+
+pub const DRIVER: DeviceDriver = DeviceDriver {
+    name: "virtio-rng",
+    device_type: DeviceType::Entropy,
+    probe,
+};
+
+fn probe(transport: Transport, _node: &Node) -> ProbeResult {
+    let mut device = DEVICE.lock();
+    if device.get().is_some() {
+        return Err(DriverError::AlreadyProbed);
+    }
+    match EntropyDevice::new(transport) {
+        Ok(entropy) => {
+            let _ = device.set(entropy);
+            Ok(())
+        }
+        Err(_) => {
+            transport.fail();
+            Err(DriverError::Unsupported)
+        }
+    }
+}
+
+pub fn is_present() -> bool {
+    DEVICE.lock().get().is_some()
+}
+
+// Fills all of `bytes`, or returns false if there's no device to ask.
+pub fn fill(bytes: &mut [u8]) -> bool {
+    let mut device = DEVICE.lock();
+    let device = match device.get_mut() {
+        Some(device) => device,
+        None => return false,
+    };
+
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match device.read(&mut bytes[filled..]) {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => filled += read,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_entropy_device_is_probed() {
+        assert!(is_present());
+    }
+
+    #[test_case]
+    fn reads_fill_the_whole_buffer() {
+        let mut bytes = [0u8; 64];
+        assert!(fill(&mut bytes));
+        assert!(bytes.iter().any(|&b| b != 0));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::{KernelError, KernelResult};
+use crate::process;
+use crate::task::{self, Priority, ThreadId};
+use crate::trap::{self, InterruptGuard, LockIrqSave};
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use spin::Mutex;
+
+// Threads waiting for a condition that someone else, possibly an interrupt
+// handler, will make true and then notify them about.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<(ThreadId, Priority)>>,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Blocks until `condition` holds, checking it again after every
+    // notification. Outside a thread, or inside an interrupt handler, it
+    // can only wait for interrupts.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let waiter = match (task::current_id(), task::current_priority()) {
+            (Some(id), Some(priority)) if trap::trap_depth() == 0 => (id, priority),
+            _ => return spin_until(condition),
+        };
+
+        while !condition() {
+            self.join(waiter);
+            // A notification between the check and joining the queue would
+            // be missed, so check again now it can't be.
+            if condition() {
+                break;
+            }
+            task::block();
+        }
+        // Still queued if the condition came true without a notification,
+        // or block returned early for a wakeup meant for an earlier wait.
+        self.remove(waiter.0);
+    }
+
+    // As wait_until, but gives up with Interrupted, EINTR to user space, once
+    // the current process has a signal to act on. A condition that holds
+    // wins over a signal.
+    pub fn wait_until_interruptible(
+        &self,
+        mut condition: impl FnMut() -> bool,
+    ) -> KernelResult<()> {
+        let mut interrupted = false;
+        self.wait_until(|| {
+            if condition() {
+                return true;
+            }
+            interrupted = process::signal_pending();
+            interrupted
+        });
+        match interrupted {
+            true => Err(KernelError::Interrupted),
+            false => Ok(()),
+        }
+    }
+
+    fn join(&self, waiter: (ThreadId, Priority)) {
+        let mut waiters = self.waiters.lock_irqsave();
+        if !waiters.iter().any(|&(id, _)| id == waiter.0) {
+            waiters.push_back(waiter);
+        }
+    }
+
+    fn remove(&self, id: ThreadId) {
+        self.waiters
+            .lock_irqsave()
+            .retain(|&(waiter, _)| waiter != id);
+    }
+
+    // Wakes the highest-priority waiter, longest-waiting first. Returns
+    // whether there was one.
+    pub fn notify_one(&self) -> bool {
+        let waiter = {
+            let mut waiters = self.waiters.lock_irqsave();
+            let highest = waiters.iter().map(|&(_, priority)| priority).max();
+            waiters
+                .iter()
+                .position(|&(_, priority)| Some(priority) == highest)
+                .and_then(|index| waiters.remove(index))
+        };
+        match waiter {
+            Some((id, _)) => {
+                task::unblock(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns how many threads were woken.
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock_irqsave());
+        for &(id, _) in waiters.iter() {
+            task::unblock(id);
+        }
+        waiters.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock_irqsave().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Interrupts are off from each check until after the wfi, so one that
+// makes the condition true, such as the UART's for read_byte, can't be
+// taken in between and leave the wfi waiting for the next. A pending
+// interrupt still ends the wfi, and is taken once they're back on.
+fn spin_until(mut condition: impl FnMut() -> bool) {
+    loop {
+        let enabled = trap::interrupts_enabled();
+        let _interrupts = InterruptGuard::disable();
+        if condition() {
+            return;
+        }
+        if enabled {
+            unsafe { asm!("wfi") };
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::test::yield_until;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test_case]
+    fn notify_one_wakes_a_waiter() {
+        let queue = Arc::new(WaitQueue::new());
+        let ready = Arc::new(AtomicBool::new(false));
+        let woken = Arc::new(AtomicBool::new(false));
+        let (waiting, flag, done) = (queue.clone(), ready.clone(), woken.clone());
+        task::spawn(move || {
+            waiting.wait_until(|| flag.load(Ordering::Relaxed));
+            done.store(true, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        yield_until(|| !queue.is_empty());
+        assert!(!woken.load(Ordering::Relaxed));
+        ready.store(true, Ordering::Relaxed);
+        assert!(queue.notify_one());
+        yield_until(|| woken.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn notify_all_wakes_every_waiter() {
+        let queue = Arc::new(WaitQueue::new());
+        let ready = Arc::new(AtomicBool::new(false));
+        let woken = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let (waiting, flag, count) = (queue.clone(), ready.clone(), woken.clone());
+            task::spawn(move || {
+                waiting.wait_until(|| flag.load(Ordering::Relaxed));
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        yield_until(|| queue.len() == 3);
+        ready.store(true, Ordering::Relaxed);
+        assert_eq!(queue.notify_all(), 3);
+        yield_until(|| woken.load(Ordering::Relaxed) == 3);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::hart::{self, hart_id, MAX_HARTS};
+use crate::symbols::Symbolized;
+use crate::trap::TrapFrame;
+use crate::{cmdline, panic, task, timer};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+// Each hart's timer interrupt is its heartbeat, and every hart watches the
+// others', so one stuck with interrupts off is caught by one that isn't. A
+// lone hart has nobody to watch it.
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+struct Heartbeat {
+    // When the last beat was, in timer ticks; zero until the first.
+    last: AtomicU64,
+    // Where the hart was, and what it was running, when it last beat.
+    sepc: AtomicU64,
+    thread: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HEARTBEAT: Heartbeat = Heartbeat {
+    last: AtomicU64::new(0),
+    sepc: AtomicU64::new(0),
+    thread: AtomicU64::new(0),
+};
+
+struct Heartbeats([Heartbeat; MAX_HARTS]);
+
+impl Heartbeats {
+    const fn new() -> Self {
+        Self([NO_HEARTBEAT; MAX_HARTS])
+    }
+
+    fn beat(&self, hart: usize, now: u64, sepc: u64, thread: u64) {
+        let heartbeat = &self.0[hart];
+        heartbeat.sepc.store(sepc, Ordering::Relaxed);
+        heartbeat.thread.store(thread, Ordering::Relaxed);
+        heartbeat.last.store(now, Ordering::Release);
+    }
+
+    // The first of `harts` to go `timeout` ticks without a beat. Harts that
+    // have yet to beat at all aren't being watched yet.
+    fn stalled(&self, harts: u64, now: u64, timeout: u64) -> Option<usize> {
+        (0..MAX_HARTS)
+            .filter(|&hart| harts & 1 << hart != 0)
+            .find(|&hart| match self.0[hart].last.load(Ordering::Acquire) {
+                0 => false,
+                last => now.saturating_sub(last) > timeout,
+            })
+    }
+
+    fn excuse(&self, hart: usize, now: u64) {
+        let heartbeat = &self.0[hart];
+        if heartbeat.last.load(Ordering::Relaxed) != 0 {
+            heartbeat.last.store(now, Ordering::Release);
+        }
+    }
+
+    // Counts every hart as having just beaten.
+    fn restart(&self, now: u64) {
+        for heartbeat in self.0.iter() {
+            if heartbeat.last.load(Ordering::Relaxed) != 0 {
+                heartbeat.last.store(now, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+static HEARTBEATS: Heartbeats = Heartbeats::new();
+// In timer ticks; zero turns the watchdog off.
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static PAUSED: AtomicUsize = AtomicUsize::new(0);
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+// How full each hart's run queue is, without waiting on a lock the stuck
+// hart might be holding.
+struct RunQueues(u64);
+
+impl fmt::Display for RunQueues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for hart in (0..MAX_HARTS).filter(|&hart| self.0 & 1 << hart != 0) {
+            match task::try_ready_count(hart) {
+                Some(ready) => write!(f, " hart {}: {} ready;", hart, ready)?,
+                None => write!(f, " hart {}: locked;", hart)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fire(hart: usize, now: u64) -> ! {
+    let heartbeat = &HEARTBEATS.0[hart];
+    let silent = now.saturating_sub(heartbeat.last.load(Ordering::Relaxed));
+    panic!(
+        "Watchdog: hart {} has had no timer interrupt for {} ms\n  last at {} in thread {}\n  run queues:{}",
+        hart,
+        silent * 1000 / timer::timebase_frequency(),
+        Symbolized(heartbeat.sepc.load(Ordering::Relaxed)),
+        heartbeat.thread.load(Ordering::Relaxed),
+        RunQueues(hart::online_harts())
+    );
+}
+
+// Called from the timer interrupt on every hart.
+pub fn beat(frame: &TrapFrame) {
+    let hart = hart_id();
+    let now = timer::read_time();
+    let thread = task::current_id().map_or(0, |id| id.0);
+    HEARTBEATS.beat(hart, now, frame.sepc, thread);
+
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 || PAUSED.load(Ordering::Relaxed) > 0 || panic::is_panicking() {
+        return;
+    }
+    // Idle harts with their tick stopped are quiet on purpose.
+    let others = hart::online_harts() & !(1 << hart) & !timer::tickless_harts();
+    if let Some(stalled) = HEARTBEATS.stalled(others, now, timeout) {
+        if !FIRED.swap(true, Ordering::Relaxed) {
+            fire(stalled, now);
+        }
+    }
+}
+
+// When `hart`'s timer last interrupted it, in timer ticks; zero if it
+// never has.
+pub fn last_beat(hart: usize) -> u64 {
+    HEARTBEATS.0[hart].last.load(Ordering::Acquire)
+}
+
+// Called by a hart restarting its tick, before it's watched again, so the
+// time it spent idle isn't held against it.
+pub fn woken(hart: usize) {
+    HEARTBEATS.excuse(hart, timer::read_time());
+}
+
+// For anything that stops a hart on purpose with interrupts off, like a
+// debugger session. Each pause needs a resume.
+pub fn pause() {
+    PAUSED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn resume() {
+    HEARTBEATS.restart(timer::read_time());
+    PAUSED.fetch_sub(1, Ordering::Relaxed);
+}
+
+// watchdog=<seconds> sets how long a hart may go without a timer interrupt,
+// and watchdog=0 turns it off.
+pub fn init() {
+    let seconds = cmdline::get_u64("watchdog").unwrap_or(DEFAULT_TIMEOUT_SECS);
+    TIMEOUT.store(seconds * timer::timebase_frequency(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn this_hart_beats_while_waiting() {
+        let start = timer::read_time();
+        crate::time::sleep(crate::time::Duration::from_millis(30));
+        let last = HEARTBEATS.0[hart_id()].last.load(Ordering::Relaxed);
+        assert!(last > start);
+    }
+
+    #[test_case]
+    fn only_harts_that_stop_beating_are_stalled() {
+        let heartbeats = Heartbeats::new();
+        heartbeats.beat(0, 1000, 0, 0);
+        heartbeats.beat(1, 1900, 0, 0);
+        // Hart 2 has never beaten.
+        let harts = 0b111;
+
+        assert_eq!(heartbeats.stalled(harts, 2000, 500), Some(0));
+        assert_eq!(heartbeats.stalled(harts & !1, 2000, 500), None);
+
+        heartbeats.restart(2000);
+        assert_eq!(heartbeats.stalled(harts, 2400, 500), None);
+    }
+
+    #[test_case]
+    fn a_hart_back_from_tickless_idle_starts_afresh() {
+        let heartbeats = Heartbeats::new();
+        heartbeats.beat(0, 1000, 0, 0);
+        heartbeats.excuse(0, 5000);
+        heartbeats.excuse(1, 5000);
+
+        assert_eq!(heartbeats.stalled(0b11, 5400, 500), None);
+        assert_eq!(heartbeats.stalled(0b11, 5600, 500), Some(0));
+        assert_eq!(heartbeats.stalled(0b10, 9000, 500), None);
+    }
+}
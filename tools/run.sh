@@ -0,0 +1,26 @@
+#!/bin/sh
+# Cargo runner: embed the symbol table, then boot the kernel under QEMU.
+# The disk is RISCVOS_DISK if that's set, or else a blank scratch image.
+# Anything else is passed to QEMU, so `cargo test -- -append test=page_table`
+# runs only the tests with page_table in their paths, and
+# `cargo test -- -append "bench test=bench_"` measures the benchmarks.
+set -e
+
+kernel="$1"
+shift
+
+python3 "$(dirname "$0")/symbolize.py" "$kernel"
+
+disk="${RISCVOS_DISK:-}"
+if [ -z "$disk" ]; then
+	disk="$(dirname "$kernel")/scratch.img"
+	rm -f "$disk"
+	truncate -s 16M "$disk"
+fi
+
+exec qemu-system-riscv64 -machine virt -cpu rv64 -m 128M -smp 4 -bios default \
+	-nographic -serial mon:stdio -s \
+	-device virtio-rng-device \
+	-drive file="$disk",if=none,format=raw,id=disk \
+	-device virtio-blk-device,drive=disk \
+	-kernel "$kernel" "$@"
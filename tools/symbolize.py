@@ -0,0 +1,83 @@
+#!/usr/bin/env python3
+# Writes the kernel's function symbols into its reserved .symbols section so
+# panics and traps can name the code they point at. See src/symbols.rs for
+# the format.
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+MAGIC = b"RVSYMTAB"
+HEADER = struct.Struct("<8sQQ")
+ENTRY = struct.Struct("<QII")
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def function_symbols(elf, nm):
+    output = subprocess.run(
+        [nm, "--defined-only", "--numeric-sort", "--print-size", "--demangle", elf],
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+
+    symbols = {}
+    for line in output.splitlines():
+        fields = line.split(maxsplit=3)
+        if len(fields) == 4:
+            address, size, kind, name = fields
+        elif len(fields) == 3:
+            (address, kind, name), size = fields, "0"
+        else:
+            continue
+        if kind not in "tTwW":
+            continue
+        symbols.setdefault(int(address, 16), (int(size, 16), HASH_SUFFIX.sub("", name)))
+    return sorted((address, size, name) for address, (size, name) in symbols.items())
+
+
+def section_size(elf, objdump):
+    output = subprocess.run(
+        [objdump, "--section-headers", "--section=.symbols", elf],
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+    for line in output.splitlines():
+        fields = line.split()
+        if len(fields) >= 3 and fields[1] == ".symbols":
+            return int(fields[2], 16)
+    sys.exit(f"{elf} has no .symbols section")
+
+
+def build_table(symbols, capacity):
+    strings = bytearray()
+    entries = bytearray()
+    for address, size, name in symbols:
+        entries += ENTRY.pack(address, len(strings), min(size, 0xFFFF_FFFF))
+        strings += name.encode() + b"\0"
+
+    strings_offset = HEADER.size + len(entries)
+    table = HEADER.pack(MAGIC, len(symbols), strings_offset) + entries + strings
+    if len(table) > capacity:
+        sys.exit(f"symbol table needs {len(table)} bytes, .symbols holds {capacity}")
+    return table + bytes(capacity - len(table))
+
+
+def main():
+    elf = sys.argv[1]
+    nm = os.environ.get("NM", "llvm-nm")
+    objdump = os.environ.get("OBJDUMP", "llvm-objdump")
+    objcopy = os.environ.get("OBJCOPY", "llvm-objcopy")
+
+    table = build_table(function_symbols(elf, nm), section_size(elf, objdump))
+    with tempfile.NamedTemporaryFile() as blob:
+        blob.write(table)
+        blob.flush()
+        subprocess.run([objcopy, f"--update-section=.symbols={blob.name}", elf], check=True)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,495 @@
+#![no_std]
+#![no_main]
+
+// The first user process. It runs through the system calls the kernel
+// has, exiting with the number of the check that failed if any don't
+// behave. Once they all pass it stays to reap the orphans it adopts, unless
+// it's been started as something other than PID 1.
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const DUP: usize = 23;
+const UNLINKAT: usize = 35;
+const OPENAT: usize = 56;
+const CLOSE: usize = 57;
+const PIPE2: usize = 59;
+const LSEEK: usize = 62;
+const READ: usize = 63;
+const WRITE: usize = 64;
+const FSTAT: usize = 80;
+const EXIT: usize = 93;
+const FUTEX: usize = 98;
+const KILL: usize = 129;
+const RT_SIGACTION: usize = 134;
+const GETRUSAGE: usize = 165;
+const GETPID: usize = 172;
+const BRK: usize = 214;
+const MUNMAP: usize = 215;
+const CLONE: usize = 220;
+const EXECVE: usize = 221;
+const MMAP: usize = 222;
+const WAIT4: usize = 260;
+const PRLIMIT64: usize = 261;
+const SHM_OPEN: usize = 1024;
+
+const AT_FDCWD: usize = -100isize as usize;
+const O_RDONLY: usize = 0o0;
+const O_RDWR: usize = 0o2;
+const O_CREAT: usize = 0o100;
+const O_EXCL: usize = 0o200;
+const SEEK_SET: usize = 0;
+const EPERM: isize = -1;
+const ENOENT: isize = -2;
+const EAGAIN: isize = -11;
+const EACCES: isize = -13;
+const EFAULT: isize = -14;
+const EMFILE: isize = -24;
+const RLIMIT_NOFILE: usize = 7;
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+
+const SIGUSR1: usize = 10;
+const SIGSEGV: usize = 11;
+const SIGTERM: usize = 15;
+const SIGCHLD: usize = 17;
+const S_IFMT: u32 = 0o170000;
+const S_IFCHR: u32 = 0o020000;
+const PAGE_SIZE: usize = 4096;
+
+fn syscall(number: usize, args: [usize; 6]) -> isize {
+    let result: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => result,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a7") number,
+            options(nostack),
+        );
+    }
+    result
+}
+
+fn read(fd: usize, buffer: &mut [u8]) -> isize {
+    syscall(
+        READ,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0],
+    )
+}
+
+fn write(fd: usize, bytes: &[u8]) -> isize {
+    syscall(WRITE, [fd, bytes.as_ptr() as usize, bytes.len(), 0, 0, 0])
+}
+
+fn exit(code: i32) -> ! {
+    syscall(EXIT, [code as usize, 0, 0, 0, 0, 0]);
+    unreachable!()
+}
+
+fn getpid() -> isize {
+    syscall(GETPID, [0; 6])
+}
+
+fn fork() -> isize {
+    syscall(CLONE, [SIGCHLD, 0, 0, 0, 0, 0])
+}
+
+// `path` must end in a NUL.
+fn exec(path: &[u8]) -> isize {
+    syscall(EXECVE, [path.as_ptr() as usize, 0, 0, 0, 0, 0])
+}
+
+// The child's PID and its exit code.
+fn wait(pid: isize) -> Result<(isize, i32), isize> {
+    let mut status: i32 = 0;
+    let result = syscall(
+        WAIT4,
+        [pid as usize, &mut status as *mut i32 as usize, 0, 0, 0, 0],
+    );
+    if result < 0 {
+        return Err(result);
+    }
+    Ok((result, (status >> 8) & 0xff))
+}
+
+// wait() that also fills in the child's struct rusage.
+fn wait_with_usage(pid: isize, usage: *mut usize) -> isize {
+    syscall(WAIT4, [pid as usize, 0, 0, usage as usize, 0, 0])
+}
+
+fn close(fd: usize) -> isize {
+    syscall(CLOSE, [fd, 0, 0, 0, 0, 0])
+}
+
+// The read end and then the write end.
+fn pipe() -> Option<(usize, usize)> {
+    let mut fds = [0i32; 2];
+    match syscall(PIPE2, [fds.as_mut_ptr() as usize, 0, 0, 0, 0, 0]) {
+        0 => Some((fds[0] as usize, fds[1] as usize)),
+        _ => None,
+    }
+}
+
+fn brk(address: usize) -> usize {
+    syscall(BRK, [address, 0, 0, 0, 0, 0]) as usize
+}
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match write(1, s.as_bytes()) {
+            n if n < 0 => Err(fmt::Error),
+            _ => Ok(()),
+        }
+    }
+}
+
+macro_rules! println {
+    ($($arg:tt)*) => {
+        let _ = writeln!(Console, $($arg)*);
+    };
+}
+
+fn check(number: i32, name: &str, passed: bool) {
+    if !passed {
+        println!("init: check {} ({}) failed", number, name);
+        exit(number);
+    }
+}
+
+fn check_fork_and_exec() -> bool {
+    let child = fork();
+    if child == 0 {
+        exec(b"/bin/hello\0");
+        exit(127);
+    }
+    // hello exits with how much it wrote.
+    child > 0 && wait(child) == Ok((child, 21)) && wait(child).is_err()
+}
+
+// The child's store faults in its own copy, which shows up in the usage
+// waiting for it returns.
+fn check_copy_on_write() -> bool {
+    let mut value = 1;
+    let child = fork();
+    if child == 0 {
+        unsafe { core::ptr::write_volatile(&mut value, 2) };
+        exit(value);
+    }
+    // struct rusage, with ru_minflt at word 8.
+    let mut usage = [0usize; 18];
+    child > 0
+        && wait_with_usage(child, usage.as_mut_ptr()) == child
+        && usage[8] > 0
+        && unsafe { core::ptr::read_volatile(&value) } == 1
+        && check_wait_to_a_bad_address()
+}
+
+// The child is still reaped when its usage can't be written, here to the
+// kernel's own memory.
+fn check_wait_to_a_bad_address() -> bool {
+    let child = fork();
+    if child == 0 {
+        exit(0);
+    }
+    child > 0 && wait_with_usage(child, 0x8020_0000 as *mut usize) == EFAULT && wait(child).is_err()
+}
+
+fn check_brk() -> bool {
+    let start = brk(0);
+    if brk(start + 2 * PAGE_SIZE) != start + 2 * PAGE_SIZE {
+        return false;
+    }
+    let memory = start as *mut usize;
+    unsafe {
+        memory.add(PAGE_SIZE / 8).write_volatile(42);
+        if memory.add(PAGE_SIZE / 8).read_volatile() != 42 || memory.read_volatile() != 0 {
+            return false;
+        }
+    }
+    brk(start) == start
+}
+
+fn check_mmap() -> bool {
+    // PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS
+    let address = syscall(MMAP, [0, PAGE_SIZE, 3, 0x22, usize::MAX, 0]);
+    if address < 0 {
+        return false;
+    }
+    let memory = address as *mut usize;
+    unsafe {
+        memory.write_volatile(7);
+        if memory.read_volatile() != 7 {
+            return false;
+        }
+    }
+    syscall(MUNMAP, [address as usize, PAGE_SIZE, 0, 0, 0, 0]) == 0
+}
+
+fn check_pipe() -> bool {
+    let (reader, writer) = match pipe() {
+        Some(fds) => fds,
+        None => return false,
+    };
+    let child = fork();
+    if child == 0 {
+        close(reader);
+        let written = write(writer, b"ping");
+        exit(written as i32);
+    }
+    // Otherwise the read below would never see the end of the pipe.
+    close(writer);
+
+    let mut buffer = [0; 8];
+    let mut received = 0;
+    loop {
+        match read(reader, &mut buffer[received..]) {
+            n if n > 0 => received += n as usize,
+            n => {
+                if n < 0 {
+                    return false;
+                }
+                break;
+            }
+        }
+    }
+    close(reader) == 0 && wait(child) == Ok((child, 4)) && &buffer[..received] == b"ping"
+}
+
+// Standard output is the console, and a copy of it works the same.
+fn check_dup() -> bool {
+    let fd = syscall(DUP, [1, 0, 0, 0, 0, 0]);
+    if fd < 0 {
+        return false;
+    }
+    let mut stat = [0u8; 128];
+    let mode = match syscall(FSTAT, [fd as usize, stat.as_mut_ptr() as usize, 0, 0, 0, 0]) {
+        0 => u32::from_le_bytes([stat[16], stat[17], stat[18], stat[19]]),
+        _ => return false,
+    };
+    mode & S_IFMT == S_IFCHR
+        && write(fd as usize, b"init: dup works\n") == 16
+        && close(fd as usize) == 0
+}
+
+// A file made in the root file system can be read back, and is gone once
+// it's unlinked.
+fn check_files() -> bool {
+    let path = b"/init-check\0";
+    let open = |flags| {
+        syscall(
+            OPENAT,
+            [AT_FDCWD, path.as_ptr() as usize, flags, 0o644, 0, 0],
+        )
+    };
+    let fd = open(O_RDWR | O_CREAT | O_EXCL);
+    if fd < 0 {
+        return false;
+    }
+    let fd = fd as usize;
+    let mut buffer = [0; 8];
+    let passed = write(fd, b"stored") == 6
+        && syscall(LSEEK, [fd, 0, SEEK_SET, 0, 0, 0]) == 0
+        && read(fd, &mut buffer) == 6
+        && &buffer[..6] == b"stored";
+    close(fd) == 0
+        && passed
+        && syscall(UNLINKAT, [AT_FDCWD, path.as_ptr() as usize, 0, 0, 0, 0]) == 0
+        && open(O_RDWR) == ENOENT
+}
+
+// Device nodes read and write as they do on Linux.
+fn check_devices() -> bool {
+    let open = |path: &[u8]| syscall(OPENAT, [AT_FDCWD, path.as_ptr() as usize, O_RDWR, 0, 0, 0]);
+    let fds = [
+        open(b"/dev/zero\0"),
+        open(b"/dev/null\0"),
+        open(b"/dev/urandom\0"),
+    ];
+    if fds.iter().any(|&fd| fd < 0) {
+        return false;
+    }
+    let [zero, null, random] = fds.map(|fd| fd as usize);
+    let mut buffer = [0xff; 16];
+    let passed = read(zero, &mut buffer) == 16
+        && buffer == [0; 16]
+        && write(null, b"discarded") == 9
+        && read(null, &mut buffer) == 0
+        && read(random, &mut buffer) == 16
+        && buffer != [0; 16];
+    fds.iter().all(|&fd| close(fd as usize) == 0) && passed
+}
+
+// Nothing can be waiting on a futex only this process can see, and a wait
+// for a value the word doesn't hold returns at once.
+fn check_futex() -> bool {
+    let word: u32 = 1;
+    let address = &word as *const u32 as usize;
+    syscall(FUTEX, [address, FUTEX_WAIT, 0, 0, 0, 0]) == EAGAIN
+        && syscall(FUTEX, [address, FUTEX_WAKE, 1, 0, 0, 0]) == 0
+}
+
+fn sigaction(signal: usize, handler: usize) -> isize {
+    // sa_handler, sa_flags and sa_mask.
+    let action = [handler, 0, 0];
+    syscall(RT_SIGACTION, [signal, action.as_ptr() as usize, 0, 8, 0, 0])
+}
+
+fn kill(pid: isize, signal: usize) -> isize {
+    syscall(KILL, [pid as usize, signal, 0, 0, 0, 0])
+}
+
+static CAUGHT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn catch_signal(signal: usize) {
+    CAUGHT.store(signal, Ordering::Relaxed);
+}
+
+extern "C" fn exit_with_signal(signal: usize) {
+    exit(signal as i32)
+}
+
+// A handler runs for a signal sent to itself and returns to where it left
+// off, a fault can be caught, and a signal nothing handles kills with 128
+// plus its number.
+fn check_signals() -> bool {
+    if sigaction(SIGUSR1, catch_signal as usize) != 0
+        || kill(getpid(), SIGUSR1) != 0
+        || CAUGHT.load(Ordering::Relaxed) != SIGUSR1
+    {
+        return false;
+    }
+
+    let child = fork();
+    if child == 0 {
+        sigaction(SIGSEGV, exit_with_signal as usize);
+        unsafe { core::ptr::read_volatile(8 as *const usize) };
+        exit(0);
+    }
+    if child < 0 || wait(child) != Ok((child, SIGSEGV as i32)) {
+        return false;
+    }
+
+    let child = fork();
+    if child == 0 {
+        loop {
+            getpid();
+        }
+    }
+    child > 0 && kill(child, SIGTERM) == 0 && wait(child) == Ok((child, 128 + SIGTERM as i32))
+}
+
+fn map_shared(fd: usize, prot: usize) -> isize {
+    // MAP_SHARED
+    syscall(MMAP, [0, PAGE_SIZE, prot, 0x01, fd, 0])
+}
+
+// A forked child writes through a mapping it inherited, and shared memory
+// opened by name read-only can't be mapped writable.
+fn check_shared_memory() -> bool {
+    let name = b"/init-shm\0".as_ptr() as usize;
+    let fd = syscall(SHM_OPEN, [name, O_RDWR | O_CREAT, PAGE_SIZE, 0, 0, 0]);
+    if fd < 0 {
+        return false;
+    }
+    // PROT_READ | PROT_WRITE
+    let address = map_shared(fd as usize, 3);
+    if address < 0 {
+        return false;
+    }
+    let memory = address as *mut usize;
+
+    let child = fork();
+    if child == 0 {
+        let fd = syscall(SHM_OPEN, [name, O_RDONLY, 0, 0, 0, 0]);
+        if fd < 0 || map_shared(fd as usize, 3) != EACCES || map_shared(fd as usize, 1) < 0 {
+            exit(1);
+        }
+        unsafe { memory.write_volatile(42) };
+        exit(0);
+    }
+    child > 0
+        && wait(child) == Ok((child, 0))
+        && unsafe { memory.read_volatile() } == 42
+        && syscall(MUNMAP, [address as usize, PAGE_SIZE, 0, 0, 0, 0]) == 0
+        && close(fd as usize) == 0
+}
+
+// The pages init has faulted in show up in its usage, and once the
+// descriptor limit is down to the descriptors it has, a dup fails.
+fn check_limits() -> bool {
+    // struct rusage, with ru_maxrss at word 4 and ru_minflt at word 8.
+    let mut usage = [0usize; 18];
+    if syscall(GETRUSAGE, [0, usage.as_mut_ptr() as usize, 0, 0, 0, 0]) != 0
+        || usage[4] == 0
+        || usage[8] == 0
+    {
+        return false;
+    }
+
+    let fd = syscall(DUP, [0, 0, 0, 0, 0, 0]);
+    if fd < 0 {
+        return false;
+    }
+    // The soft limit can come down and go back up, but the hard one can
+    // only come down.
+    let mut saved = [0usize; 2];
+    let old = saved.as_mut_ptr() as usize;
+    if syscall(PRLIMIT64, [0, RLIMIT_NOFILE, 0, old, 0, 0]) != 0 {
+        close(fd as usize);
+        return false;
+    }
+    let lowered = [fd as usize + 1, saved[1]];
+    let raised = [saved[0], saved[1] + 1];
+    let (lower, raise) = (lowered.as_ptr() as usize, raised.as_ptr() as usize);
+    let passed = syscall(PRLIMIT64, [0, RLIMIT_NOFILE, lower, 0, 0, 0]) == 0
+        && syscall(DUP, [0, 0, 0, 0, 0, 0]) == EMFILE
+        && syscall(PRLIMIT64, [0, RLIMIT_NOFILE, raise, 0, 0, 0]) == EPERM
+        && syscall(PRLIMIT64, [0, RLIMIT_NOFILE, old, 0, 0, 0]) == 0;
+    close(fd as usize) == 0 && passed
+}
+
+#[no_mangle]
+#[link_section = ".text._start"]
+extern "C" fn _start() -> ! {
+    let pid = getpid();
+    println!("init: running as PID {}", pid);
+
+    check(1, "getpid", pid > 0);
+    check(2, "fork and exec", check_fork_and_exec());
+    check(3, "copy on write", check_copy_on_write());
+    check(4, "brk", check_brk());
+    check(5, "mmap", check_mmap());
+    check(6, "pipe", check_pipe());
+    check(7, "dup", check_dup());
+    check(8, "files", check_files());
+    check(9, "devices", check_devices());
+    check(10, "futex", check_futex());
+    check(11, "signals", check_signals());
+    check(12, "shared memory", check_shared_memory());
+    check(13, "limits", check_limits());
+
+    println!("init: all checks passed");
+    if pid != 1 {
+        exit(0);
+    }
+    // Orphans are handed to init whenever their parents exit, so rather
+    // than failing with no children, waiting sleeps until there's one.
+    loop {
+        let _ = wait(-1);
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    let _ = write(2, b"init: panicked\n");
+    exit(255)
+}